@@ -1,7 +1,18 @@
-use std::hint::black_box;
+use std::{
+    hint::black_box,
+    sync::{atomic::AtomicBool, Mutex},
+};
 
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
-use post::{pow, prove::Prover, prove::Prover8_56, prove::ProvingParams};
+use post::{
+    config::{ProofConfig, ScryptParams},
+    initialize::{CpuInitializer, Initialize},
+    pow::{self, randomx::RandomXFlag},
+    prove::{
+        generate_proof_with_options, Prover, Prover8_56, ProvingOptions, ProvingParams,
+        StopCondition,
+    },
+};
 #[cfg(not(windows))]
 use pprof::criterion::{Output, PProfProfiler};
 use rand::{thread_rng, RngCore};
@@ -83,6 +94,113 @@ fn prover_bench(c: &mut Criterion) {
     }
 }
 
+/// Compare passing candidates one by one and in batches to a `consume` taking a lock,
+/// with a low difficulty (many candidates).
+fn consume_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("consume");
+
+    let mut data = vec![0; 64 * MIB];
+    thread_rng().fill_bytes(&mut data);
+    group.throughput(criterion::Throughput::Bytes(data.len() as u64));
+
+    let chunk_size = 64 * KIB;
+    let nonces = 64;
+    let params = ProvingParams {
+        difficulty: u64::MAX / 64,  // ~1.5% of labels are candidates for a nonce
+        pow_difficulty: [0xFF; 32], // extremely easy to find pow nonce
+    };
+    let mut pow_prover = pow::MockProver::new();
+    pow_prover.expect_prove().returning(|_, _, _, _| Ok(0));
+    let prover = Prover8_56::new(CHALLENGE, 0..nonces, params, &pow_prover, &[7; 32]).unwrap();
+    let candidates = Mutex::new(vec![0usize; nonces as usize]);
+
+    group.bench_function("single", |b| {
+        b.iter(|| {
+            data.chunks_exact(chunk_size)
+                .par_bridge()
+                .for_each(|chunk| {
+                    prover.prove(chunk, 0, |nonce, _| {
+                        candidates.lock().unwrap()[nonce as usize] += 1;
+                        None
+                    });
+                })
+        });
+    });
+    group.bench_function("batched", |b| {
+        b.iter(|| {
+            data.chunks_exact(chunk_size)
+                .par_bridge()
+                .for_each(|chunk| {
+                    prover.prove_batched(chunk, 0, |nonce, indices| {
+                        candidates.lock().unwrap()[nonce as usize] += indices.len();
+                        None
+                    });
+                })
+        });
+    });
+    black_box(candidates);
+}
+
+/// Compare the latency of passes failing to find a proof, with the PoW of the next pass
+/// calculated after the search of the current one (sequential) and during it (pipelined).
+fn pipeline_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pipeline");
+    group.sample_size(10);
+
+    let datadir = tempfile::tempdir().unwrap();
+    CpuInitializer::new(ScryptParams::new(2, 1, 1))
+        .initialize(
+            datadir.path(),
+            &[77; 32],
+            &[0u8; 32],
+            64 * 1024,
+            4,
+            64 * 1024,
+            None,
+        )
+        .unwrap();
+    let cfg = ProofConfig {
+        k1: 26,
+        k2: 37,
+        k3: 37,
+        pow_difficulty: [0x3F; 32], // ~16 RandomX hashes per nonce group (with 4 units)
+    };
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let pow = std::sync::Arc::new(pow::randomx::PoW::new(pow_flags).unwrap());
+
+    for pipeline_pow in [false, true] {
+        let name = if pipeline_pow {
+            "pipelined"
+        } else {
+            "sequential"
+        };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let options = ProvingOptions {
+                    // impossible to find a proof
+                    target_indices: Some(u32::MAX),
+                    pow_prover: Some(pow.clone()),
+                    pipeline_pow,
+                    stop_condition: StopCondition {
+                        max_passes: Some(4),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                generate_proof_with_options(
+                    datadir.path(),
+                    b"hello world, CHALLENGE me!!!!!!!",
+                    cfg,
+                    64,
+                    AtomicBool::new(false),
+                    options,
+                )
+                .expect_err("no proof should be found")
+            });
+        });
+    }
+}
+
 #[cfg(not(windows))]
 fn config() -> Criterion {
     Criterion::default().with_profiler(PProfProfiler::new(100, Output::Flamegraph(None)))
@@ -95,7 +213,7 @@ fn config() -> Criterion {
 criterion_group!(
     name = benches;
     config = config();
-    targets=prover_bench,
+    targets=prover_bench, consume_bench, pipeline_bench,
 );
 
 criterion_main!(benches);
@@ -66,6 +66,7 @@ pub unsafe extern "C" fn free_proof(proof: *mut Proof) {
 /// Generates a proof of space for the given challenge using the provided parameters.
 /// Returns a pointer to a Proof struct which should be freed with free_proof() after use.
 /// If an error occurs, prints it on stderr and returns null.
+/// `threads` equal to 0 means all available threads.
 /// # Safety
 /// `challenge` must be a 32-byte array.
 #[no_mangle]
@@ -106,6 +107,7 @@ fn _generate_proof(
     let challenge = challenge.try_into()?;
 
     let stop = AtomicBool::new(false);
+    let threads = prove::threads_or_available(threads);
     let proof = prove::generate_proof(datadir, challenge, cfg, nonces, threads, pow_flags, stop)?;
     Ok(Box::new(Proof::from(proof)))
 }
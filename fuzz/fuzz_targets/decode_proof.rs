@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use post::prove::Proof;
+
+fuzz_target!(|input: (u64, u32, &[u8])| {
+    let (num_labels, k2, data) = input;
+    // Any number of labels must be handled without panicking.
+    if let Ok(proof) = Proof::decode(data, num_labels, k2) {
+        assert_eq!(data, proof.encode());
+        // The length is validated, so there are always K2 indices.
+        proof.unpack_indices(num_labels, k2).unwrap();
+    }
+});
@@ -0,0 +1,70 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use post::{
+    config::{InitConfig, ProofConfig, ScryptParams},
+    metadata::ProofMetadata,
+    pow::{self, PowVerifier},
+    prove::Proof,
+    verification::Verifier,
+};
+
+/// Accepts any PoW, so that the fuzzer gets to the checks of the indices.
+struct AnyPoW;
+
+impl PowVerifier for AnyPoW {
+    fn verify(
+        &self,
+        _pow: u64,
+        _nonce_group: u8,
+        _challenge: &[u8; 8],
+        _difficulty: &[u8; 32],
+        _miner_id: &[u8; 32],
+    ) -> Result<(), pow::Error> {
+        Ok(())
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input<'a> {
+    proof: &'a [u8],
+    node_id: [u8; 32],
+    commitment_atx_id: [u8; 32],
+    challenge: [u8; 32],
+    num_units: u32,
+}
+
+fuzz_target!(|input: Input| {
+    // Not decoded, to get proofs with indices of any length to the verifier.
+    if input.proof.len() < 12 {
+        return;
+    }
+    let (header, indices) = input.proof.split_at(12);
+    let proof = Proof {
+        nonce: u32::from_le_bytes(header[..4].try_into().unwrap()),
+        indices: indices.into(),
+        pow: u64::from_le_bytes(header[4..].try_into().unwrap()),
+    };
+    let cfg = ProofConfig {
+        k1: 26,
+        k2: 37,
+        k3: 4,
+        pow_difficulty: [0xFF; 32],
+    };
+    let init_cfg = InitConfig {
+        min_num_units: 0,
+        max_num_units: u32::MAX,
+        labels_per_unit: 1 << 20,
+        scrypt: ScryptParams::new(2, 1, 1),
+    };
+    let metadata = ProofMetadata {
+        node_id: input.node_id,
+        commitment_atx_id: input.commitment_atx_id,
+        challenge: input.challenge,
+        num_units: input.num_units,
+    };
+    let verifier = Verifier::new(Box::new(AnyPoW));
+    // Only an error is allowed for invalid input, never a panic.
+    let _ = verifier.verify(&proof, &metadata, &cfg, &init_cfg);
+});
@@ -9,8 +9,10 @@ use base64::{engine::general_purpose, Engine};
 use clap::{Args, Parser, Subcommand, ValueEnum};
 use eyre::Context;
 use post::{
+    checksums,
     config::ScryptParams,
     initialize::{CpuInitializer, Initialize, LABEL_SIZE},
+    reader::ReaderConfig,
 };
 use rand::seq::IteratorRandom;
 use rayon::prelude::{ParallelBridge, ParallelIterator};
@@ -71,6 +73,11 @@ struct InitializeArgs {
     #[arg(long)]
     provider: Option<u32>,
 
+    /// Write checksums of the data blocks next to the POS files,
+    /// to detect corruption while proving.
+    #[arg(long)]
+    checksums: bool,
+
     #[clap(value_enum, default_value_t = InitializationMethod::Gpu)]
     method: InitializationMethod,
 }
@@ -204,6 +211,15 @@ fn initialize(args: InitializeArgs) -> eyre::Result<()> {
             labels_initialized as f64 * 16.0 / elapsed.as_secs_f64() / 1024.0 / 1024.0,
             metadata.nonce,
         );
+    if args.checksums {
+        checksums::write_checksums(
+            &[args.output.as_path()],
+            &metadata,
+            &ReaderConfig::default(),
+            checksums::DEFAULT_BLOCK_SIZE,
+        )
+        .wrap_err("writing checksums")?;
+    }
     Ok(())
 }
 
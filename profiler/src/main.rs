@@ -230,10 +230,14 @@ fn proving(args: ProvingArgs) -> eyre::Result<()> {
         let reader = post::reader::read_from(BufReader::new(file), batch_size, total_size, None);
         let start = time::Instant::now();
         pool.install(|| {
-            reader.par_bridge().for_each(|batch| {
-                prover.prove(&batch.data, batch.pos, |_, _| None);
-            })
-        });
+            reader
+                .par_bridge()
+                .try_for_each(|batch| -> eyre::Result<()> {
+                    let batch = batch?;
+                    prover.prove(&batch.data, batch.pos, |_, _| None);
+                    Ok(())
+                })
+        })?;
         total_time += start.elapsed();
         processed += args.data_size;
     }
@@ -53,7 +53,7 @@ impl PostService {
             cfg,
             init_cfg,
             nonces,
-            threads,
+            threads: post::prove::threads_or_available(threads),
             pow_flags,
             verifier: Verifier::new(Box::new(PoW::new(RandomXFlag::get_recommended_flags())?)),
             stop: Arc::new(AtomicBool::new(false)),
@@ -93,10 +93,8 @@ impl crate::client::PostService for PostService {
             }
         }
 
-        let ch: [u8; 32] = challenge
-            .as_slice()
-            .try_into()
-            .map_err(|_| eyre::eyre!("invalid challenge format"))?;
+        let ch = post::challenge::from_slice(&challenge)
+            .map_err(|e| eyre::eyre!("invalid challenge format: {e}"))?;
         log::info!("starting proof generation for challenge {ch:X?}");
         let pow_flags = self.pow_flags;
         let cfg = self.cfg;
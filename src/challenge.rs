@@ -0,0 +1,35 @@
+//! Challenges passed as slices, i.e. over FFI, the network or parsed from hex.
+//!
+//! The proving and verification APIs take `&[u8; 32]`. Converting to it
+//! with [from_slice] gives an error instead of a panic on a wrong length.
+
+/// The size of a challenge in bytes.
+pub const CHALLENGE_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error("invalid challenge length: {0} bytes (expected {CHALLENGE_SIZE})")]
+    InvalidLength(usize),
+}
+
+/// Get the challenge from `s`, which must be exactly [CHALLENGE_SIZE] bytes long.
+pub fn from_slice(s: &[u8]) -> Result<[u8; CHALLENGE_SIZE], Error> {
+    s.try_into().map_err(|_| Error::InvalidLength(s.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_from_slice() {
+        let bytes = (0..40).collect::<Vec<u8>>();
+        assert_eq!(
+            Ok(<[u8; 32]>::try_from(&bytes[..32]).unwrap()),
+            from_slice(&bytes[..32])
+        );
+        assert_eq!(Err(Error::InvalidLength(31)), from_slice(&bytes[..31]));
+        assert_eq!(Err(Error::InvalidLength(33)), from_slice(&bytes[..33]));
+        assert_eq!(Err(Error::InvalidLength(0)), from_slice(&[]));
+    }
+}
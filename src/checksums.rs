@@ -0,0 +1,226 @@
+//! Checksums of fixed-size blocks of the POS data, to detect silent corruption while proving.
+//!
+//! The checksums of a POS file are stored in a sidecar file next to it
+//! (see [sidecar_path]): the block size (u64 LE) followed by the checksum
+//! (u64 LE) of every block, the last one possibly partial.
+//! They are written once the data is initialized (see [write_checksums]) and checked
+//! for every batch read if enabled with [ReaderConfig::verify_checksums].
+//! A mismatch fails the proving with [ReaderError::BatchCorrupt] pointing at the corrupt block.
+//!
+//! [ReaderConfig::verify_checksums]: crate::reader::ReaderConfig::verify_checksums
+//! [ReaderError::BatchCorrupt]: crate::reader::ReaderError::BatchCorrupt
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use eyre::Context;
+
+use crate::{
+    metadata::PostMetadata,
+    reader::{self, ReaderConfig},
+};
+
+/// The default size of a checksummed block. It divides the default batch size.
+pub const DEFAULT_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// The sidecar file with the checksums of the POS file: `{pos_file}.checksums`.
+pub fn sidecar_path(pos_file: &Path) -> PathBuf {
+    let mut path = pos_file.as_os_str().to_owned();
+    path.push(".checksums");
+    path.into()
+}
+
+/// A cheap checksum of a block: the first 8 bytes of its blake3 hash.
+pub fn checksum(block: &[u8]) -> u64 {
+    u64::from_le_bytes(blake3::hash(block).as_bytes()[..8].try_into().unwrap())
+}
+
+/// The checksums of the blocks of a single POS file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumTable {
+    block_size: usize,
+    sums: Vec<u64>,
+}
+
+impl ChecksumTable {
+    /// Calculate the checksums of blocks of `block_size` bytes read from `reader`.
+    pub fn compute<R: Read>(mut reader: R, block_size: usize) -> io::Result<Self> {
+        if block_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "block size must be > 0",
+            ));
+        }
+        let mut sums = Vec::new();
+        let mut block = Vec::with_capacity(block_size);
+        loop {
+            block.clear();
+            reader
+                .by_ref()
+                .take(block_size as u64)
+                .read_to_end(&mut block)?;
+            if block.is_empty() {
+                break;
+            }
+            sums.push(checksum(&block));
+        }
+        Ok(Self { block_size, sums })
+    }
+
+    pub fn read_from(path: &Path) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        BufReader::new(File::open(path)?).read_to_end(&mut bytes)?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid checksums file");
+        if bytes.len() < 8 || bytes.len() % 8 != 0 {
+            return Err(invalid());
+        }
+        let mut words = bytes
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()));
+        let block_size = words.next().unwrap();
+        if block_size == 0 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            block_size: block_size.try_into().map_err(|_| invalid())?,
+            sums: words.collect(),
+        })
+    }
+
+    pub fn write_to(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&(self.block_size as u64).to_le_bytes())?;
+        for sum in &self.sums {
+            writer.write_all(&sum.to_le_bytes())?;
+        }
+        writer.flush()
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Check `data` read at `offset` in the file, which must be at a block boundary.
+    ///
+    /// Returns the offset of the first block that doesn't match its checksum.
+    pub(crate) fn check(&self, offset: u64, data: &[u8]) -> Result<(), u64> {
+        debug_assert_eq!(0, offset % self.block_size as u64);
+        let first = (offset / self.block_size as u64) as usize;
+        for (i, block) in data.chunks(self.block_size).enumerate() {
+            if self.sums.get(first + i) != Some(&checksum(block)) {
+                return Err(offset + (i * self.block_size) as u64);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write the checksum sidecar files of all POS files in `datadirs`.
+///
+/// The files are named according to [ReaderConfig::file_name] and might be split
+/// between the directories, like for proving. Every sidecar file is written
+/// next to its POS file.
+///
+/// [ReaderConfig::file_name]: crate::reader::ReaderConfig::file_name
+pub fn write_checksums(
+    datadirs: &[&Path],
+    metadata: &PostMetadata,
+    cfg: &ReaderConfig,
+    block_size: usize,
+) -> eyre::Result<()> {
+    for index in 0..metadata.expected_file_count() {
+        let path = reader::find_file(datadirs, cfg, index)?;
+        let file = File::open(&path).wrap_err_with(|| format!("opening {}", path.display()))?;
+        let table = ChecksumTable::compute(BufReader::new(file), block_size)
+            .wrap_err_with(|| format!("calculating checksums of {}", path.display()))?;
+        let sidecar = sidecar_path(&path);
+        table
+            .write_to(&sidecar)
+            .wrap_err_with(|| format!("writing {}", sidecar.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn checking_blocks() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let table = ChecksumTable::compute(Cursor::new(&data), 32).unwrap();
+        assert_eq!(4, table.sums.len());
+
+        assert_eq!(Ok(()), table.check(0, &data));
+        assert_eq!(Ok(()), table.check(64, &data[64..]));
+
+        let mut corrupt = data.clone();
+        corrupt[70] ^= 1;
+        assert_eq!(Err(64), table.check(0, &corrupt));
+        assert_eq!(Ok(()), table.check(0, &corrupt[..64]));
+        // more data than checksummed
+        assert_eq!(
+            Err(96),
+            table.check(64, &[data[64..].to_vec(), vec![0; 8]].concat())
+        );
+    }
+
+    #[test]
+    fn sidecar_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let pos_file = dir.path().join("postdata_0.bin");
+        assert_eq!(
+            dir.path().join("postdata_0.bin.checksums"),
+            sidecar_path(&pos_file)
+        );
+
+        let table = ChecksumTable::compute(Cursor::new(vec![7u8; 1000]), 128).unwrap();
+        table.write_to(&sidecar_path(&pos_file)).unwrap();
+        assert_eq!(
+            table,
+            ChecksumTable::read_from(&sidecar_path(&pos_file)).unwrap()
+        );
+
+        std::fs::write(sidecar_path(&pos_file), [0u8; 8]).unwrap();
+        assert!(ChecksumTable::read_from(&sidecar_path(&pos_file)).is_err());
+    }
+
+    #[test]
+    fn writing_checksums_of_split_data() {
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let cfg = ReaderConfig {
+            file_name: |i| format!("data-{i}.bin"),
+            ..Default::default()
+        };
+        // 2 files of 64 bytes, each in another directory.
+        let metadata = PostMetadata {
+            node_id: [0; 32],
+            commitment_atx_id: [0; 32],
+            labels_per_unit: 4,
+            num_units: 2,
+            max_file_size: 64,
+            nonce: None,
+            last_position: None,
+        };
+        for (index, dir) in dirs.iter().enumerate() {
+            let path = dir.path().join((cfg.file_name)(index));
+            std::fs::write(path, [index as u8; 64]).unwrap();
+        }
+
+        let datadirs = [dirs[0].path(), dirs[1].path()];
+        write_checksums(&datadirs, &metadata, &cfg, 32).unwrap();
+        for (index, dir) in dirs.iter().enumerate() {
+            let sidecar = sidecar_path(&dir.path().join((cfg.file_name)(index)));
+            let expected = ChecksumTable::compute(Cursor::new([index as u8; 64]), 32).unwrap();
+            assert_eq!(expected, ChecksumTable::read_from(&sidecar).unwrap());
+        }
+
+        // A file in none of the directories.
+        assert!(write_checksums(&datadirs[..1], &metadata, &cfg, 32).is_err());
+    }
+}
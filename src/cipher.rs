@@ -1,6 +1,72 @@
 use aes::cipher::{generic_array::GenericArray, KeyInit};
 use aes::Aes128;
 
+use crate::verify_core;
+
+/// The AES implementation used by the [aes] crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AesBackend {
+    /// CPU instructions (AES-NI on x86, ARMv8 Cryptography Extensions on aarch64).
+    Hardware,
+    /// Constant-time software implementation. It's many times slower.
+    Software,
+}
+
+/// Reports which AES backend is in use.
+///
+/// It mirrors the selection done by the [aes] crate, which picks the hardware
+/// backend at runtime if the CPU supports it (the feature might be masked in VMs).
+pub fn aes_backend() -> AesBackend {
+    #[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), not(aes_force_soft)))]
+    if std::is_x86_feature_detected!("aes") {
+        return AesBackend::Hardware;
+    }
+    // The ARMv8 backend must be explicitly enabled with `--cfg aes_armv8`.
+    #[cfg(all(target_arch = "aarch64", aes_armv8, not(aes_force_soft)))]
+    if std::arch::is_aarch64_feature_detected!("aes") {
+        return AesBackend::Hardware;
+    }
+    AesBackend::Software
+}
+
+/// Derives the keys of the AES ciphers used for proving.
+///
+/// Meant for researching alternate schemes. Proofs created with a derivation
+/// other than [Blake3KeyDerivation] are rejected by the [verifier](crate::verification::Verifier).
+pub trait KeyDerivation: std::fmt::Debug {
+    /// The key of the cipher shared by the nonces in the nonce group.
+    fn cipher_key(&self, challenge: &[u8; 32], nonce_group: u32, pow: u64) -> [u8; 16];
+    /// The key of the cipher checking the LSB part of the difficulty for a nonce.
+    fn lazy_cipher_key(
+        &self,
+        challenge: &[u8; 32],
+        nonce: u32,
+        nonce_group: u32,
+        pow: u64,
+    ) -> [u8; 16];
+}
+
+/// The key derivation of the protocol, see [verify_core::cipher_key]
+/// and [verify_core::lazy_cipher_key].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake3KeyDerivation;
+
+impl KeyDerivation for Blake3KeyDerivation {
+    fn cipher_key(&self, challenge: &[u8; 32], nonce_group: u32, pow: u64) -> [u8; 16] {
+        verify_core::cipher_key(challenge, nonce_group, pow)
+    }
+
+    fn lazy_cipher_key(
+        &self,
+        challenge: &[u8; 32],
+        nonce: u32,
+        nonce_group: u32,
+        pow: u64,
+    ) -> [u8; 16] {
+        verify_core::lazy_cipher_key(challenge, nonce, nonce_group, pow)
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct AesCipher {
     pub(crate) aes: Aes128,
@@ -12,29 +78,46 @@ impl AesCipher {
     /// Create new AES cipher for the given challenge and nonce.
     /// AES key = blake3(challenge, nonce_group, pow)
     pub(crate) fn new(challenge: &[u8; 32], nonce_group: u32, pow: u64) -> Self {
-        let mut hasher = blake3::Hasher::new();
-        hasher.update(challenge);
-        hasher.update(&nonce_group.to_le_bytes());
-        hasher.update(&pow.to_le_bytes());
+        Self::new_with(&Blake3KeyDerivation, challenge, nonce_group, pow)
+    }
+
+    pub(crate) fn new_lazy(challenge: &[u8; 32], nonce: u32, nonce_group: u32, pow: u64) -> Self {
+        Self::new_lazy_with(&Blake3KeyDerivation, challenge, nonce, nonce_group, pow)
+    }
+
+    /// Like [AesCipher::new], with the key derived by `derivation`.
+    pub(crate) fn new_with<K: KeyDerivation + ?Sized>(
+        derivation: &K,
+        challenge: &[u8; 32],
+        nonce_group: u32,
+        pow: u64,
+    ) -> Self {
         Self {
-            aes: Aes128::new(GenericArray::from_slice(
-                &hasher.finalize().as_bytes()[..16],
-            )),
+            aes: Aes128::new(GenericArray::from_slice(&derivation.cipher_key(
+                challenge,
+                nonce_group,
+                pow,
+            ))),
             nonce_group,
             pow,
         }
     }
 
-    pub(crate) fn new_lazy(challenge: &[u8; 32], nonce: u32, nonce_group: u32, pow: u64) -> Self {
-        let mut hasher = blake3::Hasher::new();
-        hasher.update(challenge);
-        hasher.update(&nonce_group.to_le_bytes());
-        hasher.update(&pow.to_le_bytes());
-        hasher.update(&nonce.to_le_bytes());
+    /// Like [AesCipher::new_lazy], with the key derived by `derivation`.
+    pub(crate) fn new_lazy_with<K: KeyDerivation + ?Sized>(
+        derivation: &K,
+        challenge: &[u8; 32],
+        nonce: u32,
+        nonce_group: u32,
+        pow: u64,
+    ) -> Self {
         Self {
-            aes: Aes128::new(GenericArray::from_slice(
-                &hasher.finalize().as_bytes()[..16],
-            )),
+            aes: Aes128::new(GenericArray::from_slice(&derivation.lazy_cipher_key(
+                challenge,
+                nonce,
+                nonce_group,
+                pow,
+            ))),
             nonce_group,
             pow,
         }
@@ -46,7 +129,66 @@ mod tests {
     use aes::cipher::{generic_array::GenericArray, BlockEncrypt};
     use proptest::prelude::*;
 
-    use crate::cipher::AesCipher;
+    use crate::cipher::{aes_backend, AesBackend, AesCipher, Blake3KeyDerivation, KeyDerivation};
+
+    #[test]
+    #[cfg(all(target_arch = "x86_64", not(aes_force_soft)))]
+    fn detects_hardware_aes() {
+        let expected = if std::is_x86_feature_detected!("aes") {
+            AesBackend::Hardware
+        } else {
+            AesBackend::Software
+        };
+        assert_eq!(expected, aes_backend());
+    }
+
+    #[test]
+    #[cfg(aes_force_soft)]
+    fn forced_software_aes() {
+        assert_eq!(AesBackend::Software, aes_backend());
+    }
+
+    #[test]
+    fn default_key_derivation_vector() {
+        let challenge: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let pow = 0x1234_5678_9abc_def0;
+        assert_eq!(
+            [
+                0x0d, 0x2d, 0x52, 0xb1, 0x6f, 0x44, 0x6f, 0x3d, 0x06, 0xc2, 0xa3, 0xd3, 0xe7, 0x7b,
+                0x41, 0xa8
+            ],
+            Blake3KeyDerivation.cipher_key(&challenge, 3, pow)
+        );
+        assert_eq!(
+            [
+                0x58, 0x97, 0x56, 0x30, 0xf2, 0x70, 0x7e, 0x10, 0x02, 0x10, 0xb1, 0x1b, 0x4b, 0x75,
+                0xfc, 0xf5
+            ],
+            Blake3KeyDerivation.lazy_cipher_key(&challenge, 50, 3, pow)
+        );
+
+        let mut out = GenericArray::from([0u8; 16]);
+        AesCipher::new(&challenge, 3, pow)
+            .aes
+            .encrypt_block_b2b(&GenericArray::from([0u8; 16]), &mut out);
+        assert_eq!(
+            [
+                0xe8, 0x92, 0x94, 0x6e, 0x5e, 0x29, 0x0d, 0xc5, 0x5c, 0xcc, 0x47, 0x6d, 0x6c, 0x61,
+                0x17, 0x3d
+            ],
+            out.as_slice()
+        );
+        AesCipher::new_lazy(&challenge, 50, 3, pow)
+            .aes
+            .encrypt_block_b2b(&GenericArray::from([0u8; 16]), &mut out);
+        assert_eq!(
+            [
+                0xb7, 0x17, 0x30, 0x31, 0x9d, 0x33, 0x33, 0x7e, 0xb6, 0xc4, 0xfd, 0xb4, 0x09, 0x9a,
+                0xbf, 0x73
+            ],
+            out.as_slice()
+        );
+    }
 
     proptest! {
         #[test]
@@ -13,18 +13,22 @@ pub(crate) fn compress_indices(indexes: &[u64], keep_bits: usize) -> Vec<u8> {
 
 /// Decompress indexes from a byte slice, previously compressed with `compress_indices`.
 /// Might return more indexes than the original, if the last byte contains unused bits.
+///
+/// Returns no indexes if `bits` is not in 1..=64.
 pub(crate) fn decompress_indexes(indexes: &[u8], bits: usize) -> impl Iterator<Item = u64> + '_ {
+    let indexes = if (1..=64).contains(&bits) {
+        indexes
+    } else {
+        &[]
+    };
     BitSlice::<_, Lsb0>::from_slice(indexes)
-        .chunks_exact(bits)
+        .chunks_exact(bits.clamp(1, 64))
         .map(|chunk| chunk.load_le::<u64>())
 }
 
 /// Calculate the number of bits required to store the value.
 pub(crate) fn required_bits(value: u64) -> usize {
-    if value == 0 {
-        return 0;
-    }
-    (value.ilog2() + 1) as usize
+    crate::verify_core::required_bits(value)
 }
 
 #[cfg(test)]
@@ -64,6 +68,66 @@ mod tests {
             let decompressed: Vec<_> = decompress_indexes(&compressed, bits).take(indexes.len()).collect();
             assert_eq!(indexes.as_slice(), &decompressed);
         }
+
+        #[test]
+        fn decompress_index_matches_compression(indices: Vec<u64>, bits in 1usize..=64) {
+            let indices = indices.into_iter().map(|i| i & (u64::MAX >> (64 - bits))).collect::<Vec<_>>();
+            let compressed = compress_indices(&indices, bits);
+            for (pos, index) in indices.iter().enumerate() {
+                assert_eq!(Some(*index), crate::verify_core::decompress_index(&compressed, bits, pos));
+            }
+        }
+    }
+
+    #[test]
+    fn compress_decompress_all_keep_bits() {
+        for bits in 1..=64 {
+            let max = u64::MAX >> (64 - bits);
+            let indexes = [
+                0,
+                1,
+                max,
+                max >> 1,
+                (max >> 1) + 1,
+                max - 1,
+                0x5555_5555_5555_5555 & max,
+            ];
+            let compressed = compress_indices(&indexes, bits);
+            assert_eq!(
+                (indexes.len() * bits).div_ceil(8),
+                compressed.len(),
+                "bits: {bits}"
+            );
+            let decompressed: Vec<_> = decompress_indexes(&compressed, bits)
+                .take(indexes.len())
+                .collect();
+            assert_eq!(indexes.as_slice(), &decompressed, "bits: {bits}");
+        }
+    }
+
+    #[test]
+    fn required_bits_fit_all_indices() {
+        // Indices are in 0..num_labels, but the bits are derived from num_labels itself.
+        // For powers of two it takes one bit more than the biggest index needs (never less).
+        for bits in 1..64 {
+            let num_labels = 1u64 << bits;
+            assert_eq!(bits + 1, required_bits(num_labels));
+            assert_eq!(bits, required_bits(num_labels - 1));
+            assert_eq!(bits + 1, required_bits(num_labels + 1));
+
+            let max_index = num_labels - 1;
+            let keep_bits = required_bits(num_labels);
+            let compressed = compress_indices(&[max_index, 0, max_index], keep_bits);
+            let decompressed: Vec<_> = decompress_indexes(&compressed, keep_bits).take(3).collect();
+            assert_eq!(vec![max_index, 0, max_index], decompressed);
+        }
+    }
+
+    #[test]
+    fn decompress_invalid_bits() {
+        assert_eq!(0, decompress_indexes(&[0xFF; 16], 0).count());
+        assert_eq!(0, decompress_indexes(&[0xFF; 16], 65).count());
+        assert_eq!(2, decompress_indexes(&[0xFF; 16], 64).count());
     }
 
     #[test]
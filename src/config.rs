@@ -31,6 +31,40 @@ pub struct ProofConfig {
     pub pow_difficulty: [u8; 32],
 }
 
+/// The number of nonces tried in a single pass over the POS data, if not configured.
+pub const DEFAULT_NONCES_PER_PASS: u32 = 128;
+
+/// The proving configuration: the network parameters and the settings tuned per-hardware.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    pub proof: ProofConfig,
+    /// The number of nonces to try in a single pass over the POS data.
+    /// Each group of 16 nonces requires a separate PoW. Must be a positive multiple of 16.
+    /// Defaults to [DEFAULT_NONCES_PER_PASS].
+    ///
+    /// Although a pass can cover a partial nonce group (i.e. an assigned range
+    /// of `5..27`), the passes follow each other. With a count that isn't a multiple
+    /// of 16, the consecutive passes would split groups between them and calculate
+    /// the PoW of every split group twice.
+    #[serde(default)]
+    pub nonces_per_pass: Option<u32>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("nonces per pass must be a positive multiple of 16 (got {0})")]
+pub struct InvalidNoncesPerPass(pub u32);
+
+impl Config {
+    /// The configured (or default) number of nonces per pass.
+    pub fn nonces_per_pass(&self) -> Result<u32, InvalidNoncesPerPass> {
+        match self.nonces_per_pass.unwrap_or(DEFAULT_NONCES_PER_PASS) {
+            nonces if nonces > 0 && nonces % 16 == 0 => Ok(nonces),
+            nonces => Err(InvalidNoncesPerPass(nonces)),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Deserialize)]
 pub struct ScryptParams {
@@ -58,3 +92,42 @@ impl From<ScryptParams> for scrypt_jane::scrypt::ScryptParams {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserialize_config() {
+        let json = r#"{"k1": 26, "k2": 37, "k3": 37, "pow_difficulty": "000dfb23b0979b4b000000000000000000000000000000000000000000000000"}"#;
+        let cfg: Config = serde_json::from_str(json).unwrap();
+        assert_eq!(37, cfg.proof.k2);
+        assert_eq!(None, cfg.nonces_per_pass);
+        assert_eq!(Ok(DEFAULT_NONCES_PER_PASS), cfg.nonces_per_pass());
+
+        let json = r#"{"k1": 26, "k2": 37, "k3": 37, "pow_difficulty": "000dfb23b0979b4b000000000000000000000000000000000000000000000000", "nonces_per_pass": 64}"#;
+        let cfg: Config = serde_json::from_str(json).unwrap();
+        assert_eq!(Ok(64), cfg.nonces_per_pass());
+    }
+
+    #[test]
+    fn invalid_nonces_per_pass() {
+        let cfg = Config {
+            proof: ProofConfig {
+                k1: 26,
+                k2: 37,
+                k3: 37,
+                pow_difficulty: [0xFF; 32],
+            },
+            nonces_per_pass: Some(0),
+        };
+        assert_eq!(Err(InvalidNoncesPerPass(0)), cfg.nonces_per_pass());
+        for nonces in [1, 15, 17, 100] {
+            let cfg = Config {
+                nonces_per_pass: Some(nonces),
+                ..cfg
+            };
+            assert_eq!(Err(InvalidNoncesPerPass(nonces)), cfg.nonces_per_pass());
+        }
+    }
+}
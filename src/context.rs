@@ -0,0 +1,203 @@
+//! Values derived from the POS metadata and the proof config,
+//! computed once and shared by repeated proving and verification.
+
+use crate::{
+    config::{InitConfig, ProofConfig},
+    difficulty::{proving_difficulty, scale_pow_difficulty},
+    metadata::{PostMetadata, ProofMetadata},
+    verify_core::required_bits,
+};
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ContextError {
+    #[error("invalid number of labels: {num_units} units * {labels_per_unit} labels per unit")]
+    InvalidNumLabels {
+        num_units: u32,
+        labels_per_unit: u64,
+    },
+    #[error("invalid difficulty: {0}")]
+    InvalidDifficulty(String),
+    #[error("context created for {expected}, used for {got}")]
+    Mismatch { expected: String, got: String },
+}
+
+/// The constants of proving and verifying for a number of units and a proof config.
+///
+/// Build it once with [PostContext::new] (or [PostContext::for_proof] on the verifier side)
+/// and pass it to [ProvingOptions::context](crate::prove::ProvingOptions::context)
+/// or [Verifier::verify_with_context](crate::verification::Verifier::verify_with_context).
+#[derive(Debug, Clone, Copy)]
+pub struct PostContext {
+    num_units: u32,
+    labels_per_unit: u64,
+    cfg: ProofConfig,
+    num_labels: u64,
+    difficulty: u64,
+    pow_difficulty: [u8; 32],
+    required_bits: usize,
+}
+
+impl PostContext {
+    pub fn new(metadata: &PostMetadata, cfg: &ProofConfig) -> Result<Self, ContextError> {
+        Self::from_parts(metadata.num_units, metadata.labels_per_unit, cfg)
+    }
+
+    /// The context for verifying proofs with `metadata`.
+    pub fn for_proof(
+        metadata: &ProofMetadata,
+        cfg: &ProofConfig,
+        init_cfg: &InitConfig,
+    ) -> Result<Self, ContextError> {
+        Self::from_parts(metadata.num_units, init_cfg.labels_per_unit, cfg)
+    }
+
+    fn from_parts(
+        num_units: u32,
+        labels_per_unit: u64,
+        cfg: &ProofConfig,
+    ) -> Result<Self, ContextError> {
+        let num_labels = match (num_units as u64).checked_mul(labels_per_unit) {
+            Some(0) | None => {
+                return Err(ContextError::InvalidNumLabels {
+                    num_units,
+                    labels_per_unit,
+                })
+            }
+            Some(num_labels) => num_labels,
+        };
+        let difficulty =
+            proving_difficulty(cfg.k1, num_labels).map_err(ContextError::InvalidDifficulty)?;
+        Ok(Self {
+            num_units,
+            labels_per_unit,
+            cfg: *cfg,
+            num_labels,
+            difficulty,
+            pow_difficulty: scale_pow_difficulty(&cfg.pow_difficulty, num_units),
+            required_bits: required_bits(num_labels),
+        })
+    }
+
+    pub fn num_units(&self) -> u32 {
+        self.num_units
+    }
+
+    pub fn cfg(&self) -> &ProofConfig {
+        &self.cfg
+    }
+
+    /// The total number of labels: `num_units * labels_per_unit`.
+    pub fn num_labels(&self) -> u64 {
+        self.num_labels
+    }
+
+    /// The difficulty a label must be below, derived from K1 and the number of labels.
+    pub fn difficulty(&self) -> u64 {
+        self.difficulty
+    }
+
+    /// The PoW difficulty scaled by the number of units.
+    pub fn pow_difficulty(&self) -> &[u8; 32] {
+        &self.pow_difficulty
+    }
+
+    /// The number of bits of every compressed index of a proof.
+    pub fn required_bits(&self) -> usize {
+        self.required_bits
+    }
+
+    /// Check that the context was created for the number of units and labels and the config.
+    pub(crate) fn check_matches(
+        &self,
+        num_units: u32,
+        labels_per_unit: u64,
+        cfg: &ProofConfig,
+    ) -> Result<(), ContextError> {
+        let same_cfg = self.cfg.k1 == cfg.k1
+            && self.cfg.k2 == cfg.k2
+            && self.cfg.k3 == cfg.k3
+            && self.cfg.pow_difficulty == cfg.pow_difficulty;
+        if !same_cfg {
+            return Err(ContextError::Mismatch {
+                expected: format!("{:?}", self.cfg),
+                got: format!("{cfg:?}"),
+            });
+        }
+        self.check_units(num_units, labels_per_unit)
+    }
+
+    /// Check that the context was created for the number of units and labels.
+    pub(crate) fn check_units(
+        &self,
+        num_units: u32,
+        labels_per_unit: u64,
+    ) -> Result<(), ContextError> {
+        if self.num_units != num_units || self.labels_per_unit != labels_per_unit {
+            return Err(ContextError::Mismatch {
+                expected: format!(
+                    "{} units of {} labels",
+                    self.num_units, self.labels_per_unit
+                ),
+                got: format!("{num_units} units of {labels_per_unit} labels"),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prove::ProvingParams;
+
+    #[test]
+    fn derived_values() {
+        let cfg = ProofConfig {
+            k1: 279,
+            k2: 300,
+            k3: 65,
+            pow_difficulty: [0x0F; 32],
+        };
+        let metadata = PostMetadata {
+            num_units: 4,
+            labels_per_unit: 1000,
+            ..Default::default()
+        };
+        let context = PostContext::new(&metadata, &cfg).unwrap();
+        let params = ProvingParams::new(&metadata, &cfg).unwrap();
+        assert_eq!(4000, context.num_labels());
+        assert_eq!(params.difficulty, context.difficulty());
+        assert_eq!(&params.pow_difficulty, context.pow_difficulty());
+        assert_eq!(12, context.required_bits());
+
+        assert!(context.check_matches(4, 1000, &cfg).is_ok());
+        assert!(context.check_matches(5, 1000, &cfg).is_err());
+        assert!(context.check_units(4, 1001).is_err());
+        let other = ProofConfig { k2: 301, ..cfg };
+        assert!(context.check_matches(4, 1000, &other).is_err());
+    }
+
+    #[test]
+    fn invalid_number_of_labels() {
+        let cfg = ProofConfig {
+            k1: 279,
+            k2: 300,
+            k3: 65,
+            pow_difficulty: [0xFF; 32],
+        };
+        for (num_units, labels_per_unit) in [(0, 1000), (2, u64::MAX)] {
+            let metadata = PostMetadata {
+                num_units,
+                labels_per_unit,
+                ..Default::default()
+            };
+            assert_eq!(
+                ContextError::InvalidNumLabels {
+                    num_units,
+                    labels_per_unit
+                },
+                PostContext::new(&metadata, &cfg).unwrap_err()
+            );
+        }
+    }
+}
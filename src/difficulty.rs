@@ -1,5 +1,26 @@
 use primitive_types::U256;
 
+/// Constants of the proving difficulty computation.
+///
+/// The probability that a label is good is K1 / num_labels, multiplied by
+/// `target_numerator / target_denominator`. The default (1/1) is the protocol's
+/// difficulty. Other values are meant for experimenting with protocol variants,
+/// proofs generated with them won't pass the protocol verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DifficultyParams {
+    pub target_numerator: u32,
+    pub target_denominator: u32,
+}
+
+impl Default for DifficultyParams {
+    fn default() -> Self {
+        Self {
+            target_numerator: 1,
+            target_denominator: 1,
+        }
+    }
+}
+
 /// Calculate proving difficulty.
 ///
 /// K1 defines how many good labels are expected to be within all the labels.
@@ -8,15 +29,35 @@ use primitive_types::U256;
 /// The difficulty is calculated as:
 /// difficulty = 2^64 * K1 / num_labels
 pub(crate) fn proving_difficulty(k1: u32, num_labels: u64) -> Result<u64, String> {
+    proving_difficulty_with_params(k1, num_labels, &DifficultyParams::default())
+}
+
+/// Calculate proving difficulty with custom [DifficultyParams].
+///
+/// The difficulty is calculated as:
+/// difficulty = 2^64 * K1 * target_numerator / (num_labels * target_denominator)
+pub fn proving_difficulty_with_params(
+    k1: u32,
+    num_labels: u64,
+    params: &DifficultyParams,
+) -> Result<u64, String> {
     if num_labels == 0 {
         return Err("number of label blocks must be > 0".to_string());
     }
-    if num_labels <= k1 as u64 {
+    if params.target_denominator == 0 {
+        return Err("difficulty target denominator must be > 0".to_string());
+    }
+    // The expected number of good labels must be below the number of labels.
+    let expected = k1 as u128 * params.target_numerator as u128;
+    let labels = num_labels as u128 * params.target_denominator as u128;
+    if labels <= expected {
         return Err(format!(
-            "number of labels ({num_labels}) must be bigger than k1 ({k1})"
+            "number of labels ({num_labels}) must be bigger than k1 ({k1}) scaled by the target ({}/{})",
+            params.target_numerator, params.target_denominator
         ));
     }
-    let difficulty = (1u128 << 64) * k1 as u128 / num_labels as u128;
+    // 2^64 * expected fits in u128 as expected < 2^64.
+    let difficulty = (1u128 << 64) * expected / labels;
     u64::try_from(difficulty).or(Err("difficulty doesn't fit in u64".to_string()))
 }
 
@@ -51,6 +92,44 @@ fn difficulty_calculation() {
     assert_eq!(proving_difficulty(1, 128).unwrap(), 1u64 << (64 - 7));
 }
 
+#[test]
+fn default_params_match_protocol() {
+    let params = DifficultyParams::default();
+    for (k1, num_labels) in [(1, 2), (1, 4), (1, 128), (26, 1 << 30), (279, 4096 * 1024)] {
+        assert_eq!(
+            proving_difficulty(k1, num_labels),
+            proving_difficulty_with_params(k1, num_labels, &params)
+        );
+    }
+}
+
+#[test]
+fn difficulty_with_params() {
+    let params = DifficultyParams {
+        target_numerator: 1,
+        target_denominator: 2,
+    };
+    assert_eq!(
+        proving_difficulty_with_params(1, 4, &params).unwrap(),
+        1u64 << (64 - 3)
+    );
+    let params = DifficultyParams {
+        target_numerator: 2,
+        target_denominator: 1,
+    };
+    assert_eq!(
+        proving_difficulty_with_params(1, 4, &params).unwrap(),
+        1u64 << 63
+    );
+    // 2 * K1 good labels out of 2 labels
+    assert!(proving_difficulty_with_params(1, 2, &params).is_err());
+    let params = DifficultyParams {
+        target_numerator: 1,
+        target_denominator: 0,
+    };
+    assert!(proving_difficulty_with_params(1, 4, &params).is_err());
+}
+
 /// Test that PoW threshold is scaled with num_units.
 #[test]
 fn scaling_pow_thresholds() {
@@ -10,16 +10,18 @@ use mockall::automock;
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 use scrypt_jane::scrypt::scrypt;
 
-use crate::{config::ScryptParams, metadata::PostMetadata};
+use crate::{
+    config::ScryptParams,
+    metadata::{self, PostMetadata},
+    reader,
+};
 
 pub const LABEL_SIZE: usize = 16;
 pub const ENTIRE_LABEL_SIZE: usize = 32;
 
+/// See [metadata::commitment].
 pub fn calc_commitment(node_id: &[u8; 32], commitment_atx_id: &[u8; 32]) -> [u8; 32] {
-    let mut hasher = blake3::Hasher::new();
-    hasher.update(node_id);
-    hasher.update(commitment_atx_id);
-    hasher.finalize().into()
+    metadata::commitment(node_id, commitment_atx_id)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -54,7 +56,8 @@ pub trait Initialize {
         }
         let mut nonce = None;
         for file_id in 0..files_number {
-            let mut post_data = File::create(datadir.join(format!("postdata_{}.bin", file_id)))?;
+            let mut post_data =
+                File::create(datadir.join(reader::default_file_name(file_id as usize)))?;
             let index = file_id * labels_per_file;
             let labels = index..total_labels.min(index + labels_per_file);
             let new_nonce =
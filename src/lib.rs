@@ -1,12 +1,21 @@
-mod cipher;
+pub mod challenge;
+pub mod checksums;
+pub mod cipher;
 mod compression;
 pub mod config;
-mod difficulty;
+pub mod context;
+pub mod difficulty;
 pub mod initialize;
+pub mod merkle;
 pub mod metadata;
 pub mod pos_verification;
 pub mod pow;
+#[cfg(feature = "prost")]
+pub mod proto;
 pub mod prove;
 mod random_values_gen;
 pub mod reader;
 pub mod verification;
+
+/// The pure math of the verification, see [post_verify_core].
+pub use post_verify_core as verify_core;
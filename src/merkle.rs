@@ -0,0 +1,217 @@
+//! A Merkle tree committing to all labels of the POS data.
+//!
+//! It lets a verifier holding only the root check the labels of a proof
+//! (see [Verifier::verify_with_merkle_proofs](crate::verification::Verifier::verify_with_merkle_proofs)).
+//!
+//! ## Encoding
+//!
+//! The tree is built over all labels in the order of their indices, with blake3 as the hash:
+//! ```text
+//! leaf = blake3(0x00 || label)          // label: 16 bytes
+//! node = blake3(0x01 || left || right)  // left, right: 32 bytes
+//! ```
+//! The prefixes separate leaves from nodes, so that a node can't be passed off as a leaf.
+//! A level with an odd number of nodes promotes its last node to the level above unchanged
+//! (it's not hashed with itself). A tree of a single label has the leaf as its root.
+//!
+//! An inclusion proof of the label with index `i` is the list of siblings on the path
+//! from its leaf to the root, starting at the leaf level. Levels in which the node on
+//! the path is promoted have no sibling in the proof.
+
+use crate::initialize::LABEL_SIZE;
+
+pub type Hash = [u8; 32];
+
+/// The hash of a leaf holding `label`.
+pub fn leaf_hash(label: &[u8; LABEL_SIZE]) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[0x00]);
+    hasher.update(label);
+    *hasher.finalize().as_bytes()
+}
+
+/// The hash of a node with the `left` and `right` children.
+pub fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    *hasher.finalize().as_bytes()
+}
+
+/// An inclusion proof of a label, see the [module docs](self) for the encoding.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MerkleProof {
+    pub siblings: Vec<Hash>,
+}
+
+impl MerkleProof {
+    /// Calculate the root of a tree of `num_labels` labels, given that
+    /// the label with index `index` is `label`.
+    ///
+    /// Returns `None` if the index is out of range or the proof has a wrong number of siblings.
+    pub fn root(&self, index: u64, num_labels: u64, label: &[u8; LABEL_SIZE]) -> Option<Hash> {
+        if index >= num_labels {
+            return None;
+        }
+        let mut siblings = self.siblings.iter();
+        let mut hash = leaf_hash(label);
+        let (mut index, mut width) = (index, num_labels);
+        while width > 1 {
+            if index % 2 == 1 {
+                hash = node_hash(siblings.next()?, &hash);
+            } else if index + 1 < width {
+                hash = node_hash(&hash, siblings.next()?);
+            }
+            index /= 2;
+            width = (width + 1) / 2;
+        }
+        match siblings.next() {
+            Some(_) => None,
+            None => Some(hash),
+        }
+    }
+
+    /// Check if the label with index `index` is `label` in the tree of `num_labels` labels with the `root`.
+    pub fn verify(
+        &self,
+        root: &Hash,
+        index: u64,
+        num_labels: u64,
+        label: &[u8; LABEL_SIZE],
+    ) -> bool {
+        self.root(index, num_labels, label).as_ref() == Some(root)
+    }
+}
+
+/// A Merkle tree of labels held in memory, for committing to the labels and creating the proofs.
+///
+/// It keeps all levels of the tree (about 4 times the size of the labels).
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    /// Build the tree over `labels` in the order of their indices.
+    pub fn new<'a>(labels: impl IntoIterator<Item = &'a [u8; LABEL_SIZE]>) -> Self {
+        let mut levels = vec![labels.into_iter().map(leaf_hash).collect::<Vec<_>>()];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(level);
+        }
+        Self { levels }
+    }
+
+    /// The number of labels in the tree.
+    pub fn num_labels(&self) -> u64 {
+        self.levels[0].len() as u64
+    }
+
+    /// The root of the tree. It's all zeros for a tree without labels.
+    pub fn root(&self) -> Hash {
+        self.levels
+            .last()
+            .unwrap()
+            .first()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Create the inclusion proof of the label with index `index`.
+    pub fn proof(&self, index: u64) -> Option<MerkleProof> {
+        if index >= self.num_labels() {
+            return None;
+        }
+        let mut index = index as usize;
+        let mut siblings = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(index ^ 1) {
+                siblings.push(*sibling);
+            }
+            index /= 2;
+        }
+        Some(MerkleProof { siblings })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(count: u64) -> Vec<[u8; LABEL_SIZE]> {
+        (0..count)
+            .map(|i| {
+                let mut label = [0u8; LABEL_SIZE];
+                label[..8].copy_from_slice(&i.to_le_bytes());
+                label
+            })
+            .collect()
+    }
+
+    #[test]
+    fn proving_inclusion() {
+        for count in 1..=33 {
+            let labels = labels(count);
+            let tree = MerkleTree::new(&labels);
+            let root = tree.root();
+            for (index, label) in labels.iter().enumerate() {
+                let proof = tree.proof(index as u64).unwrap();
+                assert!(proof.verify(&root, index as u64, count, label));
+                // Another label or index
+                assert!(!proof.verify(&root, index as u64, count, &[0xFF; LABEL_SIZE]));
+                if count > 1 {
+                    let other = (index as u64 + 1) % count;
+                    assert!(!proof.verify(&root, other, count, label));
+                }
+            }
+            assert!(tree.proof(count).is_none());
+        }
+    }
+
+    #[test]
+    fn encoding() {
+        let labels = labels(3);
+        let tree = MerkleTree::new(&labels);
+        let expected = node_hash(
+            &node_hash(&leaf_hash(&labels[0]), &leaf_hash(&labels[1])),
+            &leaf_hash(&labels[2]),
+        );
+        assert_eq!(expected, tree.root());
+        assert_eq!(
+            vec![leaf_hash(&labels[1]), leaf_hash(&labels[2])],
+            tree.proof(0).unwrap().siblings
+        );
+        // The last label is promoted.
+        assert_eq!(
+            vec![node_hash(&leaf_hash(&labels[0]), &leaf_hash(&labels[1]))],
+            tree.proof(2).unwrap().siblings
+        );
+
+        assert_eq!(leaf_hash(&labels[0]), MerkleTree::new(&labels[..1]).root());
+        assert_eq!([0u8; 32], MerkleTree::new(&labels[..0]).root());
+    }
+
+    #[test]
+    fn rejecting_malformed_proofs() {
+        let labels = labels(8);
+        let tree = MerkleTree::new(&labels);
+        let mut proof = tree.proof(5).unwrap();
+        assert_eq!(3, proof.siblings.len());
+
+        proof.siblings.push([0; 32]);
+        assert_eq!(None, proof.root(5, 8, &labels[5]));
+        proof.siblings.truncate(2);
+        assert_eq!(None, proof.root(5, 8, &labels[5]));
+        assert_eq!(None, tree.proof(5).unwrap().root(8, 8, &labels[5]));
+    }
+}
@@ -4,6 +4,8 @@ use serde::{Deserialize, Serialize};
 use serde_with::base64::Base64;
 use serde_with::serde_as;
 
+use crate::initialize::LABEL_SIZE;
+
 const METADATA_FILE_NAME: &str = "postdata_metadata.json";
 
 #[serde_as]
@@ -26,19 +28,42 @@ impl PostMetadata {
         self.num_units as u64 * self.labels_per_unit
     }
 
+    #[deprecated(note = "use `expected_total_bytes`")]
     pub fn total_size(&self) -> u64 {
-        self.total_labels() * 16
+        self.expected_total_bytes()
     }
 
+    #[deprecated(note = "use `expected_file_count`")]
     pub fn num_files(&self) -> usize {
-        (self.total_size() as f64 / self.max_file_size as f64).ceil() as usize
+        self.expected_file_count()
+    }
+
+    /// The number of bytes of all POS files of a complete data directory.
+    pub fn expected_total_bytes(&self) -> u64 {
+        self.total_labels() * LABEL_SIZE as u64
+    }
+
+    /// The number of POS files of a complete data directory:
+    /// all of them hold `max_file_size` bytes, except possibly the last one.
+    ///
+    /// It's 0 for an invalid `max_file_size` of 0.
+    pub fn expected_file_count(&self) -> usize {
+        if self.max_file_size == 0 {
+            return 0;
+        }
+        let total = self.expected_total_bytes();
+        (total / self.max_file_size + u64::from(total % self.max_file_size != 0)) as usize
+    }
+
+    pub fn commitment(&self) -> [u8; 32] {
+        commitment(&self.node_id, &self.commitment_atx_id)
     }
 
     pub fn labels_in_file(&self, idx: usize) -> usize {
         assert_eq!(0, self.max_file_size % 16);
         let labels_in_files = self.max_file_size as usize / 16;
         match idx {
-            idx if idx == self.num_files() - 1 => {
+            idx if idx == self.expected_file_count() - 1 => {
                 let remainder = self.total_labels() as usize % labels_in_files;
                 if remainder > 0 {
                     remainder
@@ -46,10 +71,39 @@ impl PostMetadata {
                     labels_in_files
                 }
             }
-            idx if idx < self.num_files() - 1 => labels_in_files,
+            idx if idx < self.expected_file_count() - 1 => labels_in_files,
             _ => 0,
         }
     }
+
+    /// The location of the label with the given index in the POS data:
+    /// the index of the file and the offset (in bytes) within it.
+    ///
+    /// Returns `None` if the index is past the data.
+    pub fn label_location(&self, index: u64) -> Option<(usize, u64)> {
+        if index >= self.total_labels() || self.max_file_size < 16 {
+            return None;
+        }
+        let labels_in_file = self.max_file_size / 16;
+        Some((
+            (index / labels_in_file) as usize,
+            index % labels_in_file * 16,
+        ))
+    }
+}
+
+/// Calculate the commitment that labels are derived from.
+///
+/// commitment = blake3(node_id || commitment_atx_id)
+///
+/// It is the scrypt password for each label, while the label index is the salt.
+/// The initializer and the verifier must use the same commitment for the
+/// recomputed labels to match.
+pub fn commitment(node_id: &[u8; 32], commitment_atx_id: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(node_id);
+    hasher.update(commitment_atx_id);
+    hasher.finalize().into()
 }
 
 pub fn load(datadir: &Path) -> eyre::Result<PostMetadata> {
@@ -82,13 +136,18 @@ impl ProofMetadata {
             num_units: post_metadata.num_units,
         }
     }
+
+    pub fn commitment(&self) -> [u8; 32] {
+        commitment(&self.node_id, &self.commitment_atx_id)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::PostMetadata;
+    use super::{PostMetadata, ProofMetadata};
 
     #[test]
+    #[allow(deprecated)]
     fn test_num_files() {
         let m = PostMetadata {
             labels_per_unit: 1,
@@ -107,6 +166,63 @@ mod tests {
         assert_eq!(m.num_files(), 121);
     }
 
+    #[test]
+    fn test_expected_files() {
+        // exact division
+        let m = PostMetadata {
+            labels_per_unit: 64,
+            num_units: 4,
+            max_file_size: 16 * 32,
+            ..Default::default()
+        };
+        assert_eq!(4 * 64 * 16, m.expected_total_bytes());
+        assert_eq!(8, m.expected_file_count());
+        assert_eq!(32, m.labels_in_file(7));
+
+        // the last file holding the remainder
+        let m = PostMetadata {
+            labels_per_unit: 10,
+            num_units: 3,
+            max_file_size: 16 * 8,
+            ..Default::default()
+        };
+        assert_eq!(30 * 16, m.expected_total_bytes());
+        assert_eq!(4, m.expected_file_count());
+        assert_eq!(6, m.labels_in_file(3));
+
+        // too big for f64 to be exact
+        let m = PostMetadata {
+            labels_per_unit: (1 << 53) + 1,
+            num_units: 1,
+            max_file_size: 16 << 53,
+            ..Default::default()
+        };
+        assert_eq!(2, m.expected_file_count());
+
+        let m = PostMetadata {
+            labels_per_unit: 10,
+            num_units: 1,
+            max_file_size: 0,
+            ..Default::default()
+        };
+        assert_eq!(0, m.expected_file_count());
+    }
+
+    #[test]
+    fn test_label_location() {
+        let m = PostMetadata {
+            labels_per_unit: 10,
+            num_units: 2,
+            max_file_size: 16 * 8,
+            ..Default::default()
+        };
+        assert_eq!(Some((0, 0)), m.label_location(0));
+        assert_eq!(Some((0, 7 * 16)), m.label_location(7));
+        assert_eq!(Some((1, 0)), m.label_location(8));
+        assert_eq!(Some((2, 3 * 16)), m.label_location(19));
+        assert_eq!(None, m.label_location(20));
+    }
+
     #[test]
     fn test_labels_in_file() {
         let m = PostMetadata {
@@ -118,4 +234,21 @@ mod tests {
         assert_eq!(1, m.labels_in_file(0));
         assert_eq!(0, m.labels_in_file(1));
     }
+
+    #[test]
+    fn test_commitment() {
+        let m = PostMetadata {
+            node_id: [1; 32],
+            commitment_atx_id: [2; 32],
+            ..Default::default()
+        };
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&[1; 32]);
+        hasher.update(&[2; 32]);
+        let expected: [u8; 32] = hasher.finalize().into();
+
+        assert_eq!(expected, m.commitment());
+        assert_eq!(expected, ProofMetadata::new(m, [0; 32]).commitment());
+        assert_ne!(expected, super::commitment(&[2; 32], &[1; 32]));
+    }
 }
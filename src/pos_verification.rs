@@ -8,8 +8,8 @@ use rayon::prelude::{ParallelBridge, ParallelIterator};
 
 use crate::{
     config::ScryptParams,
-    initialize::{calc_commitment, CpuInitializer, Initialize},
-    metadata,
+    initialize::{CpuInitializer, Initialize},
+    metadata, reader,
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -31,21 +31,35 @@ pub fn verify_files(
     to_file: Option<usize>,   // inclusive
     scrypt: ScryptParams,
 ) -> Result<(), VerificationError> {
-    log::info!("verifying POS data in {}", datadir.display());
     let metadata = metadata::load(datadir)?;
+    verify_files_with_metadata(&metadata, datadir, fraction, from_file, to_file, scrypt)
+}
+
+/// Like [verify_files], but with the metadata given explicitly.
+/// Only the POS data files are read from `datadir`, so the metadata
+/// doesn't have to be stored next to them (i.e. for relocated or archived data).
+pub fn verify_files_with_metadata(
+    metadata: &metadata::PostMetadata,
+    datadir: &Path,           // path to directory with POS data files
+    fraction: f64,            // total % of labels to verify
+    from_file: Option<usize>, // inclusive
+    to_file: Option<usize>,   // inclusive
+    scrypt: ScryptParams,
+) -> Result<(), VerificationError> {
+    log::info!("verifying POS data in {}", datadir.display());
 
     let from_file = from_file.unwrap_or(0);
-    let to_file = to_file.unwrap_or(metadata.num_files() - 1);
+    let to_file = to_file.unwrap_or(metadata.expected_file_count() - 1);
     log::info!("verifying POS files {from_file} -> {to_file}");
 
     for idx in from_file..=to_file {
-        let file_path = datadir.join(format!("postdata_{}.bin", idx));
+        let file_path = datadir.join(reader::default_file_name(idx));
         log::info!("verifying file {}", file_path.display());
 
         let file = std::fs::File::open(file_path)?;
         let reader = std::io::BufReader::new(file);
 
-        verify(reader, idx, fraction, &metadata, scrypt)?;
+        verify(reader, idx, fraction, metadata, scrypt)?;
     }
 
     Ok(())
@@ -58,7 +72,7 @@ fn verify<R: Read + Seek + Send>(
     metadata: &metadata::PostMetadata,
     scrypt_params: ScryptParams,
 ) -> Result<(), VerificationError> {
-    let commitment = calc_commitment(&metadata.node_id, &metadata.commitment_atx_id);
+    let commitment = metadata.commitment();
 
     let labels_count = metadata.labels_in_file(file_idx);
     let labels_offset = file_idx as u64 * metadata.max_file_size / 16;
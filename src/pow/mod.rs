@@ -7,6 +7,8 @@
 //! without actually holding the whole POST data.
 
 pub mod randomx;
+use std::sync::atomic::AtomicBool;
+
 use mockall::*;
 use thiserror::Error;
 
@@ -16,6 +18,10 @@ pub enum Error {
     PoWNotFound,
     #[error("proof of work is invalid")]
     InvalidPoW,
+    #[error("proof of work initialization was cancelled")]
+    Cancelled,
+    #[error("invalid maximum number of VMs: {0} (must be at least 1)")]
+    InvalidMaxVms(usize),
     #[error(transparent)]
     Internal(Box<dyn std::error::Error + Send + Sync>),
 }
@@ -42,3 +48,193 @@ pub trait PowVerifier {
         miner_id: &[u8; 32],
     ) -> Result<(), Error>;
 }
+
+/// The PoW value returned by [NoPoWProver].
+///
+/// Real PoWs are always below 2^56, so it can't be mistaken for one.
+pub const NO_POW: u64 = u64::MAX;
+
+/// A [Prover] that skips the PoW, always "finding" [NO_POW].
+///
+/// Proofs created with it are NOT valid under the protocol rules.
+/// It's meant for environments where the PoW is not needed (i.e. testing),
+/// to avoid the cost of initializing RandomX.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoPoWProver;
+
+impl Prover for NoPoWProver {
+    fn prove(
+        &self,
+        _nonce_group: u8,
+        _challenge: &[u8; 8],
+        _difficulty: &[u8; 32],
+        _miner_id: &[u8; 32],
+    ) -> Result<u64, Error> {
+        Ok(NO_POW)
+    }
+}
+
+/// A [Prover] "finding" the same PoW for every nonce group.
+///
+/// Like [NoPoWProver], proofs created with it are NOT valid under the protocol rules.
+#[derive(Debug, Clone, Copy)]
+pub struct ConstantProver(pub u64);
+
+impl Prover for ConstantProver {
+    fn prove(
+        &self,
+        _nonce_group: u8,
+        _challenge: &[u8; 8],
+        _difficulty: &[u8; 32],
+        _miner_id: &[u8; 32],
+    ) -> Result<u64, Error> {
+        Ok(self.0)
+    }
+}
+
+/// The algorithm of the PoW proving, see [new].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PowAlgorithm {
+    /// RandomX, as required by the protocol.
+    #[default]
+    RandomX,
+    /// The same PoW for every nonce group ([ConstantProver]). ONLY FOR TESTS.
+    Constant(u64),
+}
+
+/// Create the PoW prover for the algorithm.
+/// The `flags` and `max_vms` (see [randomx::PoW::with_max_vms]) configure RandomX
+/// and are ignored by other algorithms.
+///
+/// Fails with [Error::Cancelled] if `stop` is set while initializing RandomX.
+pub fn new(
+    algorithm: PowAlgorithm,
+    flags: randomx::RandomXFlag,
+    max_vms: Option<usize>,
+    stop: &AtomicBool,
+) -> Result<Box<dyn Prover + Send + Sync>, Error> {
+    Ok(match algorithm {
+        PowAlgorithm::RandomX => {
+            let pow = randomx::PoW::new_with_fallback(flags, stop)?;
+            match max_vms {
+                Some(max_vms) => Box::new(pow.with_max_vms(max_vms)?),
+                None => Box::new(pow),
+            }
+        }
+        PowAlgorithm::Constant(pow) => Box::new(ConstantProver(pow)),
+    })
+}
+
+impl<T: Prover + ?Sized> Prover for std::sync::Arc<T> {
+    fn prove(
+        &self,
+        nonce_group: u8,
+        challenge: &[u8; 8],
+        difficulty: &[u8; 32],
+        miner_id: &[u8; 32],
+    ) -> Result<u64, Error> {
+        (**self).prove(nonce_group, challenge, difficulty, miner_id)
+    }
+}
+
+impl std::fmt::Debug for dyn Prover + Send + Sync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Prover")
+    }
+}
+
+impl<T: PowVerifier + ?Sized> PowVerifier for std::sync::Arc<T> {
+    fn verify(
+        &self,
+        pow: u64,
+        nonce_group: u8,
+        challenge: &[u8; 8],
+        difficulty: &[u8; 32],
+        miner_id: &[u8; 32],
+    ) -> Result<(), Error> {
+        (**self).verify(pow, nonce_group, challenge, difficulty, miner_id)
+    }
+}
+
+/// Verify the PoWs of many nonce groups, given as `(nonce_group, pow)` pairs.
+///
+/// Returns the position in `groups` of the first group with an invalid PoW
+/// (or a nonce group out of bounds).
+pub fn verify_groups<V: PowVerifier + ?Sized>(
+    verifier: &V,
+    challenge: &[u8; 8],
+    groups: &[(u32, u64)],
+    difficulty: &[u8; 32],
+    miner_id: &[u8; 32],
+) -> Result<(), usize> {
+    groups
+        .iter()
+        .position(|&(nonce_group, pow)| match u8::try_from(nonce_group) {
+            Ok(nonce_group) => verifier
+                .verify(pow, nonce_group, challenge, difficulty, miner_id)
+                .is_err(),
+            Err(_) => true,
+        })
+        .map_or(Ok(()), Err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_algorithm() {
+        let stop = AtomicBool::new(true);
+        let prover = new(
+            PowAlgorithm::Constant(7),
+            randomx::RandomXFlag::empty(),
+            None,
+            &stop,
+        )
+        .unwrap();
+        assert_eq!(7, prover.prove(3, &[0; 8], &[0; 32], &[0; 32]).unwrap());
+        assert_eq!(7, prover.prove(4, &[1; 8], &[0xFF; 32], &[1; 32]).unwrap());
+    }
+
+    #[test]
+    fn randomx_with_max_vms() {
+        let flags = randomx::RandomXFlag::get_recommended_flags();
+        let stop = AtomicBool::new(false);
+        assert!(matches!(
+            new(PowAlgorithm::RandomX, flags, Some(0), &stop),
+            Err(Error::InvalidMaxVms(0))
+        ));
+
+        let prover = new(PowAlgorithm::RandomX, flags, Some(1), &stop).unwrap();
+        let difficulty = &[0xFF; 32];
+        let pow = prover.prove(3, b"hello!!!", difficulty, &[6; 32]).unwrap();
+        randomx::PoW::new(flags)
+            .unwrap()
+            .verify(pow, 3, b"hello!!!", difficulty, &[6; 32])
+            .unwrap();
+    }
+
+    #[test]
+    fn verifying_groups() {
+        let mut verifier = MockPowVerifier::new();
+        // The valid PoW of a group is its number (+ 100).
+        verifier
+            .expect_verify()
+            .returning(
+                |pow, nonce_group, _, _, _| match pow == nonce_group as u64 + 100 {
+                    true => Ok(()),
+                    false => Err(Error::InvalidPoW),
+                },
+            );
+        let verify = |groups: &[(u32, u64)]| {
+            verify_groups(&verifier, &[0; 8], groups, &[0xFF; 32], &[0; 32])
+        };
+
+        assert_eq!(Ok(()), verify(&[]));
+        assert_eq!(Ok(()), verify(&[(0, 100), (1, 101), (255, 355)]));
+        assert_eq!(Err(1), verify(&[(0, 100), (1, 100), (2, 100)]));
+        assert_eq!(Err(0), verify(&[(3, 100), (1, 101)]));
+        // nonce group out of bounds
+        assert_eq!(Err(2), verify(&[(0, 100), (1, 101), (256, 356)]));
+    }
+}
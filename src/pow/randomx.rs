@@ -1,32 +1,101 @@
 pub use randomx_rs::RandomXFlag;
 use randomx_rs::{RandomXCache, RandomXDataset, RandomXError, RandomXVM};
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc,
+};
+use std::time::Duration;
 use thread_local::ThreadLocal;
 
 use super::{Error, PowVerifier, Prover};
 
 const RANDOMX_CACHE_KEY: &[u8] = b"spacemesh-randomx-cache-key";
 
+/// How often [PoW::new_interruptible] checks if it was cancelled.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 impl From<randomx_rs::RandomXError> for Error {
     fn from(e: randomx_rs::RandomXError) -> Self {
         Error::Internal(Box::new(e))
     }
 }
 
+/// RandomX based PoW prover and verifier.
+///
+/// It's `Send + Sync` and can be shared (i.e. in an `Arc`) between proving and
+/// verifying running concurrently, so the cache/dataset is initialized only once.
+/// The cache and dataset are only read after initialization. Each thread
+/// lazily creates its own VM on first use, VMs are never shared between threads,
+/// so the threads hashing in [PoW::prove] don't contend.
+///
+/// Every VM has its own 2 MiB scratchpad (on top of the shared cache/dataset),
+/// kept until the [PoW] is dropped. By default [PoW::prove] hashes on all threads
+/// of the current rayon pool, see [PoW::with_max_vms] to cap the number of VMs it creates.
 pub struct PoW {
     cache: Option<RandomXCache>,
     dataset: Option<RandomXDataset>,
     flags: RandomXFlag,
     vms: ThreadLocal<RandomXVM>,
+    num_vms: AtomicUsize,
+    pool: Option<rayon::ThreadPool>,
 }
 
 impl PoW {
+    /// Initialize RandomX with the given flags.
+    ///
+    /// With [RandomXFlag::FLAG_FULL_MEM] a ~2 GiB dataset is built from the cache,
+    /// which takes much longer than the light mode (256 MiB cache only),
+    /// but then hashing is several times faster. It pays off for proving,
+    /// which computes many hashes, but not for verifying a few proofs.
+    ///
+    /// NOTE: the dataset is initialized on a single thread. The `randomx-rs` bindings
+    /// only initialize a whole dataset at once and don't allow splitting the work.
     pub fn new(flags: RandomXFlag) -> Result<PoW, Error> {
+        Self::new_interruptible(flags, &AtomicBool::new(false))
+    }
+
+    /// Like [PoW::new], but fails with [Error::Cancelled] as soon as `stop` is set.
+    ///
+    /// The bindings can't abort building the dataset (nor build it in chunks),
+    /// so it's built on a background thread while `stop` is polled.
+    ///
+    /// NOTE: cancelling only returns early. The thread keeps building the dataset
+    /// and its memory (~2 GiB plus the 256 MiB cache) stays allocated until the build
+    /// completes, then it's freed right away. Initializing RandomX again before that
+    /// starts another build, holding the memory twice.
+    pub fn new_interruptible(flags: RandomXFlag, stop: &AtomicBool) -> Result<PoW, Error> {
+        let cancelled = || stop.load(Ordering::Relaxed);
+        if cancelled() {
+            return Err(Error::Cancelled);
+        }
         log::debug!("initializing RandomX");
         let cache = RandomXCache::new(flags, RANDOMX_CACHE_KEY)?;
+        if cancelled() {
+            return Err(Error::Cancelled);
+        }
         let (cache, dataset) = if flags.contains(RandomXFlag::FLAG_FULL_MEM) {
-            (None, Some(RandomXDataset::new(flags, cache, 0)?))
+            let (tx, rx) = mpsc::sync_channel(1);
+            std::thread::spawn(move || {
+                // If cancelled, the receiver is gone and the dataset is dropped here.
+                _ = tx.send(RandomXDataset::new(flags, cache, 0));
+            });
+            let dataset = loop {
+                match rx.recv_timeout(CANCEL_POLL_INTERVAL) {
+                    Ok(dataset) => break dataset?,
+                    Err(mpsc::RecvTimeoutError::Timeout) if cancelled() => {
+                        log::debug!("RandomX initialization cancelled");
+                        return Err(Error::Cancelled);
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        return Err(Error::Internal(
+                            "RandomX dataset initialization panicked".into(),
+                        ))
+                    }
+                }
+            };
+            (None, Some(dataset))
         } else {
             (Some(cache), None)
         };
@@ -37,12 +106,86 @@ impl PoW {
             dataset,
             flags,
             vms: ThreadLocal::new(),
+            num_vms: AtomicUsize::new(0),
+            pool: None,
         })
     }
 
+    /// Hash on a dedicated pool of `max_vms` threads in [PoW::prove],
+    /// so that proving creates at most `max_vms` VMs (and scratchpads).
+    ///
+    /// Every other thread calling [PoW::verify] still gets a VM of its own.
+    ///
+    /// Fails with [Error::InvalidMaxVms] for 0 VMs.
+    pub fn with_max_vms(mut self, max_vms: usize) -> Result<Self, Error> {
+        if max_vms == 0 {
+            return Err(Error::InvalidMaxVms(max_vms));
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(max_vms)
+            .thread_name(|i| format!("randomx-{i}"))
+            .build()
+            .map_err(|err| Error::Internal(Box::new(err)))?;
+        self.pool = Some(pool);
+        Ok(self)
+    }
+
+    /// The number of VMs created so far (one per thread that hashed).
+    pub fn num_vms(&self) -> usize {
+        self.num_vms.load(Ordering::Relaxed)
+    }
+
+    /// Like [PoW::new_interruptible], but if initializing with large pages fails
+    /// (i.e. they can't be allocated on a fragmented system), it retries without them.
+    pub fn new_with_fallback(flags: RandomXFlag, stop: &AtomicBool) -> Result<PoW, Error> {
+        with_large_pages_fallback(flags, |flags| PoW::new_interruptible(flags, stop))
+    }
+
     fn get_vm(&self) -> Result<&RandomXVM, RandomXError> {
-        self.vms
-            .get_or_try(|| RandomXVM::new(self.flags, self.cache.clone(), self.dataset.clone()))
+        self.vms.get_or_try(|| {
+            let vm = RandomXVM::new(self.flags, self.cache.clone(), self.dataset.clone())?;
+            self.num_vms.fetch_add(1, Ordering::Relaxed);
+            Ok(vm)
+        })
+    }
+}
+
+impl std::fmt::Debug for PoW {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PoW")
+            .field("flags", &self.flags)
+            .field("num_vms", &self.num_vms())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Runs `init` with `flags`, and if it failed to allocate memory with large pages,
+/// once more without them. Other errors (i.e. [Error::Cancelled]) are returned unchanged.
+fn with_large_pages_fallback<T, F>(flags: RandomXFlag, init: F) -> Result<T, Error>
+where
+    F: Fn(RandomXFlag) -> Result<T, Error>,
+{
+    match init(flags) {
+        Err(err)
+            if flags.contains(RandomXFlag::FLAG_LARGE_PAGES) && is_allocation_failure(&err) =>
+        {
+            let mut flags = flags;
+            flags.remove(RandomXFlag::FLAG_LARGE_PAGES);
+            log::warn!("initializing RandomX with large pages failed ({err}), retrying without large pages: {flags:?}");
+            init(flags)
+        }
+        result => result,
+    }
+}
+
+/// RandomX reports failing to allocate the cache, dataset or VM as a creation error.
+fn is_allocation_failure(err: &Error) -> bool {
+    match err {
+        Error::Internal(err) => matches!(
+            err.downcast_ref::<RandomXError>(),
+            Some(RandomXError::CreationError(_))
+        ),
+        _ => false,
     }
 }
 
@@ -53,6 +196,24 @@ impl Prover for PoW {
         challenge: &[u8; 8],
         difficulty: &[u8; 32],
         miner_id: &[u8; 32],
+    ) -> Result<u64, Error> {
+        match &self.pool {
+            Some(pool) => {
+                pool.install(|| self.search(nonce_group, challenge, difficulty, miner_id))
+            }
+            None => self.search(nonce_group, challenge, difficulty, miner_id),
+        }
+    }
+}
+
+impl PoW {
+    /// Search for the PoW on the threads of the current rayon pool.
+    fn search(
+        &self,
+        nonce_group: u8,
+        challenge: &[u8; 8],
+        difficulty: &[u8; 32],
+        miner_id: &[u8; 32],
     ) -> Result<u64, Error> {
         let pow_input = [
             [0u8; 7].as_slice(),
@@ -184,6 +345,143 @@ mod tests {
         assert_ne!(hash_0, hash_1);
     }
 
+    #[test]
+    fn cancelled_initialization() {
+        let stop = AtomicBool::new(true);
+        let flags = RandomXFlag::get_recommended_flags() | RandomXFlag::FLAG_FULL_MEM;
+        assert!(matches!(
+            PoW::new_interruptible(flags, &stop),
+            Err(Error::Cancelled)
+        ));
+        assert!(matches!(
+            PoW::new_with_fallback(flags | RandomXFlag::FLAG_LARGE_PAGES, &stop),
+            Err(Error::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn pow_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<PoW>();
+    }
+
+    #[test]
+    fn concurrent_prove_and_verify() {
+        let challenge = b"hello!!!";
+        let difficulty = &[
+            0x0f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xff, 0xff, 0xff,
+        ];
+        let pow = std::sync::Arc::new(PoW::new(RandomXFlag::get_recommended_flags()).unwrap());
+        let proven = pow.prove(0, challenge, difficulty, &[6; 32]).unwrap();
+
+        std::thread::scope(|s| {
+            let prover = pow.clone();
+            let proving = s.spawn(move || {
+                (1..4)
+                    .map(|group| {
+                        let pow = prover
+                            .prove(group, challenge, difficulty, &[6; 32])
+                            .unwrap();
+                        (group, pow)
+                    })
+                    .collect::<Vec<_>>()
+            });
+            let verifier = pow.clone();
+            let verifying = s.spawn(move || {
+                for _ in 0..10 {
+                    verifier
+                        .verify(proven, 0, challenge, difficulty, &[6; 32])
+                        .unwrap();
+                }
+            });
+            verifying.join().unwrap();
+            for (group, proven) in proving.join().unwrap() {
+                pow.verify(proven, group, challenge, difficulty, &[6; 32])
+                    .unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn capped_number_of_vms() {
+        let challenge = b"hello!!!";
+        let difficulty = &[
+            0x0f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xff, 0xff, 0xff,
+        ];
+        let pow = PoW::new(RandomXFlag::get_recommended_flags())
+            .unwrap()
+            .with_max_vms(2)
+            .unwrap();
+        assert_eq!(0, pow.num_vms());
+        for group in 0..4 {
+            let proven = pow.prove(group, challenge, difficulty, &[6; 32]).unwrap();
+            assert!(pow.num_vms() <= 2);
+            // Verifying on this thread creates one more VM.
+            pow.verify(proven, group, challenge, difficulty, &[6; 32])
+                .unwrap();
+            assert!(pow.num_vms() <= 3);
+        }
+    }
+
+    #[test]
+    fn zero_vms() {
+        let pow = PoW::new(RandomXFlag::get_recommended_flags()).unwrap();
+        assert!(matches!(pow.with_max_vms(0), Err(Error::InvalidMaxVms(0))));
+    }
+
+    #[test]
+    fn fallback_without_large_pages() {
+        let flags = RandomXFlag::FLAG_LARGE_PAGES | RandomXFlag::FLAG_FULL_MEM;
+        let calls = std::sync::Mutex::new(Vec::new());
+        let result = with_large_pages_fallback(flags, |flags| {
+            calls.lock().unwrap().push(flags);
+            if flags.contains(RandomXFlag::FLAG_LARGE_PAGES) {
+                Err(RandomXError::CreationError("Could not allocate cache".into()).into())
+            } else {
+                Ok(flags)
+            }
+        });
+        assert_eq!(RandomXFlag::FLAG_FULL_MEM, result.unwrap());
+        assert_eq!(
+            vec![flags, RandomXFlag::FLAG_FULL_MEM],
+            calls.into_inner().unwrap()
+        );
+    }
+
+    #[test]
+    fn no_fallback_without_large_pages_flag() {
+        let calls = std::cell::Cell::new(0);
+        let result: Result<(), _> = with_large_pages_fallback(RandomXFlag::FLAG_FULL_MEM, |_| {
+            calls.set(calls.get() + 1);
+            Err(Error::Internal("failed".into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(1, calls.get());
+    }
+
+    #[test]
+    fn no_fallback_on_other_errors() {
+        let flags = RandomXFlag::FLAG_LARGE_PAGES | RandomXFlag::FLAG_FULL_MEM;
+        let errors: [fn() -> Error; 3] = [
+            || Error::Cancelled,
+            || Error::Internal("failed".into()),
+            || RandomXError::ParameterError("invalid".into()).into(),
+        ];
+        for err in errors {
+            let calls = std::cell::Cell::new(0);
+            let result: Result<(), _> = with_large_pages_fallback(flags, |_| {
+                calls.set(calls.get() + 1);
+                Err(err())
+            });
+            assert_eq!(err().to_string(), result.unwrap_err().to_string());
+            assert_eq!(1, calls.get());
+        }
+    }
+
     #[test]
     fn get_recommended_flags() {
         dbg!(RandomXFlag::get_recommended_flags());
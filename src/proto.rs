@@ -0,0 +1,68 @@
+//! Protobuf messages of the `proto/proof.proto` schema.
+//!
+//! The messages are derived by hand (instead of a build script) to not require `protoc`.
+//! They must be kept in sync with the schema.
+
+use std::borrow::Cow;
+
+use crate::prove;
+
+/// A proof of space-time, wire compatible with `spacemesh.v1.Proof`.
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct Proof {
+    #[prost(uint32, tag = "1")]
+    pub nonce: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub indices: Vec<u8>,
+    #[prost(uint64, tag = "3")]
+    pub pow: u64,
+}
+
+impl prove::Proof<'_> {
+    pub fn to_proto(&self) -> Proof {
+        Proof {
+            nonce: self.nonce,
+            indices: self.indices.to_vec(),
+            pow: self.pow,
+        }
+    }
+}
+
+impl prove::Proof<'static> {
+    pub fn from_proto(proof: Proof) -> Self {
+        Self {
+            nonce: proof.nonce,
+            indices: Cow::Owned(proof.indices),
+            pow: proof.pow,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+
+    #[test]
+    fn proof_round_trip() {
+        let proof = prove::Proof::new(17, &[1, 5, 200, 77], 256, 12345);
+        let bytes = proof.to_proto().encode_to_vec();
+        let decoded = prove::Proof::from_proto(Proof::decode(bytes.as_slice()).unwrap());
+        assert_eq!(proof, decoded);
+    }
+
+    #[test]
+    fn proof_wire_format() {
+        let proof = Proof {
+            nonce: 1,
+            indices: vec![0xAB],
+            pow: 2,
+        };
+        // (tag 1, varint) 1, (tag 2, length-delimited) [0xAB], (tag 3, varint) 2
+        assert_eq!(
+            vec![0x08, 0x01, 0x12, 0x01, 0xAB, 0x18, 0x02],
+            proof.encode_to_vec()
+        );
+    }
+}
@@ -9,15 +9,24 @@
 //! TODO: explain
 
 use std::borrow::{Borrow, Cow};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Mutex,
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    mpsc, Arc, Mutex,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Range,
+    path::{Path, PathBuf},
+    thread::{self, ScopedJoinHandle},
+    time::{Duration, Instant},
 };
-use std::{collections::HashMap, ops::Range, path::Path, time::Instant};
 
 use aes::cipher::block_padding::NoPadding;
 use aes::cipher::BlockEncrypt;
 use eyre::Context;
+use itertools::Itertools;
 use primitive_types::U256;
 use randomx_rs::RandomXFlag;
 use rayon::prelude::{ParallelBridge, ParallelIterator};
@@ -25,19 +34,28 @@ use serde::{Deserialize, Serialize};
 use serde_with::{base64::Base64, serde_as};
 
 use crate::{
-    cipher::AesCipher,
-    compression::{compress_indices, required_bits},
-    config::ProofConfig,
-    difficulty::proving_difficulty,
+    cipher::{aes_backend, AesBackend, AesCipher, Blake3KeyDerivation, KeyDerivation},
+    compression::{compress_indices, decompress_indexes, required_bits},
+    config::{Config, ProofConfig},
+    context::PostContext,
+    difficulty::{proving_difficulty_with_params, scale_pow_difficulty, DifficultyParams},
     metadata::{self, PostMetadata},
-    pow,
-    reader::read_data,
+    pow::{self, randomx::PoW},
+    reader::{find_file, read_data, BufferPool, DataSnapshot, ReaderConfig},
+    verify_core,
 };
 
 const LABEL_SIZE: usize = 16;
 const BLOCK_SIZE: usize = 16; // size of the aes block
 const AES_BATCH: usize = 8; // will use encrypt8 asm method
-const CHUNK_SIZE: usize = BLOCK_SIZE * AES_BATCH;
+pub(crate) const CHUNK_SIZE: usize = BLOCK_SIZE * AES_BATCH;
+
+#[cfg(feature = "gpu")]
+pub mod gpu;
+#[cfg(feature = "proof-log")]
+mod proof_log;
+#[cfg(feature = "proof-log")]
+pub use proof_log::{LogReader, LoggedProof, ProofLog};
 
 #[serde_as]
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
@@ -49,6 +67,38 @@ pub struct Proof<'a> {
 }
 
 impl Proof<'static> {
+    /// The size of the encoded nonce and PoW, preceding the indices.
+    const ENCODED_HEADER_SIZE: usize = 4 + 8;
+
+    /// Decode a proof encoded with [Proof::encode], for `num_labels` labels and `k2` indices.
+    ///
+    /// The compressed indices must be exactly as long as `k2` indices of
+    /// `required_bits(num_labels)` bits, so a truncated proof or one with
+    /// trailing bytes is rejected. The values of the indices are not checked here,
+    /// that's up to the verification.
+    pub fn decode(bytes: &[u8], num_labels: u64, k2: u32) -> Result<Self, DecodeError> {
+        if num_labels == 0 {
+            return Err(DecodeError::NoLabels);
+        }
+        if bytes.len() < Self::ENCODED_HEADER_SIZE {
+            return Err(DecodeError::TooShort { len: bytes.len() });
+        }
+        let (nonce, rest) = bytes.split_at(4);
+        let (pow, indices) = rest.split_at(8);
+        let expected = verify_core::expected_indices_bytes(required_bits(num_labels), k2);
+        if indices.len() != expected {
+            return Err(DecodeError::LengthMismatch {
+                expected,
+                got: indices.len(),
+            });
+        }
+        Ok(Self {
+            nonce: u32::from_le_bytes(nonce.try_into().unwrap()),
+            indices: Cow::Owned(indices.to_vec()),
+            pow: u64::from_le_bytes(pow.try_into().unwrap()),
+        })
+    }
+
     pub fn new(nonce: u32, indices: &[u64], num_labels: u64, pow: u64) -> Self {
         Self {
             nonce,
@@ -58,6 +108,323 @@ impl Proof<'static> {
     }
 }
 
+impl Proof<'_> {
+    /// A short, stable identifier of the proof for deduplication and log correlation.
+    ///
+    /// It's a truncated blake3 hash of `nonce || pow || indices`.
+    /// It is NOT a cryptographic commitment to the proof.
+    pub fn fingerprint(&self) -> [u8; 16] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.nonce.to_le_bytes());
+        hasher.update(&self.pow.to_le_bytes());
+        hasher.update(&self.indices);
+        hasher.finalize().as_bytes()[..16].try_into().unwrap()
+    }
+
+    /// Encode the proof as `nonce (u32 LE) || pow (u64 LE) || compressed indices`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Proof::ENCODED_HEADER_SIZE + self.indices.len());
+        bytes.extend_from_slice(&self.nonce.to_le_bytes());
+        bytes.extend_from_slice(&self.pow.to_le_bytes());
+        bytes.extend_from_slice(&self.indices);
+        bytes
+    }
+
+    /// The nonce group of the proof's nonce. The proof's PoW is the PoW of this group.
+    pub fn nonce_group(&self) -> u32 {
+        self.nonce / verify_core::NONCES_PER_AES
+    }
+
+    /// Whether the proof was created without calculating the PoW.
+    /// See [ProvingOptions::skip_pow].
+    pub fn is_pow_skipped(&self) -> bool {
+        self.pow == pow::NO_POW
+    }
+
+    /// Decompress the `k2` proven indices, given the number of labels the proof was created for.
+    pub fn unpack_indices(&self, num_labels: u64, k2: u32) -> Result<Vec<u64>, ProveError> {
+        if num_labels == 0 {
+            return Err(ProveError::NoLabels);
+        }
+        let indices = decompress_indexes(&self.indices, required_bits(num_labels))
+            .take(k2 as usize)
+            .collect::<Vec<_>>();
+        if indices.len() < k2 as usize {
+            return Err(ProveError::NotEnoughIndices {
+                expected: k2 as usize,
+                got: indices.len(),
+            });
+        }
+        Ok(indices)
+    }
+
+    /// Recompress the indices of a proof created for `from_num_labels` labels
+    /// to the bit width required for `to_num_labels` labels.
+    ///
+    /// `k2` is required because the compressed indices might be padded
+    /// with enough bits to fit another index.
+    pub fn repack(
+        &self,
+        k2: u32,
+        from_num_labels: u64,
+        to_num_labels: u64,
+    ) -> Result<Proof<'static>, ProveError> {
+        let indices = self.unpack_indices(from_num_labels, k2)?;
+        if let Some(&index) = indices.iter().find(|&&index| index >= to_num_labels) {
+            return Err(ProveError::IndexOutOfRange {
+                index,
+                num_labels: to_num_labels,
+            });
+        }
+        Ok(Proof::new(self.nonce, &indices, to_num_labels, self.pow))
+    }
+
+    /// Sort the `k2` proven indices in increasing order.
+    ///
+    /// The protocol doesn't require any order: the verifier accepts the indices
+    /// in any order, and [generate_proof] emits them in the order they were found.
+    /// The canonical (strictly increasing) form gives one encoding per set of indices,
+    /// for verifiers and tooling that want to enforce it.
+    ///
+    /// `k2` is required like in [Proof::repack]. Fails without modifying the proof
+    /// if an index repeats, as such a proof can't be made canonical.
+    pub fn canonicalize(&mut self, num_labels: u64, k2: u32) -> Result<(), ProveError> {
+        let mut indices = self.unpack_indices(num_labels, k2)?;
+        indices.sort_unstable();
+        if let Some(pair) = indices.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(ProveError::DuplicateIndex {
+                nonce: self.nonce,
+                index: pair[0],
+            });
+        }
+        self.indices = Cow::Owned(compress_indices(&indices, required_bits(num_labels)));
+        Ok(())
+    }
+
+    /// Whether the `k2` proven indices are strictly increasing. See [Proof::canonicalize].
+    pub fn is_canonical(&self, num_labels: u64, k2: u32) -> bool {
+        self.unpack_indices(num_labels, k2)
+            .is_ok_and(|indices| indices.windows(2).all(|pair| pair[0] < pair[1]))
+    }
+}
+
+/// A breakdown of a proof, see [explain].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProofExplanation {
+    pub nonce: u32,
+    pub nonce_group: u32,
+    pub pow: u64,
+    /// The number of bits per compressed index, implied by the number of labels.
+    pub keep_bits: usize,
+    /// The number of indices decoded (at most K2).
+    pub index_count: usize,
+    pub min_index: Option<u64>,
+    pub max_index: Option<u64>,
+    /// The length of the compressed indices expected for K2 indices.
+    pub expected_bytes: usize,
+    pub actual_bytes: usize,
+    /// Whether all decoded indices are below the number of labels.
+    pub indices_in_range: bool,
+}
+
+impl ProofExplanation {
+    /// Whether the length of the compressed indices matches K2.
+    pub fn is_length_consistent(&self) -> bool {
+        self.expected_bytes == self.actual_bytes
+    }
+}
+
+impl std::fmt::Display for ProofExplanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "nonce: {} (group {})", self.nonce, self.nonce_group)?;
+        writeln!(f, "pow: {}", self.pow)?;
+        writeln!(
+            f,
+            "indices: {} decoded, {} bits each",
+            self.index_count, self.keep_bits
+        )?;
+        match (self.min_index, self.max_index) {
+            (Some(min), Some(max)) => writeln!(f, "index range: {min}..={max}")?,
+            _ => writeln!(f, "index range: none")?,
+        }
+        writeln!(f, "indices in range: {}", self.indices_in_range)?;
+        write!(
+            f,
+            "length: {} bytes (expected {}, {})",
+            self.actual_bytes,
+            self.expected_bytes,
+            if self.is_length_consistent() {
+                "consistent"
+            } else {
+                "INCONSISTENT"
+            }
+        )
+    }
+}
+
+/// Describe the proof without verifying it (no PoW or labels are checked).
+///
+/// Meant for debugging proofs suspected to be malformed, before running the full verification.
+pub fn explain(proof: &Proof, cfg: &ProofConfig, num_labels: u64) -> ProofExplanation {
+    let keep_bits = required_bits(num_labels);
+    let indices = decompress_indexes(&proof.indices, keep_bits)
+        .take(cfg.k2 as usize)
+        .collect::<Vec<_>>();
+    ProofExplanation {
+        nonce: proof.nonce,
+        nonce_group: proof.nonce_group(),
+        pow: proof.pow,
+        keep_bits,
+        index_count: indices.len(),
+        min_index: indices.iter().copied().min(),
+        max_index: indices.iter().copied().max(),
+        expected_bytes: verify_core::expected_indices_bytes(keep_bits, cfg.k2),
+        actual_bytes: proof.indices.len(),
+        indices_in_range: indices.iter().all(|&index| index < num_labels),
+    }
+}
+
+/// The total number of labels, failing if it doesn't fit in u64.
+fn num_labels(metadata: &PostMetadata) -> Result<u64, ProveError> {
+    (metadata.num_units as u64)
+        .checked_mul(metadata.labels_per_unit)
+        .ok_or(ProveError::MetadataTooLarge {
+            num_units: metadata.num_units,
+            labels_per_unit: metadata.labels_per_unit,
+        })
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProveError {
+    #[error("proof generation was stopped")]
+    Cancelled,
+    #[error("metadata too large: {num_units} units of {labels_per_unit} labels")]
+    MetadataTooLarge {
+        num_units: u32,
+        labels_per_unit: u64,
+    },
+    #[error("number of labels must be > 0")]
+    NoLabels,
+    #[error("not enough indices in the proof (expected: {expected}, got: {got})")]
+    NotEnoughIndices { expected: usize, got: usize },
+    #[error("index {index} out of range for {num_labels} labels")]
+    IndexOutOfRange { index: u64, num_labels: u64 },
+    #[error("no proof found before reaching the stop condition: {0}")]
+    StopConditionReached(StopReason),
+    #[error("index {index} found twice for nonce {nonce}")]
+    DuplicateIndex { nonce: u32, index: u64 },
+    #[error("proving stalled in the pass of nonces {nonces:?}")]
+    Stalled { nonces: Range<u32> },
+    #[error("no precomputed PoW for nonce group {nonce_group}")]
+    MissingPow { nonce_group: u32 },
+    #[error("no proof found within the budget of PoW computations")]
+    PoWBudgetExhausted,
+    #[error("invalid number of threads: {0} (must be at least 1)")]
+    InvalidThreadCount(usize),
+    #[error("building a thread pool of {threads} threads")]
+    ThreadPool {
+        threads: usize,
+        #[source]
+        source: rayon::ThreadPoolBuildError,
+    },
+}
+
+/// The number of threads to prove with, given `threads` as configured
+/// by a user, where 0 means all available threads.
+///
+/// The proving functions take the number of threads as it is and fail
+/// with [ProveError::InvalidThreadCount] for 0, unless [ProvingOptions::threads]
+/// is not set, which defaults to all available threads.
+pub fn threads_or_available(threads: usize) -> usize {
+    match threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        threads => threads,
+    }
+}
+
+/// Errors of [Proof::decode].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DecodeError {
+    #[error("number of labels must be > 0")]
+    NoLabels,
+    #[error("encoded proof too short: {len} bytes")]
+    TooShort { len: usize },
+    #[error("compressed indices must be {expected} bytes long, got {got}")]
+    LengthMismatch { expected: usize, got: usize },
+}
+
+/// What to do when the same index is found more than once for a nonce.
+///
+/// It never happens, unless there is a bug in reading the POS data
+/// or in calculating the indices (i.e. overlapping batches).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateIndices {
+    /// Don't check, collect it like any other index.
+    #[default]
+    Ignore,
+    /// Collect only the first occurrence.
+    Skip,
+    /// Fail the proving with [ProveError::DuplicateIndex].
+    Fail,
+}
+
+/// Limits of the search for a proof. Once any of them is reached,
+/// the proving gives up with [ProveError::StopConditionReached].
+///
+/// The default has no limits: the search goes on until the nonce space is exhausted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StopCondition {
+    /// The maximum number of passes over the POS data.
+    pub max_passes: Option<u32>,
+    /// The maximum time to search for. It's checked between batches of POS data,
+    /// the PoW of a pass in progress is not interrupted.
+    pub max_duration: Option<Duration>,
+    /// Don't try nonces at or above it. A pass crossing it is cut short,
+    /// a pass starting at or above it is not started.
+    pub max_nonce: Option<u32>,
+}
+
+/// Which of the [StopCondition] limits was reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum StopReason {
+    #[error("made {0} passes")]
+    MaxPasses(u32),
+    #[error("searched for {0:?}")]
+    MaxDuration(Duration),
+    #[error("reached nonce {0}")]
+    MaxNonce(u32),
+}
+
+impl StopCondition {
+    /// Checks the limits before starting a pass at `next_nonce`,
+    /// after `passes` passes taking `elapsed` in total.
+    fn reached(&self, passes: u32, next_nonce: u32, elapsed: Duration) -> Option<StopReason> {
+        match *self {
+            Self {
+                max_passes: Some(max),
+                ..
+            } if passes >= max => Some(StopReason::MaxPasses(max)),
+            Self {
+                max_duration: Some(max),
+                ..
+            } if elapsed >= max => Some(StopReason::MaxDuration(max)),
+            Self {
+                max_nonce: Some(max),
+                ..
+            } if next_nonce >= max => Some(StopReason::MaxNonce(max)),
+            _ => None,
+        }
+    }
+
+    /// Cuts the pass of `nonces` short at [StopCondition::max_nonce].
+    fn limit_nonces(&self, nonces: Range<u32>) -> Range<u32> {
+        match self.max_nonce {
+            Some(max) => nonces.start..nonces.end.min(max),
+            None => nonces,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct ProvingParams {
     pub difficulty: u64,
@@ -66,25 +433,228 @@ pub struct ProvingParams {
 
 impl ProvingParams {
     pub fn new(metadata: &PostMetadata, cfg: &ProofConfig) -> eyre::Result<Self> {
-        let num_labels = metadata.num_units as u64 * metadata.labels_per_unit;
-        let mut pow_difficulty = [0u8; 32];
-        let difficulty_scaled = U256::from_big_endian(&cfg.pow_difficulty) / metadata.num_units;
-        difficulty_scaled.to_big_endian(&mut pow_difficulty);
+        Self::with_difficulty_params(metadata, cfg, &DifficultyParams::default())
+    }
+
+    /// Like [ProvingParams::new], but with custom [DifficultyParams].
+    /// Only the default parameters produce proofs that pass the protocol verification.
+    pub fn with_difficulty_params(
+        metadata: &PostMetadata,
+        cfg: &ProofConfig,
+        params: &DifficultyParams,
+    ) -> eyre::Result<Self> {
+        let num_labels = num_labels(metadata)?;
+        // The verifier derives the thresholds the same way.
+        let difficulty = proving_difficulty_with_params(cfg.k1, num_labels, params)
+            .map_err(|e| eyre::eyre!(e))?;
+        Self::checked(
+            difficulty,
+            scale_pow_difficulty(&cfg.pow_difficulty, metadata.num_units),
+            cfg,
+            num_labels,
+        )
+    }
+
+    /// The parameters derived once in the `context`, like [ProvingParams::new].
+    pub fn from_context(context: &PostContext) -> eyre::Result<Self> {
+        Self::checked(
+            context.difficulty(),
+            *context.pow_difficulty(),
+            context.cfg(),
+            context.num_labels(),
+        )
+    }
+
+    fn checked(
+        difficulty: u64,
+        pow_difficulty: [u8; 32],
+        cfg: &ProofConfig,
+        num_labels: u64,
+    ) -> eyre::Result<Self> {
+        // The MSB of the difficulty is 0 whenever K1 < num_labels / 256 (as in all real configs),
+        // then the LSB of every label with MSB 0 is checked too. Only 0 can't be satisfied.
+        eyre::ensure!(
+            difficulty > 0,
+            "proving difficulty is 0, no label can satisfy it: k1 ({}) is likely misconfigured for {num_labels} labels",
+            cfg.k1,
+        );
+        if difficulty >> 56 == 0 {
+            log::debug!("difficulty MSB is 0, the LSB of all labels with MSB 0 will be checked");
+        }
         Ok(Self {
-            difficulty: proving_difficulty(cfg.k1, num_labels).map_err(|e| eyre::eyre!(e))?,
+            difficulty,
             pow_difficulty,
         })
     }
+
+    /// The parameters `proof` must have been generated with, to audit a stored proof.
+    ///
+    /// They are the same as [ProvingParams::new] returns: the verifier derives the difficulty
+    /// from the metadata and config too, never from the proof. Fails if the proof
+    /// can't have been found with them (i.e. its indices don't fit K2 indices of the labels
+    /// in `metadata`). It doesn't check the PoW or the labels, that's up to the verification.
+    pub fn expected_for(
+        proof: &Proof,
+        metadata: &PostMetadata,
+        cfg: &ProofConfig,
+    ) -> eyre::Result<Self> {
+        let params = Self::new(metadata, cfg)?;
+        let num_labels = num_labels(metadata)?;
+        let explanation = explain(proof, cfg, num_labels);
+        eyre::ensure!(
+            explanation.is_length_consistent(),
+            "proof has {} bytes of indices, expected {} for K2={} and {num_labels} labels",
+            explanation.actual_bytes,
+            explanation.expected_bytes,
+            cfg.k2,
+        );
+        eyre::ensure!(
+            explanation.indices_in_range,
+            "proof has indices up to {:?}, beyond {num_labels} labels",
+            explanation.max_index,
+        );
+        eyre::ensure!(
+            explanation.nonce_group <= u8::MAX as u32,
+            "nonce group {} of the proof is out of bounds",
+            explanation.nonce_group,
+        );
+        Ok(params)
+    }
+
+    /// The MSB of the difficulty, checked against a byte of the nonce group's AES output.
+    pub fn difficulty_msb(&self) -> u8 {
+        Prover8_56::split_difficulty(self.difficulty).0
+    }
+
+    /// The lower 56 bits of the difficulty, checked against the output
+    /// of the per-nonce AES when the MSB equals [ProvingParams::difficulty_msb].
+    pub fn difficulty_lsb(&self) -> u64 {
+        Prover8_56::split_difficulty(self.difficulty).1
+    }
+
+    /// The PoW difficulty as a number. A PoW hash (big-endian) must be below it.
+    pub fn pow_difficulty_u256(&self) -> U256 {
+        U256::from_big_endian(&self.pow_difficulty)
+    }
+
+    /// Parameters that every label and every PoW satisfy.
+    ///
+    /// **WARNING**: ONLY FOR TESTS. Proofs generated with these parameters
+    /// DON'T satisfy the protocol difficulty and will FAIL verification.
+    /// It's meant to quickly produce valid-shaped proofs in tests,
+    /// by proving with [ProvingOptions::params].
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn trivial() -> Self {
+        Self {
+            difficulty: u64::MAX,
+            pow_difficulty: [0xFF; 32],
+        }
+    }
 }
 
+/// Looks for labels satisfying the difficulty for a range of nonces.
+///
+/// It has generic methods, so it can't be used as a trait object. Tooling generic
+/// over provers can use [CheckLabel] instead of needing the concrete [Prover8_56].
 pub trait Prover {
     fn prove<F>(&self, batch: &[u8], index: u64, consume: F) -> Option<(u32, Vec<u64>)>
     where
         F: FnMut(u32, u64) -> Option<Vec<u64>>;
 
+    /// Like [Prover::prove], but buffers the candidates and passes them to `consume`
+    /// in batches: all indices buffered for a nonce at once, in increasing order.
+    /// The buffer is flushed when it's full and at the end of `batch`.
+    ///
+    /// It's much cheaper than [Prover::prove] for a `consume` that takes a lock
+    /// for every call, especially with a low difficulty (many candidates).
+    fn prove_batched<F>(&self, batch: &[u8], index: u64, consume: F) -> Option<(u32, Vec<u64>)>
+    where
+        F: FnMut(u32, &[u64]) -> Option<Vec<u64>>,
+    {
+        let mut buffer = CandidateBuffer::new(consume);
+        // The buffer keeps the found indices, `prove` only needs to stop.
+        self.prove(batch, index, |nonce, index| {
+            buffer.push(nonce, index).then(Vec::new)
+        });
+        buffer.finish()
+    }
+
     fn get_pow(&self, nonce: u32) -> Option<u64>;
 }
 
+/// Checks single labels like a [Prover], without the data.
+///
+/// It's separate from [Prover], so it can be used as a trait object
+/// (i.e. `&dyn CheckLabel`) and implementing [Prover] doesn't require it.
+pub trait CheckLabel {
+    /// The difficulty the labels are checked against.
+    fn difficulty(&self) -> u64;
+
+    /// Whether the label satisfies the difficulty for the nonce,
+    /// like [Prover::prove] would find it. Always false for nonces not proven for.
+    fn check_label(&self, nonce: u32, label: &[u8; LABEL_SIZE]) -> bool;
+}
+
+/// The number of candidates buffered by [Prover::prove_batched] before flushing.
+pub const CANDIDATE_BUFFER_SIZE: usize = 1024;
+
+/// Buffers candidates for a batched `consume`, see [Prover::prove_batched].
+struct CandidateBuffer<F> {
+    candidates: Vec<(u32, u64)>,
+    indices: Vec<u64>,
+    consume: F,
+    found: Option<(u32, Vec<u64>)>,
+}
+
+impl<F> CandidateBuffer<F>
+where
+    F: FnMut(u32, &[u64]) -> Option<Vec<u64>>,
+{
+    fn new(consume: F) -> Self {
+        Self {
+            candidates: Vec::with_capacity(CANDIDATE_BUFFER_SIZE),
+            indices: Vec::new(),
+            consume,
+            found: None,
+        }
+    }
+
+    /// Buffers a candidate. Returns true once `consume` returned the indices.
+    fn push(&mut self, nonce: u32, index: u64) -> bool {
+        self.candidates.push((nonce, index));
+        self.candidates.len() >= CANDIDATE_BUFFER_SIZE && self.flush()
+    }
+
+    /// Passes the buffered candidates to `consume`, grouped by nonce.
+    /// Returns true once `consume` returned the indices.
+    fn flush(&mut self) -> bool {
+        // Stable, so the indices of a nonce stay in the order they were found.
+        self.candidates.sort_by_key(|&(nonce, _)| nonce);
+        let mut rest = &self.candidates[..];
+        while let Some(&(nonce, _)) = rest.first() {
+            let len = rest.iter().take_while(|&&(n, _)| n == nonce).count();
+            self.indices.clear();
+            self.indices
+                .extend(rest[..len].iter().map(|&(_, index)| index));
+            rest = &rest[len..];
+            if let Some(indices) = (self.consume)(nonce, &self.indices) {
+                self.found = Some((nonce, indices));
+                break;
+            }
+        }
+        self.candidates.clear();
+        self.found.is_some()
+    }
+
+    /// Flushes the remaining candidates and returns the found indices.
+    fn finish(mut self) -> Option<(u32, Vec<u64>)> {
+        if self.found.is_none() {
+            self.flush();
+        }
+        self.found
+    }
+}
+
 // Calculate nonce value given nonce group and its offset within the group.
 #[inline(always)]
 fn calc_nonce(nonce_group: u32, per_aes: u32, offset: usize) -> u32 {
@@ -103,84 +673,286 @@ fn nonce_group_range(nonces: Range<u32>, per_aes: u32) -> Range<u32> {
     start_group..end_group
 }
 
+/// The nonce groups whose PoW is needed to prove for the nonces.
+///
+/// It's never empty: an empty range of nonces still belongs to a nonce group.
+pub fn required_nonce_groups(nonces: Range<u32>) -> Range<u32> {
+    nonce_group_range(nonces, Prover8_56::NONCES_PER_AES)
+}
+
+/// Serves PoWs calculated in advance, see [Prover8_56::from_precomputed_pow]
+/// and [ProvingOptions::precomputed_pows]. The PoWs of other nonce groups
+/// are calculated with the `fallback`, if any.
+struct PrecomputedPow<'a> {
+    pows: &'a HashMap<u32, u64>,
+    fallback: Option<&'a (dyn pow::Prover + Send + Sync)>,
+}
+
+impl pow::Prover for PrecomputedPow<'_> {
+    fn prove(
+        &self,
+        nonce_group: u8,
+        challenge: &[u8; 8],
+        difficulty: &[u8; 32],
+        miner_id: &[u8; 32],
+    ) -> Result<u64, pow::Error> {
+        match (self.pows.get(&(nonce_group as u32)), self.fallback) {
+            (Some(&pow), _) => Ok(pow),
+            (None, Some(fallback)) => fallback.prove(nonce_group, challenge, difficulty, miner_id),
+            (None, None) => Err(pow::Error::PoWNotFound),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Prover8_56 {
+    nonces: Range<u32>,
     ciphers: Vec<AesCipher>,
     lazy_ciphers: Vec<AesCipher>,
+    difficulty: u64,
     difficulty_msb: u8,
     difficulty_lsb: u64,
+    // Only if the nonces have different difficulties,
+    // otherwise the proving compares with `difficulty_msb` and `difficulty_lsb`.
+    nonce_difficulties: Option<NonceDifficulties>,
+    pow_times: Vec<Duration>,
+}
+
+/// The difficulties of [ProverOptions::nonce_difficulty].
+#[derive(Debug)]
+struct NonceDifficulties {
+    // The difficulty of every nonce, by offset from `nonces.start`.
+    difficulties: Vec<u64>,
+    // The MSB of the difficulty of the nonces of every nonce group (parallel to `ciphers`),
+    // indexed by the offset of the nonce in the group.
+    msb_difficulties: Vec<[u8; verify_core::NONCES_PER_AES as usize]>,
+}
+
+/// Optional settings of [Prover8_56::new_with_options].
+#[derive(Clone, Default)]
+pub struct ProverOptions<'a> {
+    /// Checked between calculating the PoW of consecutive nonce groups.
+    /// When set, the construction fails with [ProveError::Cancelled].
+    pub stop: Option<&'a AtomicBool>,
+    /// Record how long the PoW of every nonce group took (see [Prover8_56::pow_times]).
+    pub record_pow_times: bool,
+    /// Derives the keys of the AES ciphers. Defaults to [Blake3KeyDerivation].
+    pub key_derivation: Option<&'a dyn KeyDerivation>,
+    /// The difficulty of every nonce, overriding [ProvingParams::difficulty]
+    /// for protocol variants where it varies by nonce. Called once for every nonce
+    /// when the prover is created, so the proving compares with a precomputed threshold.
+    ///
+    /// NOTE: proofs found with a difficulty other than [ProvingParams::difficulty]
+    /// don't verify under the protocol rules.
+    pub nonce_difficulty: Option<&'a dyn Fn(u32) -> u64>,
+}
+
+impl std::fmt::Debug for ProverOptions<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProverOptions")
+            .field("stop", &self.stop)
+            .field("record_pow_times", &self.record_pow_times)
+            .field("key_derivation", &self.key_derivation)
+            .field("nonce_difficulty", &self.nonce_difficulty.map(|_| "Fn"))
+            .finish()
+    }
 }
 
 impl Prover8_56 {
-    pub(crate) const NONCES_PER_AES: u32 = 16;
+    pub(crate) const NONCES_PER_AES: u32 = verify_core::NONCES_PER_AES;
 
-    pub fn new<P: pow::Prover>(
+    pub fn new<P: pow::Prover + ?Sized>(
         challenge: &[u8; 32],
         nonces: Range<u32>,
         params: ProvingParams,
         pow_prover: &P,
         miner_id: &[u8; 32],
     ) -> eyre::Result<Self> {
-        // TODO consider to relax it to allow any range of nonces
-        eyre::ensure!(
-            nonces.start % Self::NONCES_PER_AES == 0,
-            "nonces must start at a multiple of 16"
-        );
-        eyre::ensure!(
-            !nonces.is_empty() && nonces.len() % Self::NONCES_PER_AES as usize == 0,
-            "nonces must be a multiple of 16"
+        Self::new_with_options(
+            challenge,
+            nonces,
+            params,
+            pow_prover,
+            miner_id,
+            ProverOptions::default(),
+        )
+    }
+
+    pub fn new_with_options<P: pow::Prover + ?Sized>(
+        challenge: &[u8; 32],
+        nonces: Range<u32>,
+        params: ProvingParams,
+        pow_prover: &P,
+        miner_id: &[u8; 32],
+        options: ProverOptions,
+    ) -> eyre::Result<Self> {
+        eyre::ensure!(!nonces.is_empty(), "nonces must not be empty");
+        log::info!(
+            "calculating proof of work for nonces {nonces:?} ({} nonce groups)",
+            Self::nonce_groups_for(&nonces)
         );
-        log::info!("calculating proof of work for nonces {nonces:?}",);
+        let derivation = options.key_derivation.unwrap_or(&Blake3KeyDerivation);
+        let mut pow_times = Vec::new();
         let ciphers: Vec<AesCipher> = nonce_group_range(nonces.clone(), Self::NONCES_PER_AES)
             .map(|nonce_group| {
+                if options
+                    .stop
+                    .is_some_and(|stop| stop.load(Ordering::Relaxed))
+                {
+                    return Err(ProveError::Cancelled.into());
+                }
                 log::debug!("calculating proof of work for nonce group {nonce_group}");
+                let started = options.record_pow_times.then(Instant::now);
                 let pow = pow_prover.prove(
                     nonce_group.try_into()?,
                     challenge[..8].try_into().unwrap(),
                     &params.pow_difficulty,
                     miner_id,
                 )?;
-                log::debug!("proof of work: {pow}");
+                if let Some(started) = started {
+                    pow_times.push(started.elapsed());
+                }
+                // Not logging the value, it would be the PoW of the proof for one of the groups
+                // (see `ProvingOptions::redact_pow`).
+                log::debug!("proof of work for nonce group {nonce_group} calculated");
 
-                Ok(AesCipher::new(challenge, nonce_group, pow))
+                Ok(AesCipher::new_with(derivation, challenge, nonce_group, pow))
             })
             .collect::<eyre::Result<_>>()?;
 
+        let start_group = calc_nonce_group(nonces.start, Self::NONCES_PER_AES);
         let lazy_ciphers = nonces
+            .clone()
             .map(|nonce| {
                 let nonce_group = calc_nonce_group(nonce, Self::NONCES_PER_AES);
-                AesCipher::new_lazy(
+                AesCipher::new_lazy_with(
+                    derivation,
                     challenge,
                     nonce,
                     nonce_group as u32,
-                    ciphers[nonce_group % ciphers.len()].pow,
+                    ciphers[nonce_group - start_group].pow,
                 )
             })
             .collect();
 
+        let nonce_difficulties = options
+            .nonce_difficulty
+            .map(|nonce_difficulty| nonces.clone().map(nonce_difficulty).collect::<Vec<_>>())
+            .filter(|difficulties| difficulties.iter().any(|&d| d != params.difficulty))
+            .map(|difficulties| {
+                let msb_difficulties = ciphers
+                    .iter()
+                    .map(|cipher| {
+                        std::array::from_fn(|offset| {
+                            let nonce =
+                                calc_nonce(cipher.nonce_group, Self::NONCES_PER_AES, offset);
+                            // Nonces out of the range are skipped anyway.
+                            nonce
+                                .checked_sub(nonces.start)
+                                .and_then(|i| difficulties.get(i as usize))
+                                .map_or(0, |&difficulty| Self::split_difficulty(difficulty).0)
+                        })
+                    })
+                    .collect();
+                NonceDifficulties {
+                    difficulties,
+                    msb_difficulties,
+                }
+            });
         let (difficulty_msb, difficulty_lsb) = Self::split_difficulty(params.difficulty);
         Ok(Self {
+            nonces,
             ciphers,
             lazy_ciphers,
+            difficulty: params.difficulty,
             difficulty_msb,
             difficulty_lsb,
+            nonce_difficulties,
+            pow_times,
         })
     }
 
+    /// Create a prover with the PoWs calculated in advance (i.e. on another machine),
+    /// given for every nonce group in [required_nonce_groups].
+    ///
+    /// The PoWs are not verified here. A wrong one makes the proof invalid.
+    pub fn from_precomputed_pow(
+        challenge: &[u8; 32],
+        nonces: Range<u32>,
+        params: ProvingParams,
+        pows: &HashMap<u32, u64>,
+    ) -> eyre::Result<Self> {
+        if let Some(nonce_group) =
+            required_nonce_groups(nonces.clone()).find(|g| !pows.contains_key(g))
+        {
+            return Err(ProveError::MissingPow { nonce_group }.into());
+        }
+        let pow_prover = PrecomputedPow {
+            pows,
+            fallback: None,
+        };
+        Self::new(challenge, nonces, params, &pow_prover, &[0; 32])
+    }
+
+    /// How long the PoW of each nonce group took, in the order of nonce groups.
+    /// Empty unless enabled with [ProverOptions::record_pow_times].
+    pub fn pow_times(&self) -> &[Duration] {
+        &self.pow_times
+    }
+
+    /// The number of nonce groups (and so PoWs to calculate) needed to cover
+    /// the given range of nonces.
+    pub fn nonce_groups_for(nonces: &Range<u32>) -> usize {
+        nonce_group_range(nonces.clone(), Self::NONCES_PER_AES).len()
+    }
+
+    /// The number of nonce groups covered by this prover.
+    /// There is one AES cipher (and PoW) for each of them.
+    pub fn num_nonce_groups(&self) -> usize {
+        self.ciphers.len()
+    }
+
+    /// The range of nonces this prover looks for a proof for.
+    pub fn nonces(&self) -> Range<u32> {
+        self.nonces.clone()
+    }
+
+    /// The difficulty the labels are checked against for the nonce,
+    /// see [ProverOptions::nonce_difficulty]. `None` for nonces not proven for.
+    pub fn nonce_difficulty(&self, nonce: u32) -> Option<u64> {
+        if !self.nonces.contains(&nonce) {
+            return None;
+        }
+        match &self.nonce_difficulties {
+            Some(nonce_difficulties) => nonce_difficulties
+                .difficulties
+                .get((nonce - self.nonces.start) as usize)
+                .copied(),
+            None => Some(self.difficulty),
+        }
+    }
+
+    /// Whether all nonces have the same difficulty ([Prover::difficulty]).
+    /// Then the proving compares all labels with the same threshold.
+    pub fn has_uniform_difficulty(&self) -> bool {
+        self.nonce_difficulties.is_none()
+    }
+
     pub(crate) fn split_difficulty(difficulty: u64) -> (u8, u64) {
-        ((difficulty >> 56) as u8, difficulty & 0x00ff_ffff_ffff_ffff)
+        verify_core::split_difficulty(difficulty)
     }
 
     #[inline(always)]
     fn cipher(&self, nonce: u32) -> Option<&AesCipher> {
-        self.ciphers
-            .get(calc_nonce_group(nonce, Self::NONCES_PER_AES) % self.ciphers.len())
+        let start_group = calc_nonce_group(self.nonces.start, Self::NONCES_PER_AES);
+        let nonce_group = calc_nonce_group(nonce, Self::NONCES_PER_AES);
+        self.ciphers.get(nonce_group.checked_sub(start_group)?)
     }
 
     #[inline(always)]
     fn lazy_cipher(&self, nonce: u32) -> Option<&AesCipher> {
         self.lazy_ciphers
-            .get(nonce as usize % self.lazy_ciphers.len())
+            .get(nonce.checked_sub(self.nonces.start)? as usize)
     }
 
     /// LSB part of the difficulty is checked with second sequence of AES ciphers.
@@ -191,10 +963,12 @@ impl Prover8_56 {
         nonce_offset: usize,
         base_index: u64,
         mut consume: F,
+        diagnostics: &mut PassDiagnostics,
     ) -> Option<(u32, Vec<u64>)>
     where
         F: FnMut(u32, u64) -> Option<Vec<u64>>,
     {
+        diagnostics.lsb_checks += 1;
         let mut out = [0u64; 2];
 
         self.lazy_cipher(nonce)
@@ -203,7 +977,12 @@ impl Prover8_56 {
             .encrypt_block_b2b(label.into(), bytemuck::cast_slice_mut(&mut out).into());
 
         let lsb = out[0].to_le() & 0x00ff_ffff_ffff_ffff;
-        if lsb < self.difficulty_lsb {
+        let difficulty_lsb = match &self.nonce_difficulties {
+            Some(_) => Self::split_difficulty(self.nonce_difficulty(nonce).unwrap()).1,
+            None => self.difficulty_lsb,
+        };
+        if lsb < difficulty_lsb {
+            diagnostics.lsb_passes += 1;
             let index = base_index + (nonce_offset / Self::NONCES_PER_AES as usize) as u64;
             if let Some(indexes) = consume(nonce, index) {
                 return Some((nonce, indexes));
@@ -213,57 +992,582 @@ impl Prover8_56 {
     }
 }
 
+impl CheckLabel for Prover8_56 {
+    /// The difficulty of [ProvingParams::difficulty]. The nonces might have
+    /// other ones, see [Prover8_56::nonce_difficulty].
+    fn difficulty(&self) -> u64 {
+        self.difficulty
+    }
+
+    fn check_label(&self, nonce: u32, label: &[u8; LABEL_SIZE]) -> bool {
+        let (Some(cipher), Some(lazy_cipher), Some(difficulty)) = (
+            self.cipher(nonce),
+            self.lazy_cipher(nonce),
+            self.nonce_difficulty(nonce),
+        ) else {
+            return false;
+        };
+        let (difficulty_msb, difficulty_lsb) = Self::split_difficulty(difficulty);
+        let mut out = [0u8; BLOCK_SIZE];
+        cipher
+            .aes
+            .encrypt_block_b2b(label.as_slice().into(), out.as_mut_slice().into());
+        let msb = out[(nonce % Self::NONCES_PER_AES) as usize];
+        match msb.cmp(&difficulty_msb) {
+            std::cmp::Ordering::Less => true,
+            std::cmp::Ordering::Greater => false,
+            std::cmp::Ordering::Equal => {
+                lazy_cipher
+                    .aes
+                    .encrypt_block_b2b(label.as_slice().into(), out.as_mut_slice().into());
+                let lsb = u64::from_le_bytes(out[..8].try_into().unwrap()) & 0x00ff_ffff_ffff_ffff;
+                lsb < difficulty_lsb
+            }
+        }
+    }
+}
+
 impl Prover for Prover8_56 {
     fn get_pow(&self, nonce: u32) -> Option<u64> {
         self.cipher(nonce).map(|aes| aes.pow)
     }
 
-    fn prove<F>(&self, batch: &[u8], mut index: u64, mut consume: F) -> Option<(u32, Vec<u64>)>
+    fn prove<F>(&self, batch: &[u8], index: u64, consume: F) -> Option<(u32, Vec<u64>)>
     where
         F: FnMut(u32, u64) -> Option<Vec<u64>>,
     {
-        let mut u8s = [0u8; CHUNK_SIZE];
+        self.prove_with_diagnostics(batch, index, consume, &mut PassDiagnostics::default())
+    }
+}
 
-        for chunk in batch.chunks_exact(CHUNK_SIZE) {
-            for cipher in &self.ciphers {
-                _ = cipher.aes.encrypt_padded_b2b::<NoPadding>(chunk, &mut u8s);
+/// Statistics of looking for a proof, to tell why a pass found nothing.
+///
+/// Few MSB passes mean that the difficulty is too hard (K1 is too low),
+/// many MSB passes but low `max_indices` mean that K2 is too high.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PassDiagnostics {
+    /// The nonces of the pass.
+    pub nonces: Range<u32>,
+    /// Labels with MSB below the MSB of the difficulty (accepted without checking LSB).
+    pub msb_passes: u64,
+    /// Labels with MSB equal to the MSB of the difficulty, so the LSB had to be checked.
+    pub lsb_checks: u64,
+    /// Labels that passed the LSB check.
+    pub lsb_passes: u64,
+    /// The biggest number of indices collected by a single nonce.
+    pub max_indices: usize,
+    /// How long the PoW of each nonce group of the pass took.
+    /// Empty unless enabled with [ProvingOptions::record_pow_times].
+    pub pow_times: Vec<Duration>,
+}
 
-                for (offset, &msb) in u8s.iter().enumerate() {
-                    if msb <= self.difficulty_msb {
-                        if msb == self.difficulty_msb {
-                            // Check LSB
-                            let nonce =
-                                calc_nonce(cipher.nonce_group, Self::NONCES_PER_AES, offset);
-                            let label_offset = offset / Self::NONCES_PER_AES as usize * LABEL_SIZE;
-                            if let Some(p) = self.check_lsb(
-                                &chunk[label_offset..label_offset + LABEL_SIZE],
-                                nonce,
-                                offset,
-                                index,
-                                &mut consume,
-                            ) {
-                                return Some(p);
-                            }
-                        } else {
-                            // valid label
-                            let index = index + (offset as u32 / Self::NONCES_PER_AES) as u64;
-                            let nonce =
-                                calc_nonce(cipher.nonce_group, Self::NONCES_PER_AES, offset);
-                            if let Some(indexes) = consume(nonce, index) {
-                                return Some((nonce, indexes));
-                            }
+impl PassDiagnostics {
+    fn add(&mut self, other: &PassDiagnostics) {
+        self.msb_passes += other.msb_passes;
+        self.lsb_checks += other.lsb_checks;
+        self.lsb_passes += other.lsb_passes;
+        self.max_indices = self.max_indices.max(other.max_indices);
+        self.pow_times.extend_from_slice(&other.pow_times);
+    }
+}
+
+impl Prover8_56 {
+    /// Like [Prover::prove], but also counts the labels that passed the checks
+    /// into `diagnostics`. It doesn't update [PassDiagnostics::max_indices],
+    /// as the indices are collected by `consume`.
+    pub fn prove_with_diagnostics<F>(
+        &self,
+        batch: &[u8],
+        mut index: u64,
+        mut consume: F,
+        diagnostics: &mut PassDiagnostics,
+    ) -> Option<(u32, Vec<u64>)>
+    where
+        F: FnMut(u32, u64) -> Option<Vec<u64>>,
+    {
+        // Every label must be fully encrypted: the AES output is pseudorandom,
+        // so no cheaper function of the label (i.e. a SipHash) can predict its MSB.
+        // A prefilter that never drops a true candidate would have to pass all labels.
+        let mut chunks = batch.chunks_exact(CHUNK_SIZE);
+        for chunk in &mut chunks {
+            if let Some(p) = self.prove_chunk(chunk, AES_BATCH, index, &mut consume, diagnostics) {
+                return Some(p);
+            }
+            index += AES_BATCH as u64;
+        }
+
+        // The data might end with a partial chunk (if the number of labels isn't
+        // a multiple of AES_BATCH). Pad it, but check only the whole labels in it.
+        let tail = chunks.remainder();
+        let labels = tail.len() / LABEL_SIZE;
+        if labels > 0 {
+            let mut chunk = [0u8; CHUNK_SIZE];
+            chunk[..tail.len()].copy_from_slice(tail);
+            return self.prove_chunk(&chunk, labels, index, &mut consume, diagnostics);
+        }
+        None
+    }
+
+    /// Checks the first `labels` labels of the chunk for all nonces.
+    /// `index` is the index of the first label in the chunk.
+    fn prove_chunk<F>(
+        &self,
+        chunk: &[u8],
+        labels: usize,
+        index: u64,
+        consume: &mut F,
+        diagnostics: &mut PassDiagnostics,
+    ) -> Option<(u32, Vec<u64>)>
+    where
+        F: FnMut(u32, u64) -> Option<Vec<u64>>,
+    {
+        match &self.nonce_difficulties {
+            // The common case keeps comparing with a single threshold.
+            None => self.prove_chunk_with(chunk, labels, index, consume, diagnostics, |_, _| {
+                self.difficulty_msb
+            }),
+            Some(nonce_difficulties) => self.prove_chunk_with(
+                chunk,
+                labels,
+                index,
+                consume,
+                diagnostics,
+                |group, offset| {
+                    nonce_difficulties.msb_difficulties[group]
+                        [offset % Self::NONCES_PER_AES as usize]
+                },
+            ),
+        }
+    }
+
+    /// [Prover8_56::prove_chunk] with the MSB of the difficulty given by `difficulty_msb`
+    /// for the position of the nonce group in `ciphers` and the output offset.
+    #[inline(always)]
+    fn prove_chunk_with<F, D>(
+        &self,
+        chunk: &[u8],
+        labels: usize,
+        index: u64,
+        consume: &mut F,
+        diagnostics: &mut PassDiagnostics,
+        difficulty_msb: D,
+    ) -> Option<(u32, Vec<u64>)>
+    where
+        F: FnMut(u32, u64) -> Option<Vec<u64>>,
+        D: Fn(usize, usize) -> u8,
+    {
+        let mut u8s = [0u8; CHUNK_SIZE];
+        // Every label gives one output byte for each nonce of the group.
+        let outputs = labels * Self::NONCES_PER_AES as usize;
+        for (group, cipher) in self.ciphers.iter().enumerate() {
+            _ = cipher.aes.encrypt_padded_b2b::<NoPadding>(chunk, &mut u8s);
+
+            for (offset, &msb) in u8s[..outputs].iter().enumerate() {
+                let difficulty_msb = difficulty_msb(group, offset);
+                if msb <= difficulty_msb {
+                    let nonce = calc_nonce(cipher.nonce_group, Self::NONCES_PER_AES, offset);
+                    // The first and last nonce groups might be only partially in the range.
+                    if !self.nonces.contains(&nonce) {
+                        continue;
+                    }
+                    if msb == difficulty_msb {
+                        // Check LSB
+                        let label_offset = offset / Self::NONCES_PER_AES as usize * LABEL_SIZE;
+                        if let Some(p) = self.check_lsb(
+                            &chunk[label_offset..label_offset + LABEL_SIZE],
+                            nonce,
+                            offset,
+                            index,
+                            &mut *consume,
+                            diagnostics,
+                        ) {
+                            return Some(p);
+                        }
+                    } else {
+                        // valid label
+                        diagnostics.msb_passes += 1;
+                        let index = index + (offset as u32 / Self::NONCES_PER_AES) as u64;
+                        if let Some(indexes) = consume(nonce, index) {
+                            return Some((nonce, indexes));
                         }
                     }
                 }
             }
-            index += AES_BATCH as u64;
         }
-
         None
     }
 }
 
-/// Generate a proof that data is still held, given the challenge.
+/// Count how many times each MSB value is produced by encrypting the labels in `data`
+/// with the AES cipher of the nonce group.
+///
+/// Every label (trailing partial label is ignored) gives 16 MSB values,
+/// one for each nonce in the group. A label is a candidate for a nonce in
+/// [Prover8_56::prove] if its MSB is <= the MSB of the difficulty.
+pub fn msb_histogram(data: &[u8], challenge: &[u8; 32], nonce_group: u32, pow: u64) -> [u64; 256] {
+    let cipher = AesCipher::new(challenge, nonce_group, pow);
+    let mut histogram = [0u64; 256];
+    let mut out = [0u8; BLOCK_SIZE];
+    for label in data.chunks_exact(LABEL_SIZE) {
+        cipher
+            .aes
+            .encrypt_block_b2b(label.into(), (&mut out).into());
+        for msb in out {
+            histogram[msb as usize] += 1;
+        }
+    }
+    histogram
+}
+
+/// The expected number of bytes of POS data read before a proof is found,
+/// when searching `nonces` nonces per pass, to size the storage bandwidth.
+///
+/// Every label satisfies the difficulty for a nonce with probability
+/// `p = difficulty / 2^64`, independently, so the number of indices a nonce collects
+/// in the first `n` labels is approximated with Poisson(`n * p`). The pass reads until
+/// any of its nonces collects `k2` indices. A pass that finds no proof reads all
+/// data and is followed by another one, with other nonces.
+///
+/// It's a lower bound of what is actually read: the threads of [generate_proof]
+/// read ahead and finish their batches. Returns `u64::MAX` if a proof can't be found.
+pub fn expected_bytes_scanned(
+    params: &ProvingParams,
+    metadata: &PostMetadata,
+    k2: u32,
+    nonces: u32,
+) -> u64 {
+    let p = params.difficulty as f64 / 2f64.powi(64);
+    let num_labels = metadata.total_labels() as f64;
+    if p == 0.0 || num_labels == 0.0 {
+        return u64::MAX;
+    }
+    // The probability that no nonce collected `k2` indices yet,
+    // after reading the labels expected to yield `lambda` indices per nonce.
+    let none_found = |lambda: f64| poisson_cdf_below(k2, lambda).powi(nonces as i32);
+
+    // E[labels read in a pass] = integral of P(no proof in the first t labels) over t.
+    const STEPS: usize = 10_000;
+    let max_lambda = num_labels * p;
+    let step = max_lambda / STEPS as f64;
+    let mut integral = (none_found(0.0) + none_found(max_lambda)) / 2.0;
+    for i in 1..STEPS {
+        integral += none_found(i as f64 * step);
+    }
+    let labels_per_pass = integral * step / p;
+
+    let pass_fails = none_found(max_lambda);
+    if pass_fails >= 1.0 {
+        return u64::MAX;
+    }
+    (labels_per_pass / (1.0 - pass_fails) * LABEL_SIZE as f64) as u64
+}
+
+/// P(X < k) for X ~ Poisson(lambda).
+fn poisson_cdf_below(k: u32, lambda: f64) -> f64 {
+    let mut term = (-lambda).exp();
+    let mut sum = 0.0;
+    for i in 0..k {
+        sum += term;
+        term *= lambda / (i + 1) as f64;
+    }
+    sum.min(1.0)
+}
+
+/// Optional tweaks of the proving process for [generate_proof_with_options].
+///
+/// The defaults give exactly the same behavior as [generate_proof] with all available
+/// threads and the recommended PoW flags.
+#[derive(Debug, Clone, Default)]
+pub struct ProvingOptions {
+    /// The number of threads to prove with. Defaults to all available threads,
+    /// see [threads_or_available].
+    pub threads: Option<usize>,
+    /// The flags to initialize RandomX with. Defaults to [RandomXFlag::get_recommended_flags].
+    pub pow_flags: Option<RandomXFlag>,
+    /// The number of indices to collect for a nonce before it's accepted as a proof.
+    /// Defaults to `cfg.k2` when not set.
+    ///
+    /// NOTE: proofs produced with a target other than `cfg.k2` won't verify under
+    /// the protocol rules. It's meant for tooling (i.e. previews or analysis).
+    pub target_indices: Option<u32>,
+    /// How to read the POS data.
+    pub reader: ReaderConfig,
+    /// The nonce to start the search at. Defaults to 0.
+    ///
+    /// The passes go up to the end of the nonce space and then wrap around to 0,
+    /// until all nonces are tried. See [seeded_start_nonce] for spreading
+    /// the PoW work of provers in a fleet.
+    ///
+    /// There is no state kept between calls. An interrupted proving restarted with
+    /// the same `start_nonce` redoes the same passes (including the PoW) from the beginning.
+    pub start_nonce: Option<u32>,
+    /// Use this PoW prover instead of initializing a new one (the `pow_flags` are ignored).
+    /// It allows sharing a single RandomX [PoW] with the [Verifier](crate::verification::Verifier).
+    pub pow_prover: Option<Arc<dyn pow::Prover + Send + Sync>>,
+    /// Don't calculate the PoW (RandomX is not initialized at all).
+    /// The proof gets [pow::NO_POW] as its PoW (see [Proof::is_pow_skipped]).
+    ///
+    /// NOTE: such proofs won't verify. Only for environments validating PoW separately or for tests.
+    pub skip_pow: bool,
+    /// The PoW algorithm, unless `pow_prover` is given (or `skip_pow` set). Defaults to RandomX.
+    pub pow_algorithm: pow::PowAlgorithm,
+    /// The maximum number of RandomX VMs calculating the PoW, see [PoW::with_max_vms].
+    /// Every VM has a 2 MiB scratchpad. Defaults to one VM per thread of the proving pool.
+    ///
+    /// Ignored if the `pow_prover` is given.
+    pub max_pow_vms: Option<usize>,
+    /// PoWs calculated in advance (i.e. by another machine), by nonce group.
+    ///
+    /// They are used as they are, without verifying. The PoWs of other nonce groups
+    /// are calculated as usual. RandomX is initialized only once a pass needs
+    /// a PoW that is not here. See [required_nonce_groups] for the groups of a pass.
+    pub precomputed_pows: HashMap<u32, u64>,
+    /// The maximum number of PoWs (one per nonce group) to calculate over all passes.
+    /// The [precomputed PoWs](ProvingOptions::precomputed_pows) don't count.
+    ///
+    /// A pass that would go over the budget is not started,
+    /// failing the proving with [ProveError::PoWBudgetExhausted].
+    pub max_pow_computations: Option<u32>,
+    /// Receives the [PassDiagnostics] after every pass, including the one that found the proof.
+    pub diagnostics: Option<mpsc::Sender<PassDiagnostics>>,
+    /// When to give up searching for a proof.
+    pub stop_condition: StopCondition,
+    /// Whether to check for indices found more than once for a nonce.
+    pub duplicate_indices: DuplicateIndices,
+    /// Watch for passes making no progress, i.e. because of a hung disk.
+    pub watchdog: Option<Watchdog>,
+    /// Record how long the PoW of every nonce group took, see [PassDiagnostics::pow_times].
+    ///
+    /// A group taking much longer than the others hints at thermal throttling
+    /// or memory issues of the machine.
+    pub record_pow_times: bool,
+    /// Calculate the PoW of the next pass in the background while the current pass
+    /// is searching the POS data, so that it's ready if no proof is found.
+    /// The PoW and the search share the proving threads.
+    ///
+    /// The PoW of the next pass is wasted if the current pass finds a proof.
+    /// It's not started if it would go over the [budget](ProvingOptions::max_pow_computations)
+    /// or the [stop condition](ProvingOptions::stop_condition) would stop before the next pass.
+    pub pipeline_pow: bool,
+    /// Don't log the PoW of the found proof (i.e. in multi-tenant environments).
+    /// The indices are logged only at the trace level anyway.
+    pub redact_pow: bool,
+    /// Notified about the progress of the proving, i.e. to show it live in a UI.
+    pub observer: Option<Arc<dyn ProvingObserver>>,
+    /// The parameters derived from the metadata and config in advance, to reuse them
+    /// over repeated proving instead of deriving them every time.
+    /// It must be created for the metadata and config proven with.
+    pub context: Option<PostContext>,
+    /// Prove with these parameters instead of the ones derived from the metadata
+    /// and config (or the `context`), i.e. [ProvingParams::trivial] to find a proof
+    /// quickly in tests.
+    ///
+    /// NOTE: proofs found with other than the derived parameters won't verify.
+    pub params: Option<ProvingParams>,
+}
+
+impl ProvingOptions {
+    fn pow_flags(&self) -> RandomXFlag {
+        self.pow_flags
+            .unwrap_or_else(RandomXFlag::get_recommended_flags)
+    }
+}
+
+/// A push-based feed of the progress of proving, see [ProvingOptions::observer].
+///
+/// All methods do nothing by default. They are called from the proving threads,
+/// once per pass or batch of POS data, except [ProvingObserver::on_candidate]
+/// which is called for every label satisfying the difficulty, so it must be cheap
+/// (i.e. bump a counter).
+pub trait ProvingObserver: Send + Sync {
+    /// A pass over the POS data for the nonces in `range` starts (after its PoW).
+    fn on_pass_start(&self, _range: Range<u32>) {}
+    /// A batch of `n` bytes of POS data was read and proven.
+    fn on_bytes_read(&self, _n: u64) {}
+    /// The label at `index` satisfies the difficulty for the nonce.
+    fn on_candidate(&self, _nonce: u32, _index: u64) {}
+    /// A proof was found. The pass it was found in ends next.
+    fn on_proof_found(&self, _proof: &Proof) {}
+    /// The pass ended, `found` tells whether a proof was found in it.
+    fn on_pass_end(&self, _found: bool) {}
+}
+
+impl std::fmt::Debug for dyn ProvingObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProvingObserver")
+    }
+}
+
+/// Detects stalled proving, see [ProvingOptions::watchdog].
+///
+/// Reading a batch of POS data and finding a candidate label count as progress.
+/// Only the reading of the data is watched, not the PoW of a pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchdog {
+    /// For how long there must be no progress to consider the proving stalled.
+    pub interval: Duration,
+    /// On a stall, stop the pass and fail with [ProveError::Stalled]
+    /// instead of only logging a warning.
+    ///
+    /// NOTE: a read blocked in the kernel can't be interrupted. The pass stops
+    /// once the read returns.
+    pub fail: bool,
+}
+
+/// Progress of the passes observed by the [Watchdog].
+#[derive(Debug, Default)]
+struct Progress {
+    /// Bumped on every batch read and candidate found.
+    events: AtomicU64,
+    /// The nonces of the pass reading the data, `None` outside of reading.
+    reading: Mutex<Option<Range<u32>>>,
+    stalled: AtomicBool,
+    done: AtomicBool,
+}
+
+impl Progress {
+    fn bump(&self) {
+        self.events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Watches the progress until `done` is set.
+    fn watch(&self, watchdog: Watchdog) {
+        let tick =
+            (watchdog.interval / 10).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        let mut watcher = Watcher::new(self, Instant::now());
+        while !self.done.load(Ordering::Relaxed) {
+            std::thread::sleep(tick);
+            if let Some((nonces, idle)) = watcher.check(self, Instant::now(), watchdog.interval) {
+                log::warn!("no progress in proving the nonces {nonces:?} for {idle:?}");
+                if watchdog.fail {
+                    self.stalled.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+/// The last progress seen by [Progress::watch].
+struct Watcher {
+    events: u64,
+    since: Instant,
+}
+
+impl Watcher {
+    fn new(progress: &Progress, now: Instant) -> Self {
+        Self {
+            events: progress.events.load(Ordering::Relaxed),
+            since: now,
+        }
+    }
+
+    /// Checks the progress at `now`. Returns the nonces of the pass and for how long
+    /// it has made no progress, once it's at least `interval` (and again every `interval`).
+    fn check(
+        &mut self,
+        progress: &Progress,
+        now: Instant,
+        interval: Duration,
+    ) -> Option<(Range<u32>, Duration)> {
+        let events = progress.events.load(Ordering::Relaxed);
+        let reading = progress.reading.lock().unwrap().clone();
+        let Some(nonces) = reading.filter(|_| events == self.events) else {
+            self.events = events;
+            self.since = now;
+            return None;
+        };
+        let idle = now.duration_since(self.since);
+        if idle < interval {
+            return None;
+        }
+        self.since = now;
+        Some((nonces, idle))
+    }
+}
+
+/// Stops the watching thread when dropped.
+struct Watching(Arc<Progress>);
+
+impl Watching {
+    fn start(progress: Arc<Progress>, watchdog: Watchdog) -> Self {
+        let watched = progress.clone();
+        std::thread::spawn(move || watched.watch(watchdog));
+        Self(progress)
+    }
+}
+
+impl Drop for Watching {
+    fn drop(&mut self) {
+        self.0.done.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The number of all nonces. The nonce group must fit in a byte.
+pub const NONCE_SPACE: u32 = 256 * Prover8_56::NONCES_PER_AES;
+
+/// Pick a nonce to start the search at, derived from challenge and node ID.
+///
+/// start_nonce = blake3(challenge || node_id) (as u32 LE) mod NONCE_SPACE,
+/// aligned down to a multiple of 16.
+///
+/// Different nodes start at different nonces, but the same node proving
+/// the same challenge always starts at the same one.
+pub fn seeded_start_nonce(challenge: &[u8; 32], node_id: &[u8; 32]) -> u32 {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(challenge);
+    hasher.update(node_id);
+    let hash = u32::from_le_bytes(hasher.finalize().as_bytes()[..4].try_into().unwrap());
+    let nonce = hash % NONCE_SPACE;
+    nonce - nonce % Prover8_56::NONCES_PER_AES
+}
+
+/// Split nonces `0..total_nonces` between `machines` for proving in parallel.
+///
+/// The ranges don't overlap and together cover all the nonces. All of them start
+/// at a multiple of 16, so no nonce group (and its PoW) is shared between machines.
+/// The ranges differ by at most one nonce group. If there are fewer nonce groups
+/// than machines, only as many ranges as nonce groups are returned.
+///
+/// A machine searches its range with [generate_proof_with_options] setting
+/// [ProvingOptions::start_nonce] to the range start and limiting the search to the range
+/// with the [stop condition](ProvingOptions::stop_condition): [StopCondition::max_nonce]
+/// at the range end and [StopCondition::max_passes] to `range.len().div_ceil(nonces)`
+/// (with `nonces` per pass). Without them, the passes after the first one go on into
+/// the ranges of the other machines. `max_nonce` alone isn't enough for the last range:
+/// the passes wrap around to nonce 0 at the end of the nonce space.
+pub fn partition_nonces(total_nonces: u32, machines: usize) -> Vec<Range<u32>> {
+    let per_aes = Prover8_56::NONCES_PER_AES;
+    let groups = total_nonces.div_ceil(per_aes) as usize;
+    let machines = machines.min(groups);
+    if machines == 0 {
+        return Vec::new();
+    }
+
+    let mut start = 0;
+    (0..machines)
+        .map(|machine| {
+            let groups = groups / machines + usize::from(machine < groups % machines);
+            let end = (start + groups as u32 * per_aes).min(total_nonces);
+            let range = start..end;
+            start = end;
+            range
+        })
+        .collect()
+}
+
+/// Ranges of nonces for consecutive passes, starting at `start`
+/// and wrapping around at the end of the nonce space.
+fn nonce_passes(start: u32, per_pass: u32) -> impl Iterator<Item = Range<u32>> {
+    let first = (start..NONCE_SPACE)
+        .step_by(per_pass as usize)
+        .map(move |s| s..(s + per_pass).min(NONCE_SPACE));
+    let wrapped = (0..start)
+        .step_by(per_pass as usize)
+        .map(move |s| s..(s + per_pass).min(start));
+    first.chain(wrapped)
+}
+
+/// Generate a proof that data is still held, given the challenge.
 #[allow(clippy::too_many_arguments)]
 pub fn generate_proof<Stopper>(
     datadir: &Path,
@@ -277,381 +1581,2790 @@ pub fn generate_proof<Stopper>(
 where
     Stopper: Borrow<AtomicBool>,
 {
-    let stop = stop.borrow();
+    let options = ProvingOptions {
+        threads: Some(threads),
+        pow_flags: Some(pow_flags),
+        ..Default::default()
+    };
+    generate_proof_with_options(datadir, challenge, cfg, nonces, stop, options)
+}
+
+/// Generate a proof that data is still held, given the challenge and proving options.
+pub fn generate_proof_with_options<Stopper>(
+    datadir: &Path,
+    challenge: &[u8; 32],
+    cfg: ProofConfig,
+    nonces: usize,
+    stop: Stopper,
+    options: ProvingOptions,
+) -> eyre::Result<Proof<'static>>
+where
+    Stopper: Borrow<AtomicBool>,
+{
     let metadata = metadata::load(datadir).wrap_err("loading metadata")?;
-    let params = ProvingParams::new(&metadata, &cfg)?;
-    log::info!("generating proof with PoW flags: {pow_flags:?} and params: {params:?}");
-    let pow_prover = pow::randomx::PoW::new(pow_flags)?;
+    let input = PostInput {
+        datadirs: &[datadir],
+        metadata: &metadata,
+        challenge,
+        cfg,
+    };
+    find_proof(&input, nonces, stop.borrow(), &options, None)
+}
+
+/// Generate a proof, taking the number of nonces per pass from `cfg`,
+/// unless overridden with `nonces`.
+pub fn generate_proof_with_config<Stopper>(
+    datadir: &Path,
+    challenge: &[u8; 32],
+    cfg: Config,
+    nonces: Option<usize>,
+    stop: Stopper,
+    options: ProvingOptions,
+) -> eyre::Result<Proof<'static>>
+where
+    Stopper: Borrow<AtomicBool>,
+{
+    let nonces = match nonces {
+        Some(nonces) => nonces,
+        None => cfg.nonces_per_pass()? as usize,
+    };
+    generate_proof_with_options(datadir, challenge, cfg.proof, nonces, stop, options)
+}
+
+/// Like [generate_proof_with_options], but with the metadata already in memory
+/// (i.e. just written by the initializer), so it's not loaded from `datadir`.
+///
+/// Fails early if the POS data files in `datadir` don't match the metadata.
+pub fn generate_proof_with_metadata<Stopper>(
+    datadir: &Path,
+    metadata: &PostMetadata,
+    challenge: &[u8; 32],
+    cfg: ProofConfig,
+    nonces: usize,
+    stop: Stopper,
+    options: ProvingOptions,
+) -> eyre::Result<Proof<'static>>
+where
+    Stopper: Borrow<AtomicBool>,
+{
+    let input = PostInput {
+        datadirs: &[datadir],
+        metadata,
+        challenge,
+        cfg,
+    };
+    check_data_files(input.datadirs, metadata, &options.reader)?;
+    find_proof(&input, nonces, stop.borrow(), &options, None)
+}
+
+/// Like [generate_proof_with_options], but with the POS data files split between
+/// `datadirs` (i.e. when they don't fit on a single filesystem).
+///
+/// The metadata is loaded from the first directory. Every file is looked for
+/// in the directories in order, so a file is read from the first directory holding it.
+/// The files are read in the order of their indices, like from a single directory.
+/// Fails early if a file is missing or the files don't hold exactly the labels of the metadata.
+pub fn generate_proof_with_datadirs<Stopper>(
+    datadirs: &[&Path],
+    challenge: &[u8; 32],
+    cfg: ProofConfig,
+    nonces: usize,
+    stop: Stopper,
+    options: ProvingOptions,
+) -> eyre::Result<Proof<'static>>
+where
+    Stopper: Borrow<AtomicBool>,
+{
+    let Some(datadir) = datadirs.first() else {
+        eyre::bail!("no data directories given");
+    };
+    let metadata = metadata::load(datadir).wrap_err("loading metadata")?;
+    let total_size = check_data_files(datadirs, &metadata, &options.reader)?;
+    let expected = num_labels(&metadata)? * LABEL_SIZE as u64;
+    eyre::ensure!(
+        total_size == expected,
+        "POS files in {datadirs:?} hold {total_size} bytes, expected {expected} bytes for the metadata"
+    );
+    let input = PostInput {
+        datadirs,
+        metadata: &metadata,
+        challenge,
+        cfg,
+    };
+    find_proof(&input, nonces, stop.borrow(), &options, None)
+}
+
+/// Checks that all POS data files expected by the metadata exist in `datadirs`
+/// and are big enough to hold their labels. Returns their total size.
+fn check_data_files(
+    datadirs: &[&Path],
+    metadata: &PostMetadata,
+    reader: &ReaderConfig,
+) -> eyre::Result<u64> {
+    let mut total_size = 0;
+    for index in 0..metadata.expected_file_count() {
+        let path = find_file(datadirs, reader, index)?;
+        let size = std::fs::metadata(&path)
+            .wrap_err_with(|| format!("reading {}", path.display()))?
+            .len();
+        let expected = metadata.labels_in_file(index) as u64 * LABEL_SIZE as u64;
+        eyre::ensure!(
+            size >= expected,
+            "POS file {} is too small for the metadata: {size} < {expected} bytes",
+            path.display()
+        );
+        total_size += size;
+    }
+    Ok(total_size)
+}
+
+/// Progress of [generate_proof_with_events].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProvingEvent {
+    /// A pass over the POS data finished for the nonces in `range`.
+    /// `found` is the proof found in it, if any (it's then the last event).
+    PassComplete {
+        range: Range<u32>,
+        found: Option<Proof<'static>>,
+    },
+}
+
+/// Like [generate_proof_with_options], but sends a [ProvingEvent] to `events`
+/// after every pass. Sending doesn't fail the proving if the receiver is gone.
+pub fn generate_proof_with_events<Stopper>(
+    datadir: &Path,
+    challenge: &[u8; 32],
+    cfg: ProofConfig,
+    nonces: usize,
+    stop: Stopper,
+    options: ProvingOptions,
+    events: mpsc::Sender<ProvingEvent>,
+) -> eyre::Result<Proof<'static>>
+where
+    Stopper: Borrow<AtomicBool>,
+{
+    let metadata = metadata::load(datadir).wrap_err("loading metadata")?;
+    let input = PostInput {
+        datadirs: &[datadir],
+        metadata: &metadata,
+        challenge,
+        cfg,
+    };
+    find_proof(&input, nonces, stop.borrow(), &options, Some(&events))
+}
+
+/// The proof found by [generate_proof_with_report] and how long it took to find it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProvingReport {
+    pub proof: Proof<'static>,
+    /// The number of passes over the POS data, including the one that found the proof.
+    /// It's 1 if the proof was found in the first pass.
+    pub passes_taken: u32,
+    /// The number of nonces searched in all passes.
+    pub nonces_searched: u32,
+    pub duration: Duration,
+}
+
+/// Like [generate_proof_with_options], but also reports how many passes
+/// (and nonces) it took to find the proof, to tune the number of nonces per pass.
+pub fn generate_proof_with_report<Stopper>(
+    datadir: &Path,
+    challenge: &[u8; 32],
+    cfg: ProofConfig,
+    nonces: usize,
+    stop: Stopper,
+    options: ProvingOptions,
+) -> eyre::Result<ProvingReport>
+where
+    Stopper: Borrow<AtomicBool>,
+{
+    let started = Instant::now();
+    let (events, passes) = mpsc::channel();
+    let proof = generate_proof_with_events(datadir, challenge, cfg, nonces, stop, options, events)?;
+    let (passes_taken, nonces_searched) =
+        passes
+            .try_iter()
+            .fold((0, 0), |(passes, nonces), event| match event {
+                ProvingEvent::PassComplete { range, .. } => {
+                    (passes + 1, nonces + range.len() as u32)
+                }
+            });
+    Ok(ProvingReport {
+        proof,
+        passes_taken,
+        nonces_searched,
+        duration: started.elapsed(),
+    })
+}
+
+/// The proving of all `generate_proof_*` variants looking for the first proof.
+fn find_proof(
+    input: &PostInput,
+    nonces: usize,
+    stop: &AtomicBool,
+    options: &ProvingOptions,
+    events: Option<&mpsc::Sender<ProvingEvent>>,
+) -> eyre::Result<Proof<'static>> {
+    let target_indices = options.target_indices.unwrap_or(input.cfg.k2) as usize;
+    let total_time = Instant::now();
+    run_passes(input, nonces, stop, options, |pass| {
+        let found = find_proof_in_pass(pass, target_indices, options, total_time)?;
+        send_diagnostics(
+            events,
+            ProvingEvent::PassComplete {
+                range: pass.nonces.clone(),
+                found: found.clone(),
+            },
+        );
+        Ok(found)
+    })
+}
+
+/// Searches the pass for a nonce collecting `target_indices` indices
+/// and creates the proof for it.
+fn find_proof_in_pass(
+    pass: &Pass,
+    target_indices: usize,
+    options: &ProvingOptions,
+    total_time: Instant,
+) -> eyre::Result<Option<Proof<'static>>> {
+    let collector = IndexCollector::new(pass.nonces.clone(), target_indices)
+        .with_duplicates(options.duplicate_indices);
+    let result = pass.search(|nonce, indices| collector.collect_batch(nonce, indices))?;
+    pass.record_max_indices(collector.max_collected());
+    collector.check_duplicates()?;
+
+    Ok(result.map(|(nonce, indices)| {
+        let pow = pass.prover.get_pow(nonce).unwrap();
+        let total_minutes = total_time.elapsed().as_secs() / 60;
+        let logged_pow = if options.redact_pow {
+            "<redacted>".to_string()
+        } else {
+            pow.to_string()
+        };
+        log::info!(
+            "Found proof for nonce: {nonce}, pow: {logged_pow} with {} indices. Proof took {total_minutes} minutes",
+            indices.len()
+        );
+        log::trace!("Proof indices: {indices:?}");
+        let proof = Proof::new(nonce, &indices, pass.num_labels, pow);
+        if let Some(observer) = pass.observer {
+            observer.on_proof_found(&proof);
+        }
+        proof
+    }))
+}
+
+/// Proving that can be paused (freeing the RandomX memory) and resumed later
+/// in the same process, continuing with the passes it didn't complete.
+///
+/// A pass interrupted by a stop is done again from its start on resume,
+/// the completed ones are never repeated.
+pub struct ProvingSession {
+    datadir: PathBuf,
+    metadata: PostMetadata,
+    challenge: [u8; 32],
+    cfg: ProofConfig,
+    options: ProvingOptions,
+    /// Whether `options.pow_prover` was created by the session (not given by the caller).
+    owns_pow_prover: bool,
+    /// The passes not completed yet.
+    remaining: VecDeque<Range<u32>>,
+}
+
+impl ProvingSession {
+    /// Plans the passes like [generate_proof_with_options]. RandomX is initialized
+    /// on the first [resume](ProvingSession::resume) (unless given in `options.pow_prover`).
+    pub fn new(
+        datadir: &Path,
+        challenge: &[u8; 32],
+        cfg: ProofConfig,
+        nonces: usize,
+        options: ProvingOptions,
+    ) -> eyre::Result<Self> {
+        let metadata = metadata::load(datadir).wrap_err("loading metadata")?;
+        let remaining = plan_passes(options.start_nonce, nonces)?.into();
+        Ok(Self {
+            datadir: datadir.to_path_buf(),
+            metadata,
+            challenge: *challenge,
+            cfg,
+            options,
+            owns_pow_prover: false,
+            remaining,
+        })
+    }
+
+    /// The nonce ranges of passes not completed yet, in order.
+    pub fn remaining(&self) -> impl Iterator<Item = &Range<u32>> {
+        self.remaining.iter()
+    }
+
+    /// Continue proving with the next pass not completed yet.
+    ///
+    /// Fails with [ProveError::Cancelled] when `stop` is set (or
+    /// [ProveError::StopConditionReached]), keeping the progress for
+    /// the next call.
+    pub fn resume(&mut self, stop: &AtomicBool) -> eyre::Result<Proof<'static>> {
+        if self.options.pow_prover.is_none()
+            && !self.options.skip_pow
+            && self.options.pow_algorithm == pow::PowAlgorithm::RandomX
+        {
+            let pow_flags = self.options.pow_flags();
+            let mut pow = PoW::new_with_fallback(pow_flags, stop).map_err(pow_init_error)?;
+            if let Some(max_vms) = self.options.max_pow_vms {
+                pow = pow.with_max_vms(max_vms).map_err(pow_init_error)?;
+            }
+            self.options.pow_prover = Some(Arc::new(pow));
+            self.owns_pow_prover = true;
+        }
+        let target_indices = self.options.target_indices.unwrap_or(self.cfg.k2) as usize;
+        let total_time = Instant::now();
+        let mut completed = 0;
+        let input = PostInput {
+            datadirs: &[self.datadir.as_path()],
+            metadata: &self.metadata,
+            challenge: &self.challenge,
+            cfg: self.cfg,
+        };
+        let result = run_passes_over(
+            &input,
+            self.remaining.iter().cloned().collect(),
+            stop,
+            &self.options,
+            |pass| {
+                let found = find_proof_in_pass(pass, target_indices, &self.options, total_time)?;
+                // The pass that found the proof is done too, the next resume continues after it.
+                if found.is_some() || !pass.interrupted() {
+                    completed += 1;
+                }
+                Ok(found)
+            },
+        );
+        self.remaining.drain(..completed);
+        result
+    }
+
+    /// Free the RandomX memory. It's initialized again on the next
+    /// [resume](ProvingSession::resume).
+    ///
+    /// A PoW prover given in `options.pow_prover` is kept, it's up to the caller to free it.
+    pub fn pause(&mut self) {
+        if self.owns_pow_prover {
+            self.options.pow_prover = None;
+            self.owns_pow_prover = false;
+        }
+    }
+}
+
+/// A nonce that collected enough indices during a pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub nonce: u32,
+    pub indices: Vec<u64>,
+    pub pow: u64,
+}
+
+/// Generate a proof that data is still held, choosing the nonce with `select`.
+///
+/// Unlike [generate_proof_with_options], every pass reads all of the POS data
+/// and collects all nonces that reached the target number of indices. If any
+/// did, `select` is called with these candidates (sorted by nonce) and returns
+/// the position of the one to build the proof from.
+pub fn generate_proof_select<Stopper, S>(
+    datadir: &Path,
+    challenge: &[u8; 32],
+    cfg: ProofConfig,
+    nonces: usize,
+    stop: Stopper,
+    options: ProvingOptions,
+    select: S,
+) -> eyre::Result<Proof<'static>>
+where
+    Stopper: Borrow<AtomicBool>,
+    S: Fn(&[Candidate]) -> usize,
+{
+    let target_indices = options.target_indices.unwrap_or(cfg.k2) as usize;
+    let metadata = metadata::load(datadir).wrap_err("loading metadata")?;
+    let input = PostInput {
+        datadirs: &[datadir],
+        metadata: &metadata,
+        challenge,
+        cfg,
+    };
+    run_passes(&input, nonces, stop.borrow(), &options, |pass| {
+        let candidates = find_candidates_in_pass(pass, target_indices, &options)?;
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+        let selected = select(&candidates);
+        let Some(candidate) = candidates.get(selected) else {
+            eyre::bail!(
+                "selected candidate {selected} out of {} candidates",
+                candidates.len()
+            );
+        };
+        log::info!(
+            "Selected nonce: {} out of {} candidates",
+            candidate.nonce,
+            candidates.len()
+        );
+        let proof = Proof::new(
+            candidate.nonce,
+            &candidate.indices,
+            pass.num_labels,
+            candidate.pow,
+        );
+        if let Some(observer) = pass.observer {
+            observer.on_proof_found(&proof);
+        }
+        Ok(Some(proof))
+    })
+}
+
+/// Generate all proofs found in up to `max_passes` passes, sorted by nonce within a pass.
+///
+/// Unlike [generate_proof_with_options], it doesn't stop at the first proof:
+/// every pass reads all of the POS data and all passes are made.
+/// It's meant for debugging and analysis, i.e. how many proofs a challenge has.
+pub fn generate_all_proofs<Stopper>(
+    datadir: &Path,
+    challenge: &[u8; 32],
+    cfg: ProofConfig,
+    nonces: usize,
+    stop: Stopper,
+    options: ProvingOptions,
+    max_passes: usize,
+) -> eyre::Result<Vec<Proof<'static>>>
+where
+    Stopper: Borrow<AtomicBool>,
+{
+    eyre::ensure!(max_passes > 0, "number of passes must be > 0");
+    let target_indices = options.target_indices.unwrap_or(cfg.k2) as usize;
+    let metadata = metadata::load(datadir).wrap_err("loading metadata")?;
+    let passes = plan_passes(options.start_nonce, nonces)?
+        .into_iter()
+        .take(max_passes)
+        .collect::<Vec<_>>();
+    let num_passes = passes.len();
+    let mut done = 0;
+    let mut proofs = Vec::new();
+    let input = PostInput {
+        datadirs: &[datadir],
+        metadata: &metadata,
+        challenge,
+        cfg,
+    };
+    run_passes_over(&input, passes, stop.borrow(), &options, |pass| {
+        let candidates = find_candidates_in_pass(pass, target_indices, &options)?;
+        log::info!(
+            "Found {} proofs in the pass of nonces {:?}",
+            candidates.len(),
+            pass.nonces
+        );
+        proofs.extend(candidates.into_iter().map(|candidate| {
+            Proof::new(
+                candidate.nonce,
+                &candidate.indices,
+                pass.num_labels,
+                candidate.pow,
+            )
+        }));
+        done += 1;
+        Ok((done == num_passes).then_some(()))
+    })?;
+    Ok(proofs)
+}
+
+/// Searches the whole pass for all nonces collecting `target_indices` indices,
+/// sorted by nonce.
+fn find_candidates_in_pass(
+    pass: &Pass,
+    target_indices: usize,
+    options: &ProvingOptions,
+) -> eyre::Result<Vec<Candidate>> {
+    let collector = IndexCollector::new(pass.nonces.clone(), target_indices)
+        .with_duplicates(options.duplicate_indices);
+    let candidates = Mutex::new(Vec::<Candidate>::new());
+    pass.search(|nonce, indices| {
+        if let Some(indices) = collector.collect_batch(nonce, indices) {
+            candidates.lock().unwrap().push(Candidate {
+                nonce,
+                indices,
+                pow: pass.prover.get_pow(nonce).unwrap(),
+            });
+            // Every nonce is a candidate already, stop reading.
+            if collector.all_done() {
+                return Some(Vec::new());
+            }
+        }
+        None
+    })?;
+    pass.record_max_indices(collector.max_collected());
+    collector.check_duplicates()?;
+
+    let mut candidates = candidates.into_inner().unwrap();
+    candidates.sort_unstable_by_key(|c| c.nonce);
+    Ok(candidates)
+}
+
+/// Count the indices every nonce in `nonces` collects in a single pass over all POS data.
+///
+/// Unlike [generate_proof], it never stops early when a nonce reaches `cfg.k2`.
+/// It's meant for analytics, i.e. calibrating the difficulty (K1, K2) for a data set.
+/// Nonces that didn't find any index are included with a 0 count.
+pub fn analyze(
+    datadir: &Path,
+    challenge: &[u8; 32],
+    cfg: ProofConfig,
+    nonces: Range<u32>,
+    threads: usize,
+    pow_flags: RandomXFlag,
+) -> eyre::Result<HashMap<u32, usize>> {
+    eyre::ensure!(!nonces.is_empty(), "nonces must not be empty");
+    eyre::ensure!(
+        nonces.end <= NONCE_SPACE,
+        "nonces ({nonces:?}) must be below {NONCE_SPACE}"
+    );
+    let metadata = metadata::load(datadir).wrap_err("loading metadata")?;
+    let options = ProvingOptions {
+        threads: Some(threads),
+        pow_flags: Some(pow_flags),
+        start_nonce: Some(nonces.start),
+        ..Default::default()
+    };
+    let input = PostInput {
+        datadirs: &[datadir],
+        metadata: &metadata,
+        challenge,
+        cfg,
+    };
+    run_passes(
+        &input,
+        nonces.len(),
+        &AtomicBool::new(false),
+        &options,
+        |pass| {
+            let counts = pass
+                .nonces
+                .clone()
+                .map(|_| AtomicUsize::new(0))
+                .collect::<Vec<_>>();
+            pass.search(|nonce, indices| {
+                counts[(nonce - pass.nonces.start) as usize]
+                    .fetch_add(indices.len(), Ordering::Relaxed);
+                None
+            })?;
+            let counts = pass
+                .nonces
+                .clone()
+                .zip(counts)
+                .map(|(nonce, count)| (nonce, count.into_inner()))
+                .collect::<HashMap<_, _>>();
+            pass.record_max_indices(counts.values().copied().max().unwrap_or(0));
+            Ok(Some(counts))
+        },
+    )
+}
+
+/// Find a single index satisfying the difficulty for any of the nonces,
+/// returning `(nonce, index, pow)`.
+///
+/// It's a cheap spot check that the POS data is still held and readable (i.e. for monitoring),
+/// a pass stops at the first index found (which one is found first is not deterministic).
+///
+/// NOTE: it's NOT a valid proof under the protocol rules, which require `cfg.k2` indices.
+pub fn generate_spot_proof(
+    datadir: &Path,
+    challenge: &[u8; 32],
+    cfg: ProofConfig,
+    nonces: usize,
+    threads: usize,
+    pow_flags: RandomXFlag,
+) -> eyre::Result<(u32, u64, u64)> {
+    let metadata = metadata::load(datadir).wrap_err("loading metadata")?;
+    let options = ProvingOptions {
+        threads: Some(threads),
+        pow_flags: Some(pow_flags),
+        ..Default::default()
+    };
+    let input = PostInput {
+        datadirs: &[datadir],
+        metadata: &metadata,
+        challenge,
+        cfg,
+    };
+    run_passes(&input, nonces, &AtomicBool::new(false), &options, |pass| {
+        let found = pass.search(|_, indices| indices.first().map(|&index| vec![index]))?;
+        Ok(found.map(|(nonce, indices)| {
+            let pow = pass.prover.get_pow(nonce).unwrap();
+            log::info!("Found spot proof for nonce: {nonce}, index: {}", indices[0]);
+            (nonce, indices[0], pow)
+        }))
+    })
+}
+
+/// Read the labels pointed to by the indices of the proof, in the order of the indices.
+///
+/// Only the files holding the labels are opened and every label is read
+/// directly at its offset. `k2` is needed to tell the proven indices from padding.
+/// The files are found in `datadirs` like for proving, see [ReaderConfig::file_name].
+pub fn contested_labels(
+    proof: &Proof,
+    metadata: &PostMetadata,
+    k2: u32,
+    datadirs: &[&Path],
+    reader: &ReaderConfig,
+) -> eyre::Result<Vec<[u8; LABEL_SIZE]>> {
+    let indices = proof.unpack_indices(num_labels(metadata)?, k2)?;
+    let mut locations = indices
+        .iter()
+        .enumerate()
+        .map(|(position, &index)| {
+            let location = metadata
+                .label_location(index)
+                .ok_or(ProveError::IndexOutOfRange {
+                    index,
+                    num_labels: metadata.total_labels(),
+                })?;
+            Ok((location, position))
+        })
+        .collect::<Result<Vec<_>, ProveError>>()?;
+    // Read the files one by one, going forward within each.
+    locations.sort_unstable();
+
+    let mut labels = vec![[0u8; LABEL_SIZE]; indices.len()];
+    for (file_index, group) in &locations.iter().group_by(|((file, _), _)| *file) {
+        let path = find_file(datadirs, reader, file_index)?;
+        let mut file = File::open(&path).wrap_err_with(|| format!("opening {}", path.display()))?;
+        for &((_, offset), position) in group {
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(&mut labels[position]))
+                .wrap_err_with(|| format!("reading label at {offset} in {}", path.display()))?;
+        }
+    }
+    Ok(labels)
+}
+
+/// Collects indices for a range of nonces.
+///
+/// Every nonce has its own lock so that threads finding labels for different
+/// nonces don't contend with each other.
+///
+/// The memory is bounded: a nonce never holds more than `target` indices, and
+/// they are handed over once it reaches it. Indices found for a nonce that is
+/// done are dropped without taking its lock.
+struct IndexCollector {
+    start: u32,
+    target: usize,
+    // `None` once the nonce reached the target.
+    slots: Vec<Mutex<Option<Vec<u64>>>>,
+    // Set once the nonce reached the target, checked before locking its slot.
+    done: Vec<AtomicBool>,
+    remaining: AtomicUsize,
+    duplicates: DuplicateIndices,
+    // The first duplicate found with [DuplicateIndices::Fail].
+    duplicate: Mutex<Option<(u32, u64)>>,
+}
+
+impl IndexCollector {
+    fn new(nonces: Range<u32>, target: usize) -> Self {
+        Self {
+            start: nonces.start,
+            target,
+            slots: nonces
+                .clone()
+                .map(|_| Mutex::new(Some(Vec::new())))
+                .collect(),
+            done: nonces.clone().map(|_| AtomicBool::new(false)).collect(),
+            remaining: AtomicUsize::new(nonces.len()),
+            duplicates: DuplicateIndices::Ignore,
+            duplicate: Mutex::new(None),
+        }
+    }
+
+    fn with_duplicates(mut self, duplicates: DuplicateIndices) -> Self {
+        self.duplicates = duplicates;
+        self
+    }
+
+    /// Records `index` for `nonce`. Returns the collected indices once the
+    /// nonce reaches the target. Indices found for it afterwards are ignored.
+    fn collect(&self, nonce: u32, index: u64) -> Option<Vec<u64>> {
+        self.collect_batch(nonce, &[index])
+    }
+
+    /// Like [IndexCollector::collect], but records the indices in order,
+    /// locking the slot of the nonce once.
+    fn collect_batch(&self, nonce: u32, batch: &[u64]) -> Option<Vec<u64>> {
+        let pos = (nonce - self.start) as usize;
+        if self.done[pos].load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut slot = self.slots[pos].lock().unwrap();
+        let indices = slot.as_mut()?;
+        for &index in batch {
+            if self.duplicates != DuplicateIndices::Ignore && indices.contains(&index) {
+                if self.duplicates == DuplicateIndices::Fail {
+                    self.duplicate.lock().unwrap().get_or_insert((nonce, index));
+                }
+                continue;
+            }
+            indices.push(index);
+            if indices.len() >= self.target {
+                self.done[pos].store(true, Ordering::Relaxed);
+                self.remaining.fetch_sub(1, Ordering::Relaxed);
+                return slot.take();
+            }
+        }
+        None
+    }
+
+    /// Whether every nonce reached the target, so there is nothing more to collect.
+    fn all_done(&self) -> bool {
+        self.remaining.load(Ordering::Relaxed) == 0
+    }
+
+    /// Fails if a duplicate was found with [DuplicateIndices::Fail].
+    fn check_duplicates(&self) -> Result<(), ProveError> {
+        match *self.duplicate.lock().unwrap() {
+            Some((nonce, index)) => Err(ProveError::DuplicateIndex { nonce, index }),
+            None => Ok(()),
+        }
+    }
+
+    /// The biggest number of indices collected by a nonce so far.
+    fn max_collected(&self) -> usize {
+        self.slots
+            .iter()
+            .map(|slot| match &*slot.lock().unwrap() {
+                Some(indices) => indices.len(),
+                None => self.target,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// A single pass over the POS data for a range of nonces.
+struct Pass<'a> {
+    nonces: Range<u32>,
+    num_labels: u64,
+    datadirs: &'a [&'a Path],
+    metadata: &'a PostMetadata,
+    reader: &'a ReaderConfig,
+    pool: &'a rayon::ThreadPool,
+    /// Reused buffers of batches, see [ReaderConfig::buffer_pool_size].
+    buffers: Option<&'a Arc<BufferPool>>,
+    /// The POS files when the proving started, see [ReaderConfig::detect_modifications].
+    snapshot: Option<&'a DataSnapshot>,
+    observer: Option<&'a dyn ProvingObserver>,
+    stop: &'a AtomicBool,
+    prover: &'a Prover8_56,
+    /// The pass stops reading when it's reached (see [StopCondition::max_duration]).
+    deadline: Option<Instant>,
+    /// Only with a [ProvingOptions::watchdog].
+    progress: Option<&'a Progress>,
+    diagnostics: Mutex<PassDiagnostics>,
+}
+
+impl Pass<'_> {
+    /// Reads the POS data once, feeding `consume` with the labels that passed
+    /// the difficulty check, in batches like [Prover::prove_batched].
+    /// Stops as soon as `consume` returns `Some`.
+    fn search<F>(&self, consume: F) -> eyre::Result<Option<(u32, Vec<u64>)>>
+    where
+        F: Fn(u32, &[u64]) -> Option<Vec<u64>> + Sync,
+    {
+        let consume = |nonce, indices: &[u64]| {
+            self.bump_progress();
+            consume(nonce, indices)
+        };
+        let read_time = Instant::now();
+        let data_reader = read_data(
+            self.datadirs,
+            self.reader,
+            self.metadata.max_file_size,
+            self.metadata.expected_file_count(),
+            self.buffers.cloned(),
+        )?;
+        log::info!("Started reading POST data");
+        let result = self
+            .pool
+            .install(|| {
+                data_reader
+                    .par_bridge()
+                    .take_any_while(|_| !self.interrupted())
+                    .find_map_any(|batch| {
+                        let batch = match batch {
+                            Ok(batch) => batch,
+                            Err(err) => return Some(Err(err)),
+                        };
+                        self.bump_progress();
+                        let mut diagnostics = PassDiagnostics::default();
+                        let mut buffer = CandidateBuffer::new(&consume);
+                        self.prover.prove_with_diagnostics(
+                            &batch.data,
+                            batch.pos / BLOCK_SIZE as u64,
+                            |nonce, index| {
+                                if let Some(observer) = self.observer {
+                                    observer.on_candidate(nonce, index);
+                                }
+                                buffer.push(nonce, index).then(Vec::new)
+                            },
+                            &mut diagnostics,
+                        );
+                        if let Some(observer) = self.observer {
+                            observer.on_bytes_read(batch.data.len() as u64);
+                        }
+                        if let Some(buffers) = self.buffers {
+                            buffers.put(batch.data);
+                        }
+                        self.diagnostics.lock().unwrap().add(&diagnostics);
+                        buffer.finish().map(Ok)
+                    })
+            })
+            .transpose()
+            .wrap_err("reading POS data")?;
+        if let Some(snapshot) = self.snapshot {
+            snapshot.check()?;
+        }
+
+        let read_mins = read_time.elapsed().as_secs() / 60;
+        log::info!("Finished reading POST data in {} minutes", read_mins);
+        Ok(result)
+    }
+
+    /// Whether the reading was stopped before the end of the POS data.
+    fn interrupted(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+            || self.deadline.is_some_and(|d| Instant::now() >= d)
+            || self
+                .progress
+                .is_some_and(|progress| progress.stalled.load(Ordering::Relaxed))
+    }
+
+    fn bump_progress(&self) {
+        if let Some(progress) = self.progress {
+            progress.bump();
+        }
+    }
+
+    fn record_max_indices(&self, max_indices: usize) {
+        let mut diagnostics = self.diagnostics.lock().unwrap();
+        diagnostics.max_indices = diagnostics.max_indices.max(max_indices);
+    }
+}
+
+/// Sends `value` to the optional receiver of the proving diagnostics or events.
+fn send_diagnostics<T>(sender: Option<&mpsc::Sender<T>>, value: T) {
+    if let Some(sender) = sender {
+        // The receiver might be gone, it's not an error.
+        let _ = sender.send(value);
+    }
+}
+
+/// Reports a PoW initialization cancelled by `stop` like a cancelled proving.
+fn pow_init_error(err: pow::Error) -> eyre::Report {
+    match err {
+        pow::Error::Cancelled => ProveError::Cancelled.into(),
+        err => err.into(),
+    }
+}
+
+/// The POS data and the challenge to prove, shared by all proving variants.
+struct PostInput<'a> {
+    /// The directories holding the POS data files, see [find_file].
+    datadirs: &'a [&'a Path],
+    metadata: &'a PostMetadata,
+    challenge: &'a [u8; 32],
+    cfg: ProofConfig,
+}
+
+/// Runs passes over consecutive nonce ranges until `pass` returns a result.
+fn run_passes<T, F>(
+    input: &PostInput,
+    nonces: usize,
+    stop: &AtomicBool,
+    options: &ProvingOptions,
+    pass: F,
+) -> eyre::Result<T>
+where
+    F: FnMut(&Pass) -> eyre::Result<Option<T>>,
+{
+    let passes = plan_passes(options.start_nonce, nonces)?;
+    run_passes_over(input, passes, stop, options, pass)
+}
+
+/// The nonce ranges of consecutive passes of `nonces` nonces, starting at `start_nonce`
+/// (0 by default) and wrapping around at the end of the nonce space.
+fn plan_passes(start_nonce: Option<u32>, nonces: usize) -> eyre::Result<Vec<Range<u32>>> {
+    let start_nonce = start_nonce.unwrap_or(0);
+    eyre::ensure!(nonces > 0, "number of nonces must be > 0");
+    eyre::ensure!(
+        start_nonce < NONCE_SPACE,
+        "start nonce ({start_nonce}) must be below {NONCE_SPACE}"
+    );
+    Ok(nonce_passes(start_nonce, nonces as u32).collect())
+}
+
+/// Like [run_passes], but over the given nonce ranges.
+fn run_passes_over<T, F>(
+    input: &PostInput,
+    passes: Vec<Range<u32>>,
+    stop: &AtomicBool,
+    options: &ProvingOptions,
+    mut pass: F,
+) -> eyre::Result<T>
+where
+    F: FnMut(&Pass) -> eyre::Result<Option<T>>,
+{
+    let &PostInput {
+        datadirs,
+        metadata,
+        challenge,
+        cfg,
+    } = input;
+    let threads = options.threads.unwrap_or_else(|| threads_or_available(0));
+    let pow_flags = options.pow_flags();
+    if threads == 0 {
+        return Err(ProveError::InvalidThreadCount(threads).into());
+    }
+    options.reader.validate()?;
+    let num_labels = num_labels(metadata)?;
+    let params = match (options.params, &options.context) {
+        (Some(params), _) => params,
+        (None, Some(context)) => {
+            context.check_matches(metadata.num_units, metadata.labels_per_unit, &cfg)?;
+            ProvingParams::from_context(context)?
+        }
+        (None, None) => ProvingParams::new(metadata, &cfg)?,
+    };
+    log::info!("generating proof with PoW flags: {pow_flags:?} and params: {params:?}");
+    if aes_backend() == AesBackend::Software {
+        log::warn!("hardware AES is not available, proving will be much slower");
+    }
+    // Created on the first pass needing a PoW that wasn't precomputed.
+    let mut pow_prover: Option<Arc<dyn pow::Prover + Send + Sync>> = match &options.pow_prover {
+        _ if options.skip_pow => {
+            log::warn!("skipping PoW, the proof won't be valid");
+            Some(Arc::new(pow::NoPoWProver))
+        }
+        Some(pow_prover) => Some(pow_prover.clone()),
+        None => None,
+    };
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|source| ProveError::ThreadPool { threads, source })?;
+
+    let limits = options.stop_condition;
+    let started = Instant::now();
+    let deadline = limits.max_duration.and_then(|d| started.checked_add(d));
+
+    let mut pow_computations = 0u32;
+
+    let buffers = (options.reader.buffer_pool_size > 0)
+        .then(|| Arc::new(BufferPool::new(options.reader.buffer_pool_size)));
+    let snapshot = options
+        .reader
+        .detect_modifications
+        .then(|| DataSnapshot::take(datadirs, &options.reader, metadata.expected_file_count()))
+        .transpose()?;
+
+    // Not tracked without a watchdog, it's updated on every batch.
+    let progress = options.watchdog.map(|_| Arc::new(Progress::default()));
+    let _watching = options
+        .watchdog
+        .zip(progress.clone())
+        .map(|(watchdog, progress)| Watching::start(progress, watchdog));
+
+    let precomputed = &options.precomputed_pows;
+    // The number of PoWs to calculate for the pass of `nonces`.
+    let pows_needed = |nonces: &Range<u32>| {
+        required_nonce_groups(nonces.clone())
+            .filter(|g| !precomputed.contains_key(g))
+            .count() as u32
+    };
+    let init_pow_prover = |pow_prover: &mut Option<Arc<dyn pow::Prover + Send + Sync>>,
+                           nonces: &Range<u32>|
+     -> eyre::Result<()> {
+        if pow_prover.is_none() && pows_needed(nonces) > 0 {
+            let prover = pow::new(options.pow_algorithm, pow_flags, options.max_pow_vms, stop)
+                .map_err(pow_init_error)?;
+            *pow_prover = Some(prover.into());
+        }
+        Ok(())
+    };
+    let record_pow_times = options.record_pow_times;
+    let create_prover = |nonces: Range<u32>,
+                         fallback: Option<Arc<dyn pow::Prover + Send + Sync>>,
+                         stop: &AtomicBool| {
+        let pass_pow = PrecomputedPow {
+            pows: precomputed,
+            fallback: fallback.as_deref(),
+        };
+        pool.install(|| {
+            Prover8_56::new_with_options(
+                challenge,
+                nonces,
+                params,
+                &pass_pow,
+                &metadata.node_id,
+                ProverOptions {
+                    stop: Some(stop),
+                    record_pow_times,
+                    ..Default::default()
+                },
+            )
+            .wrap_err("creating prover")
+        })
+    };
+    let create_prover = &create_prover;
+    // Stops the PoW of the next pass once it's not needed anymore.
+    let discard_prefetch = AtomicBool::new(false);
+
+    thread::scope(|scope| -> eyre::Result<T> {
+        let _discard_on_exit = SetOnDrop(&discard_prefetch);
+        // The prover of the next pass, with the PoW calculated in the background.
+        let mut prefetched: Option<(Range<u32>, ScopedJoinHandle<eyre::Result<Prover8_56>>)> = None;
+        let mut passes = passes.into_iter().enumerate().peekable();
+        while let Some((index, nonces)) = passes.next() {
+            if stop.load(Ordering::Relaxed) {
+                return Err(ProveError::Cancelled.into());
+            }
+            if let Some(reason) = limits.reached(index as u32, nonces.start, started.elapsed()) {
+                return Err(ProveError::StopConditionReached(reason).into());
+            }
+            let nonces = limits.limit_nonces(nonces);
+
+            pow_computations += pows_needed(&nonces);
+            if options
+                .max_pow_computations
+                .is_some_and(|max| pow_computations > max)
+            {
+                return Err(ProveError::PoWBudgetExhausted.into());
+            }
+
+            let pow_time = Instant::now();
+            let prover =
+                match prefetched.take() {
+                    Some((prefetched_nonces, handle)) if prefetched_nonces == nonces => handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?,
+                    _ => {
+                        init_pow_prover(&mut pow_prover, &nonces)?;
+                        create_prover(nonces.clone(), pow_prover.clone(), stop)?
+                    }
+                };
+
+            let pow_mins = pow_time.elapsed().as_secs() / 60;
+            log::info!("Finished k2pow in {} minutes", pow_mins);
+
+            if options.pipeline_pow {
+                if let Some((next_index, next)) = passes.peek() {
+                    let next = limits.limit_nonces(next.clone());
+                    let within_budget = options
+                        .max_pow_computations
+                        .map_or(true, |max| pow_computations + pows_needed(&next) <= max);
+                    let within_limits = limits
+                        .reached(*next_index as u32, next.start, started.elapsed())
+                        .is_none();
+                    if !next.is_empty() && within_budget && within_limits {
+                        init_pow_prover(&mut pow_prover, &next)?;
+                        let fallback = pow_prover.clone();
+                        let discard = &discard_prefetch;
+                        let handle = scope.spawn({
+                            let next = next.clone();
+                            move || create_prover(next, fallback, discard)
+                        });
+                        prefetched = Some((next, handle));
+                    }
+                }
+            }
+
+            let current = Pass {
+                nonces: nonces.clone(),
+                num_labels,
+                datadirs,
+                metadata,
+                reader: &options.reader,
+                pool: &pool,
+                buffers: buffers.as_ref(),
+                snapshot: snapshot.as_ref(),
+                observer: options.observer.as_deref(),
+                stop,
+                prover: &prover,
+                deadline,
+                progress: progress.as_deref(),
+                diagnostics: Mutex::new(PassDiagnostics {
+                    nonces,
+                    pow_times: prover.pow_times().to_vec(),
+                    ..Default::default()
+                }),
+            };
+            if let Some(observer) = current.observer {
+                observer.on_pass_start(current.nonces.clone());
+            }
+            let set_reading = |nonces: Option<Range<u32>>| {
+                if let Some(progress) = &progress {
+                    *progress.reading.lock().unwrap() = nonces;
+                }
+            };
+            set_reading(Some(current.nonces.clone()));
+            let result = pass(&current);
+            set_reading(None);
+            let result = result?;
+            if let Some(observer) = current.observer {
+                observer.on_pass_end(result.is_some());
+            }
+            let diagnostics = current.diagnostics.into_inner().unwrap();
+            if result.is_none() {
+                log::info!("no proof found in the pass: {diagnostics:?}");
+            }
+            send_diagnostics(options.diagnostics.as_ref(), diagnostics);
+            if let Some(result) = result {
+                return Ok(result);
+            }
+            if progress
+                .as_ref()
+                .is_some_and(|progress| progress.stalled.load(Ordering::Relaxed))
+            {
+                return Err(ProveError::Stalled {
+                    nonces: current.nonces,
+                }
+                .into());
+            }
+        }
+        eyre::bail!("nonce space exhausted, no proof found for any of the {NONCE_SPACE} nonces")
+    })
+}
+
+/// Sets the flag when dropped.
+struct SetOnDrop<'a>(&'a AtomicBool);
+
+impl Drop for SetOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::difficulty::proving_difficulty;
+    use mockall::predicate::{always, eq};
+    use rand::{thread_rng, RngCore};
+    use std::collections::HashSet;
+    use std::iter::repeat;
+
+    #[test]
+    fn creating_proof() {
+        let indices = vec![0, 1, 2, 3, 4, 5, 6, 7, 8];
+        let keep_bits = 4;
+        let proof = Proof::new(7, &indices, 9, 77);
+        assert_eq!(7, proof.nonce);
+        assert_eq!(77, proof.pow);
+        assert_eq!(
+            indices,
+            decompress_indexes(&proof.indices, keep_bits)
+                .take(indices.len())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn encoding_proof() {
+        let proof = Proof::new(7, &[1, 2, 3], 9, 77);
+        let encoded = proof.encode();
+        assert_eq!(12 + proof.indices.len(), encoded.len());
+        assert_eq!(proof, Proof::decode(&encoded, 9, 3).unwrap());
+
+        let no_indices = Proof::decode(&encoded[..12], 9, 0).unwrap();
+        assert_eq!((7, 77), (no_indices.nonce, no_indices.pow));
+        assert!(no_indices.indices.is_empty());
+
+        assert_eq!(
+            Err(DecodeError::TooShort { len: 11 }),
+            Proof::decode(&encoded[..11], 9, 0)
+        );
+        assert_eq!(Err(DecodeError::NoLabels), Proof::decode(&encoded, 0, 3));
+    }
+
+    #[test]
+    fn decoding_proof_checks_length() {
+        // 3 indices of 4 bits
+        let encoded = Proof::new(7, &[1, 2, 3], 9, 77).encode();
+        assert_eq!(12 + 2, encoded.len());
+
+        let truncated = &encoded[..13];
+        assert_eq!(
+            Err(DecodeError::LengthMismatch {
+                expected: 2,
+                got: 1
+            }),
+            Proof::decode(truncated, 9, 3)
+        );
+        let trailing = [encoded.as_slice(), &[0]].concat();
+        assert_eq!(
+            Err(DecodeError::LengthMismatch {
+                expected: 2,
+                got: 3
+            }),
+            Proof::decode(&trailing, 9, 3)
+        );
+        // More indices fit the same number of bytes only with another K2.
+        assert!(Proof::decode(&encoded, 9, 4).is_ok());
+        assert!(Proof::decode(&encoded, 9, 5).is_err());
+        // Wider indices for more labels.
+        assert!(Proof::decode(&encoded, 16, 3).is_ok());
+        assert!(Proof::decode(&encoded, 32, 3).is_err());
+    }
+
+    #[test]
+    fn splitting_params_difficulty() {
+        let mut pow_difficulty = [0; 32];
+        pow_difficulty[30] = 0x12;
+        pow_difficulty[31] = 0x34;
+        let params = ProvingParams {
+            difficulty: 0xAB12_3456_789A_BCDE,
+            pow_difficulty,
+        };
+        assert_eq!(0xAB, params.difficulty_msb());
+        assert_eq!(0x12_3456_789A_BCDE, params.difficulty_lsb());
+        assert_eq!(U256::from(0x1234), params.pow_difficulty_u256());
+
+        let trivial = ProvingParams::trivial();
+        assert_eq!(0xFF, trivial.difficulty_msb());
+        assert_eq!(0xFF_FFFF_FFFF_FFFF, trivial.difficulty_lsb());
+        assert_eq!(U256::MAX, trivial.pow_difficulty_u256());
+    }
+
+    #[test]
+    fn params_difficulty_with_zero_msb() {
+        let cfg = ProofConfig {
+            k1: 10,
+            k2: 3,
+            k3: 3,
+            pow_difficulty: [0xFF; 32],
+        };
+        let params = |num_labels| {
+            let meta = PostMetadata {
+                labels_per_unit: num_labels,
+                num_units: 1,
+                ..Default::default()
+            };
+            ProvingParams::new(&meta, &cfg)
+        };
+        // K1 / num_labels = 1 / 256
+        let boundary = params(256 * 10).unwrap();
+        assert_eq!(
+            (1, 0),
+            (boundary.difficulty_msb(), boundary.difficulty_lsb())
+        );
+        // Any more labels and the MSB is 0, it's still a valid difficulty.
+        let above = params(256 * 10 + 1).unwrap();
+        assert_eq!(0, above.difficulty_msb());
+        assert!(above.difficulty_lsb() > 0xFF_0000_0000_0000);
+
+        let k1 = ProofConfig { k1: 0, ..cfg };
+        let meta = PostMetadata {
+            labels_per_unit: 1000,
+            num_units: 1,
+            ..Default::default()
+        };
+        assert!(ProvingParams::new(&meta, &k1).is_err());
+    }
+
+    #[test]
+    fn proving_with_zero_msb_difficulty() {
+        use crate::verify_core::LabelChecker;
+
+        let challenge = [3; 32];
+        let difficulty = (1 << 56) - 1;
+        let params = ProvingParams {
+            difficulty,
+            pow_difficulty: [0xFF; 32],
+        };
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover.expect_prove().returning(|_, _, _, _| Ok(5));
+        let prover = Prover8_56::new(&challenge, 0..16, params, &pow_prover, &[0; 32]).unwrap();
+        assert_eq!(0, prover.difficulty_msb);
+
+        let mut data = vec![0u8; 2000 * LABEL_SIZE];
+        thread_rng().fill_bytes(&mut data);
+        let mut found = Vec::new();
+        prover.prove(&data, 0, |nonce, index| {
+            found.push((nonce, index));
+            None
+        });
+        // The same labels pass as in the verification.
+        let mut expected = Vec::new();
+        for nonce in 0..16 {
+            let checker = LabelChecker::new(&challenge, nonce, 5, difficulty);
+            for (index, label) in data.chunks_exact(LABEL_SIZE).enumerate() {
+                if checker.check(label.try_into().unwrap()).is_ok() {
+                    expected.push((nonce, index as u64));
+                }
+            }
+        }
+        found.sort_unstable();
+        assert!(!expected.is_empty());
+        assert_eq!(expected, found);
+    }
+
+    #[test]
+    fn proving_with_per_nonce_difficulty() {
+        use crate::verify_core::LabelChecker;
+
+        let challenge = [3; 32];
+        let params = ProvingParams {
+            difficulty: 0x0480_0000_0000_0000,
+            pow_difficulty: [0xFF; 32],
+        };
+        // Even nonces are ~4 times easier than odd ones.
+        let difficulty_of = |nonce: u32| {
+            if nonce % 2 == 0 {
+                0x1080_0000_0000_0000
+            } else {
+                params.difficulty
+            }
+        };
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover.expect_prove().returning(|_, _, _, _| Ok(5));
+        let prover = Prover8_56::new_with_options(
+            &challenge,
+            8..40,
+            params,
+            &pow_prover,
+            &[0; 32],
+            ProverOptions {
+                nonce_difficulty: Some(&difficulty_of),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(params.difficulty, prover.difficulty());
+        assert_eq!(Some(0x1080_0000_0000_0000), prover.nonce_difficulty(8));
+        assert_eq!(Some(params.difficulty), prover.nonce_difficulty(39));
+        assert_eq!(None, prover.nonce_difficulty(40));
+        assert!(!prover.has_uniform_difficulty());
+        // The same difficulty for all nonces is the same as none given.
+        let uniform = Prover8_56::new_with_options(
+            &challenge,
+            8..40,
+            params,
+            &pow_prover,
+            &[0; 32],
+            ProverOptions {
+                nonce_difficulty: Some(&|_: u32| params.difficulty),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(uniform.has_uniform_difficulty());
+        assert_eq!(Some(params.difficulty), uniform.nonce_difficulty(8));
+
+        let mut data = vec![0u8; 2000 * LABEL_SIZE];
+        thread_rng().fill_bytes(&mut data);
+        let mut found = Vec::new();
+        prover.prove(&data, 0, |nonce, index| {
+            found.push((nonce, index));
+            None
+        });
+        // The same labels pass as in the verification with the difficulty of the nonce.
+        let mut expected = Vec::new();
+        for nonce in 8..40 {
+            let checker = LabelChecker::new(&challenge, nonce, 5, difficulty_of(nonce));
+            for (index, label) in data.chunks_exact(LABEL_SIZE).enumerate() {
+                let label = label.try_into().unwrap();
+                let passes = checker.check(label).is_ok();
+                assert_eq!(passes, prover.check_label(nonce, label));
+                if passes {
+                    expected.push((nonce, index as u64));
+                }
+            }
+        }
+        found.sort_unstable();
+        assert_eq!(expected, found);
+
+        let count = |parity| found.iter().filter(|(n, _)| n % 2 == parity).count();
+        assert!(count(0) > 2 * count(1));
+        assert!(count(1) > 0);
+    }
+
+    #[test]
+    fn expected_params_for_proof() {
+        let meta = PostMetadata {
+            labels_per_unit: 1000,
+            num_units: 2,
+            ..Default::default()
+        };
+        let cfg = ProofConfig {
+            k1: 279,
+            k2: 3,
+            k3: 3,
+            pow_difficulty: [0x0F; 32],
+        };
+        let expected = ProvingParams::new(&meta, &cfg).unwrap();
+
+        let proof = Proof::new(17, &[5, 1900, 77], 2000, 42);
+        let params = ProvingParams::expected_for(&proof, &meta, &cfg).unwrap();
+        assert_eq!(expected.difficulty, params.difficulty);
+        assert_eq!(expected.pow_difficulty, params.pow_difficulty);
+
+        // created for fewer labels
+        let proof = Proof::new(17, &[5, 900, 77], 1000, 42);
+        assert!(ProvingParams::expected_for(&proof, &meta, &cfg).is_err());
+        // too many indices
+        let proof = Proof::new(17, &[5, 900, 77, 1], 2000, 42);
+        assert!(ProvingParams::expected_for(&proof, &meta, &cfg).is_err());
+        // index past the labels
+        let proof = Proof::new(17, &[5, 2040, 77], 2000, 42);
+        assert!(ProvingParams::expected_for(&proof, &meta, &cfg).is_err());
+        // nonce group out of bounds
+        let proof = Proof::new(256 * 16, &[5, 1900, 77], 2000, 42);
+        assert!(ProvingParams::expected_for(&proof, &meta, &cfg).is_err());
+    }
+
+    #[test]
+    fn explaining_proof() {
+        let cfg = ProofConfig {
+            k1: 10,
+            k2: 3,
+            k3: 3,
+            pow_difficulty: [0xFF; 32],
+        };
+        let proof = Proof::new(17, &[5, 900, 77], 1000, 42);
+        let explanation = explain(&proof, &cfg, 1000);
+        assert_eq!(
+            ProofExplanation {
+                nonce: 17,
+                nonce_group: 1,
+                pow: 42,
+                keep_bits: 10,
+                index_count: 3,
+                min_index: Some(5),
+                max_index: Some(900),
+                expected_bytes: 4,
+                actual_bytes: 4,
+                indices_in_range: true,
+            },
+            explanation
+        );
+        assert!(explanation.is_length_consistent());
+
+        // Index 900 is past the data
+        let explanation = explain(&proof, &cfg, 600);
+        assert_eq!(10, explanation.keep_bits);
+        assert!(!explanation.indices_in_range);
+
+        let truncated = Proof {
+            indices: Cow::Owned(proof.indices[..2].to_vec()),
+            ..proof
+        };
+        let explanation = explain(&truncated, &cfg, 1000);
+        assert_eq!(1, explanation.index_count);
+        assert!(!explanation.is_length_consistent());
+        assert!(explanation.to_string().contains("INCONSISTENT"));
+    }
+
+    #[test]
+    fn proof_fingerprint() {
+        let proof = Proof::new(7, &[1, 2, 3], 9, 77);
+        assert_eq!(proof.fingerprint(), proof.clone().fingerprint());
+
+        let other_nonce = Proof {
+            nonce: 8,
+            ..proof.clone()
+        };
+        assert_ne!(proof.fingerprint(), other_nonce.fingerprint());
+
+        let other_pow = Proof {
+            pow: 78,
+            ..proof.clone()
+        };
+        assert_ne!(proof.fingerprint(), other_pow.fingerprint());
+
+        let other_indices = Proof::new(7, &[1, 2, 4], 9, 77);
+        assert_ne!(proof.fingerprint(), other_indices.fingerprint());
+    }
+
+    #[test]
+    fn creating_prover() {
+        let meta = PostMetadata {
+            labels_per_unit: 1000,
+            num_units: 1,
+            max_file_size: 1024,
+            ..Default::default()
+        };
+        let cfg = ProofConfig {
+            k1: 279,
+            k2: 300,
+            k3: 65,
+            pow_difficulty: [0xFF; 32],
+        };
+        let params = ProvingParams::new(&meta, &cfg).unwrap();
+        let mut pow_prover = pow::MockProver::new();
+
+        pow_prover
+            .expect_prove()
+            .with(eq(0), eq([0; 8]), eq(cfg.pow_difficulty), always())
+            .once()
+            .returning(|_, _, _, _| Ok(0));
+        assert!(Prover8_56::new(&[0; 32], 0..16, params, &pow_prover, &meta.node_id).is_ok());
+
+        pow_prover
+            .expect_prove()
+            .with(eq(1), eq([0; 8]), eq(cfg.pow_difficulty), always())
+            .once()
+            .returning(|_, _, _, _| Ok(0));
+        assert!(Prover8_56::new(&[0; 32], 16..32, params, &pow_prover, &meta.node_id).is_ok());
+
+        assert!(Prover8_56::new(&[0; 32], 0..0, params, &pow_prover, &meta.node_id).is_err());
+
+        // Nonce ranges don't need to be aligned to nonce groups
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover
+            .expect_prove()
+            .with(eq(0), eq([0; 8]), eq(cfg.pow_difficulty), always())
+            .once()
+            .returning(|_, _, _, _| Ok(0));
+        assert!(Prover8_56::new(&[0; 32], 1..16, params, &pow_prover, &meta.node_id).is_ok());
+    }
+
+    #[test]
+    fn recording_pow_times() {
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover.expect_prove().returning(|_, _, _, _| Ok(0));
+        let create = |record_pow_times| {
+            Prover8_56::new_with_options(
+                &[0; 32],
+                0..64,
+                ProvingParams::trivial(),
+                &pow_prover,
+                &[0; 32],
+                ProverOptions {
+                    record_pow_times,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        assert_eq!(4, create(true).pow_times().len());
+        assert!(create(false).pow_times().is_empty());
+    }
+
+    #[test]
+    fn proving_with_key_derivation() {
+        #[derive(Debug)]
+        struct ByNonce;
+        impl KeyDerivation for ByNonce {
+            fn cipher_key(&self, _: &[u8; 32], nonce_group: u32, _: u64) -> [u8; 16] {
+                [nonce_group as u8; 16]
+            }
+            fn lazy_cipher_key(&self, _: &[u8; 32], nonce: u32, _: u32, _: u64) -> [u8; 16] {
+                [nonce as u8 | 0x80; 16]
+            }
+        }
+
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover.expect_prove().returning(|_, _, _, _| Ok(0));
+        let create = |key_derivation: Option<&dyn KeyDerivation>| {
+            Prover8_56::new_with_options(
+                &[0; 32],
+                0..32,
+                ProvingParams::trivial(),
+                &pow_prover,
+                &[0; 32],
+                ProverOptions {
+                    key_derivation,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let encrypt = |cipher: &AesCipher| {
+            let mut out = [0u8; BLOCK_SIZE];
+            cipher
+                .aes
+                .encrypt_block_b2b((&[0u8; BLOCK_SIZE]).into(), (&mut out).into());
+            out
+        };
+
+        let default = create(None);
+        let explicit = create(Some(&Blake3KeyDerivation));
+        let custom = create(Some(&ByNonce));
+        for nonce in 0..32 {
+            let expected = AesCipher::new_with(&ByNonce, &[0; 32], nonce / 16, 0);
+            assert_eq!(encrypt(&expected), encrypt(custom.cipher(nonce).unwrap()));
+            let expected = AesCipher::new_lazy_with(&ByNonce, &[0; 32], nonce, nonce / 16, 0);
+            assert_eq!(
+                encrypt(&expected),
+                encrypt(custom.lazy_cipher(nonce).unwrap())
+            );
+            assert_eq!(
+                encrypt(default.cipher(nonce).unwrap()),
+                encrypt(explicit.cipher(nonce).unwrap())
+            );
+            assert_ne!(
+                encrypt(default.cipher(nonce).unwrap()),
+                encrypt(custom.cipher(nonce).unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn creating_prover_from_precomputed_pow() {
+        let challenge = [7; 32];
+        assert_eq!(1..3, required_nonce_groups(20..40));
+        assert_eq!(0..1, required_nonce_groups(0..16));
+        assert_eq!(2..3, required_nonce_groups(32..32));
+
+        // Only some labels pass, depending on the cipher keys (and so the PoWs).
+        let params = ProvingParams {
+            difficulty: 0x20ff_ffff_ffff_ffff,
+            ..ProvingParams::trivial()
+        };
+        let pows = HashMap::from([(1, 11), (2, 22), (5, 55)]);
+        let prover = Prover8_56::from_precomputed_pow(&challenge, 20..40, params, &pows).unwrap();
+        assert_eq!(20..40, prover.nonces());
+        assert_eq!(Some(11), prover.get_pow(20));
+        assert_eq!(Some(22), prover.get_pow(39));
+
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover
+            .expect_prove()
+            .returning(|group, _, _, _| Ok(group as u64 * 11));
+        let computed = Prover8_56::new(&challenge, 20..40, params, &pow_prover, &[0; 32]).unwrap();
+        let mut data = vec![0u8; 64 * LABEL_SIZE];
+        thread_rng().fill_bytes(&mut data);
+        let collect = |prover: &Prover8_56| {
+            let mut found = Vec::new();
+            prover.prove(&data, 0, |nonce, index| {
+                found.push((nonce, index));
+                None
+            });
+            found
+        };
+        assert_eq!(collect(&computed), collect(&prover));
+        let other_pows = HashMap::from([(1, 12), (2, 22)]);
+        let other =
+            Prover8_56::from_precomputed_pow(&challenge, 20..40, params, &other_pows).unwrap();
+        assert_ne!(collect(&other), collect(&prover));
+
+        let err =
+            Prover8_56::from_precomputed_pow(&challenge, 20..90, ProvingParams::trivial(), &pows)
+                .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProveError>(),
+            Some(ProveError::MissingPow { nonce_group: 3 })
+        ));
+    }
+
+    #[test]
+    fn cancel_creating_prover() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut pow_prover = pow::MockProver::new();
+        let stop_on_second_group = stop.clone();
+        pow_prover
+            .expect_prove()
+            .times(2)
+            .returning(move |group, _, _, _| {
+                if group == 1 {
+                    stop_on_second_group.store(true, Ordering::Relaxed);
+                }
+                Ok(0)
+            });
+        let err = Prover8_56::new_with_options(
+            &[0; 32],
+            0..64,
+            ProvingParams::trivial(),
+            &pow_prover,
+            &[0; 32],
+            ProverOptions {
+                stop: Some(&*stop),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProveError>(),
+            Some(ProveError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn creating_prover_fails_pow() {
+        let meta = PostMetadata {
+            labels_per_unit: 1000,
+            num_units: 1,
+            max_file_size: 1024,
+            ..Default::default()
+        };
+        let cfg = ProofConfig {
+            k1: 279,
+            k2: 300,
+            k3: 65,
+            pow_difficulty: [0xFF; 32],
+        };
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover
+            .expect_prove()
+            .once()
+            .returning(|_, _, _, _| Err(pow::Error::PoWNotFound));
+        let params = ProvingParams::new(&meta, &cfg).unwrap();
+        assert!(Prover8_56::new(&[0; 32], 0..16, params, &pow_prover, &meta.node_id).is_err());
+    }
+
+    #[test]
+    fn prover_nonce_groups() {
+        assert_eq!(1, Prover8_56::nonce_groups_for(&(0..16)));
+        assert_eq!(4, Prover8_56::nonce_groups_for(&(32..96)));
+
+        let meta = PostMetadata {
+            labels_per_unit: 1000,
+            num_units: 1,
+            max_file_size: 1024,
+            ..Default::default()
+        };
+        let cfg = ProofConfig {
+            k1: 279,
+            k2: 300,
+            k3: 65,
+            pow_difficulty: [0xFF; 32],
+        };
+        let params = ProvingParams::new(&meta, &cfg).unwrap();
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover
+            .expect_prove()
+            .times(4)
+            .returning(|_, _, _, _| Ok(0));
+        let prover = Prover8_56::new(&[0; 32], 32..96, params, &pow_prover, &meta.node_id).unwrap();
+        assert_eq!(4, prover.num_nonce_groups());
+        assert_eq!(32..96, prover.nonces());
+    }
+
+    #[test]
+    fn metadata_too_large() {
+        let meta = PostMetadata {
+            labels_per_unit: u64::MAX / 2,
+            num_units: 3,
+            max_file_size: 1024,
+            ..Default::default()
+        };
+        let cfg = ProofConfig {
+            k1: 279,
+            k2: 300,
+            k3: 65,
+            pow_difficulty: [0xFF; 32],
+        };
+        let err = ProvingParams::new(&meta, &cfg).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProveError>(),
+            Some(ProveError::MetadataTooLarge {
+                num_units: 3,
+                labels_per_unit: _,
+            })
+        ));
+
+        // Just fits
+        let meta = PostMetadata {
+            num_units: 2,
+            ..meta
+        };
+        assert_eq!(u64::MAX - 1, num_labels(&meta).unwrap());
+    }
+
+    /// Test that PoW threshold is scaled with num_units.
+    #[test]
+    fn scaling_pows_thresholds() {
+        let cfg = ProofConfig {
+            k1: 32,
+            k2: 32,
+            k3: 10,
+            pow_difficulty: [0x0F; 32],
+        };
+        let metadata = PostMetadata {
+            num_units: 1,
+            labels_per_unit: 100,
+            max_file_size: 1,
+            node_id: [0u8; 32],
+            commitment_atx_id: [0u8; 32],
+            nonce: None,
+            last_position: None,
+        };
+        {
+            let params = ProvingParams::new(&metadata, &cfg).unwrap();
+            assert_eq!(params.pow_difficulty, cfg.pow_difficulty);
+        }
+        {
+            let params = ProvingParams::new(
+                &PostMetadata {
+                    num_units: 10,
+                    ..metadata
+                },
+                &cfg,
+            )
+            .unwrap();
+            assert!(params.pow_difficulty < cfg.pow_difficulty);
+        }
+    }
+
+    #[test]
+    fn sanity() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let challenge = b"hello world, challenge me!!!!!!!";
+        let params = ProvingParams {
+            difficulty: u64::MAX,
+            pow_difficulty: [0xFF; 32],
+        };
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover.expect_prove().returning(|_, _, _, _| Ok(0));
+
+        let prover = Prover8_56::new(
+            challenge,
+            0..Prover8_56::NONCES_PER_AES,
+            params,
+            &pow_prover,
+            &[7; 32],
+        )
+        .unwrap();
+        let res = prover.prove(&[0u8; 8 * LABEL_SIZE], 0, |nonce, index| {
+            let _ = tx.send((nonce, index));
+            None
+        });
+        assert!(res.is_none());
+        drop(tx);
+        let rst: Vec<(u32, u64)> = rx.into_iter().collect();
+        assert_eq!(
+            (0..8)
+                .flat_map(move |x| (0..Prover8_56::NONCES_PER_AES).zip(std::iter::repeat(x)))
+                .collect::<Vec<_>>(),
+            rst,
+        );
+    }
+
+    #[test]
+    /// Test if indicies in a proof are distributed more less uniformly across the whole input range.
+    fn indicies_distribution() {
+        let challenge = b"hello world, challenge me!!!!!!!";
+
+        const NUM_LABELS: usize = 1024 * 1024;
+        const K1: u32 = 1000;
+        const K2: usize = 1000;
+
+        let mut data = vec![0u8; NUM_LABELS * LABEL_SIZE];
+        thread_rng().fill_bytes(&mut data);
+
+        let mut start_nonce = 0;
+        let mut end_nonce = start_nonce + Prover8_56::NONCES_PER_AES;
+        let params = ProvingParams {
+            difficulty: proving_difficulty(K1, NUM_LABELS as u64).unwrap(),
+            pow_difficulty: [0xFF; 32],
+        };
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover.expect_prove().returning(|_, _, _, _| Ok(0));
+
+        let indexes = loop {
+            let mut indicies = HashMap::<u32, Vec<u64>>::new();
+
+            let prover = Prover8_56::new(
+                challenge,
+                start_nonce..end_nonce,
+                params,
+                &pow_prover,
+                &[7; 32],
+            )
+            .unwrap();
+
+            let result = prover.prove(&data, 0, |nonce, index| {
+                let vec = indicies.entry(nonce).or_default();
+                vec.push(index);
+
+                if vec.len() >= K2 {
+                    return Some(std::mem::take(vec));
+                }
+                None
+            });
+            if let Some((_, indexes)) = result {
+                break indexes;
+            }
+            (start_nonce, end_nonce) = (end_nonce, end_nonce + 20);
+        };
+
+        // verify distribution
+        let buckets = 10;
+        let expected = K2 / buckets;
+        let bucket_id = |idx: u64| -> u64 { idx / (NUM_LABELS / LABEL_SIZE / buckets) as u64 };
+
+        let buckets = indexes
+            .into_iter()
+            .fold(HashMap::<u64, usize>::new(), |mut buckets, idx| {
+                *buckets.entry(bucket_id(idx)).or_default() += 1;
+                buckets
+            });
 
-    let mut start_nonce = 0;
-    let mut end_nonce = start_nonce + nonces as u32;
+        for (id, occurences) in buckets {
+            let deviation_from_expected =
+                (occurences as isize - expected as isize) as f64 / expected as f64;
+            // VERY rough check. The point is to make sure if indexes are not concentrated in any bucket.
+            assert!(
+                deviation_from_expected.abs() <= 1.0,
+                "Too big deviation in proof indexes distribution in bucket {id}: {deviation_from_expected} ({occurences} indexes of {expected} expected)"
+            );
+        }
+    }
 
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(threads)
-        .build()
-        .wrap_err("building thread pool")?;
+    #[test]
+    fn proving_vector() {
+        let challenge = b"hello world, CHALLENGE me!!!!!!!";
 
-    let total_time = Instant::now();
-    loop {
-        if stop.load(Ordering::Relaxed) {
-            eyre::bail!("proof generation was stopped");
+        let num_labels = 128;
+        let k1 = 4;
+        let k2 = 32;
+        let params = ProvingParams {
+            difficulty: proving_difficulty(k1, num_labels as u64).unwrap(),
+            pow_difficulty: [0xFF; 32],
+        };
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover
+            .expect_prove()
+            .once()
+            .returning(|_, _, _, _| Ok(0));
+        let data = repeat(0..=11) // it's important for range len to not be a multiple of AES block
+            .flatten()
+            .take(num_labels * LABEL_SIZE)
+            .collect::<Vec<u8>>();
+
+        let prover = Prover8_56::new(
+            challenge,
+            0..Prover8_56::NONCES_PER_AES,
+            params,
+            &pow_prover,
+            &[7; 32],
+        )
+        .unwrap();
+
+        let mut indexes = HashMap::<u32, Vec<u64>>::new();
+
+        let (nonce, indexes) = prover
+            .prove(&data, 0, |nonce, index| {
+                let vec = indexes.entry(nonce).or_default();
+                vec.push(index);
+                if vec.len() >= k2 {
+                    return Some(std::mem::take(vec));
+                }
+                None
+            })
+            .unwrap();
+        assert_eq!(3, nonce);
+
+        assert_eq!(
+            &[
+                0, 3, 6, 9, 12, 15, 18, 21, 24, 27, 30, 33, 36, 39, 42, 45, 48, 51, 54, 57, 60, 63,
+                66, 69, 72, 75, 78, 81, 84, 87, 90, 93
+            ],
+            indexes.as_slice()
+        );
+    }
+
+    /// The labels of a partial chunk at the end of the data must be checked too.
+    #[test]
+    fn proving_partial_chunk() {
+        let challenge = b"hello world, CHALLENGE me!!!!!!!";
+        let num_labels = AES_BATCH * 2 + 3;
+        let difficulty = proving_difficulty(8, num_labels as u64).unwrap();
+        let params = ProvingParams {
+            difficulty,
+            pow_difficulty: [0xFF; 32],
+        };
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover.expect_prove().returning(|_, _, _, _| Ok(0));
+        let prover = Prover8_56::new(challenge, 0..16, params, &pow_prover, &[7; 32]).unwrap();
+
+        let mut data = vec![0u8; num_labels * LABEL_SIZE];
+        thread_rng().fill_bytes(&mut data);
+        // A trailing partial label is ignored.
+        let data = [data, vec![0xAB; 5]].concat();
+
+        let mut expected = Vec::new();
+        for nonce in 0..16 {
+            let checker = verify_core::LabelChecker::new(challenge, nonce, 0, difficulty);
+            for (index, label) in data.chunks_exact(LABEL_SIZE).enumerate() {
+                if checker.check(label.try_into().unwrap()).is_ok() {
+                    expected.push((nonce, index as u64));
+                }
+            }
         }
+        // Some labels in the partial chunk must be good for the test to be meaningful.
+        assert!(expected
+            .iter()
+            .any(|&(_, index)| index >= 2 * AES_BATCH as u64));
 
-        let indexes = Mutex::new(HashMap::<u32, Vec<u64>>::new());
+        let mut found = Vec::new();
+        prover.prove(&data, 0, |nonce, index| {
+            found.push((nonce, index));
+            None
+        });
+        found.sort();
+        expected.sort();
+        assert_eq!(expected, found);
 
-        let pow_time = Instant::now();
-        let prover = pool.install(|| {
-            Prover8_56::new(
-                challenge,
-                start_nonce..end_nonce,
-                params,
-                &pow_prover,
-                &metadata.node_id,
-            )
-            .wrap_err("creating prover")
-        })?;
+        // Splitting the data at a label (but not chunk) boundary gives the same result.
+        let mut split = Vec::new();
+        let at = 5 * LABEL_SIZE;
+        prover.prove(&data[..at], 0, |nonce, index| {
+            split.push((nonce, index));
+            None
+        });
+        prover.prove(&data[at..], 5, |nonce, index| {
+            split.push((nonce, index));
+            None
+        });
+        split.sort();
+        assert_eq!(expected, split);
+    }
 
-        let pow_mins = pow_time.elapsed().as_secs() / 60;
-        log::info!("Finished k2pow in {} minutes", pow_mins);
+    #[test]
+    fn labels_in_partial_chunk_are_found() {
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover.expect_prove().returning(|_, _, _, _| Ok(0));
+        let prover = Prover8_56::new(
+            &[0; 32],
+            0..1,
+            ProvingParams::trivial(),
+            &pow_prover,
+            &[0; 32],
+        )
+        .unwrap();
 
-        let read_time = Instant::now();
-        let data_reader = read_data(datadir, 1024 * 1024, metadata.max_file_size)?;
-        log::info!("Started reading POST data");
-        let result = pool.install(|| {
-            data_reader
-                .par_bridge()
-                .take_any_while(|_| !stop.load(Ordering::Relaxed))
-                .find_map_any(|batch| {
-                    prover.prove(
-                        &batch.data,
-                        batch.pos / BLOCK_SIZE as u64,
-                        |nonce, index| {
-                            let mut indexes = indexes.lock().unwrap();
-                            let vec = indexes.entry(nonce).or_default();
-                            vec.push(index);
-                            if vec.len() >= cfg.k2 as usize {
-                                return Some(std::mem::take(vec));
-                            }
-                            None
-                        },
-                    )
-                })
+        // Every label satisfies the trivial difficulty.
+        let data = vec![0u8; (AES_BATCH + 3) * LABEL_SIZE];
+        let mut indices = Vec::new();
+        prover.prove(&data, 100, |_, index| {
+            indices.push(index);
+            None
         });
+        assert_eq!(
+            (100..100 + AES_BATCH as u64 + 3).collect::<Vec<_>>(),
+            indices
+        );
+    }
 
-        let read_mins = read_time.elapsed().as_secs() / 60;
-        log::info!("Finished reading POST data in {} minutes", read_mins);
+    #[test]
+    fn calculating_nonce_group_range() {
+        assert_eq!(0..1, nonce_group_range(0..1, 16));
+        assert_eq!(0..1, nonce_group_range(0..4, 16));
+        assert_eq!(0..1, nonce_group_range(0..16, 16));
+        assert_eq!(0..2, nonce_group_range(0..17, 16));
+        assert_eq!(0..2, nonce_group_range(0..18, 16));
+        assert_eq!(0..2, nonce_group_range(0..32, 16));
+        assert_eq!(0..2, nonce_group_range(1..17, 16));
+        assert_eq!(0..2, nonce_group_range(15..17, 16));
+        assert_eq!(1..2, nonce_group_range(16..17, 16));
+        assert_eq!(1..3, nonce_group_range(30..48, 16));
+        assert_eq!(2..3, nonce_group_range(47..48, 16));
+    }
+
+    #[test]
+    fn nonce_group_for_nonce() {
+        assert_eq!(0, calc_nonce_group(0, 16));
+        assert_eq!(0, calc_nonce_group(1, 16));
+        assert_eq!(0, calc_nonce_group(15, 16));
+        assert_eq!(1, calc_nonce_group(16, 16));
+        assert_eq!(1, calc_nonce_group(17, 16));
+        assert_eq!(1, calc_nonce_group(31, 16));
+        assert_eq!(2, calc_nonce_group(32, 16));
+    }
 
-        if let Some((nonce, indices)) = result {
-            let num_labels = metadata.num_units as u64 * metadata.labels_per_unit;
-            let pow = prover.get_pow(nonce).unwrap();
+    #[test]
+    fn watchdog_detects_stall() {
+        let interval = Duration::from_millis(50);
+        let progress = Progress::default();
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut watcher = Watcher::new(&progress, start);
 
-            let total_minutes = total_time.elapsed().as_secs() / 60;
+        // Not reading (i.e. calculating the PoW), it's not a stall.
+        assert_eq!(None, watcher.check(&progress, at(200), interval));
 
-            log::info!("Found proof for nonce: {nonce}, pow: {pow} with {indices:?} indices. Proof took {total_minutes} minutes");
-            return Ok(Proof::new(nonce, &indices, num_labels, pow));
+        // Reading with progress
+        *progress.reading.lock().unwrap() = Some(0..16);
+        for millis in (210..400).step_by(10) {
+            progress.bump();
+            assert_eq!(None, watcher.check(&progress, at(millis), interval));
         }
 
-        (start_nonce, end_nonce) = (end_nonce, end_nonce + nonces as u32);
+        // No progress
+        assert_eq!(None, watcher.check(&progress, at(430), interval));
+        assert_eq!(
+            Some((0..16, Duration::from_millis(60))),
+            watcher.check(&progress, at(450), interval)
+        );
+        // Reported again after another interval
+        assert_eq!(None, watcher.check(&progress, at(460), interval));
+        assert_eq!(
+            Some((0..16, interval)),
+            watcher.check(&progress, at(500), interval)
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{compression::decompress_indexes, difficulty::proving_difficulty};
-    use mockall::predicate::{always, eq};
-    use rand::{thread_rng, RngCore};
-    use std::{collections::HashMap, iter::repeat};
+    #[test]
+    fn watching_stops_on_drop() {
+        let progress = Arc::new(Progress::default());
+        let watching = Watching::start(
+            progress.clone(),
+            Watchdog {
+                interval: Duration::from_millis(50),
+                fail: true,
+            },
+        );
+        drop(watching);
+        assert!(progress.done.load(Ordering::Relaxed));
+        assert!(!progress.stalled.load(Ordering::Relaxed));
+    }
 
     #[test]
-    fn creating_proof() {
-        let indices = vec![0, 1, 2, 3, 4, 5, 6, 7, 8];
-        let keep_bits = 4;
-        let proof = Proof::new(7, &indices, 9, 77);
-        assert_eq!(7, proof.nonce);
-        assert_eq!(77, proof.pow);
+    fn stop_condition() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(None, StopCondition::default().reached(1000, 4000, minute));
+
+        let limits = StopCondition {
+            max_passes: Some(3),
+            max_duration: Some(minute),
+            max_nonce: Some(256),
+        };
+        assert_eq!(None, limits.reached(2, 128, Duration::ZERO));
         assert_eq!(
-            indices,
-            decompress_indexes(&proof.indices, keep_bits)
-                .take(indices.len())
-                .collect::<Vec<_>>()
+            Some(StopReason::MaxPasses(3)),
+            limits.reached(3, 128, Duration::ZERO)
+        );
+        assert_eq!(
+            Some(StopReason::MaxDuration(minute)),
+            limits.reached(2, 128, minute)
+        );
+        assert_eq!(
+            Some(StopReason::MaxNonce(256)),
+            limits.reached(2, 256, Duration::ZERO)
+        );
+        // The first reached limit is reported.
+        assert_eq!(
+            Some(StopReason::MaxPasses(3)),
+            limits.reached(5, 512, 2 * minute)
         );
     }
 
     #[test]
-    fn creating_prover() {
-        let meta = PostMetadata {
-            labels_per_unit: 1000,
-            num_units: 1,
-            max_file_size: 1024,
-            ..Default::default()
-        };
-        let cfg = ProofConfig {
-            k1: 279,
-            k2: 300,
-            k3: 65,
-            pow_difficulty: [0xFF; 32],
+    fn proof_nonce_group() {
+        for (nonce, group) in [(0, 0), (15, 0), (16, 1), (31, 1), (32, 2), (4095, 255)] {
+            let proof = Proof::new(nonce, &[], 1, 0);
+            assert_eq!(group, proof.nonce_group());
+            assert_eq!(calc_nonce_group(nonce, 16), group as usize);
+        }
+    }
+
+    #[test]
+    fn repacking_proof() {
+        let indices = (0..9).map(|i| i * 100).collect::<Vec<u64>>();
+        let proof = Proof::new(7, &indices, 1000, 77);
+
+        let repacked = proof.repack(9, 1000, 100_000).unwrap();
+        assert_eq!(7, repacked.nonce);
+        assert_eq!(77, repacked.pow);
+        assert_eq!(indices, repacked.unpack_indices(100_000, 9).unwrap());
+        assert_eq!(proof, repacked.repack(9, 100_000, 1000).unwrap());
+
+        assert!(matches!(
+            proof.repack(9, 1000, 800),
+            Err(ProveError::IndexOutOfRange {
+                index: 800,
+                num_labels: 800
+            })
+        ));
+        assert!(matches!(
+            proof.repack(10, 1000, 1000),
+            Err(ProveError::NotEnoughIndices {
+                expected: 10,
+                got: 9
+            })
+        ));
+        assert!(matches!(
+            proof.repack(9, 0, 1000),
+            Err(ProveError::NoLabels)
+        ));
+    }
+
+    #[test]
+    fn canonicalizing_proof() {
+        let indices = [500, 3, 900, 7, 100];
+        let mut proof = Proof::new(7, &indices, 1000, 77);
+        assert!(!proof.is_canonical(1000, 5));
+
+        proof.canonicalize(1000, 5).unwrap();
+        assert!(proof.is_canonical(1000, 5));
+        assert_eq!(
+            vec![3, 7, 100, 500, 900],
+            proof.unpack_indices(1000, 5).unwrap()
+        );
+        assert_eq!(Proof::new(7, &[3, 7, 100, 500, 900], 1000, 77), proof);
+
+        let mut duplicated = Proof::new(7, &[5, 3, 5], 1000, 77);
+        let original = duplicated.clone();
+        assert!(matches!(
+            duplicated.canonicalize(1000, 3),
+            Err(ProveError::DuplicateIndex { nonce: 7, index: 5 })
+        ));
+        assert_eq!(original, duplicated);
+        assert!(!Proof::new(7, &[3, 5, 5], 1000, 77).is_canonical(1000, 3));
+        assert!(!proof.is_canonical(1000, 6));
+    }
+
+    #[test]
+    fn partitioned_ranges_stay_disjoint() {
+        // How many times every nonce is searched by the machines,
+        // planning the passes like `run_passes_over`.
+        let searched = |ranges: &[Range<u32>], per_pass: u32, with_max_passes: bool| {
+            let mut searched = vec![0u32; NONCE_SPACE as usize];
+            for range in ranges {
+                let limits = StopCondition {
+                    max_nonce: Some(range.end),
+                    max_passes: with_max_passes.then(|| (range.len() as u32).div_ceil(per_pass)),
+                    ..Default::default()
+                };
+                for (index, nonces) in nonce_passes(range.start, per_pass).enumerate() {
+                    if limits
+                        .reached(index as u32, nonces.start, Duration::ZERO)
+                        .is_some()
+                    {
+                        break;
+                    }
+                    for nonce in limits.limit_nonces(nonces) {
+                        searched[nonce as usize] += 1;
+                    }
+                }
+            }
+            searched
         };
-        let params = ProvingParams::new(&meta, &cfg).unwrap();
-        let mut pow_prover = pow::MockProver::new();
+        for machines in [1, 3, 5, 256] {
+            let ranges = partition_nonces(NONCE_SPACE, machines);
+            for per_pass in [16, 288, 2048] {
+                assert!(
+                    searched(&ranges, per_pass, true).iter().all(|&n| n == 1),
+                    "machines: {machines}, per pass: {per_pass}"
+                );
+            }
+        }
+        // Without the pass limit, the last machine wraps around into the range of the first.
+        let ranges = partition_nonces(NONCE_SPACE, 3);
+        assert_eq!(2, searched(&ranges, 288, false)[0]);
+    }
 
-        pow_prover
-            .expect_prove()
-            .with(eq(0), eq([0; 8]), eq(cfg.pow_difficulty), always())
-            .once()
-            .returning(|_, _, _, _| Ok(0));
-        assert!(Prover8_56::new(&[0; 32], 0..16, params, &pow_prover, &meta.node_id).is_ok());
+    #[test]
+    fn nonce_passes_wrap_around() {
+        assert_eq!(
+            vec![0..2048, 2048..4096],
+            nonce_passes(0, 2048).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![4000..4096, 0..1600, 1600..3200, 3200..4000],
+            nonce_passes(4000, 1600).collect::<Vec<_>>()
+        );
+        let total = nonce_passes(seeded_start_nonce(&[1; 32], &[2; 32]), 288)
+            .map(|r| r.len())
+            .sum::<usize>();
+        assert_eq!(NONCE_SPACE as usize, total);
+    }
+
+    #[test]
+    fn seeded_start_nonce_is_aligned() {
+        for i in 0..100u8 {
+            let nonce = seeded_start_nonce(&[i; 32], &[0; 32]);
+            assert!(nonce < NONCE_SPACE);
+            assert_eq!(0, nonce % Prover8_56::NONCES_PER_AES);
+        }
+        assert_eq!(
+            seeded_start_nonce(&[1; 32], &[2; 32]),
+            seeded_start_nonce(&[1; 32], &[2; 32])
+        );
+    }
 
+    #[test]
+    fn prover_with_unaligned_range() {
+        let challenge = b"hello world, challenge me!!!!!!!";
+        let mut pow_prover = pow::MockProver::new();
         pow_prover
             .expect_prove()
-            .with(eq(1), eq([0; 8]), eq(cfg.pow_difficulty), always())
-            .once()
-            .returning(|_, _, _, _| Ok(0));
-        assert!(Prover8_56::new(&[0; 32], 16..32, params, &pow_prover, &meta.node_id).is_ok());
+            .returning(|group, _, _, _| Ok(group as u64));
 
-        assert!(Prover8_56::new(&[0; 32], 0..0, params, &pow_prover, &meta.node_id).is_err());
-        assert!(Prover8_56::new(&[0; 32], 1..16, params, &pow_prover, &meta.node_id).is_err());
+        // The range doesn't start at a multiple of its length
+        let prover = Prover8_56::new(
+            challenge,
+            48..80,
+            ProvingParams::trivial(),
+            &pow_prover,
+            &[0; 32],
+        )
+        .unwrap();
+        assert_eq!(Some(3), prover.get_pow(48));
+        assert_eq!(Some(4), prover.get_pow(79));
+        assert_eq!(None, prover.get_pow(80));
+        assert_eq!(None, prover.get_pow(47));
+
+        let (nonce, _) = prover
+            .prove(&[0u8; CHUNK_SIZE], 0, |nonce, index| {
+                Some(vec![nonce as u64, index])
+            })
+            .unwrap();
+        assert!(prover.nonces().contains(&nonce));
     }
 
     #[test]
-    fn creating_prover_fails_pow() {
-        let meta = PostMetadata {
-            labels_per_unit: 1000,
+    fn expected_bytes_scanned_model() {
+        let metadata = PostMetadata {
+            labels_per_unit: 1_000_000,
             num_units: 1,
-            max_file_size: 1024,
+            max_file_size: 16_000_000,
             ..Default::default()
         };
-        let cfg = ProofConfig {
-            k1: 279,
-            k2: 300,
-            k3: 65,
+        // A single index, found on average every 1000 labels.
+        let params = ProvingParams {
+            difficulty: u64::MAX / 1000,
             pow_difficulty: [0xFF; 32],
         };
-        let mut pow_prover = pow::MockProver::new();
-        pow_prover
-            .expect_prove()
-            .once()
-            .returning(|_, _, _, _| Err(pow::Error::PoWNotFound));
-        let params = ProvingParams::new(&meta, &cfg).unwrap();
-        assert!(Prover8_56::new(&[0; 32], 0..16, params, &pow_prover, &meta.node_id).is_err());
+        let bytes = expected_bytes_scanned(&params, &metadata, 1, 1);
+        assert!((15_900..16_100).contains(&bytes), "{bytes}");
+        assert!(expected_bytes_scanned(&params, &metadata, 1, 16) < bytes);
+        assert!(expected_bytes_scanned(&params, &metadata, 2, 1) > bytes);
+        assert_eq!(0, expected_bytes_scanned(&params, &metadata, 0, 1));
+        assert_eq!(u64::MAX, expected_bytes_scanned(&params, &metadata, 1, 0));
+        let impossible = ProvingParams {
+            difficulty: 0,
+            ..params
+        };
+        assert_eq!(
+            u64::MAX,
+            expected_bytes_scanned(&impossible, &metadata, 1, 1)
+        );
     }
 
-    /// Test that PoW threshold is scaled with num_units.
+    /// Compares the model with the bytes the prover reads to find a proof in random data.
     #[test]
-    fn scaling_pows_thresholds() {
-        let cfg = ProofConfig {
-            k1: 32,
-            k2: 32,
-            k3: 10,
-            pow_difficulty: [0x0F; 32],
-        };
+    fn expected_bytes_scanned_matches_prover() {
+        let num_labels = 4096;
+        let (k2, nonces) = (8, 16);
         let metadata = PostMetadata {
+            labels_per_unit: num_labels,
             num_units: 1,
-            labels_per_unit: 100,
-            max_file_size: 1,
-            node_id: [0u8; 32],
-            commitment_atx_id: [0u8; 32],
-            nonce: None,
-            last_position: None,
+            max_file_size: num_labels * LABEL_SIZE as u64,
+            ..Default::default()
         };
-        {
-            let params = ProvingParams::new(&metadata, &cfg).unwrap();
-            assert_eq!(params.pow_difficulty, cfg.pow_difficulty);
-        }
-        {
-            let params = ProvingParams::new(
-                &PostMetadata {
-                    num_units: 10,
-                    ..metadata
-                },
-                &cfg,
-            )
-            .unwrap();
-            assert!(params.pow_difficulty < cfg.pow_difficulty);
+        // Every label satisfies the difficulty for a nonce with probability 1/256.
+        let params = ProvingParams {
+            difficulty: 1 << 56,
+            pow_difficulty: [0xFF; 32],
+        };
+        let expected = expected_bytes_scanned(&params, &metadata, k2, nonces);
+
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover.expect_prove().returning(|_, _, _, _| Ok(0));
+        let prover = Prover8_56::new(&[0; 32], 0..nonces, params, &pow_prover, &[0; 32]).unwrap();
+        let mut data = vec![0u8; num_labels as usize * LABEL_SIZE];
+        let trials = 200;
+        let mut total = 0;
+        for _ in 0..trials {
+            // Passes over new random data until a proof is found.
+            loop {
+                thread_rng().fill_bytes(&mut data);
+                let mut counts = [0u32; 16];
+                let found = prover.prove(&data, 0, |nonce, index| {
+                    counts[nonce as usize] += 1;
+                    (counts[nonce as usize] == k2).then(|| vec![index])
+                });
+                match found {
+                    Some((_, indices)) => {
+                        total += (indices[0] + 1) * LABEL_SIZE as u64;
+                        break;
+                    }
+                    None => total += data.len() as u64,
+                }
+            }
         }
+        let measured = total / trials;
+        let error = measured.abs_diff(expected) as f64 / expected as f64;
+        assert!(error < 0.1, "expected: {expected}, measured: {measured}");
     }
 
     #[test]
-    fn sanity() {
-        let (tx, rx) = std::sync::mpsc::channel();
+    fn msb_histogram_matches_prover() {
         let challenge = b"hello world, challenge me!!!!!!!";
+        let mut data = vec![0u8; 1024 * LABEL_SIZE + 7];
+        thread_rng().fill_bytes(&mut data);
+
+        let histogram = msb_histogram(&data, challenge, 1, 7);
+        assert_eq!(1024 * 16, histogram.iter().sum::<u64>());
+
+        // Count labels passing the MSB check for all nonces in the group.
+        // The LSB check (practically) always passes with the max LSB difficulty.
+        let difficulty_msb = 0x0F;
         let params = ProvingParams {
-            difficulty: u64::MAX,
+            difficulty: (difficulty_msb as u64) << 56 | 0x00ff_ffff_ffff_ffff,
             pow_difficulty: [0xFF; 32],
         };
         let mut pow_prover = pow::MockProver::new();
-        pow_prover.expect_prove().returning(|_, _, _, _| Ok(0));
-
-        let prover = Prover8_56::new(
-            challenge,
-            0..Prover8_56::NONCES_PER_AES,
-            params,
-            &pow_prover,
-            &[7; 32],
-        )
-        .unwrap();
-        let res = prover.prove(&[0u8; 8 * LABEL_SIZE], 0, |nonce, index| {
-            let _ = tx.send((nonce, index));
+        pow_prover.expect_prove().returning(|_, _, _, _| Ok(7));
+        let prover = Prover8_56::new(challenge, 16..32, params, &pow_prover, &[0; 32]).unwrap();
+        let mut found = 0;
+        prover.prove(&data[..1024 * LABEL_SIZE], 0, |_, _| {
+            found += 1;
             None
         });
-        assert!(res.is_none());
-        drop(tx);
-        let rst: Vec<(u32, u64)> = rx.into_iter().collect();
         assert_eq!(
-            (0..8)
-                .flat_map(move |x| (0..Prover8_56::NONCES_PER_AES).zip(std::iter::repeat(x)))
-                .collect::<Vec<_>>(),
-            rst,
+            histogram[..=difficulty_msb as usize].iter().sum::<u64>(),
+            found
         );
     }
 
     #[test]
-    /// Test if indicies in a proof are distributed more less uniformly across the whole input range.
-    fn indicies_distribution() {
+    fn prove_with_diagnostics() {
         let challenge = b"hello world, challenge me!!!!!!!";
-
-        const NUM_LABELS: usize = 1024 * 1024;
-        const K1: u32 = 1000;
-        const K2: usize = 1000;
-
-        let mut data = vec![0u8; NUM_LABELS * LABEL_SIZE];
+        let mut data = vec![0u8; 1024 * LABEL_SIZE];
         thread_rng().fill_bytes(&mut data);
-
-        let mut start_nonce = 0;
-        let mut end_nonce = start_nonce + Prover8_56::NONCES_PER_AES;
         let params = ProvingParams {
-            difficulty: proving_difficulty(K1, NUM_LABELS as u64).unwrap(),
+            difficulty: proving_difficulty(64, 1024).unwrap(),
             pow_difficulty: [0xFF; 32],
         };
         let mut pow_prover = pow::MockProver::new();
         pow_prover.expect_prove().returning(|_, _, _, _| Ok(0));
+        let prover = Prover8_56::new(challenge, 0..16, params, &pow_prover, &[0; 32]).unwrap();
 
-        let indexes = loop {
-            let mut indicies = HashMap::<u32, Vec<u64>>::new();
-
-            let prover = Prover8_56::new(
-                challenge,
-                start_nonce..end_nonce,
-                params,
-                &pow_prover,
-                &[7; 32],
-            )
-            .unwrap();
-
-            let result = prover.prove(&data, 0, |nonce, index| {
-                let vec = indicies.entry(nonce).or_default();
-                vec.push(index);
-
-                if vec.len() >= K2 {
-                    return Some(std::mem::take(vec));
-                }
+        let mut diagnostics = PassDiagnostics::default();
+        let mut found = 0;
+        prover.prove_with_diagnostics(
+            &data,
+            0,
+            |_, _| {
+                found += 1;
                 None
-            });
-            if let Some((_, indexes)) = result {
-                break indexes;
-            }
-            (start_nonce, end_nonce) = (end_nonce, end_nonce + 20);
-        };
-
-        // verify distribution
-        let buckets = 10;
-        let expected = K2 / buckets;
-        let bucket_id = |idx: u64| -> u64 { idx / (NUM_LABELS / LABEL_SIZE / buckets) as u64 };
-
-        let buckets = indexes
-            .into_iter()
-            .fold(HashMap::<u64, usize>::new(), |mut buckets, idx| {
-                *buckets.entry(bucket_id(idx)).or_default() += 1;
-                buckets
-            });
-
-        for (id, occurences) in buckets {
-            let deviation_from_expected =
-                (occurences as isize - expected as isize) as f64 / expected as f64;
-            // VERY rough check. The point is to make sure if indexes are not concentrated in any bucket.
-            assert!(
-                deviation_from_expected.abs() <= 1.0,
-                "Too big deviation in proof indexes distribution in bucket {id}: {deviation_from_expected} ({occurences} indexes of {expected} expected)"
-            );
-        }
+            },
+            &mut diagnostics,
+        );
+        assert_eq!(found, diagnostics.msb_passes + diagnostics.lsb_passes);
+        assert!(diagnostics.lsb_passes <= diagnostics.lsb_checks);
+        assert_eq!(0, diagnostics.max_indices);
     }
 
     #[test]
-    fn proving_vector() {
-        let challenge = b"hello world, CHALLENGE me!!!!!!!";
+    fn adding_diagnostics() {
+        let mut total = PassDiagnostics {
+            nonces: 0..16,
+            msb_passes: 1,
+            lsb_checks: 2,
+            lsb_passes: 1,
+            max_indices: 5,
+            pow_times: vec![Duration::from_secs(1)],
+        };
+        total.add(&PassDiagnostics {
+            nonces: 16..32,
+            msb_passes: 3,
+            lsb_checks: 1,
+            lsb_passes: 0,
+            max_indices: 2,
+            pow_times: vec![Duration::from_secs(2)],
+        });
+        assert_eq!(
+            PassDiagnostics {
+                nonces: 0..16,
+                msb_passes: 4,
+                lsb_checks: 3,
+                lsb_passes: 1,
+                max_indices: 5,
+                pow_times: vec![Duration::from_secs(1), Duration::from_secs(2)],
+            },
+            total
+        );
+    }
 
-        let num_labels = 128;
-        let k1 = 4;
-        let k2 = 32;
+    #[test]
+    fn prove_unaligned_nonce_range() {
+        let challenge = b"hello world, challenge me!!!!!!!";
+        let mut data = vec![0u8; 256 * LABEL_SIZE];
+        thread_rng().fill_bytes(&mut data);
         let params = ProvingParams {
-            difficulty: proving_difficulty(k1, num_labels as u64).unwrap(),
+            difficulty: proving_difficulty(64, 256).unwrap(),
             pow_difficulty: [0xFF; 32],
         };
         let mut pow_prover = pow::MockProver::new();
         pow_prover
             .expect_prove()
-            .once()
-            .returning(|_, _, _, _| Ok(0));
-        let data = repeat(0..=11) // it's important for range len to not be a multiple of AES block
-            .flatten()
-            .take(num_labels * LABEL_SIZE)
-            .collect::<Vec<u8>>();
+            .times(2)
+            .returning(|group, _, _, _| Ok(group as u64));
+        let prover = Prover8_56::new(challenge, 5..27, params, &pow_prover, &[0; 32]).unwrap();
+        assert_eq!(2, prover.num_nonce_groups());
+
+        let mut found = HashMap::<u32, Vec<u64>>::new();
+        prover.prove(&data, 0, |nonce, index| {
+            found.entry(nonce).or_default().push(index);
+            None
+        });
+        assert!(found.keys().all(|nonce| (5..27).contains(nonce)));
+
+        // Compare with provers for the whole nonce groups
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover
+            .expect_prove()
+            .times(2)
+            .returning(|group, _, _, _| Ok(group as u64));
+        let aligned = Prover8_56::new(challenge, 0..32, params, &pow_prover, &[0; 32]).unwrap();
+        let mut expected = HashMap::<u32, Vec<u64>>::new();
+        aligned.prove(&data, 0, |nonce, index| {
+            if (5..27).contains(&nonce) {
+                expected.entry(nonce).or_default().push(index);
+            }
+            None
+        });
+        assert_eq!(expected, found);
+    }
+
+    #[rstest::rstest]
+    #[case(NONCE_SPACE, 1)]
+    #[case(NONCE_SPACE, 7)]
+    #[case(288, 3)]
+    #[case(100, 2)]
+    #[case(32, 5)]
+    fn partitioning_nonces(#[case] total: u32, #[case] machines: usize) {
+        let ranges = partition_nonces(total, machines);
+        assert!(!ranges.is_empty() && ranges.len() <= machines);
+        assert_eq!(0, ranges[0].start);
+        assert_eq!(total, ranges.last().unwrap().end);
+        for (a, b) in ranges.iter().zip(ranges.iter().skip(1)) {
+            assert_eq!(a.end, b.start);
+        }
+        for range in &ranges {
+            assert!(!range.is_empty());
+            assert_eq!(0, range.start % Prover8_56::NONCES_PER_AES);
+        }
+        let groups = ranges
+            .iter()
+            .map(|r| Prover8_56::nonce_groups_for(r))
+            .collect::<Vec<_>>();
+        let (min, max) = (groups.iter().min().unwrap(), groups.iter().max().unwrap());
+        assert!(max - min <= 1);
+    }
+
+    #[test]
+    fn partitioning_nonces_edge_cases() {
+        assert!(partition_nonces(0, 4).is_empty());
+        assert!(partition_nonces(64, 0).is_empty());
+        assert_eq!(vec![0..16, 16..20], partition_nonces(20, 4));
+    }
+
+    #[test]
+    fn index_collector_returns_indices_at_target() {
+        let collector = IndexCollector::new(16..32, 3);
+        assert_eq!(None, collector.collect(16, 1));
+        assert_eq!(None, collector.collect(31, 7));
+        assert_eq!(None, collector.collect(16, 2));
+        assert_eq!(Some(vec![1, 2, 3]), collector.collect(16, 3));
+        // the nonce is done, further indices are ignored
+        assert_eq!(None, collector.collect(16, 4));
+        assert_eq!(None, collector.collect(16, 5));
+        assert_eq!(None, collector.collect(16, 6));
+        assert_eq!(None, collector.collect(31, 8));
+        assert_eq!(Some(vec![7, 8, 9]), collector.collect(31, 9));
+    }
+
+    #[test]
+    fn index_collector_all_done() {
+        let collector = IndexCollector::new(0..2, 2);
+        collector.collect(0, 1);
+        assert_eq!(Some(vec![1, 2]), collector.collect(0, 2));
+        assert!(!collector.all_done());
+        // a done nonce doesn't hold any more indices
+        collector.collect(0, 3);
+        assert!(collector.slots[0].lock().unwrap().is_none());
+        collector.collect(1, 4);
+        assert!(!collector.all_done());
+        assert_eq!(Some(vec![4, 5]), collector.collect(1, 5));
+        assert!(collector.all_done());
+        assert_eq!(None, collector.collect(1, 6));
+    }
+
+    #[test]
+    fn index_collector_max_collected() {
+        let collector = IndexCollector::new(0..4, 3);
+        assert_eq!(0, collector.max_collected());
+        collector.collect(1, 1);
+        collector.collect(1, 2);
+        collector.collect(2, 3);
+        assert_eq!(2, collector.max_collected());
+        collector.collect(1, 4);
+        // done nonces count as reaching the target
+        assert_eq!(3, collector.max_collected());
+    }
+
+    #[test]
+    fn index_collector_duplicates() {
+        let collector = IndexCollector::new(0..2, 3);
+        collector.collect(0, 1);
+        collector.collect(0, 1);
+        assert_eq!(Some(vec![1, 1, 2]), collector.collect(0, 2));
+        assert!(collector.check_duplicates().is_ok());
+
+        let collector = IndexCollector::new(0..2, 3).with_duplicates(DuplicateIndices::Skip);
+        collector.collect(0, 1);
+        collector.collect(0, 1);
+        collector.collect(1, 1);
+        collector.collect(0, 2);
+        assert_eq!(Some(vec![1, 2, 3]), collector.collect(0, 3));
+        assert!(collector.check_duplicates().is_ok());
+
+        let collector = IndexCollector::new(0..2, 3).with_duplicates(DuplicateIndices::Fail);
+        collector.collect(1, 5);
+        collector.collect(1, 5);
+        collector.collect(1, 6);
+        collector.collect(1, 6);
+        assert!(matches!(
+            collector.check_duplicates(),
+            Err(ProveError::DuplicateIndex { nonce: 1, index: 5 })
+        ));
+    }
 
+    /// Guards against overlapping batches or wrong index calculation:
+    /// every index must be found at most once for a nonce, whatever the batches.
+    #[rstest::rstest]
+    #[case(CHUNK_SIZE)]
+    #[case(LABEL_SIZE)]
+    #[case(LABEL_SIZE * 13)]
+    #[case(CHUNK_SIZE * 3 + LABEL_SIZE * 5)]
+    fn no_duplicate_indices_across_batches(#[case] batch_size: usize) {
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover.expect_prove().returning(|_, _, _, _| Ok(0));
         let prover = Prover8_56::new(
-            challenge,
-            0..Prover8_56::NONCES_PER_AES,
-            params,
+            &[0; 32],
+            0..32,
+            ProvingParams::trivial(),
             &pow_prover,
-            &[7; 32],
+            &[0; 32],
         )
         .unwrap();
 
-        let mut indexes = HashMap::<u32, Vec<u64>>::new();
+        let num_labels = 100;
+        let mut data = vec![0u8; num_labels * LABEL_SIZE];
+        thread_rng().fill_bytes(&mut data);
+        // Every label satisfies the trivial difficulty, no nonce can reach the target.
+        let collector =
+            IndexCollector::new(0..32, num_labels + 1).with_duplicates(DuplicateIndices::Fail);
+        for (i, batch) in data.chunks(batch_size).enumerate() {
+            let index = (i * batch_size / LABEL_SIZE) as u64;
+            prover.prove(batch, index, |nonce, index| collector.collect(nonce, index));
+        }
+        collector.check_duplicates().unwrap();
+        assert_eq!(num_labels, collector.max_collected());
+    }
 
-        let (nonce, indexes) = prover
-            .prove(&data, 0, |nonce, index| {
-                let vec = indexes.entry(nonce).or_default();
-                vec.push(index);
-                if vec.len() >= k2 {
-                    return Some(std::mem::take(vec));
-                }
-                None
-            })
-            .unwrap();
-        assert_eq!(3, nonce);
+    #[test]
+    fn checking_single_labels() {
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover.expect_prove().returning(|_, _, _, _| Ok(0));
+        let params = ProvingParams {
+            difficulty: 0x20ff_ffff_ffff_ffff,
+            pow_difficulty: [0xFF; 32],
+        };
+        // The last nonce group only partially
+        let prover = Prover8_56::new(&[0; 32], 0..20, params, &pow_prover, &[0; 32]).unwrap();
+        assert_eq!(params.difficulty, prover.difficulty());
+        let mut data = vec![0u8; 1000 * LABEL_SIZE];
+        thread_rng().fill_bytes(&mut data);
 
-        assert_eq!(
-            &[
-                0, 3, 6, 9, 12, 15, 18, 21, 24, 27, 30, 33, 36, 39, 42, 45, 48, 51, 54, 57, 60, 63,
-                66, 69, 72, 75, 78, 81, 84, 87, 90, 93
-            ],
-            indexes.as_slice()
-        );
+        let mut expected = HashSet::new();
+        prover.prove(&data, 0, |nonce, index| {
+            expected.insert((nonce, index));
+            None
+        });
+        assert!(!expected.is_empty());
+        for (index, label) in data.chunks_exact(LABEL_SIZE).enumerate() {
+            let label = label.try_into().unwrap();
+            for nonce in 0..32 {
+                assert_eq!(
+                    expected.contains(&(nonce, index as u64)),
+                    prover.check_label(nonce, label)
+                );
+            }
+        }
     }
 
     #[test]
-    fn calculating_nonce_group_range() {
-        assert_eq!(0..1, nonce_group_range(0..1, 16));
-        assert_eq!(0..1, nonce_group_range(0..4, 16));
-        assert_eq!(0..1, nonce_group_range(0..16, 16));
-        assert_eq!(0..2, nonce_group_range(0..17, 16));
-        assert_eq!(0..2, nonce_group_range(0..18, 16));
-        assert_eq!(0..2, nonce_group_range(0..32, 16));
-        assert_eq!(0..2, nonce_group_range(1..17, 16));
-        assert_eq!(0..2, nonce_group_range(15..17, 16));
-        assert_eq!(1..2, nonce_group_range(16..17, 16));
-        assert_eq!(1..3, nonce_group_range(30..48, 16));
-        assert_eq!(2..3, nonce_group_range(47..48, 16));
+    fn proving_batched() {
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover.expect_prove().returning(|_, _, _, _| Ok(0));
+        let params = ProvingParams {
+            difficulty: u64::MAX / 4,
+            pow_difficulty: [0xFF; 32],
+        };
+        let prover = Prover8_56::new(&[0; 32], 0..32, params, &pow_prover, &[0; 32]).unwrap();
+        let mut data = vec![0u8; 1000 * LABEL_SIZE];
+        thread_rng().fill_bytes(&mut data);
+
+        // The same candidates, grouped by nonce.
+        let mut expected = HashMap::<u32, Vec<u64>>::new();
+        prover.prove(&data, 0, |nonce, index| {
+            expected.entry(nonce).or_default().push(index);
+            None
+        });
+        let mut batched = HashMap::<u32, Vec<u64>>::new();
+        let mut calls = 0;
+        prover.prove_batched(&data, 0, |nonce, indices| {
+            calls += 1;
+            batched.entry(nonce).or_default().extend_from_slice(indices);
+            None
+        });
+        assert_eq!(expected, batched);
+        let candidates = expected.values().map(Vec::len).sum::<usize>();
+        assert!(candidates > CANDIDATE_BUFFER_SIZE);
+        assert!(calls < candidates / 10);
+
+        // The found indices are the same as for the unbatched collection.
+        let target = 100;
+        let collector = IndexCollector::new(0..32, target);
+        let found = prover.prove(&data, 0, |nonce, index| collector.collect(nonce, index));
+        let collector = IndexCollector::new(0..32, target);
+        let found_batched = prover.prove_batched(&data, 0, |nonce, indices| {
+            collector.collect_batch(nonce, indices)
+        });
+        let (nonce, indices) = found_batched.unwrap();
+        assert_eq!(&expected[&nonce][..target], indices);
+        assert!(found.is_some());
     }
 
     #[test]
-    fn nonce_group_for_nonce() {
-        assert_eq!(0, calc_nonce_group(0, 16));
-        assert_eq!(0, calc_nonce_group(1, 16));
-        assert_eq!(0, calc_nonce_group(15, 16));
-        assert_eq!(1, calc_nonce_group(16, 16));
-        assert_eq!(1, calc_nonce_group(17, 16));
-        assert_eq!(1, calc_nonce_group(31, 16));
-        assert_eq!(2, calc_nonce_group(32, 16));
+    fn index_collector_batch() {
+        let collector = IndexCollector::new(0..2, 3).with_duplicates(DuplicateIndices::Skip);
+        assert_eq!(None, collector.collect_batch(0, &[1, 2]));
+        assert_eq!(Some(vec![1, 2, 3]), collector.collect_batch(0, &[2, 3, 4]));
+        assert_eq!(None, collector.collect_batch(0, &[5, 6, 7]));
+        assert_eq!(
+            Some(vec![4, 5, 6]),
+            collector.collect_batch(1, &[4, 5, 6, 7])
+        );
+        assert!(collector.all_done());
+    }
+
+    #[test]
+    fn index_collector_concurrent() {
+        use rayon::prelude::*;
+
+        let collector = IndexCollector::new(0..16, 100);
+        let found = (0..16 * 1000u64)
+            .into_par_iter()
+            .filter_map(|i| collector.collect((i % 16) as u32, i).map(|v| (i % 16, v)))
+            .collect::<Vec<_>>();
+
+        assert_eq!(16, found.len());
+        for (nonce, indices) in found {
+            assert_eq!(100, indices.len());
+            assert!(indices.iter().all(|i| i % 16 == nonce));
+        }
     }
 }
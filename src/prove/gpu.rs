@@ -0,0 +1,430 @@
+//! Proving with the AES encryption of the MSB check offloaded to a GPU (via [wgpu]).
+//!
+//! The GPU encrypts every label with the ciphers of all nonce groups and
+//! marks the nonces for which the label passes the MSB part of the difficulty.
+//! Checking the LSB and collecting the indices stays on the CPU, in the same
+//! order as in [Prover8_56], so both provers give the same results.
+
+use std::ops::Range;
+use std::sync::mpsc;
+
+use wgpu::util::DeviceExt;
+
+use super::{
+    calc_nonce, CheckLabel, PassDiagnostics, Prover, Prover8_56, ProvingParams, AES_BATCH,
+    BLOCK_SIZE, CHUNK_SIZE, LABEL_SIZE,
+};
+use crate::{pow, verify_core};
+
+/// Must match `@workgroup_size` in the shader.
+const WORKGROUP_SIZE: usize = 64;
+
+#[derive(thiserror::Error, Debug)]
+pub enum GpuError {
+    #[error("no GPU adapter found")]
+    NoAdapter,
+    #[error("requesting GPU device: {0}")]
+    RequestDevice(#[from] wgpu::RequestDeviceError),
+    #[error("reading results from GPU: {0}")]
+    Mapping(#[from] wgpu::BufferAsyncError),
+    #[error("GPU device lost")]
+    DeviceLost,
+}
+
+#[derive(Debug)]
+pub struct GpuProver {
+    cpu: Prover8_56,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    sbox: wgpu::Buffer,
+    round_keys: wgpu::Buffer,
+    // The maximal number of labels encrypted in a single dispatch.
+    max_labels: usize,
+}
+
+impl GpuProver {
+    /// Creates the prover on the first high-performance GPU adapter.
+    ///
+    /// Fails with [GpuError::NoAdapter] if there is no GPU available.
+    pub fn new<P: pow::Prover + ?Sized>(
+        challenge: &[u8; 32],
+        nonces: Range<u32>,
+        params: ProvingParams,
+        pow_prover: &P,
+        miner_id: &[u8; 32],
+    ) -> eyre::Result<Self> {
+        Self::on_adapter(challenge, nonces, params, pow_prover, miner_id, false)
+    }
+
+    /// Creates the prover on the first adapter, or on a software one (i.e. lavapipe or WARP)
+    /// with `force_fallback_adapter`.
+    fn on_adapter<P: pow::Prover + ?Sized>(
+        challenge: &[u8; 32],
+        nonces: Range<u32>,
+        params: ProvingParams,
+        pow_prover: &P,
+        miner_id: &[u8; 32],
+        force_fallback_adapter: bool,
+    ) -> eyre::Result<Self> {
+        let cpu = Prover8_56::new(challenge, nonces, params, pow_prover, miner_id)?;
+        let round_keys = cpu
+            .ciphers
+            .iter()
+            .flat_map(|c| expand_key(&verify_core::cipher_key(challenge, c.nonce_group, c.pow)))
+            .collect::<Vec<_>>();
+        Ok(Self::with_round_keys(
+            cpu,
+            &round_keys,
+            force_fallback_adapter,
+        )?)
+    }
+
+    fn with_round_keys(
+        cpu: Prover8_56,
+        round_keys: &[u32],
+        force_fallback_adapter: bool,
+    ) -> Result<Self, GpuError> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter,
+            ..Default::default()
+        }))
+        .ok_or(GpuError::NoAdapter)?;
+        log::info!("proving on GPU: {:?}", adapter.get_info());
+
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("post prover"),
+                required_features: wgpu::Features::empty(),
+                required_limits: adapter.limits(),
+            },
+            None,
+        ))?;
+
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("aes"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("gpu.wgsl").into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("aes"),
+            layout: None,
+            module: &module,
+            entry_point: "main",
+        });
+        let sbox = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("sbox"),
+            contents: bytemuck::cast_slice(&SBOX.map(u32::from)),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let round_keys = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("round keys"),
+            contents: bytemuck::cast_slice(round_keys),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        let limits = device.limits();
+        let max_binding = limits.max_storage_buffer_binding_size as usize;
+        let max_labels = (limits.max_compute_workgroups_per_dimension as usize * WORKGROUP_SIZE)
+            .min(max_binding / LABEL_SIZE)
+            .min(max_binding / (4 * cpu.num_nonce_groups()));
+
+        Ok(Self {
+            cpu,
+            device,
+            queue,
+            pipeline,
+            sbox,
+            round_keys,
+            max_labels: max_labels / AES_BATCH * AES_BATCH,
+        })
+    }
+
+    /// Encrypts the labels on the GPU. Returns the mask of nonces passing
+    /// the MSB check for each nonce group and label (`[group][label]`):
+    /// - bit `i` is set if the label is a candidate for the nonce at offset `i` in the group,
+    /// - bit `16 + i` is set if the LSB must be checked for it.
+    fn msb_masks(&self, labels: &[u8]) -> Result<Vec<u32>, GpuError> {
+        let num_labels = labels.len() / LABEL_SIZE;
+        let num_groups = self.cpu.num_nonce_groups();
+        let size = (num_labels * num_groups * std::mem::size_of::<u32>()) as u64;
+
+        let params = [
+            num_labels as u32,
+            num_groups as u32,
+            // The CPU prover is created without per-nonce difficulties.
+            Prover8_56::split_difficulty(self.cpu.difficulty()).0 as u32,
+            0,
+        ];
+        let params = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("params"),
+                contents: bytemuck::cast_slice(&params),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+        let labels = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("labels"),
+                contents: &labels[..num_labels * LABEL_SIZE],
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+        let masks = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("masks"),
+            size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let staging = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("masks staging"),
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("aes"),
+            layout: &self.pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: self.sbox.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: self.round_keys.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: labels.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: masks.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("aes") });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("aes"),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(
+                num_labels.div_ceil(WORKGROUP_SIZE) as u32,
+                num_groups as u32,
+                1,
+            );
+        }
+        encoder.copy_buffer_to_buffer(&masks, 0, &staging, 0, size);
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = staging.slice(..);
+        let (tx, rx) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = tx.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        rx.recv().map_err(|_| GpuError::DeviceLost)??;
+
+        let masks = slice
+            .get_mapped_range()
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        staging.unmap();
+        Ok(masks)
+    }
+}
+
+impl CheckLabel for GpuProver {
+    fn difficulty(&self) -> u64 {
+        self.cpu.difficulty()
+    }
+
+    fn check_label(&self, nonce: u32, label: &[u8; LABEL_SIZE]) -> bool {
+        self.cpu.check_label(nonce, label)
+    }
+}
+
+impl Prover for GpuProver {
+    fn get_pow(&self, nonce: u32) -> Option<u64> {
+        self.cpu.get_pow(nonce)
+    }
+
+    fn prove<F>(&self, batch: &[u8], mut index: u64, mut consume: F) -> Option<(u32, Vec<u64>)>
+    where
+        F: FnMut(u32, u64) -> Option<Vec<u64>>,
+    {
+        // The partial chunk at the end (if any) is checked on CPU.
+        let (whole, tail) = batch.split_at(batch.len() / CHUNK_SIZE * CHUNK_SIZE);
+        let mut diagnostics = PassDiagnostics::default();
+        let step = self.max_labels * LABEL_SIZE;
+
+        for (i, labels) in whole.chunks(step).enumerate() {
+            let masks = match self.msb_masks(labels) {
+                Ok(masks) => masks,
+                Err(err) => {
+                    log::error!("encrypting labels on GPU failed, falling back to CPU: {err}");
+                    return self.cpu.prove_with_diagnostics(
+                        &batch[i * step..],
+                        index,
+                        consume,
+                        &mut diagnostics,
+                    );
+                }
+            };
+            let num_labels = labels.len() / LABEL_SIZE;
+
+            for (chunk_index, chunk) in labels.chunks_exact(CHUNK_SIZE).enumerate() {
+                for (group, cipher) in self.cpu.ciphers.iter().enumerate() {
+                    let masks = &masks[group * num_labels + chunk_index * AES_BATCH..][..AES_BATCH];
+                    for (label, &mask) in masks.iter().enumerate() {
+                        for n in (0..BLOCK_SIZE).filter(|&n| mask & (1 << n) != 0) {
+                            // the offset in the encrypted chunk
+                            let offset = label * BLOCK_SIZE + n;
+                            let nonce =
+                                calc_nonce(cipher.nonce_group, Prover8_56::NONCES_PER_AES, offset);
+                            // The first and last nonce groups might be only partially in the range.
+                            if !self.cpu.nonces.contains(&nonce) {
+                                continue;
+                            }
+                            let result = if mask & (1 << (16 + n)) != 0 {
+                                self.cpu.check_lsb(
+                                    &chunk[label * LABEL_SIZE..][..LABEL_SIZE],
+                                    nonce,
+                                    offset,
+                                    index,
+                                    &mut consume,
+                                    &mut diagnostics,
+                                )
+                            } else {
+                                consume(nonce, index + label as u64).map(|indices| (nonce, indices))
+                            };
+                            if result.is_some() {
+                                return result;
+                            }
+                        }
+                    }
+                }
+                index += AES_BATCH as u64;
+            }
+        }
+        self.cpu
+            .prove_with_diagnostics(tail, index, consume, &mut diagnostics)
+    }
+}
+
+/// AES-128 key expansion. Returns the 11 round keys as 44 words,
+/// each packing 4 bytes of the key in little-endian order (as read by the shader).
+fn expand_key(key: &[u8; 16]) -> [u32; 44] {
+    const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+    let mut words = [[0u8; 4]; 44];
+    for (word, bytes) in words.iter_mut().zip(key.chunks_exact(4)) {
+        word.copy_from_slice(bytes);
+    }
+    for i in 4..44 {
+        let mut temp = words[i - 1];
+        if i % 4 == 0 {
+            temp.rotate_left(1);
+            temp = temp.map(|b| SBOX[b as usize]);
+            temp[0] ^= RCON[i / 4 - 1];
+        }
+        let prev = words[i - 4];
+        words[i] = std::array::from_fn(|j| prev[j] ^ temp[j]);
+    }
+    words.map(u32::from_le_bytes)
+}
+
+#[rustfmt::skip]
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, RngCore};
+
+    use super::*;
+    use crate::difficulty::proving_difficulty;
+
+    #[test]
+    fn key_expansion() {
+        // FIPS-197, Appendix A.1
+        let key = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ];
+        let words = expand_key(&key);
+        assert_eq!(u32::from_le_bytes([0x2b, 0x7e, 0x15, 0x16]), words[0]);
+        assert_eq!(u32::from_le_bytes([0xa0, 0xfa, 0xfe, 0x17]), words[4]);
+        assert_eq!(u32::from_le_bytes([0xb6, 0x63, 0x0c, 0xa6]), words[43]);
+    }
+
+    #[test]
+    fn gpu_matches_cpu_prover() {
+        let challenge = b"hello world, challenge me!!!!!!!";
+        let mut data = vec![0u8; 4096 * LABEL_SIZE + 7];
+        thread_rng().fill_bytes(&mut data);
+        let params = ProvingParams {
+            difficulty: proving_difficulty(1024, 4096).unwrap(),
+            pow_difficulty: [0xFF; 32],
+        };
+        let mut pow_prover = pow::MockProver::new();
+        pow_prover
+            .expect_prove()
+            .returning(|group, _, _, _| Ok(group as u64));
+
+        // A software adapter, so that the shader is checked without a GPU.
+        // Machines without one (i.e. no lavapipe or WARP installed) skip the test.
+        let gpu = match GpuProver::on_adapter(challenge, 5..40, params, &pow_prover, &[0; 32], true)
+        {
+            Err(err) if matches!(err.downcast_ref::<GpuError>(), Some(GpuError::NoAdapter)) => {
+                eprintln!("skipping the test, no software GPU adapter found");
+                return;
+            }
+            result => result.unwrap(),
+        };
+        let cpu = Prover8_56::new(challenge, 5..40, params, &pow_prover, &[0; 32]).unwrap();
+
+        let mut expected = Vec::new();
+        cpu.prove(&data, 0, |nonce, index| {
+            expected.push((nonce, index));
+            None
+        });
+        assert!(!expected.is_empty());
+
+        let mut found = Vec::new();
+        gpu.prove(&data, 0, |nonce, index| {
+            found.push((nonce, index));
+            None
+        });
+        assert_eq!(expected, found);
+    }
+}
@@ -0,0 +1,87 @@
+// AES-128 encryption of labels with the ciphers of the nonce groups.
+//
+// One invocation encrypts one label with the cipher of one nonce group
+// and reports which of the 16 output bytes (one per nonce) pass the MSB
+// check of the difficulty:
+// - bit `i` is set if byte `i` <= difficulty MSB,
+// - bit `16 + i` is set if byte `i` == difficulty MSB (the LSB must be checked).
+
+struct Params {
+    num_labels: u32,
+    num_groups: u32,
+    difficulty_msb: u32,
+    _padding: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> sbox: array<u32, 256>;
+// 44 words (11 round keys) per nonce group, bytes packed little-endian.
+@group(0) @binding(2) var<storage, read> round_keys: array<u32>;
+@group(0) @binding(3) var<storage, read> labels: array<u32>;
+// [group][label]
+@group(0) @binding(4) var<storage, read_write> masks: array<u32>;
+
+fn xtime(b: u32) -> u32 {
+    return ((b << 1u) ^ (((b >> 7u) & 1u) * 0x1bu)) & 0xffu;
+}
+
+fn key_byte(word: u32, i: u32) -> u32 {
+    return (round_keys[word + i / 4u] >> ((i % 4u) * 8u)) & 0xffu;
+}
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let label = id.x;
+    let nonce_group = id.y;
+    if (label >= params.num_labels || nonce_group >= params.num_groups) {
+        return;
+    }
+    let keys = nonce_group * 44u;
+
+    // The state is column-major: byte `i` is in row `i % 4` and column `i / 4`.
+    var state: array<u32, 16>;
+    for (var i = 0u; i < 16u; i = i + 1u) {
+        let b = (labels[label * 4u + i / 4u] >> ((i % 4u) * 8u)) & 0xffu;
+        state[i] = b ^ key_byte(keys, i);
+    }
+
+    for (var r = 1u; r <= 10u; r = r + 1u) {
+        // SubBytes and ShiftRows
+        var shifted: array<u32, 16>;
+        for (var i = 0u; i < 16u; i = i + 1u) {
+            let row = i % 4u;
+            let column = i / 4u;
+            shifted[i] = sbox[state[((column + row) % 4u) * 4u + row]];
+        }
+        // MixColumns (skipped in the last round)
+        if (r < 10u) {
+            for (var column = 0u; column < 4u; column = column + 1u) {
+                let c = column * 4u;
+                let a0 = shifted[c];
+                let a1 = shifted[c + 1u];
+                let a2 = shifted[c + 2u];
+                let a3 = shifted[c + 3u];
+                let sum = a0 ^ a1 ^ a2 ^ a3;
+                shifted[c] = a0 ^ sum ^ xtime(a0 ^ a1);
+                shifted[c + 1u] = a1 ^ sum ^ xtime(a1 ^ a2);
+                shifted[c + 2u] = a2 ^ sum ^ xtime(a2 ^ a3);
+                shifted[c + 3u] = a3 ^ sum ^ xtime(a3 ^ a0);
+            }
+        }
+        // AddRoundKey
+        for (var i = 0u; i < 16u; i = i + 1u) {
+            state[i] = shifted[i] ^ key_byte(keys + r * 4u, i);
+        }
+    }
+
+    var mask = 0u;
+    for (var i = 0u; i < 16u; i = i + 1u) {
+        if (state[i] <= params.difficulty_msb) {
+            mask = mask | (1u << i);
+        }
+        if (state[i] == params.difficulty_msb) {
+            mask = mask | (1u << (16u + i));
+        }
+    }
+    masks[nonce_group * params.num_labels + label] = mask;
+}
@@ -0,0 +1,319 @@
+//! An append-only log of generated proofs, for debugging and replaying them.
+//!
+//! Every record is `challenge (32 bytes) || timestamp (u64 LE, seconds since the UNIX epoch)
+//! || length (u32 LE) || proof` with the proof encoded with [Proof::encode].
+//! Once the log file would grow beyond the size limit, it's rotated:
+//! `{path}` is renamed to `{path}.1`, `{path}.1` to `{path}.2` and so on,
+//! dropping the oldest file past the limit of files.
+//!
+//! It's a standalone utility: proving never writes to it. It's up to the caller
+//! to [append](ProofLog::append) the proofs it generates.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{DecodeError, Proof};
+
+/// The size of a record without the proof.
+const RECORD_HEADER_SIZE: u64 = 32 + 8 + 4;
+
+/// The largest logged proof. An encoded proof takes at most `12 + 8 * K2` bytes
+/// (with 64-bit indices), so it's far beyond any real K2. A record claiming
+/// a bigger proof is corrupt and is not read (nor allocated for).
+const MAX_PROOF_SIZE: u32 = 1 << 20;
+
+/// A proof read from a [ProofLog].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggedProof {
+    pub challenge: [u8; 32],
+    /// When the proof was logged (with a precision of seconds).
+    pub timestamp: SystemTime,
+    /// The proof encoded with [Proof::encode].
+    pub encoded: Vec<u8>,
+}
+
+impl LoggedProof {
+    /// Decode the proof, see [Proof::decode].
+    pub fn proof(&self, num_labels: u64, k2: u32) -> Result<Proof<'static>, DecodeError> {
+        Proof::decode(&self.encoded, num_labels, k2)
+    }
+}
+
+/// Appends proofs to a log file, rotating it by size.
+#[derive(Debug)]
+pub struct ProofLog {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl ProofLog {
+    /// Open (or create) the log at `path`, rotated once it would grow
+    /// beyond `max_size` bytes. Keeps up to `max_files` files (including the current one).
+    ///
+    /// A single record bigger than `max_size` still gets a file for itself.
+    pub fn open(path: impl Into<PathBuf>, max_size: u64, max_files: usize) -> io::Result<Self> {
+        if max_files == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "at least one log file must be kept",
+            ));
+        }
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    /// Append the proof generated for `challenge`, timestamped with the current time.
+    pub fn append(&mut self, challenge: &[u8; 32], proof: &Proof) -> io::Result<()> {
+        let encoded = proof.encode();
+        let len = u32::try_from(encoded.len())
+            .ok()
+            .filter(|&len| len <= MAX_PROOF_SIZE)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "proof too large"))?;
+        let record_size = RECORD_HEADER_SIZE + len as u64;
+        if self.size > 0 && self.size + record_size > self.max_size {
+            self.rotate()?;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut record = Vec::with_capacity(record_size as usize);
+        record.extend_from_slice(challenge);
+        record.extend_from_slice(&timestamp.to_le_bytes());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&encoded);
+        // A single write, so that a crash leaves at most one partial record at the end.
+        self.file.write_all(&record)?;
+        self.file.flush()?;
+        self.size += record_size;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let oldest = rotated_path(&self.path, self.max_files - 1);
+        if self.max_files > 1 {
+            match fs::remove_file(&oldest) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+            for i in (1..self.max_files - 1).rev() {
+                let from = rotated_path(&self.path, i);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, i + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Iterate the proofs logged at `path`, from the oldest (in the rotated files) to the newest.
+    ///
+    /// A partial record at the end of a file (i.e. after a crash) gives an error.
+    pub fn read(path: &Path) -> io::Result<LogReader> {
+        let mut files = VecDeque::new();
+        for rotated in (1..).map(|i| rotated_path(path, i)) {
+            if !rotated.exists() {
+                break;
+            }
+            files.push_front(rotated);
+        }
+        if !path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no proof log at {}", path.display()),
+            ));
+        }
+        files.push_back(path.to_path_buf());
+        Ok(LogReader {
+            files,
+            current: None,
+        })
+    }
+}
+
+/// `{path}.{i}`
+fn rotated_path(path: &Path, i: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{i}"));
+    rotated.into()
+}
+
+/// Iterates the proofs of a log, see [ProofLog::read].
+#[derive(Debug)]
+pub struct LogReader {
+    files: VecDeque<PathBuf>,
+    current: Option<BufReader<File>>,
+}
+
+impl LogReader {
+    fn read_record(reader: &mut impl Read) -> io::Result<Option<LoggedProof>> {
+        let mut challenge = [0u8; 32];
+        // A clean end of the file is only allowed between records.
+        let mut read = 0;
+        while read < challenge.len() {
+            match reader.read(&mut challenge[read..])? {
+                0 if read == 0 => return Ok(None),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => read += n,
+            }
+        }
+        let mut word = [0u8; 8];
+        reader.read_exact(&mut word)?;
+        let timestamp = UNIX_EPOCH + Duration::from_secs(u64::from_le_bytes(word));
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len);
+        if len > MAX_PROOF_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("logged proof too large: {len} bytes (max {MAX_PROOF_SIZE})"),
+            ));
+        }
+        let mut encoded = vec![0u8; len as usize];
+        reader.read_exact(&mut encoded)?;
+        Ok(Some(LoggedProof {
+            challenge,
+            timestamp,
+            encoded,
+        }))
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = io::Result<LoggedProof>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.current.is_none() {
+                let path = self.files.pop_front()?;
+                match File::open(path) {
+                    Ok(file) => self.current = Some(BufReader::new(file)),
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+            match Self::read_record(self.current.as_mut().unwrap()) {
+                Ok(None) => self.current = None,
+                Ok(Some(proof)) => return Some(Ok(proof)),
+                Err(err) => {
+                    // Skip the rest of a corrupt file.
+                    self.current = None;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proofs(log: &Path) -> Vec<(u8, u32)> {
+        ProofLog::read(log)
+            .unwrap()
+            .map(|logged| {
+                let logged = logged.unwrap();
+                (logged.challenge[0], logged.proof(16, 4).unwrap().nonce)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn appending_and_reading() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("proofs.log");
+        let mut log = ProofLog::open(&path, 1024, 3).unwrap();
+        let proof = Proof::new(7, &[1, 2, 3, 4], 16, 77);
+        log.append(&[1; 32], &proof).unwrap();
+        log.append(&[2; 32], &proof).unwrap();
+
+        let logged = ProofLog::read(&path)
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(2, logged.len());
+        assert_eq!([2; 32], logged[1].challenge);
+        assert_eq!(proof, logged[1].proof(16, 4).unwrap());
+        assert!(logged[0].timestamp <= logged[1].timestamp);
+
+        // Reopened logs are appended to.
+        drop(log);
+        let mut log = ProofLog::open(&path, 1024, 3).unwrap();
+        log.append(&[3; 32], &proof).unwrap();
+        assert_eq!(vec![(1, 7), (2, 7), (3, 7)], proofs(&path));
+    }
+
+    #[test]
+    fn rotating() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("proofs.log");
+        // 4 indices of 5 bits
+        let record = RECORD_HEADER_SIZE + 12 + 3;
+        let mut log = ProofLog::open(&path, 2 * record, 3).unwrap();
+        for i in 0..7 {
+            log.append(&[i; 32], &Proof::new(i as u32, &[1, 2, 3, 4], 16, 0))
+                .unwrap();
+        }
+        // 2 records per file, the oldest file dropped
+        assert_eq!(
+            2 * record,
+            fs::metadata(rotated_path(&path, 2)).unwrap().len()
+        );
+        assert!(!rotated_path(&path, 3).exists());
+        assert_eq!(vec![(2, 2), (3, 3), (4, 4), (5, 5), (6, 6)], proofs(&path));
+    }
+
+    #[test]
+    fn reading_record_with_corrupt_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("proofs.log");
+        let mut record = vec![1; 32 + 8];
+        record.extend_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, record).unwrap();
+
+        let mut reader = ProofLog::read(&path).unwrap();
+        let err = reader.next().unwrap().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn reading_partial_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("proofs.log");
+        let mut log = ProofLog::open(&path, 1024, 1).unwrap();
+        log.append(&[1; 32], &Proof::new(7, &[1, 2, 3, 4], 16, 0))
+            .unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0; 40]).unwrap();
+
+        let mut reader = ProofLog::read(&path).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+}
@@ -1,19 +1,243 @@
 use std::{
     fs::{DirEntry, File},
-    io::Read,
-    path::Path,
+    io::{self, Read, Seek},
+    num::NonZeroUsize,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
 };
 
 use eyre::Context;
 use itertools::Itertools;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 use regex::Regex;
 
+use crate::{
+    checksums::{self, ChecksumTable},
+    config::InitConfig,
+    initialize::{generate_label, LABEL_SIZE},
+    metadata::PostMetadata,
+    prove::CHUNK_SIZE,
+    verify_core::next_multiple_of,
+};
+
+/// The alignment of buffers, file offsets and sizes of direct IO reads.
+/// It's a multiple of the logical block size of both 512 B and 4 KiB sector devices.
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// The longest delay between retries of a failed read, see [ReaderConfig::retry_backoff].
+pub const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Batch {
     pub data: Vec<u8>,
     pub pos: u64,
 }
 
+/// Configuration of reading the POS data.
+#[derive(Debug, Clone, Copy)]
+pub struct ReaderConfig {
+    /// The size of a batch of data read at once.
+    ///
+    /// For proving, it must be a multiple of 128 bytes (see [ReaderConfig::validate]).
+    pub batch_size: usize,
+    /// How many times to retry a read that failed with a transient error
+    /// (i.e. a timeout on a network mount) before giving up.
+    pub max_retries: u32,
+    /// The delay before the first retry. It doubles with every subsequent retry,
+    /// up to [MAX_RETRY_BACKOFF].
+    pub retry_backoff: Duration,
+    /// The name of the POS data file with the given index.
+    /// Defaults to [default_file_name].
+    pub file_name: fn(usize) -> String,
+    /// The number of threads dedicated to reading the POS data, separately from
+    /// the threads searching it. The files are split between them, each thread
+    /// reads its files sequentially.
+    ///
+    /// By default (`None`) the searching threads read the data themselves,
+    /// one batch at a time, so no reading happens while all of them compute.
+    ///
+    /// Recommended settings for a data directory on a single device:
+    /// - HDD: `Some(1)`, a single sequential stream avoids seeking between files,
+    /// - SATA SSD: `Some(2)` to `Some(4)`,
+    /// - NVMe: `Some(4)` or more, as it needs deep queues to reach full throughput.
+    pub io_threads: Option<usize>,
+    /// Read the POS data with direct IO (`O_DIRECT`), bypassing the page cache,
+    /// so that a proving scan doesn't evict pages other processes need.
+    ///
+    /// Reads are done in chunks aligned to [DIRECT_IO_ALIGNMENT] (`batch_size` rounded up),
+    /// into an aligned buffer, so any `batch_size` works.
+    ///
+    /// Only supported on Linux. Falls back to buffered reads on other platforms and
+    /// on filesystems not supporting direct IO.
+    pub direct_io: bool,
+    /// The stripe width of a RAID array holding the POS data.
+    ///
+    /// The batch size is rounded up to a multiple of both the stripe width and
+    /// 128 bytes (see [ReaderConfig::effective_batch_size]). As every file is read
+    /// from its start, the batches are aligned to stripes as long as the files are.
+    /// It depends on the filesystem, but it holds for files created in sequence
+    /// if `max_file_size` is a multiple of the stripe width. Otherwise, a file
+    /// (and all of its batches) may start in the middle of a stripe.
+    pub stripe_width: Option<NonZeroUsize>,
+    /// Check every batch against the checksums in the sidecar file of its POS file
+    /// (see [checksums](crate::checksums)), failing with [ReaderError::BatchCorrupt].
+    ///
+    /// The batch size must be a multiple of the checksummed block size.
+    pub verify_checksums: bool,
+    /// The number of batch buffers kept for reuse while proving, instead of allocating
+    /// a new one for every batch. Reused buffers outlive passes, so long proving
+    /// sessions don't churn the allocator.
+    ///
+    /// It should be about the number of batches in flight: the proving threads plus
+    /// the batches queued by the `io_threads`. 0 (the default) disables the reuse.
+    pub buffer_pool_size: usize,
+    /// Fail the proving with [ReaderError::Modified] if any POS file changed
+    /// (its size or modification time) since the proving started.
+    ///
+    /// The files are checked after every pass, before accepting a proof found in it,
+    /// so a proof is never built from data modified mid-pass (i.e. by a re-initialization),
+    /// which would be invalid. It can't detect changes within the resolution of
+    /// the filesystem timestamps that keep the size. For a truly consistent view,
+    /// prove from a snapshot (i.e. a reflink copy or a filesystem snapshot).
+    pub detect_modifications: bool,
+}
+
+/// The name of the POS data files created by the initializer: `postdata_{index}.bin`.
+pub fn default_file_name(index: usize) -> String {
+    format!("postdata_{index}.bin")
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("batch size must be a positive multiple of {CHUNK_SIZE} bytes (got {0})")]
+pub struct InvalidBatchSize(pub usize);
+
+impl ReaderConfig {
+    /// The default configuration with the given batch size, validated with [ReaderConfig::validate].
+    pub fn with_batch_size(batch_size: usize) -> Result<Self, InvalidBatchSize> {
+        let cfg = Self {
+            batch_size,
+            ..Default::default()
+        };
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// The size of batches actually read: `batch_size` aligned to `stripe_width` (if set).
+    pub fn effective_batch_size(&self) -> usize {
+        match self.stripe_width {
+            Some(stripe) => {
+                let stripe = stripe.get();
+                let alignment = stripe / gcd(stripe, CHUNK_SIZE) * CHUNK_SIZE;
+                next_multiple_of(self.batch_size.max(1), alignment)
+            }
+            None => self.batch_size,
+        }
+    }
+
+    /// Checks that the configuration can be used for proving.
+    ///
+    /// The batch size must be a positive multiple of [CHUNK_SIZE] (8 labels, 128 bytes).
+    /// The prover encrypts labels in chunks of 8 and every batch is proven separately.
+    /// A batch size that is not a multiple of the label size would split labels
+    /// between batches, so they would never be checked and the indices of the following
+    /// ones would be miscalculated. A partial chunk at the end of every batch would
+    /// cost as much to encrypt as a full one, so other multiples of the label size
+    /// are rejected as well.
+    pub fn validate(&self) -> Result<(), InvalidBatchSize> {
+        if self.batch_size == 0 || self.batch_size % CHUNK_SIZE != 0 {
+            return Err(InvalidBatchSize(self.batch_size));
+        }
+        Ok(())
+    }
+}
+
+const fn gcd(mut a: usize, mut b: usize) -> usize {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+impl Default for ReaderConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 1024 * 1024,
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(100),
+            file_name: default_file_name,
+            io_threads: None,
+            direct_io: false,
+            stripe_width: None,
+            verify_checksums: false,
+            buffer_pool_size: 0,
+            detect_modifications: false,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReaderError {
+    #[error("reading POS data at position {pos}: {source}")]
+    Read { pos: u64, source: io::Error },
+    #[error("missing POS file {index}: {}", path.display())]
+    MissingFile { index: usize, path: PathBuf },
+    #[error("POS data corrupt in the block at position {pos}: checksum mismatch")]
+    BatchCorrupt { pos: u64 },
+    #[error("POS file {} was modified while proving", path.display())]
+    Modified { path: PathBuf },
+}
+
+/// Whether the IO error is likely to go away if the read is retried.
+///
+/// Network filesystems (NFS, SMB) report brief connectivity issues as timeouts
+/// or dropped connections. Errors like missing files or denied permissions are fatal.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+    )
+}
+
+/// Buffers of batches given back once consumed, see [ReaderConfig::buffer_pool_size].
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    size: usize,
+}
+
+impl BufferPool {
+    pub fn new(size: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::with_capacity(size)),
+            size,
+        }
+    }
+
+    /// An empty buffer for at least `capacity` bytes, reused if there is any.
+    pub fn get(&self, capacity: usize) -> Vec<u8> {
+        let mut buffer = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        buffer.clear();
+        buffer.reserve(capacity);
+        buffer
+    }
+
+    /// Give the buffer back for reuse. It's dropped if the pool is full.
+    pub fn put(&self, buffer: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.size {
+            buffers.push(buffer);
+        }
+    }
+}
+
 pub(crate) struct BatchingReader<T>
 where
     T: Read,
@@ -24,6 +248,10 @@ where
     batch_size: usize,
     total_size: u64,
     identifier: Option<String>,
+    max_retries: u32,
+    retry_backoff: Duration,
+    checksums: Option<ChecksumTable>,
+    buffers: Option<Arc<BufferPool>>,
 }
 
 impl<T: Read> BatchingReader<T> {
@@ -41,15 +269,45 @@ impl<T: Read> BatchingReader<T> {
             batch_size,
             total_size,
             identifier,
+            max_retries: 0,
+            retry_backoff: Duration::ZERO,
+            checksums: None,
+            buffers: None,
         }
     }
+
+    /// Read the batches into buffers taken from the pool.
+    pub fn with_buffers(mut self, buffers: Arc<BufferPool>) -> Self {
+        self.buffers = Some(buffers);
+        self
+    }
+
+    /// Check every batch against the checksums of the blocks of the file.
+    /// The batch size must be a multiple of the block size.
+    pub fn with_checksums(mut self, checksums: ChecksumTable) -> Self {
+        self.checksums = Some(checksums);
+        self
+    }
+
+    /// Retry reads failing with transient errors up to `max_retries` times,
+    /// waiting `backoff` before the first retry and doubling it after each one,
+    /// up to [MAX_RETRY_BACKOFF].
+    pub fn with_retries(mut self, max_retries: u32, backoff: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_backoff = backoff;
+        self
+    }
+}
+
+/// The delay before the next retry: twice the last one, capped at [MAX_RETRY_BACKOFF].
+fn next_backoff(backoff: Duration) -> Duration {
+    backoff.saturating_mul(2).min(MAX_RETRY_BACKOFF)
 }
 
 impl<T: Read> Iterator for BatchingReader<T> {
-    type Item = Batch;
+    type Item = Result<Batch, ReaderError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // FIXME(poszu) avoid reallocating the vector
         let pos_in_file = self.pos - self.starting_pos;
 
         if pos_in_file == 0 {
@@ -62,24 +320,53 @@ impl<T: Read> Iterator for BatchingReader<T> {
         }
         let remaining = self.total_size - pos_in_file;
         let batch_size = self.batch_size.min(remaining as usize);
-        let mut data = Vec::with_capacity(batch_size);
-        match self
-            .reader
-            .by_ref()
-            .take(batch_size as u64)
-            .read_to_end(&mut data)
-        {
-            Ok(0) => None,
-            Ok(n) => {
-                let batch = Batch {
-                    data,
-                    pos: self.pos,
-                };
-                self.pos += n as u64;
-                Some(batch)
+        let mut data = match &self.buffers {
+            Some(buffers) => buffers.get(batch_size),
+            None => Vec::with_capacity(batch_size),
+        };
+        let mut retries = 0;
+        let mut backoff = self.retry_backoff;
+        loop {
+            // On error, the bytes read so far are kept in `data`.
+            // A retry continues with the remaining part of the batch.
+            let missing = (batch_size - data.len()) as u64;
+            match self.reader.by_ref().take(missing).read_to_end(&mut data) {
+                Ok(_) => break,
+                Err(err) if retries < self.max_retries && is_transient(&err) => {
+                    retries += 1;
+                    log::warn!(
+                        "reading {} failed: {err}, retrying in {backoff:?} ({retries}/{})",
+                        self.identifier.as_deref().unwrap_or("POS data"),
+                        self.max_retries,
+                    );
+                    thread::sleep(backoff);
+                    backoff = next_backoff(backoff);
+                }
+                Err(err) => {
+                    let pos = self.pos + data.len() as u64;
+                    // Don't attempt to read anything more after a failure.
+                    self.pos = self.starting_pos + self.total_size;
+                    return Some(Err(ReaderError::Read { pos, source: err }));
+                }
             }
-            Err(_) => None,
         }
+
+        if data.is_empty() {
+            return None;
+        }
+        let n = data.len();
+        let batch = Batch {
+            data,
+            pos: self.pos,
+        };
+        self.pos += n as u64;
+        if let Some(checksums) = &self.checksums {
+            if let Err(offset) = checksums.check(pos_in_file, &batch.data) {
+                let pos = self.starting_pos + offset;
+                return Some(Err(ReaderError::BatchCorrupt { pos }));
+            }
+        }
+        Some(Ok(batch))
     }
 }
 
@@ -104,54 +391,588 @@ pub(crate) fn pos_files(datadir: &Path) -> eyre::Result<impl Iterator<Item = Dir
     Ok(files)
 }
 
+/// Split the POS data files in `datadir` into `shards` groups of roughly equal total size,
+/// i.e. to process them on separate machines.
+///
+/// Always returns `shards` groups, some may be empty if there are fewer files.
+/// The files in a group are in the order of their indices.
+pub fn shard_files(datadir: &Path, shards: usize) -> eyre::Result<Vec<Vec<PathBuf>>> {
+    eyre::ensure!(shards > 0, "number of shards must be > 0");
+    let mut files = pos_files(datadir)?
+        .enumerate()
+        .map(|(position, entry)| {
+            let size = entry
+                .metadata()
+                .wrap_err_with(|| format!("reading {}", entry.path().display()))?
+                .len();
+            Ok((position, entry.path(), size))
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+
+    // Assign the biggest files first, each one to the smallest shard so far.
+    files.sort_by_key(|(position, _, size)| (std::cmp::Reverse(*size), *position));
+    let mut sizes = vec![0u64; shards];
+    let mut assigned = vec![Vec::new(); shards];
+    for (position, path, size) in files {
+        let (shard, _) = sizes
+            .iter()
+            .enumerate()
+            .min_by_key(|(shard, size)| (**size, *shard))
+            .unwrap();
+        sizes[shard] += size;
+        assigned[shard].push((position, path));
+    }
+
+    Ok(assigned
+        .into_iter()
+        .map(|mut shard| {
+            shard.sort_by_key(|(position, _)| *position);
+            shard.into_iter().map(|(_, path)| path).collect()
+        })
+        .collect())
+}
+
+/// The POS files found for the metadata, see [scan_datadir].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataDirScan {
+    /// The number of POS files of complete data, see [PostMetadata::expected_file_count].
+    pub expected_files: usize,
+    /// The expected files that weren't found.
+    pub missing: Vec<usize>,
+    /// The files holding fewer bytes than expected.
+    pub incomplete: Vec<usize>,
+    /// The bytes held by the found files, up to the expected size of each.
+    pub total_bytes: u64,
+}
+
+impl DataDirScan {
+    /// Whether all POS data of the metadata is there,
+    /// see [PostMetadata::expected_total_bytes].
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty() && self.incomplete.is_empty()
+    }
+}
+
+/// Check which of the POS files expected from the metadata are in `datadirs`
+/// and whether they are complete. The files are found like for proving, see [find_file].
+///
+/// Only the sizes of the files are checked, not their contents.
+pub fn scan_datadir(
+    datadirs: &[&Path],
+    metadata: &PostMetadata,
+    cfg: &ReaderConfig,
+) -> eyre::Result<DataDirScan> {
+    let expected_files = metadata.expected_file_count();
+    let total_size = metadata.expected_total_bytes();
+    let mut scan = DataDirScan {
+        expected_files,
+        missing: Vec::new(),
+        incomplete: Vec::new(),
+        total_bytes: 0,
+    };
+    for index in 0..expected_files {
+        let pos = index as u64 * metadata.max_file_size;
+        let expected = metadata.max_file_size.min(total_size - pos);
+        let path = match find_file(datadirs, cfg, index) {
+            Ok(path) => path,
+            Err(ReaderError::MissingFile { .. }) => {
+                scan.missing.push(index);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let size = std::fs::metadata(&path)
+            .wrap_err_with(|| format!("reading metadata of {}", path.display()))?
+            .len();
+        if size < expected {
+            scan.incomplete.push(index);
+        }
+        scan.total_bytes += size.min(expected);
+    }
+    Ok(scan)
+}
+
+/// The POS data doesn't belong to the identity in the metadata, see [verify_identity].
+#[derive(Debug, thiserror::Error)]
+#[error("label {index} doesn't match the identity in the metadata")]
+pub struct IdentityMismatch {
+    pub index: u64,
+}
+
+/// Check that the POS data in `datadirs` was initialized for the identity
+/// (`node_id` and `commitment_atx_id`) in the metadata, by recomputing
+/// `samples` labels spread evenly over the data and comparing them with the files.
+/// The files are found like for proving, see [find_file].
+///
+/// Meant to run before proving, so that data swapped between identities
+/// is caught before a whole proving run is wasted. Fails with [IdentityMismatch].
+pub fn verify_identity(
+    datadirs: &[&Path],
+    metadata: &PostMetadata,
+    cfg: &InitConfig,
+    reader_cfg: &ReaderConfig,
+    samples: usize,
+) -> eyre::Result<()> {
+    let total_labels = metadata.total_labels();
+    let samples = (samples as u64).clamp(1, total_labels.max(1));
+    let commitment = metadata.commitment();
+    for sample in 0..samples {
+        let index = (sample as u128 * total_labels as u128 / samples as u128) as u64;
+        let (file_index, offset) = metadata
+            .label_location(index)
+            .ok_or_else(|| eyre::eyre!("no label {index} in the metadata"))?;
+        let path = find_file(datadirs, reader_cfg, file_index)?;
+        let mut label = [0u8; LABEL_SIZE];
+        File::open(&path)
+            .and_then(|mut file| {
+                file.seek(io::SeekFrom::Start(offset))?;
+                file.read_exact(&mut label)
+            })
+            .wrap_err_with(|| format!("reading label at {offset} in {}", path.display()))?;
+        if label != generate_label(&commitment, cfg.scrypt, index) {
+            return Err(IdentityMismatch { index }.into());
+        }
+    }
+    Ok(())
+}
+
+/// A range of bytes of the POS data that differs between two data directories, see [diff].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiffRegion {
+    /// The index of the POS file holding the region.
+    pub file: usize,
+    /// The global position of the first differing byte (as in [Batch::pos]).
+    pub pos: u64,
+    pub len: u64,
+}
+
+impl DiffRegion {
+    /// The indices of the labels overlapping the region.
+    pub fn labels(&self) -> Range<u64> {
+        let end = self.pos + self.len;
+        self.pos / LABEL_SIZE as u64..end.div_ceil(LABEL_SIZE as u64)
+    }
+}
+
+/// Compare two copies of the POS data of `metadata`,
+/// i.e. to check that a re-initialization reproduced identical labels.
+/// The files of either copy might be split between many directories, see [find_file].
+///
+/// Returns the differing byte ranges, in order. Adjacent differing bytes are merged
+/// into a single region. The files are compared up to the size expected from
+/// the metadata, a file shorter than that differs in its missing part.
+/// The files are read in batches of the [effective](ReaderConfig::effective_batch_size)
+/// batch size of `cfg`, in parallel (on the rayon thread pool).
+pub fn diff(
+    datadirs_a: &[&Path],
+    datadirs_b: &[&Path],
+    metadata: &PostMetadata,
+    cfg: &ReaderConfig,
+) -> eyre::Result<Vec<DiffRegion>> {
+    let batch_size = cfg.effective_batch_size();
+    let num_files = metadata.expected_file_count();
+    let total_size = metadata.expected_total_bytes();
+    let regions = (0..num_files)
+        .into_par_iter()
+        .map(|index| {
+            let pos = index as u64 * metadata.max_file_size;
+            let size = metadata.max_file_size.min(total_size - pos);
+            let open = |datadirs: &[&Path]| -> eyre::Result<_> {
+                let path = find_file(datadirs, cfg, index)?;
+                let file =
+                    File::open(&path).wrap_err_with(|| format!("opening {}", path.display()))?;
+                let name = path.display().to_string();
+                Ok(BatchingReader::new(file, pos, batch_size, size, Some(name)))
+            };
+            diff_batches(index, open(datadirs_a)?, open(datadirs_b)?)
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+    Ok(regions.into_iter().flatten().collect())
+}
+
+/// The regions differing between the batches of the same file, see [diff].
+fn diff_batches<A, B>(file: usize, mut a: A, mut b: B) -> eyre::Result<Vec<DiffRegion>>
+where
+    A: Iterator<Item = Result<Batch, ReaderError>>,
+    B: Iterator<Item = Result<Batch, ReaderError>>,
+{
+    let mut regions = Vec::<DiffRegion>::new();
+    let mut push = |pos: u64, len: u64| match regions.last_mut() {
+        Some(last) if last.pos + last.len == pos => last.len += len,
+        _ => regions.push(DiffRegion { file, pos, len }),
+    };
+    loop {
+        match (a.next().transpose()?, b.next().transpose()?) {
+            (None, None) => break,
+            // The batches are at the same positions, until the shorter file ends.
+            (Some(batch_a), Some(batch_b)) => {
+                let (a, b) = (&batch_a.data, &batch_b.data);
+                let common = a.len().min(b.len());
+                let mut offset = 0;
+                while offset < common {
+                    if a[offset] == b[offset] {
+                        offset += 1;
+                        continue;
+                    }
+                    let start = offset;
+                    while offset < common && a[offset] != b[offset] {
+                        offset += 1;
+                    }
+                    push(batch_a.pos + start as u64, (offset - start) as u64);
+                }
+                let longer = a.len().max(b.len());
+                if longer > common {
+                    push(batch_a.pos + common as u64, (longer - common) as u64);
+                }
+            }
+            (Some(batch), None) | (None, Some(batch)) => push(batch.pos, batch.data.len() as u64),
+        }
+    }
+    Ok(regions)
+}
+
+/// Hint the kernel that the file will be read sequentially, so it reads ahead more aggressively.
+///
+/// Aggressive read-ahead matters mostly for spinning disks, where seeks are expensive.
+/// On NVMe drives little difference is expected. Failures are ignored,
+/// as it's only an optimization. No-op on platforms without such hints.
+fn advise_sequential(_file: &File) {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    {
+        use std::os::fd::AsRawFd;
+        // SAFETY: the descriptor is valid for the lifetime of `file`.
+        let ret =
+            unsafe { libc::posix_fadvise(_file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL) };
+        if ret != 0 {
+            log::debug!("posix_fadvise(POSIX_FADV_SEQUENTIAL) failed: {ret}");
+        }
+    }
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    {
+        use std::os::fd::AsRawFd;
+        // SAFETY: the descriptor is valid for the lifetime of `file`.
+        let ret = unsafe { libc::fcntl(_file.as_raw_fd(), libc::F_RDAHEAD, 1 as libc::c_int) };
+        if ret == -1 {
+            log::debug!("fcntl(F_RDAHEAD) failed: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+/// Open the file for direct IO (`O_DIRECT`).
+///
+/// Returns `None` if the platform or the filesystem doesn't support it.
+fn open_direct(path: &Path) -> Option<File> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        match std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)
+        {
+            Ok(file) => Some(file),
+            Err(err) => {
+                log::warn!(
+                    "opening {} with O_DIRECT failed: {err}, falling back to buffered reads",
+                    path.display()
+                );
+                None
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        log::warn!(
+            "direct IO is not supported on this platform, reading {} buffered",
+            path.display()
+        );
+        None
+    }
+}
+
+/// Reads from the inner reader only in chunks of aligned size into an aligned buffer,
+/// as required by direct IO. Reading sequentially from the start of a file
+/// keeps the file offsets aligned too.
+///
+/// Serves reads of any size from the buffer.
+struct AlignedReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    /// The start of the aligned part of `buf`.
+    offset: usize,
+    capacity: usize,
+    /// The buffered data not consumed yet, relative to `offset`.
+    start: usize,
+    end: usize,
+    eof: bool,
+}
+
+impl<R: Read> AlignedReader<R> {
+    fn new(inner: R, size: usize) -> Self {
+        let capacity = next_multiple_of(size.max(1), DIRECT_IO_ALIGNMENT);
+        // The vector is never resized, so the aligned part doesn't move.
+        let buf = vec![0u8; capacity + DIRECT_IO_ALIGNMENT];
+        let offset = buf.as_ptr().align_offset(DIRECT_IO_ALIGNMENT);
+        assert!(offset < DIRECT_IO_ALIGNMENT, "can't align the buffer");
+        Self {
+            inner,
+            buf,
+            offset,
+            capacity,
+            start: 0,
+            end: 0,
+            eof: false,
+        }
+    }
+}
+
+impl<R: Read> Read for AlignedReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.start == self.end {
+            if self.eof {
+                return Ok(0);
+            }
+            let n = self
+                .inner
+                .read(&mut self.buf[self.offset..self.offset + self.capacity])?;
+            // A read of unaligned size happens only at the end of the file.
+            // Reading further would start at an unaligned offset.
+            if n == 0 || n % DIRECT_IO_ALIGNMENT != 0 {
+                self.eof = true;
+            }
+            self.start = 0;
+            self.end = n;
+        }
+        let n = out.len().min(self.end - self.start);
+        let start = self.offset + self.start;
+        out[..n].copy_from_slice(&self.buf[start..start + n]);
+        self.start += n;
+        Ok(n)
+    }
+}
+
+/// Find the POS data file with the given index in the first of `datadirs` holding it.
+///
+/// Fails with [ReaderError::MissingFile] (pointing to the first directory)
+/// if none of them holds it.
+pub(crate) fn find_file(
+    datadirs: &[&Path],
+    cfg: &ReaderConfig,
+    index: usize,
+) -> Result<PathBuf, ReaderError> {
+    let name = (cfg.file_name)(index);
+    datadirs
+        .iter()
+        .map(|dir| dir.join(&name))
+        .find(|path| path.exists())
+        .ok_or_else(|| ReaderError::MissingFile {
+            index,
+            path: datadirs
+                .first()
+                .map(|dir| dir.join(&name))
+                .unwrap_or_default(),
+        })
+}
+
+/// The sizes and modification times of the POS files,
+/// to detect them being modified, see [ReaderConfig::detect_modifications].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DataSnapshot {
+    files: Vec<(PathBuf, u64, Option<SystemTime>)>,
+}
+
+impl DataSnapshot {
+    /// Record the state of `num_files` POS files in `datadirs`, see [find_file].
+    pub fn take(datadirs: &[&Path], cfg: &ReaderConfig, num_files: usize) -> eyre::Result<Self> {
+        let files = (0..num_files)
+            .map(|index| {
+                let path = find_file(datadirs, cfg, index)?;
+                let (size, modified) = file_state(&path)
+                    .wrap_err_with(|| format!("reading metadata of {}", path.display()))?;
+                Ok((path, size, modified))
+            })
+            .collect::<eyre::Result<_>>()?;
+        Ok(Self { files })
+    }
+
+    /// Check that none of the files changed since the snapshot was taken.
+    pub fn check(&self) -> Result<(), ReaderError> {
+        for (path, size, modified) in &self.files {
+            match file_state(path) {
+                Ok((s, m)) if s == *size && m == *modified => {}
+                _ => return Err(ReaderError::Modified { path: path.clone() }),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn file_state(path: &Path) -> io::Result<(u64, Option<SystemTime>)> {
+    let metadata = std::fs::metadata(path)?;
+    // Not available on all platforms, the size is still checked.
+    Ok((metadata.len(), metadata.modified().ok()))
+}
+
+/// Read `num_files` POS data files from `datadirs`.
+///
+/// The files are named according to [ReaderConfig::file_name]. They might be split
+/// between the directories (i.e. on separate mount points), see [find_file].
+/// They are read in the order of their indices and the position of a batch is global,
+/// whatever directory its file is in.
+///
+/// The batches are read into buffers from `buffers`, if given. The consumer should
+/// [put](BufferPool::put) them back.
 pub(crate) fn read_data(
-    datadir: &Path,
-    batch_size: usize,
+    datadirs: &[&Path],
+    cfg: &ReaderConfig,
     file_size: u64,
-) -> eyre::Result<impl Iterator<Item = Batch>> {
-    let mut readers = Vec::<BatchingReader<File>>::new();
-    let mut files = pos_files(datadir)?.enumerate().peekable();
-
-    while let Some((id, entry)) = files.next() {
-        let pos = id as u64 * file_size;
-        let path = entry.path();
-        let file = File::open(&path)?;
+    num_files: usize,
+    buffers: Option<Arc<BufferPool>>,
+) -> eyre::Result<Box<dyn Iterator<Item = Result<Batch, ReaderError>> + Send>> {
+    let batch_size = cfg.effective_batch_size();
+    let mut readers = Vec::<BatchingReader<Box<dyn Read + Send>>>::new();
+
+    for index in 0..num_files {
+        let name = (cfg.file_name)(index);
+        let path = find_file(datadirs, cfg, index)?;
+        let file = File::open(&path).wrap_err_with(|| format!("opening {}", path.display()))?;
         let pos_file_size = file.metadata().unwrap().len();
 
         // If there are more files, check if the size of the file is correct
-        if files.peek().is_some() && pos_file_size != file_size {
+        if index + 1 < num_files && pos_file_size != file_size {
             log::warn!(
                 "invalid POS file {}, expected size: {file_size} vs actual size: {pos_file_size}",
                 path.display(),
             );
         }
 
-        let identifier = Some(entry.file_name().to_string_lossy().into_owned());
-        readers.push(BatchingReader::new(
-            file, pos, batch_size, file_size, identifier,
-        ));
+        let reader: Box<dyn Read + Send> = match cfg.direct_io.then(|| open_direct(&path)) {
+            Some(Some(direct)) => Box::new(AlignedReader::new(direct, batch_size)),
+            _ => {
+                advise_sequential(&file);
+                Box::new(file)
+            }
+        };
+
+        // All files but the last one are full, so the position of a file follows from its index.
+        // The last one is usually partial, it's read up to its actual length.
+        let pos = index as u64 * file_size;
+        let size = if index + 1 == num_files {
+            pos_file_size.min(file_size)
+        } else {
+            file_size
+        };
+        let mut reader = BatchingReader::new(reader, pos, batch_size, size, Some(name))
+            .with_retries(cfg.max_retries, cfg.retry_backoff);
+        if let Some(buffers) = &buffers {
+            reader = reader.with_buffers(buffers.clone());
+        }
+        if cfg.verify_checksums {
+            let sidecar = checksums::sidecar_path(&path);
+            let table = ChecksumTable::read_from(&sidecar)
+                .wrap_err_with(|| format!("reading checksums from {}", sidecar.display()))?;
+            eyre::ensure!(
+                batch_size % table.block_size() == 0,
+                "batch size {batch_size} is not a multiple of the checksummed block size {}",
+                table.block_size()
+            );
+            reader = reader.with_checksums(table);
+        }
+        readers.push(reader);
+    }
+
+    match cfg.io_threads {
+        Some(io_threads) => Ok(Box::new(read_on_threads(readers, io_threads))),
+        None => Ok(Box::new(readers.into_iter().flatten())),
     }
+}
 
-    Ok(readers.into_iter().flatten())
+/// Reads the files on `io_threads` threads (at least one), each reading
+/// every `io_threads`-th file. Batches are passed through a bounded queue,
+/// so the reading doesn't get far ahead of the consumer.
+///
+/// The threads stop once the returned iterator is dropped.
+fn read_on_threads<R: Read + Send + 'static>(
+    readers: Vec<BatchingReader<R>>,
+    io_threads: usize,
+) -> impl Iterator<Item = Result<Batch, ReaderError>> {
+    let io_threads = io_threads.max(1);
+    let (tx, rx) = mpsc::sync_channel(2 * io_threads);
+    let mut assigned = (0..io_threads).map(|_| Vec::new()).collect::<Vec<_>>();
+    for (index, reader) in readers.into_iter().enumerate() {
+        assigned[index % io_threads].push(reader);
+    }
+    for readers in assigned.into_iter().filter(|r| !r.is_empty()) {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for batch in readers.into_iter().flatten() {
+                if tx.send(batch).is_err() {
+                    // Nobody reads anymore (i.e. a proof was found).
+                    return;
+                }
+            }
+        });
+    }
+    rx.into_iter()
 }
 
+/// Read batches from `reader`, counting their positions from 0.
 pub fn read_from<R: Read>(
     reader: R,
     batch_size: usize,
     max_size: u64,
     identifier: Option<String>,
-) -> impl Iterator<Item = Batch> {
+) -> impl Iterator<Item = Result<Batch, ReaderError>> {
     BatchingReader::new(reader, 0, batch_size, max_size, identifier)
 }
 
+/// Read batches of up to `total_len` bytes from an arbitrary source, i.e. a `Cursor`
+/// over labels in memory or a wrapper decrypting a file, instead of the POS data files.
+///
+/// The reading starts at the current position of `source`, and the positions of the batches
+/// are absolute in `source`, so `pos / 16` is the index of the first label in a batch
+/// (as expected by [Prover::prove](crate::prove::Prover::prove)) if the source holds
+/// all labels from the first one. Reading stops at the end of `source`.
+pub fn read_from_source<R: Read + Seek>(
+    mut source: R,
+    batch_size: usize,
+    total_len: u64,
+) -> io::Result<impl Iterator<Item = Result<Batch, ReaderError>>> {
+    let start = source.stream_position()?;
+    let end = source.seek(io::SeekFrom::End(0))?;
+    source.seek(io::SeekFrom::Start(start))?;
+    let len = total_len.min(end.saturating_sub(start));
+    Ok(BatchingReader::new(source, start, batch_size, len, None))
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io::Write;
+    use std::io::{self, Read, Write};
+    use std::num::NonZeroUsize;
+    use std::sync::Arc;
+    use std::time::Duration;
     use std::{fs::File, io::Cursor};
 
     use tempfile::tempdir;
 
-    use super::{pos_files, read_data, Batch, BatchingReader};
+    use crate::checksums::{self, ChecksumTable};
+    use crate::config::{InitConfig, ScryptParams};
+    use crate::initialize::{CpuInitializer, Initialize};
+    use crate::metadata::PostMetadata;
+
+    use super::{
+        default_file_name, diff, next_backoff, pos_files, read_data, read_from_source,
+        scan_datadir, shard_files, verify_identity, AlignedReader, Batch, BatchingReader,
+        BufferPool, DataDirScan, DataSnapshot, DiffRegion, IdentityMismatch, InvalidBatchSize,
+        ReaderConfig, ReaderError, CHUNK_SIZE, DIRECT_IO_ALIGNMENT, MAX_RETRY_BACKOFF,
+    };
+
+    fn reader_config(batch_size: usize) -> ReaderConfig {
+        ReaderConfig {
+            batch_size,
+            ..Default::default()
+        }
+    }
 
     #[test]
     fn batching_reader() {
@@ -163,23 +984,94 @@ mod tests {
                 data: (0..16).collect(),
                 pos: 0,
             }),
-            reader.next()
+            reader.next().transpose().unwrap()
         );
         assert_eq!(
             Some(Batch {
                 data: (16..32).collect(),
                 pos: 16,
             }),
-            reader.next()
+            reader.next().transpose().unwrap()
         );
         assert_eq!(
             Some(Batch {
                 data: (32..40).collect(),
                 pos: 32,
             }),
-            reader.next()
+            reader.next().transpose().unwrap()
         );
-        assert_eq!(None, reader.next());
+        assert!(reader.next().is_none());
+    }
+
+    /// A reader failing the first `failures` reads with the given error kind.
+    struct FlakyReader {
+        inner: Cursor<Vec<u8>>,
+        failures: u32,
+        kind: io::ErrorKind,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::from(self.kind));
+            }
+            // Give out a few bytes at a time to fail in the middle of a batch.
+            let len = buf.len().min(5);
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn batching_reader_retries_transient_errors() {
+        let data = (0..40).collect::<Vec<u8>>();
+        let flaky = FlakyReader {
+            inner: Cursor::new(data.clone()),
+            failures: 2,
+            kind: io::ErrorKind::TimedOut,
+        };
+        let reader = BatchingReader::new(flaky, 0, 16, 40, None).with_retries(2, Duration::ZERO);
+        let read = reader
+            .flat_map(|batch| batch.unwrap().data)
+            .collect::<Vec<u8>>();
+        assert_eq!(data, read);
+    }
+
+    #[test]
+    fn batching_reader_gives_up_after_max_retries() {
+        let flaky = FlakyReader {
+            inner: Cursor::new((0..40).collect()),
+            failures: 3,
+            kind: io::ErrorKind::TimedOut,
+        };
+        let mut reader =
+            BatchingReader::new(flaky, 0, 16, 40, None).with_retries(2, Duration::ZERO);
+        assert!(matches!(
+            reader.next(),
+            Some(Err(ReaderError::Read { pos: 0, .. }))
+        ));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn batching_reader_doesnt_retry_fatal_errors() {
+        let flaky = FlakyReader {
+            inner: Cursor::new((0..40).collect()),
+            failures: 1,
+            kind: io::ErrorKind::NotFound,
+        };
+        let mut reader =
+            BatchingReader::new(flaky, 0, 16, 40, None).with_retries(5, Duration::ZERO);
+        assert!(matches!(reader.next(), Some(Err(ReaderError::Read { .. }))));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn retry_backoff_is_capped() {
+        assert_eq!(Duration::ZERO, next_backoff(Duration::ZERO));
+        assert_eq!(Duration::from_secs(2), next_backoff(Duration::from_secs(1)));
+        assert_eq!(MAX_RETRY_BACKOFF, next_backoff(Duration::from_secs(20)));
+        assert_eq!(MAX_RETRY_BACKOFF, next_backoff(Duration::MAX));
     }
 
     #[test]
@@ -195,7 +1087,16 @@ mod tests {
         let mut result = Vec::new();
         let mut next_expected_index = 0;
         let file_size = 4u64;
-        for batch in read_data(tmp_dir.path(), file_size as usize, file_size).unwrap() {
+        for batch in read_data(
+            &[tmp_dir.path()],
+            &reader_config(file_size as usize),
+            file_size,
+            data.len(),
+            None,
+        )
+        .unwrap()
+        {
+            let batch = batch.unwrap();
             assert_eq!(next_expected_index, batch.pos);
             result.extend(batch.data);
             next_expected_index += file_size;
@@ -204,6 +1105,157 @@ mod tests {
         assert_eq!(b"2Hell1Welc", result.as_slice());
     }
 
+    #[test]
+    fn reusing_buffers() {
+        let pool = BufferPool::new(1);
+        let buffer = pool.get(16);
+        assert!(buffer.capacity() >= 16);
+        let ptr = buffer.as_ptr();
+        pool.put(buffer);
+        // The pool is full, it's dropped.
+        pool.put(vec![1, 2, 3]);
+
+        let buffer = pool.get(8);
+        assert_eq!(ptr, buffer.as_ptr());
+        assert!(buffer.is_empty());
+        assert!(pool.get(8).is_empty());
+    }
+
+    #[test]
+    fn reading_pos_data_into_pooled_buffers() {
+        let tmp_dir = tempdir().unwrap();
+        for (i, part) in ["00001111", "2222"].iter().enumerate() {
+            std::fs::write(tmp_dir.path().join(format!("postdata_{i}.bin")), part).unwrap();
+        }
+        let pool = Arc::new(BufferPool::new(2));
+        let mut pooled = Vec::new();
+        for batch in read_data(
+            &[tmp_dir.path()],
+            &reader_config(4),
+            8,
+            2,
+            Some(pool.clone()),
+        )
+        .unwrap()
+        {
+            let batch = batch.unwrap();
+            pooled.push((batch.pos, String::from_utf8(batch.data.clone()).unwrap()));
+            pool.put(batch.data);
+        }
+        assert_eq!(
+            vec![
+                (0, "0000".to_string()),
+                (4, "1111".to_string()),
+                (8, "2222".to_string())
+            ],
+            pooled
+        );
+        assert_eq!(1, pool.buffers.lock().unwrap().len());
+    }
+
+    #[test]
+    fn detecting_modified_pos_data() {
+        let tmp_dir = tempdir().unwrap();
+        for i in 0..2 {
+            std::fs::write(tmp_dir.path().join(format!("postdata_{i}.bin")), "0000").unwrap();
+        }
+        let cfg = reader_config(4);
+        let snapshot = DataSnapshot::take(&[tmp_dir.path()], &cfg, 2).unwrap();
+        snapshot.check().unwrap();
+
+        let modified = tmp_dir.path().join("postdata_1.bin");
+        std::fs::write(&modified, "00001111").unwrap();
+        assert!(matches!(
+            snapshot.check(),
+            Err(ReaderError::Modified { path }) if path == modified
+        ));
+
+        // A new snapshot takes the changes
+        let snapshot = DataSnapshot::take(&[tmp_dir.path()], &cfg, 2).unwrap();
+        snapshot.check().unwrap();
+        std::fs::remove_file(&modified).unwrap();
+        assert!(matches!(
+            snapshot.check(),
+            Err(ReaderError::Modified { .. })
+        ));
+
+        assert!(DataSnapshot::take(&[tmp_dir.path()], &cfg, 3).is_err());
+    }
+
+    #[test]
+    fn reading_partial_last_file() {
+        let tmp_dir = tempdir().unwrap();
+        // 4 labels per file, the last file holds only 2 of them.
+        let labels_per_file = 4;
+        let num_labels = 10u8;
+        for (i, labels) in (0..num_labels)
+            .collect::<Vec<_>>()
+            .chunks(labels_per_file)
+            .enumerate()
+        {
+            let data = labels.iter().flat_map(|&l| [l; 16]).collect::<Vec<u8>>();
+            std::fs::write(tmp_dir.path().join(format!("postdata_{i}.bin")), data).unwrap();
+        }
+
+        let file_size = labels_per_file as u64 * 16;
+        let batches = read_data(&[tmp_dir.path()], &reader_config(48), file_size, 3, None)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            vec![(0, 48), (48, 16), (64, 48), (112, 16), (128, 32)],
+            batches
+                .iter()
+                .map(|batch| (batch.pos, batch.data.len()))
+                .collect::<Vec<_>>()
+        );
+        // The position of every label gives its index.
+        for batch in &batches {
+            for (i, label) in batch.data.chunks_exact(16).enumerate() {
+                let index = batch.pos / 16 + i as u64;
+                assert_eq!([index as u8; 16], label);
+            }
+        }
+    }
+
+    #[test]
+    fn reading_pos_data_from_multiple_dirs() {
+        let dirs = [tempdir().unwrap(), tempdir().unwrap()];
+        for (i, part) in ["0000", "1111", "22"].iter().enumerate() {
+            let dir = &dirs[i % 2];
+            std::fs::write(dir.path().join(format!("postdata_{i}.bin")), part).unwrap();
+        }
+        let datadirs = [dirs[0].path(), dirs[1].path()];
+
+        let batches = read_data(&datadirs, &reader_config(4), 4, 3, None)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let read = batches
+            .iter()
+            .map(|batch| (batch.pos, batch.data.as_slice()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![(0, b"0000".as_slice()), (4, b"1111"), (8, b"22")],
+            read
+        );
+
+        // The first directory holding a file wins.
+        std::fs::write(dirs[0].path().join("postdata_1.bin"), "xxxx").unwrap();
+        let data = read_data(&datadirs, &reader_config(4), 4, 3, None)
+            .unwrap()
+            .flat_map(|batch| batch.unwrap().data)
+            .collect::<Vec<_>>();
+        assert_eq!(b"0000xxxx22", data.as_slice());
+
+        let err = read_data(&datadirs, &reader_config(4), 4, 4, None)
+            .err()
+            .unwrap()
+            .downcast::<ReaderError>()
+            .unwrap();
+        assert!(matches!(err, ReaderError::MissingFile { index: 3, .. }));
+    }
+
     #[rstest::rstest]
     #[case("other.bin")]
     #[case("_postadata_0.bin")]
@@ -215,7 +1267,394 @@ mod tests {
         let mut tmp_file = File::create(file_path).unwrap();
         write!(tmp_file, "some data").unwrap();
 
-        assert!(read_data(tmp_dir.path(), 4, 4).unwrap().next().is_none());
+        let num_files = pos_files(tmp_dir.path()).unwrap().count();
+        assert!(
+            read_data(&[tmp_dir.path()], &reader_config(4), 4, num_files, None)
+                .unwrap()
+                .next()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn reading_with_custom_file_names() {
+        let tmp_dir = tempdir().unwrap();
+        for (i, part) in ["abcd", "efgh"].iter().enumerate() {
+            let file_path = tmp_dir.path().join(format!("data-{i:04}.bin"));
+            let mut tmp_file = File::create(file_path).unwrap();
+            write!(tmp_file, "{part}").unwrap();
+        }
+        let cfg = ReaderConfig {
+            file_name: |i| format!("data-{i:04}.bin"),
+            ..reader_config(4)
+        };
+
+        let result = read_data(&[tmp_dir.path()], &cfg, 4, 2, None)
+            .unwrap()
+            .flat_map(|batch| batch.unwrap().data)
+            .collect::<Vec<u8>>();
+        assert_eq!(b"abcdefgh", result.as_slice());
+    }
+
+    #[rstest::rstest]
+    #[case(1)]
+    #[case(2)]
+    #[case(8)]
+    fn reading_on_io_threads(#[case] io_threads: usize) {
+        let tmp_dir = tempdir().unwrap();
+        let parts = ["abcd", "efgh", "ijkl", "mn"];
+        for (i, part) in parts.iter().enumerate() {
+            let file_path = tmp_dir.path().join(format!("postdata_{i}.bin"));
+            let mut tmp_file = File::create(file_path).unwrap();
+            write!(tmp_file, "{part}").unwrap();
+        }
+        let cfg = ReaderConfig {
+            io_threads: Some(io_threads),
+            ..reader_config(2)
+        };
+
+        // The batches of different files might come in any order.
+        let mut batches = read_data(&[tmp_dir.path()], &cfg, 4, parts.len(), None)
+            .unwrap()
+            .map(|batch| batch.unwrap())
+            .collect::<Vec<_>>();
+        batches.sort_by_key(|batch| batch.pos);
+        assert_eq!(
+            vec![0, 2, 4, 6, 8, 10, 12],
+            batches.iter().map(|b| b.pos).collect::<Vec<_>>()
+        );
+        let data = batches.into_iter().flat_map(|b| b.data).collect::<Vec<_>>();
+        assert_eq!(b"abcdefghijklmn", data.as_slice());
+    }
+
+    /// A reader asserting that reads are aligned as required by direct IO.
+    struct AlignmentChecker(Cursor<Vec<u8>>);
+
+    impl Read for AlignmentChecker {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            assert_eq!(0, buf.as_ptr() as usize % DIRECT_IO_ALIGNMENT);
+            assert_eq!(0, buf.len() % DIRECT_IO_ALIGNMENT);
+            assert_eq!(0, self.0.position() % DIRECT_IO_ALIGNMENT as u64);
+            self.0.read(buf)
+        }
+    }
+
+    #[rstest::rstest]
+    #[case(1)]
+    #[case(3000)]
+    #[case(DIRECT_IO_ALIGNMENT)]
+    #[case(3 * DIRECT_IO_ALIGNMENT + 1)]
+    fn aligned_reader(#[case] batch_size: usize) {
+        let data = (0..10_000).map(|i| i as u8).collect::<Vec<u8>>();
+        let aligned = AlignedReader::new(AlignmentChecker(Cursor::new(data.clone())), batch_size);
+        let read = BatchingReader::new(aligned, 0, batch_size, data.len() as u64, None)
+            .flat_map(|batch| batch.unwrap().data)
+            .collect::<Vec<u8>>();
+        assert_eq!(data, read);
+    }
+
+    #[test]
+    fn reading_with_direct_io() {
+        let tmp_dir = tempdir().unwrap();
+        let file_size = 5000;
+        let data = (0..2 * file_size).map(|i| i as u8).collect::<Vec<u8>>();
+        for (i, part) in data.chunks(file_size).enumerate() {
+            let mut file = File::create(tmp_dir.path().join(format!("postdata_{i}.bin"))).unwrap();
+            file.write_all(part).unwrap();
+        }
+        let cfg = ReaderConfig {
+            direct_io: true,
+            ..reader_config(3000)
+        };
+
+        // Falls back to buffered reads if the filesystem doesn't support direct IO.
+        let result = read_data(&[tmp_dir.path()], &cfg, file_size as u64, 2, None)
+            .unwrap()
+            .flat_map(|batch| batch.unwrap().data)
+            .collect::<Vec<u8>>();
+        assert_eq!(data, result);
+    }
+
+    #[test]
+    fn validating_batch_size() {
+        assert!(ReaderConfig::default().validate().is_ok());
+        assert_eq!(
+            1024,
+            ReaderConfig::with_batch_size(1024).unwrap().batch_size
+        );
+        assert!(matches!(
+            ReaderConfig::with_batch_size(1000),
+            Err(InvalidBatchSize(1000))
+        ));
+        assert!(ReaderConfig::with_batch_size(0).is_err());
+        assert!(ReaderConfig::with_batch_size(16).is_err());
+    }
+
+    #[test]
+    fn aligning_batches_to_stripes() {
+        let stripe = |width| ReaderConfig {
+            stripe_width: NonZeroUsize::new(width),
+            ..reader_config(1024 * 1024)
+        };
+        assert_eq!(1024 * 1024, stripe(0).effective_batch_size());
+        assert_eq!(1024 * 1024, stripe(64 * 1024).effective_batch_size());
+        // 3 disks with 64 KiB chunks
+        assert_eq!(6 * 192 * 1024, stripe(3 * 64 * 1024).effective_batch_size());
+        // lcm(1000, 128) = 16000
+        assert_eq!(66 * 16000, stripe(1000).effective_batch_size());
+        assert_eq!(0, stripe(3 * 64 * 1024).effective_batch_size() % CHUNK_SIZE);
+    }
+
+    #[test]
+    fn reading_aligned_to_stripes() {
+        let tmp_dir = tempdir().unwrap();
+        let file_size = 1000;
+        for i in 0..2 {
+            let mut file = File::create(tmp_dir.path().join(format!("postdata_{i}.bin"))).unwrap();
+            file.write_all(&vec![i as u8; file_size]).unwrap();
+        }
+        let cfg = ReaderConfig {
+            stripe_width: NonZeroUsize::new(192),
+            ..reader_config(128)
+        };
+        let positions = read_data(&[tmp_dir.path()], &cfg, file_size as u64, 2, None)
+            .unwrap()
+            .map(|batch| batch.unwrap().pos)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 384, 768, 1000, 1384, 1768], positions);
+    }
+
+    #[test]
+    fn reading_with_checksums() {
+        let tmp_dir = tempdir().unwrap();
+        let file_size = 1000;
+        for i in 0..2 {
+            let path = tmp_dir.path().join(format!("postdata_{i}.bin"));
+            let data = (0..file_size).map(|b| (b + i) as u8).collect::<Vec<_>>();
+            std::fs::write(&path, &data).unwrap();
+            ChecksumTable::compute(data.as_slice(), 128)
+                .unwrap()
+                .write_to(&checksums::sidecar_path(&path))
+                .unwrap();
+        }
+        let cfg = ReaderConfig {
+            verify_checksums: true,
+            ..reader_config(256)
+        };
+        let read = || {
+            read_data(&[tmp_dir.path()], &cfg, file_size as u64, 2, None)
+                .unwrap()
+                .collect::<Vec<_>>()
+        };
+        assert!(read().iter().all(Result::is_ok));
+
+        // Flip a bit in the fourth block of the second file
+        let path = tmp_dir.path().join("postdata_1.bin");
+        let mut data = std::fs::read(&path).unwrap();
+        data[3 * 128 + 5] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        let corrupt = read()
+            .into_iter()
+            .filter_map(Result::err)
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            corrupt.as_slice(),
+            [ReaderError::BatchCorrupt { pos: 1384 }]
+        ));
+
+        // The batches must be made of whole blocks
+        let unaligned = ReaderConfig {
+            verify_checksums: true,
+            ..reader_config(192)
+        };
+        assert!(read_data(&[tmp_dir.path()], &unaligned, file_size as u64, 2, None).is_err());
+
+        std::fs::remove_file(checksums::sidecar_path(&path)).unwrap();
+        assert!(read_data(&[tmp_dir.path()], &cfg, file_size as u64, 2, None).is_err());
+    }
+
+    #[test]
+    fn scanning_datadir() {
+        let tmp_dir = tempdir().unwrap();
+        let metadata = CpuInitializer::new(ScryptParams::new(2, 1, 1))
+            .initialize(tmp_dir.path(), &[1; 32], &[2; 32], 100, 2, 64, None)
+            .unwrap();
+        let cfg = ReaderConfig::default();
+        let scan = scan_datadir(&[tmp_dir.path()], &metadata, &cfg).unwrap();
+        // 3 full files of 1024 bytes and the last one of 128 bytes
+        let complete = DataDirScan {
+            expected_files: 4,
+            missing: Vec::new(),
+            incomplete: Vec::new(),
+            total_bytes: metadata.expected_total_bytes(),
+        };
+        assert_eq!(complete, scan);
+        assert!(scan.is_complete());
+
+        std::fs::remove_file(tmp_dir.path().join(default_file_name(1))).unwrap();
+        File::options()
+            .write(true)
+            .open(tmp_dir.path().join(default_file_name(3)))
+            .unwrap()
+            .set_len(100)
+            .unwrap();
+        let scan = scan_datadir(&[tmp_dir.path()], &metadata, &cfg).unwrap();
+        assert_eq!(
+            DataDirScan {
+                missing: vec![1],
+                incomplete: vec![3],
+                total_bytes: 2 * 1024 + 100,
+                ..complete
+            },
+            scan
+        );
+        assert!(!scan.is_complete());
+    }
+
+    #[test]
+    fn verifying_identity() {
+        let tmp_dir = tempdir().unwrap();
+        let init_cfg = InitConfig {
+            min_num_units: 1,
+            max_num_units: 10,
+            labels_per_unit: 100,
+            scrypt: ScryptParams::new(2, 1, 1),
+        };
+        let metadata = CpuInitializer::new(init_cfg.scrypt)
+            .initialize(tmp_dir.path(), &[1; 32], &[2; 32], 100, 2, 64, None)
+            .unwrap();
+        let datadirs = [tmp_dir.path()];
+        let reader_cfg = ReaderConfig::default();
+        verify_identity(&datadirs, &metadata, &init_cfg, &reader_cfg, 10).unwrap();
+
+        let other = PostMetadata {
+            node_id: [3; 32],
+            ..metadata
+        };
+        let err = verify_identity(&datadirs, &other, &init_cfg, &reader_cfg, 10).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<IdentityMismatch>(),
+            Some(IdentityMismatch { index: 0 })
+        ));
+
+        // The files might be split between directories.
+        let other_dir = tempdir().unwrap();
+        let name = default_file_name(1);
+        std::fs::rename(tmp_dir.path().join(&name), other_dir.path().join(&name)).unwrap();
+        let split = [tmp_dir.path(), other_dir.path()];
+        verify_identity(&split, &metadata, &init_cfg, &reader_cfg, 10).unwrap();
+        assert!(verify_identity(&datadirs, &metadata, &init_cfg, &reader_cfg, 10).is_err());
+
+        // Corrupt the last sample (label 180, in the third file)
+        let path = tmp_dir.path().join("postdata_2.bin");
+        let mut data = std::fs::read(&path).unwrap();
+        data[(180 - 128) * 16] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        let err = verify_identity(&split, &metadata, &init_cfg, &reader_cfg, 10).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<IdentityMismatch>(),
+            Some(IdentityMismatch { index: 180 })
+        ));
+    }
+
+    #[test]
+    fn reading_from_source() {
+        let data = (0..100).collect::<Vec<u8>>();
+        let batches = |source: Cursor<&Vec<u8>>, total_len| {
+            read_from_source(source, 32, total_len)
+                .unwrap()
+                .map(|batch| {
+                    let batch = batch.unwrap();
+                    (batch.pos, batch.data.len())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![(0, 32), (32, 32), (64, 32), (96, 4)],
+            batches(Cursor::new(&data), u64::MAX)
+        );
+        assert_eq!(vec![(0, 32), (32, 8)], batches(Cursor::new(&data), 40));
+
+        let mut source = Cursor::new(&data);
+        source.set_position(48);
+        assert_eq!(vec![(48, 32), (80, 20)], batches(source.clone(), 1000));
+        assert_eq!(vec![(48, 16)], batches(source, 16));
+
+        let mut source = Cursor::new(&data);
+        source.set_position(48);
+        let first = read_from_source(source, 32, 1000)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(&data[48..80], first.data);
+    }
+
+    #[test]
+    fn missing_pos_file() {
+        let tmp_dir = tempdir().unwrap();
+        File::create(tmp_dir.path().join("postdata_0.bin")).unwrap();
+
+        let err = read_data(&[tmp_dir.path()], &reader_config(4), 4, 2, None)
+            .err()
+            .expect("reading should fail");
+        assert!(matches!(
+            err.downcast_ref::<ReaderError>(),
+            Some(ReaderError::MissingFile { index: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn gap_in_pos_files() {
+        let tmp_dir = tempdir().unwrap();
+        for i in [0, 2] {
+            let mut file = File::create(tmp_dir.path().join(format!("postdata_{i}.bin"))).unwrap();
+            file.write_all(&[0u8; 4]).unwrap();
+        }
+
+        let err = read_data(&[tmp_dir.path()], &reader_config(4), 4, 3, None)
+            .err()
+            .expect("reading should fail");
+        assert!(matches!(
+            err.downcast_ref::<ReaderError>(),
+            Some(ReaderError::MissingFile { index: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn sharding_files() {
+        let tmp_dir = tempdir().unwrap();
+        for (i, size) in [40, 10, 10, 20, 10, 10].iter().enumerate() {
+            let mut file = File::create(tmp_dir.path().join(format!("postdata_{i}.bin"))).unwrap();
+            file.write_all(&vec![0u8; *size]).unwrap();
+        }
+        let name =
+            |path: &std::path::PathBuf| path.file_name().unwrap().to_string_lossy().to_string();
+
+        let shards = shard_files(tmp_dir.path(), 2).unwrap();
+        let names = shards
+            .iter()
+            .map(|shard| shard.iter().map(name).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                vec!["postdata_0.bin", "postdata_4.bin"],
+                vec![
+                    "postdata_1.bin",
+                    "postdata_2.bin",
+                    "postdata_3.bin",
+                    "postdata_5.bin"
+                ],
+            ],
+            names
+        );
+
+        let shards = shard_files(tmp_dir.path(), 8).unwrap();
+        assert_eq!(8, shards.len());
+        assert_eq!(6, shards.iter().filter(|shard| !shard.is_empty()).count());
+        assert_eq!(6, shards.concat().len());
+
+        assert!(shard_files(tmp_dir.path(), 0).is_err());
     }
 
     #[test]
@@ -235,4 +1674,70 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn diffing_data_directories() {
+        let (dir_a, dir_b) = (tempdir().unwrap(), tempdir().unwrap());
+        let initializer = CpuInitializer::new(ScryptParams::new(2, 1, 1));
+        let metadata = initializer
+            .initialize(dir_a.path(), &[1; 32], &[2; 32], 100, 2, 64, None)
+            .unwrap();
+        initializer
+            .initialize(dir_b.path(), &[1; 32], &[2; 32], 100, 2, 64, None)
+            .unwrap();
+        // 3 full files of 1024 bytes and the last one of 128 bytes
+        assert_eq!(4, metadata.expected_file_count());
+        let cfg = ReaderConfig::default();
+        assert!(diff(&[dir_a.path()], &[dir_b.path()], &metadata, &cfg)
+            .unwrap()
+            .is_empty());
+        // A copy split between directories.
+        let dir_c = tempdir().unwrap();
+        let name = default_file_name(1);
+        std::fs::rename(dir_a.path().join(&name), dir_c.path().join(&name)).unwrap();
+        let split = [dir_a.path(), dir_c.path()];
+        assert!(diff(&split, &[dir_b.path()], &metadata, &cfg)
+            .unwrap()
+            .is_empty());
+
+        let corrupt = |index: usize, offsets: &[usize]| {
+            let path = dir_b.path().join(default_file_name(index));
+            let mut data = std::fs::read(&path).unwrap();
+            for &offset in offsets {
+                data[offset] ^= 0xFF;
+            }
+            std::fs::write(&path, data).unwrap();
+        };
+        corrupt(0, &[1023]);
+        corrupt(1, &[0, 10, 11, 20]);
+        let last = dir_b.path().join(default_file_name(3));
+        File::options()
+            .write(true)
+            .open(&last)
+            .unwrap()
+            .set_len(100)
+            .unwrap();
+
+        let regions = diff(&split, &[dir_b.path()], &metadata, &cfg).unwrap();
+        let region = |file, pos, len| DiffRegion { file, pos, len };
+        assert_eq!(
+            vec![
+                region(0, 1023, 1),
+                region(1, 1024, 1),
+                region(1, 1034, 2),
+                region(1, 1044, 1),
+                region(3, 3172, 28),
+            ],
+            regions
+        );
+        assert_eq!(64..65, regions[2].labels());
+        assert_eq!(198..200, regions[4].labels());
+
+        std::fs::remove_file(&last).unwrap();
+        let err = diff(&split, &[dir_b.path()], &metadata, &cfg).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ReaderError>(),
+            Some(ReaderError::MissingFile { index: 3, .. })
+        ));
+    }
 }
@@ -2,10 +2,11 @@
 //!
 //! ## Steps to verify a proof:
 //!
-//! 1. verify PoW
+//! 1. verify the nonce and PoW agree: the PoW is valid for the nonce group of the nonce (`nonce / 16`)
 //! 2. verify number of indices == K2
 //! 3. select K3 indices
-//! 4. verify each of K3 selected indices satisfy difficulty (inferred from K1)
+//! 4. verify each of K3 selected indices satisfy difficulty (inferred from K1),
+//!    with the AES ciphers derived from the nonce group and PoW of step 1 (and the nonce)
 //!
 //! ## Selecting subset of K3 proven indices
 //!
@@ -35,26 +36,39 @@
 //!     - encrypt it with AES,
 //!     - convert AES output to u64,
 //!     - compare it with difficulty.
-use std::cmp::Ordering;
+//!
+//! ## Failing indices
+//!
+//! The verification stops at the first label not satisfying the difficulty
+//! ([Error::InvalidMsb] or [Error::InvalidLsb]), so that rejecting an invalid proof
+//! is never more expensive than accepting a valid one.
+//!
+//! To tell why a proof was rejected, [Verifier::diagnose] checks all K3 labels.
+//! If none of them satisfies the difficulty, the labels were probably encrypted with
+//! other AES ciphers than the prover used, i.e. the proof was generated for another
+//! challenge, and it fails with [Error::ChallengeMismatch]. It's only a hint: labels
+//! generated from other metadata (commitment, scrypt parameters) or a forged proof
+//! fail the same way. Note that the PoW binds the first 8 bytes of the challenge,
+//! so proofs for most other challenges fail earlier with [Error::InvalidPoW].
+use std::collections::HashMap;
 
-use cipher::BlockEncrypt;
 use itertools::Itertools;
 use log::debug;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 
 use crate::{
-    cipher::AesCipher,
-    compression::{decompress_indexes, required_bits},
-    config::{InitConfig, ProofConfig},
-    difficulty::{proving_difficulty, scale_pow_difficulty},
-    initialize::{calc_commitment, generate_label},
-    metadata::ProofMetadata,
+    config::{InitConfig, ProofConfig, ScryptParams},
+    context::{ContextError, PostContext},
+    difficulty::scale_pow_difficulty,
+    initialize::{generate_label, LABEL_SIZE},
+    merkle::{self, MerkleProof},
+    metadata::{PostMetadata, ProofMetadata},
     pow::PowVerifier,
-    prove::{Proof, Prover8_56},
+    prove::Proof,
     random_values_gen::RandomValuesIterator,
+    verify_core::{decompress_index, expected_indices_bytes, LabelChecker, LabelError, AES_BATCH},
 };
 
-const NONCES_PER_AES: u32 = Prover8_56::NONCES_PER_AES;
-
 pub struct Verifier {
     pow_verifier: Box<dyn PowVerifier + Send + Sync>,
 }
@@ -81,10 +95,53 @@ pub enum Error {
         difficulty_lsb: u64,
         label: [u8; 16],
     },
+    /// None of the checked labels satisfy the difficulty, see [Verifier::diagnose].
+    #[error("none of the {checked} checked indices satisfy the difficulty, the proof was probably generated for another challenge")]
+    ChallengeMismatch { checked: usize },
     #[error(transparent)]
     InvalidMetadata(#[from] MetadataValidationError),
     #[error("invalid number of labels: (0)")]
     InvalidNumLabels(String),
+    #[error(transparent)]
+    ContextMismatch(ContextError),
+    #[error("index {index} out of range (number of labels: {num_labels})")]
+    IndexOutOfRange { index: u64, num_labels: u64 },
+    #[error("missing label for index {index}")]
+    MissingLabel { index: u64 },
+    #[error("invalid merkle proof of the label for index {index}")]
+    InvalidMerkleProof { index: u64 },
+    #[cfg(feature = "async")]
+    #[error("reading label for index {index}")]
+    ReadingLabel { index: u64, source: std::io::Error },
+}
+
+impl From<ContextError> for Error {
+    fn from(err: ContextError) -> Self {
+        match err {
+            ContextError::Mismatch { .. } => Error::ContextMismatch(err),
+            err => Error::InvalidNumLabels(err.to_string()),
+        }
+    }
+}
+
+/// How far below the difficulty the label of a proven index is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexMargin {
+    pub index: u64,
+    /// The value of the label compared with the difficulty (MSB in the top 8 bits, LSB below).
+    pub value: u64,
+    pub difficulty: u64,
+}
+
+impl IndexMargin {
+    /// The ratio of the value to the difficulty. It's below 1 for the indices
+    /// satisfying the difficulty. The lower, the stronger the index.
+    ///
+    /// With labels being random, the ratios are uniform in [0, 1) for valid proofs.
+    /// Drifting away from it over many proofs hints at miscalibrated difficulty.
+    pub fn ratio(&self) -> f64 {
+        self.value as f64 / self.difficulty as f64
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -121,6 +178,19 @@ impl Verifier {
 
     /// Verify if a proof is valid.
     ///
+    /// The labels of the checked indices are regenerated like in the initialization:
+    /// with scrypt parameters from `init_cfg` and the commitment derived from
+    /// `metadata.node_id` and `metadata.commitment_atx_id`.
+    ///
+    /// The memory used is O(K2), whatever the (declared) number of labels:
+    /// the compressed indices must be exactly as long as needed for K2 of them
+    /// and only the K3 selected labels are regenerated.
+    ///
+    /// The nonce, the PoW and the indices must agree: the PoW must be valid for the nonce
+    /// group of the nonce (`nonce / 16`) and the labels are checked with the AES ciphers
+    /// derived from that same group and PoW (and the nonce itself). A PoW calculated for
+    /// another nonce group or a nonce not the one the indices were found for fails it.
+    ///
     /// Arguments:
     ///
     /// * `proof`: The proof that to verify
@@ -133,50 +203,240 @@ impl Verifier {
         metadata: &ProofMetadata,
         cfg: &ProofConfig,
         init_cfg: &InitConfig,
+    ) -> Result<(), Error> {
+        let (checker, k3_indices) = self.prepare(proof, metadata, cfg, init_cfg)?;
+        checker.check_all(&k3_indices)
+    }
+
+    /// Verify a proof like [Verifier::verify] with the values derived from the metadata
+    /// and config in advance, i.e. to verify many proofs of the same number of units.
+    ///
+    /// Fails with [Error::ContextMismatch] if the `context` wasn't created
+    /// for the number of units of `metadata` and `init_cfg`.
+    pub fn verify_with_context(
+        &self,
+        proof: &Proof,
+        metadata: &ProofMetadata,
+        context: &PostContext,
+        init_cfg: &InitConfig,
     ) -> Result<(), Error> {
         verify_metadata(metadata, init_cfg)?;
+        context.check_units(metadata.num_units, init_cfg.labels_per_unit)?;
+        let (checker, k3_indices) =
+            self.prepare_with_context(proof, metadata, context, init_cfg)?;
+        checker.check_all(&k3_indices)
+    }
 
-        let challenge = metadata.challenge;
-        let pow_difficulty = scale_pow_difficulty(&cfg.pow_difficulty, metadata.num_units);
+    /// Verify a proof like [Verifier::verify] and report the margins
+    /// by which the checked K3 indices satisfy the difficulty.
+    pub fn verify_with_margins(
+        &self,
+        proof: &Proof,
+        metadata: &ProofMetadata,
+        cfg: &ProofConfig,
+        init_cfg: &InitConfig,
+    ) -> Result<Vec<IndexMargin>, Error> {
+        let (checker, k3_indices) = self.prepare(proof, metadata, cfg, init_cfg)?;
+        k3_indices
+            .into_iter()
+            .map(|index| checker.margin(index))
+            .collect()
+    }
 
-        // Verify K2 PoW
-        let nonce_group = proof.nonce / NONCES_PER_AES;
-        debug!(
-            "verifying K2 pow for nonce group: {nonce_group} with difficulty: {:x?}",
-            pow_difficulty
-        );
-        self.pow_verifier.verify(
-            proof.pow,
-            nonce_group
-                .try_into()
-                .map_err(|_| Error::NonceGroupOutOfBounds(nonce_group))?,
-            &challenge[..8].try_into().unwrap(),
-            &pow_difficulty,
-            &metadata.node_id,
-        )?;
+    /// Verify a proof like [Verifier::verify], checking all K3 labels to tell
+    /// why it's invalid, i.e. after [Verifier::verify] failed.
+    ///
+    /// If none of the labels satisfies the difficulty, it fails with [Error::ChallengeMismatch]
+    /// (see the [module docs](self)), otherwise with the first invalid label like
+    /// [Verifier::verify]. A single checked label (K3 = 1) is never reported as a mismatch.
+    ///
+    /// It regenerates all K3 labels of an invalid proof, so it must not be used
+    /// to verify untrusted proofs in place of [Verifier::verify].
+    pub fn diagnose(
+        &self,
+        proof: &Proof,
+        metadata: &ProofMetadata,
+        cfg: &ProofConfig,
+        init_cfg: &InitConfig,
+    ) -> Result<(), Error> {
+        let (checker, k3_indices) = self.prepare(proof, metadata, cfg, init_cfg)?;
+        checker.check_every_with(&k3_indices, |index| {
+            Ok(generate_label(&checker.commitment, checker.scrypt, index))
+        })
+    }
 
-        // Verify the number of indices against K2
+    /// Like [Verifier::diagnose] with the labels produced by `label_fn`,
+    /// see [Verifier::verify_with_label_fn].
+    pub fn diagnose_with_label_fn<F>(
+        &self,
+        proof: &Proof,
+        metadata: &ProofMetadata,
+        cfg: &ProofConfig,
+        init_cfg: &InitConfig,
+        label_fn: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(u64) -> [u8; LABEL_SIZE],
+    {
+        let (checker, k3_indices) = self.prepare(proof, metadata, cfg, init_cfg)?;
+        checker.check_every_with(&k3_indices, |index| Ok(label_fn(index)))
+    }
+
+    /// Verify a proof using the given labels (keyed by index) instead of recomputing them.
+    ///
+    /// Only the labels of the selected K3 indices are needed, but the labels of all
+    /// proven indices can be given, i.e. obtained with [contested_labels](crate::prove::contested_labels).
+    /// Fails with [Error::MissingLabel] if a label to check is not in `labels`.
+    ///
+    /// NOTE: the labels are NOT checked to be the ones generated by the initialization.
+    /// It's up to the caller to trust the source.
+    pub fn verify_with_labels(
+        &self,
+        proof: &Proof,
+        metadata: &ProofMetadata,
+        cfg: &ProofConfig,
+        init_cfg: &InitConfig,
+        labels: &HashMap<u64, [u8; LABEL_SIZE]>,
+    ) -> Result<(), Error> {
+        let (checker, k3_indices) = self.prepare(proof, metadata, cfg, init_cfg)?;
+        checker.check_all_with(&k3_indices, |index| {
+            labels
+                .get(&index)
+                .copied()
+                .ok_or(Error::MissingLabel { index })
+        })
+    }
+
+    /// Verify a proof against the `root` of a Merkle tree committing to all labels
+    /// (see [merkle] for the encoding of the tree), so that the verifier needs only the root.
+    ///
+    /// `labels` holds the labels of the selected K3 indices (keyed by index) with their
+    /// inclusion proofs. Every label is checked to be included in the tree of all
+    /// `num_units * labels_per_unit` labels before it's checked against the difficulty.
+    /// Fails with [Error::MissingLabel] if a label to check is not in `labels`
+    /// and with [Error::InvalidMerkleProof] if it's not included in the tree.
+    ///
+    /// NOTE: the root is NOT checked to commit to the labels generated by the initialization.
+    /// It's up to the caller to trust the root.
+    pub fn verify_with_merkle_proofs(
+        &self,
+        proof: &Proof,
+        metadata: &ProofMetadata,
+        cfg: &ProofConfig,
+        init_cfg: &InitConfig,
+        root: &merkle::Hash,
+        labels: &HashMap<u64, ([u8; LABEL_SIZE], MerkleProof)>,
+    ) -> Result<(), Error> {
+        let (checker, k3_indices) = self.prepare(proof, metadata, cfg, init_cfg)?;
         let num_labels = metadata.num_units as u64 * init_cfg.labels_per_unit;
-        let bits_per_index = required_bits(num_labels);
-        let expected = expected_indices_bytes(bits_per_index, cfg.k2);
-        if proof.indices.len() != expected {
-            return Err(Error::InvalidIndicesLen {
-                expected,
-                got: proof.indices.len(),
-            });
+        checker.check_all_with(&k3_indices, |index| {
+            let (label, merkle_proof) = labels.get(&index).ok_or(Error::MissingLabel { index })?;
+            if !merkle_proof.verify(root, index, num_labels, label) {
+                return Err(Error::InvalidMerkleProof { index });
+            }
+            Ok(*label)
+        })
+    }
+
+    /// Verify a proof with the labels produced by `label_fn` (given the global index of a label)
+    /// instead of recomputing them with scrypt, i.e. read from storage or a cache.
+    ///
+    /// The challenge is taken from `metadata`. It's the most flexible entry point:
+    /// [Verifier::verify] is like calling it with a function generating the labels.
+    ///
+    /// NOTE: the labels are NOT checked to be the ones generated by the initialization.
+    /// It's up to the caller to trust `label_fn`.
+    pub fn verify_with_label_fn<F>(
+        &self,
+        proof: &Proof,
+        metadata: &ProofMetadata,
+        cfg: &ProofConfig,
+        init_cfg: &InitConfig,
+        label_fn: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(u64) -> [u8; LABEL_SIZE],
+    {
+        let (checker, k3_indices) = self.prepare(proof, metadata, cfg, init_cfg)?;
+        checker.check_all_with(&k3_indices, |index| Ok(label_fn(index)))
+    }
+
+    /// Verify a proof reading the labels from `labels` instead of recomputing them.
+    ///
+    /// The selected K3 indices are sorted and the labels they point to are read
+    /// in order from the source (the label with index `i` is at offset `i * 16`).
+    /// Every label is checked as soon as it's read.
+    ///
+    /// NOTE: the labels are NOT checked to be the ones generated by the initialization.
+    /// It's up to the caller to trust the source.
+    #[cfg(feature = "async")]
+    pub async fn verify_from_source<S>(
+        &self,
+        proof: &Proof,
+        metadata: &ProofMetadata,
+        cfg: &ProofConfig,
+        init_cfg: &InitConfig,
+        labels: &mut S,
+    ) -> Result<(), Error>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin,
+    {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let (checker, mut k3_indices) = self.prepare(proof, metadata, cfg, init_cfg)?;
+        k3_indices.sort_unstable();
+
+        for index in k3_indices {
+            let mut label = [0u8; LABEL_SIZE];
+            let read = async {
+                labels
+                    .seek(std::io::SeekFrom::Start(index * LABEL_SIZE as u64))
+                    .await?;
+                labels.read_exact(&mut label).await
+            };
+            read.await
+                .map_err(|source| Error::ReadingLabel { index, source })?;
+            checker.check_label(index, label)?;
         }
+        Ok(())
+    }
 
-        let indices_unpacked = decompress_indexes(&proof.indices, bits_per_index)
-            .take(cfg.k2 as usize)
-            .collect_vec();
-        let commitment = calc_commitment(&metadata.node_id, &metadata.commitment_atx_id);
-        let cipher = AesCipher::new(&challenge, nonce_group, proof.pow);
-        let lazy_cipher = AesCipher::new_lazy(&challenge, proof.nonce, nonce_group, proof.pow);
+    /// Runs the checks of the proof that don't need labels:
+    /// metadata, PoW and the number of indices.
+    ///
+    /// Returns the checker for labels and the K3 indices to check.
+    fn prepare(
+        &self,
+        proof: &Proof,
+        metadata: &ProofMetadata,
+        cfg: &ProofConfig,
+        init_cfg: &InitConfig,
+    ) -> Result<(IndexChecker, Vec<u64>), Error> {
+        verify_metadata(metadata, init_cfg)?;
+        let context = PostContext::for_proof(metadata, cfg, init_cfg)?;
+        self.prepare_with_context(proof, metadata, &context, init_cfg)
+    }
+
+    /// Like [Verifier::prepare] with the values derived from the metadata
+    /// and config in the `context`.
+    fn prepare_with_context(
+        &self,
+        proof: &Proof,
+        metadata: &ProofMetadata,
+        context: &PostContext,
+        init_cfg: &InitConfig,
+    ) -> Result<(IndexChecker, Vec<u64>), Error> {
+        let cfg = context.cfg();
+        let challenge = metadata.challenge;
+        let pow_difficulty = *context.pow_difficulty();
 
-        let difficulty = proving_difficulty(cfg.k1, num_labels).map_err(Error::InvalidNumLabels)?;
-        let (difficulty_msb, difficulty_lsb) = Prover8_56::split_difficulty(difficulty);
+        // The labels are then checked with the ciphers of this group and PoW,
+        // binding the nonce, the PoW and the indices together.
+        self.verify_nonce_pow(proof, &challenge, &metadata.node_id, &pow_difficulty)?;
 
-        let output_index = (proof.nonce % NONCES_PER_AES) as usize;
+        // Verify the number of indices against K2
+        let indices_unpacked = unpack_indices(proof, context)?;
+        let checker = IndexChecker::new(proof, metadata, context.difficulty(), init_cfg);
 
         // Select K3 indices
         let seed = &[
@@ -186,79 +446,271 @@ impl Verifier {
             &proof.pow.to_le_bytes(),
         ];
 
-        let k3_indices = RandomValuesIterator::new(indices_unpacked, seed).take(cfg.k3 as usize);
-
-        k3_indices.into_iter().try_for_each(|index| {
-            let mut output = [0u8; 16];
-            let label = generate_label(&commitment, init_cfg.scrypt, index);
-            cipher
-                .aes
-                .encrypt_block_b2b(&label.into(), (&mut output).into());
+        let k3_indices = RandomValuesIterator::new(indices_unpacked, seed)
+            .take(cfg.k3 as usize)
+            .collect_vec();
+        Ok((checker, k3_indices))
+    }
 
-            let msb = output[output_index];
-            match msb.cmp(&difficulty_msb) {
-                Ordering::Less => {
-                    // valid
-                }
-                Ordering::Greater => {
-                    return Err(Error::InvalidMsb {
-                        index,
-                        msb,
-                        difficulty_msb,
-                        label,
-                    })
-                }
-                Ordering::Equal => {
-                    // Need to check LSB
-                    let mut output = [0u64; 2];
-                    lazy_cipher.aes.encrypt_block_b2b(
-                        &label.into(),
-                        bytemuck::cast_slice_mut(&mut output).into(),
-                    );
-                    let lsb = output[0].to_le() & 0x00ff_ffff_ffff_ffff;
-                    if lsb >= difficulty_lsb {
-                        return Err(Error::InvalidLsb {
-                            index,
-                            lsb,
-                            difficulty_lsb,
-                            label,
-                        });
-                    }
+    /// Verify only the PoW of many proofs, i.e. to cheaply reject spam before
+    /// the (much more expensive) verification of the labels.
+    ///
+    /// Returns whether the PoW is valid for each of the proofs, in order.
+    /// Proofs are checked in parallel, sharing the PoW verifier (and its RandomX dataset).
+    pub fn verify_pow_batch(
+        &self,
+        proofs: &[(Proof, PostMetadata)],
+        challenge: &[u8; 32],
+        cfg: &ProofConfig,
+    ) -> Vec<bool> {
+        proofs
+            .par_iter()
+            .map(|(proof, metadata)| {
+                if metadata.num_units == 0 {
+                    return false;
                 }
-            }
-            Ok(())
+                let pow_difficulty = scale_pow_difficulty(&cfg.pow_difficulty, metadata.num_units);
+                self.verify_nonce_pow(proof, challenge, &metadata.node_id, &pow_difficulty)
+                    .is_ok()
+            })
+            .collect()
+    }
+
+    /// Verify that the nonce and the PoW of the proof agree:
+    /// the PoW must be valid for the nonce group of the nonce (`nonce / 16`).
+    fn verify_nonce_pow(
+        &self,
+        proof: &Proof,
+        challenge: &[u8; 32],
+        node_id: &[u8; 32],
+        pow_difficulty: &[u8; 32],
+    ) -> Result<(), Error> {
+        let nonce_group = nonce_group(proof)?;
+        debug!(
+            "verifying K2 pow for nonce group: {nonce_group} with difficulty: {pow_difficulty:x?}"
+        );
+        self.pow_verifier.verify(
+            proof.pow,
+            nonce_group,
+            &challenge[..8].try_into().unwrap(),
+            pow_difficulty,
+            node_id,
+        )?;
+        Ok(())
+    }
+
+    /// Verify a random sample of the proven indices.
+    ///
+    /// Picks `sample` indices (at most K2) out of the proof and checks if they
+    /// satisfy the difficulty. Returns the checked indices on success.
+    ///
+    /// A failure means that the proof is invalid, but passing it only gives
+    /// probabilistic assurance. The PoW is NOT verified. It's meant for a cheap
+    /// pre-screening before the full [verification](Verifier::verify).
+    pub fn verify_sample<R: rand::Rng + ?Sized>(
+        &self,
+        proof: &Proof,
+        metadata: &ProofMetadata,
+        cfg: &ProofConfig,
+        init_cfg: &InitConfig,
+        sample: usize,
+        rng: &mut R,
+    ) -> Result<Vec<u64>, Error> {
+        verify_metadata(metadata, init_cfg)?;
+        let context = PostContext::for_proof(metadata, cfg, init_cfg)?;
+        nonce_group(proof)?;
+
+        let indices = unpack_indices(proof, &context)?;
+        let checker = IndexChecker::new(proof, metadata, context.difficulty(), init_cfg);
+
+        let sampled = rand::seq::index::sample(rng, indices.len(), sample.min(indices.len()))
+            .into_iter()
+            .map(|i| indices[i])
+            .collect_vec();
+        checker.check_all(&sampled)?;
+        Ok(sampled)
+    }
+}
+
+fn nonce_group(proof: &Proof) -> Result<u8, Error> {
+    let nonce_group = proof.nonce_group();
+    nonce_group
+        .try_into()
+        .map_err(|_| Error::NonceGroupOutOfBounds(nonce_group))
+}
+
+/// Decompress the proven indices, verifying that there are exactly K2 of them.
+fn unpack_indices(proof: &Proof, context: &PostContext) -> Result<Vec<u64>, Error> {
+    let cfg = context.cfg();
+    let num_labels = context.num_labels();
+    let bits_per_index = context.required_bits();
+    let expected = expected_indices_bytes(bits_per_index, cfg.k2);
+    if proof.indices.len() != expected {
+        return Err(Error::InvalidIndicesLen {
+            expected,
+            got: proof.indices.len(),
+        });
+    }
+
+    let indices = (0..cfg.k2 as usize)
+        .map(|pos| decompress_index(&proof.indices, bits_per_index, pos))
+        .collect::<Option<Vec<_>>>()
+        .ok_or(Error::InvalidIndicesLen {
+            expected,
+            got: proof.indices.len(),
+        })?;
+    // Indices past the data would let a prover pick from more labels than it holds.
+    if let Some(&index) = indices.iter().find(|&&index| index >= num_labels) {
+        return Err(Error::IndexOutOfRange { index, num_labels });
+    }
+    Ok(indices)
+}
+
+/// Checks if the labels pointed by indices satisfy the difficulty for the proof's nonce.
+struct IndexChecker {
+    commitment: [u8; 32],
+    scrypt: ScryptParams,
+    labels: LabelChecker,
+}
+
+impl IndexChecker {
+    fn new(
+        proof: &Proof,
+        metadata: &ProofMetadata,
+        difficulty: u64,
+        init_cfg: &InitConfig,
+    ) -> Self {
+        Self {
+            commitment: metadata.commitment(),
+            scrypt: init_cfg.scrypt,
+            labels: LabelChecker::new(&metadata.challenge, proof.nonce, proof.pow, difficulty),
+        }
+    }
+
+    fn margin(&self, index: u64) -> Result<IndexMargin, Error> {
+        let label = generate_label(&self.commitment, self.scrypt, index);
+        self.check_label(index, label)?;
+        Ok(IndexMargin {
+            index,
+            value: self.labels.value(&label),
+            difficulty: self.labels.difficulty(),
         })
     }
+
+    /// Check the labels of all `indices`, generated from the commitment.
+    fn check_all(&self, indices: &[u64]) -> Result<(), Error> {
+        self.check_all_with(indices, |index| {
+            Ok(generate_label(&self.commitment, self.scrypt, index))
+        })
+    }
+
+    /// Check the labels of `indices` obtained with `label_fn`.
+    ///
+    /// The labels are obtained and checked in batches of [AES_BATCH],
+    /// encrypted at once, stopping at the first batch with an invalid label.
+    fn check_all_with<F>(&self, indices: &[u64], label_fn: F) -> Result<(), Error>
+    where
+        F: Fn(u64) -> Result<[u8; LABEL_SIZE], Error>,
+    {
+        let mut labels = [[0u8; LABEL_SIZE]; AES_BATCH];
+        for chunk in indices.chunks(AES_BATCH) {
+            for (label, &index) in labels.iter_mut().zip(chunk) {
+                *label = label_fn(index)?;
+            }
+            self.labels
+                .check_batch(&labels[..chunk.len()])
+                .map_err(|(i, err)| label_error(chunk[i], labels[i], err))?;
+        }
+        Ok(())
+    }
+
+    /// Check the labels of all `indices` obtained with `label_fn`, also after one fails.
+    fn check_every_with<F>(&self, indices: &[u64], label_fn: F) -> Result<(), Error>
+    where
+        F: Fn(u64) -> Result<[u8; LABEL_SIZE], Error>,
+    {
+        let mut failures = LabelFailures::default();
+        for &index in indices {
+            match self.check_label(index, label_fn(index)?) {
+                Ok(()) => failures.passed += 1,
+                Err(err) => failures.fail(err),
+            }
+        }
+        failures.finish()
+    }
+
+    fn check_label(&self, index: u64, label: [u8; LABEL_SIZE]) -> Result<(), Error> {
+        self.labels
+            .check(&label)
+            .map_err(|err| label_error(index, label, err))
+    }
 }
 
-fn next_multiple_of(n: usize, mult: usize) -> usize {
-    let r = n % mult;
-    if r == 0 {
-        n
-    } else {
-        n + (mult - r)
+/// Tracks the checked labels, to fail with [Error::ChallengeMismatch] if none was valid.
+#[derive(Default)]
+struct LabelFailures {
+    passed: usize,
+    failed: usize,
+    first: Option<Error>,
+}
+
+impl LabelFailures {
+    fn fail(&mut self, err: Error) {
+        self.failed += 1;
+        self.first.get_or_insert(err);
+    }
+
+    /// A single failed label is reported as such, it's not a signal of another challenge.
+    fn finish(self) -> Result<(), Error> {
+        match self.first {
+            None => Ok(()),
+            Some(_) if self.passed == 0 && self.failed > 1 => Err(Error::ChallengeMismatch {
+                checked: self.failed,
+            }),
+            Some(err) => Err(err),
+        }
     }
 }
 
-/// Calculate the expected length of compressed indices.
-fn expected_indices_bytes(required_bits: usize, k2: u32) -> usize {
-    let total_bits = required_bits * k2 as usize;
-    next_multiple_of(total_bits, 8) / 8
+fn label_error(index: u64, label: [u8; LABEL_SIZE], err: LabelError) -> Error {
+    match err {
+        LabelError::InvalidMsb {
+            msb,
+            difficulty_msb,
+        } => Error::InvalidMsb {
+            index,
+            msb,
+            difficulty_msb,
+            label,
+        },
+        LabelError::InvalidLsb {
+            lsb,
+            difficulty_lsb,
+        } => Error::InvalidLsb {
+            index,
+            lsb,
+            difficulty_lsb,
+            label,
+        },
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::borrow::Cow;
+    use std::{borrow::Cow, collections::HashMap};
 
     use crate::{
         config::{InitConfig, ProofConfig, ScryptParams},
-        metadata::ProofMetadata,
+        merkle::MerkleTree,
+        metadata::{PostMetadata, ProofMetadata},
         pow::MockPowVerifier,
         prove::Proof,
         verification::Error,
+        verify_core::LabelChecker,
     };
 
-    use super::{expected_indices_bytes, next_multiple_of, Verifier};
+    use super::Verifier;
+    use crate::verify_core::{expected_indices_bytes, next_multiple_of};
 
     #[test]
     fn test_next_mutliple_of() {
@@ -380,6 +832,454 @@ mod tests {
         }
     }
 
+    #[test]
+    fn reject_index_out_of_range() {
+        let cfg = ProofConfig {
+            k1: 3,
+            k2: 2,
+            k3: 2,
+            pow_difficulty: [0xFF; 32],
+        };
+        let init_cfg = InitConfig {
+            min_num_units: 1,
+            max_num_units: 10,
+            labels_per_unit: 100,
+            scrypt: ScryptParams::new(2, 1, 1),
+        };
+        let fake_metadata = ProofMetadata {
+            node_id: [0; 32],
+            commitment_atx_id: [0; 32],
+            challenge: [0; 32],
+            num_units: 1,
+        };
+        let mut pow_verifier = Box::new(MockPowVerifier::new());
+        pow_verifier
+            .expect_verify()
+            .returning(|_, _, _, _, _| Ok(()));
+        let verifier = Verifier::new(pow_verifier);
+        // 7 bits per index are enough to encode indices up to 127
+        let proof = Proof::new(0, &[7, 120], 100, 0);
+        let result = verifier.verify(&proof, &fake_metadata, &cfg, &init_cfg);
+        assert!(matches!(
+            result,
+            Err(Error::IndexOutOfRange {
+                index: 120,
+                num_labels: 100
+            })
+        ));
+    }
+
+    #[test]
+    fn sample_rejects_invalid_indices_len() {
+        let cfg = ProofConfig {
+            k1: 10,
+            k2: 10,
+            k3: 10,
+            pow_difficulty: [0xFF; 32],
+        };
+        let init_cfg = InitConfig {
+            min_num_units: 1,
+            max_num_units: 10,
+            labels_per_unit: 2048,
+            scrypt: ScryptParams::new(2, 1, 1),
+        };
+        let fake_metadata = ProofMetadata {
+            node_id: [0; 32],
+            commitment_atx_id: [0; 32],
+            challenge: [0; 32],
+            num_units: 10,
+        };
+        let verifier = Verifier::new(Box::new(MockPowVerifier::new()));
+        let proof = Proof {
+            nonce: 0,
+            indices: Cow::from(vec![1, 2, 3]),
+            pow: 0,
+        };
+        let result = verifier.verify_sample(
+            &proof,
+            &fake_metadata,
+            &cfg,
+            &init_cfg,
+            3,
+            &mut rand::thread_rng(),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::InvalidIndicesLen {
+                expected: _,
+                got: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn reject_zero_labels() {
+        let cfg = ProofConfig {
+            k1: 3,
+            k2: 3,
+            k3: 3,
+            pow_difficulty: [0xFF; 32],
+        };
+        let init_cfg = InitConfig {
+            min_num_units: 0,
+            max_num_units: 10,
+            labels_per_unit: 2048,
+            scrypt: ScryptParams::new(2, 1, 1),
+        };
+        let metadata = ProofMetadata {
+            node_id: [0; 32],
+            commitment_atx_id: [0; 32],
+            challenge: [0; 32],
+            num_units: 0,
+        };
+        // Fails before verifying the PoW
+        let verifier = Verifier::new(Box::new(MockPowVerifier::new()));
+        let proof = Proof {
+            nonce: 0,
+            indices: Cow::from(vec![]),
+            pow: 0,
+        };
+        let result = verifier.verify(&proof, &metadata, &cfg, &init_cfg);
+        assert!(matches!(result, Err(Error::InvalidNumLabels(_))));
+        let result = verifier.verify_sample(
+            &proof,
+            &metadata,
+            &cfg,
+            &init_cfg,
+            3,
+            &mut rand::thread_rng(),
+        );
+        assert!(matches!(result, Err(Error::InvalidNumLabels(_))));
+    }
+
+    #[test]
+    fn verify_with_labels() {
+        let cfg = ProofConfig {
+            k1: 1024,
+            k2: 4,
+            k3: 4,
+            pow_difficulty: [0xFF; 32],
+        };
+        let init_cfg = InitConfig {
+            min_num_units: 1,
+            max_num_units: 10,
+            labels_per_unit: 2048,
+            scrypt: ScryptParams::new(2, 1, 1),
+        };
+        let metadata = ProofMetadata {
+            node_id: [0; 32],
+            commitment_atx_id: [0; 32],
+            challenge: [3; 32],
+            num_units: 1,
+        };
+        let mut pow_verifier = Box::new(MockPowVerifier::new());
+        pow_verifier
+            .expect_verify()
+            .returning(|_, _, _, _, _| Ok(()));
+        let verifier = Verifier::new(pow_verifier);
+
+        // Find labels satisfying the difficulty (about every second one does).
+        let difficulty = crate::difficulty::proving_difficulty(cfg.k1, 2048).unwrap();
+        let checker = LabelChecker::new(&metadata.challenge, 7, 5, difficulty);
+        let mut candidates = (0u64..).map(|i| {
+            let mut label = [0u8; 16];
+            label[..8].copy_from_slice(&i.to_le_bytes());
+            label
+        });
+        let good = candidates
+            .by_ref()
+            .filter(|label| checker.check(label).is_ok())
+            .take(4)
+            .collect::<Vec<_>>();
+        let bad = candidates
+            .find(|label| checker.check(label).is_err())
+            .unwrap();
+
+        let indices = [3, 1000, 12, 2047];
+        let proof = Proof::new(7, &indices, 2048, 5);
+        let mut labels = indices
+            .into_iter()
+            .zip(good.iter().copied())
+            .collect::<HashMap<_, _>>();
+        verifier
+            .verify_with_labels(&proof, &metadata, &cfg, &init_cfg, &labels)
+            .unwrap();
+
+        labels.insert(12, bad);
+        let result = verifier.verify_with_labels(&proof, &metadata, &cfg, &init_cfg, &labels);
+        assert!(matches!(
+            result,
+            Err(Error::InvalidMsb { index: 12, .. } | Error::InvalidLsb { index: 12, .. })
+        ));
+
+        labels.remove(&1000);
+        labels.insert(12, good[2]);
+        let result = verifier.verify_with_labels(&proof, &metadata, &cfg, &init_cfg, &labels);
+        assert!(matches!(result, Err(Error::MissingLabel { index: 1000 })));
+
+        // The same labels produced by a function
+        let label_of = |index| match index {
+            3 => good[0],
+            1000 => good[1],
+            12 => good[2],
+            2047 => good[3],
+            _ => panic!("label {index} isn't proven"),
+        };
+        verifier
+            .verify_with_label_fn(&proof, &metadata, &cfg, &init_cfg, label_of)
+            .unwrap();
+        let result = verifier.verify_with_label_fn(&proof, &metadata, &cfg, &init_cfg, |index| {
+            if index == 2047 {
+                bad
+            } else {
+                label_of(index)
+            }
+        });
+        assert!(matches!(
+            result,
+            Err(Error::InvalidMsb { index: 2047, .. } | Error::InvalidLsb { index: 2047, .. })
+        ));
+    }
+
+    #[test]
+    fn hinting_at_challenge_mismatch() {
+        let cfg = ProofConfig {
+            k1: 1024,
+            k2: 4,
+            k3: 4,
+            pow_difficulty: [0xFF; 32],
+        };
+        let init_cfg = InitConfig {
+            min_num_units: 1,
+            max_num_units: 10,
+            labels_per_unit: 2048,
+            scrypt: ScryptParams::new(2, 1, 1),
+        };
+        let metadata = ProofMetadata {
+            node_id: [0; 32],
+            commitment_atx_id: [0; 32],
+            challenge: [3; 32],
+            num_units: 1,
+        };
+        let other = ProofMetadata {
+            challenge: [4; 32],
+            ..metadata
+        };
+        let mut pow_verifier = Box::new(MockPowVerifier::new());
+        pow_verifier
+            .expect_verify()
+            .returning(|_, _, _, _, _| Ok(()));
+        let verifier = Verifier::new(pow_verifier);
+
+        // Labels valid for the challenge of the proof and invalid for the other one.
+        let difficulty = crate::difficulty::proving_difficulty(cfg.k1, 2048).unwrap();
+        let checker = LabelChecker::new(&metadata.challenge, 7, 5, difficulty);
+        let other_checker = LabelChecker::new(&other.challenge, 7, 5, difficulty);
+        let mut candidates = (0u64..).map(|i| {
+            let mut label = [0u8; 16];
+            label[..8].copy_from_slice(&i.to_le_bytes());
+            label
+        });
+        let good = candidates
+            .by_ref()
+            .filter(|label| checker.check(label).is_ok() && other_checker.check(label).is_err())
+            .take(4)
+            .collect::<Vec<_>>();
+        let bad = candidates
+            .find(|label| checker.check(label).is_err())
+            .unwrap();
+
+        let indices = [3, 1000, 12, 2047];
+        let proof = Proof::new(7, &indices, 2048, 5);
+        let mut labels = indices
+            .into_iter()
+            .zip(good.iter().copied())
+            .collect::<HashMap<_, _>>();
+        verifier
+            .diagnose_with_label_fn(&proof, &metadata, &cfg, &init_cfg, |index| labels[&index])
+            .unwrap();
+
+        // Verifying stops at the first invalid label, diagnosing checks all of them.
+        let result = verifier.verify_with_labels(&proof, &other, &cfg, &init_cfg, &labels);
+        assert!(matches!(
+            result,
+            Err(Error::InvalidMsb { .. } | Error::InvalidLsb { .. })
+        ));
+        let result = verifier
+            .diagnose_with_label_fn(&proof, &other, &cfg, &init_cfg, |index| labels[&index]);
+        assert!(matches!(
+            result,
+            Err(Error::ChallengeMismatch { checked: 4 })
+        ));
+        // A single checked label isn't a hint of another challenge
+        let single = ProofConfig { k3: 1, ..cfg };
+        let result = verifier
+            .diagnose_with_label_fn(&proof, &other, &single, &init_cfg, |index| labels[&index]);
+        assert!(matches!(
+            result,
+            Err(Error::InvalidMsb { .. } | Error::InvalidLsb { .. })
+        ));
+
+        // Only some labels are invalid
+        labels.insert(1000, bad);
+        let result = verifier
+            .diagnose_with_label_fn(&proof, &metadata, &cfg, &init_cfg, |index| labels[&index]);
+        assert!(matches!(
+            result,
+            Err(Error::InvalidMsb { index: 1000, .. } | Error::InvalidLsb { index: 1000, .. })
+        ));
+    }
+
+    #[test]
+    fn verify_with_merkle_proofs() {
+        let cfg = ProofConfig {
+            k1: 1024,
+            k2: 4,
+            k3: 4,
+            pow_difficulty: [0xFF; 32],
+        };
+        let init_cfg = InitConfig {
+            min_num_units: 1,
+            max_num_units: 10,
+            labels_per_unit: 2048,
+            scrypt: ScryptParams::new(2, 1, 1),
+        };
+        let metadata = ProofMetadata {
+            node_id: [0; 32],
+            commitment_atx_id: [0; 32],
+            challenge: [3; 32],
+            num_units: 1,
+        };
+        let mut pow_verifier = Box::new(MockPowVerifier::new());
+        pow_verifier
+            .expect_verify()
+            .returning(|_, _, _, _, _| Ok(()));
+        let verifier = Verifier::new(pow_verifier);
+
+        let difficulty = crate::difficulty::proving_difficulty(cfg.k1, 2048).unwrap();
+        let checker = LabelChecker::new(&metadata.challenge, 7, 5, difficulty);
+        let mut all_labels = (0u64..2048)
+            .map(|i| {
+                let mut label = [0u8; 16];
+                label[..8].copy_from_slice(&i.to_le_bytes());
+                label
+            })
+            .collect::<Vec<_>>();
+        let indices = all_labels
+            .iter()
+            .enumerate()
+            .filter(|(_, label)| checker.check(label).is_ok())
+            .map(|(index, _)| index as u64)
+            .take(4)
+            .collect::<Vec<_>>();
+        let bad = all_labels
+            .iter()
+            .position(|label| checker.check(label).is_err())
+            .unwrap();
+        let proof = Proof::new(7, &indices, 2048, 5);
+
+        let tree = MerkleTree::new(&all_labels);
+        let with_proofs = |tree: &MerkleTree, labels: &[[u8; 16]]| {
+            indices
+                .iter()
+                .map(|&index| (index, (labels[index as usize], tree.proof(index).unwrap())))
+                .collect::<HashMap<_, _>>()
+        };
+        let mut labels = with_proofs(&tree, &all_labels);
+        verifier
+            .verify_with_merkle_proofs(&proof, &metadata, &cfg, &init_cfg, &tree.root(), &labels)
+            .unwrap();
+
+        // A label not in the tree
+        let index = indices[1];
+        labels.get_mut(&index).unwrap().0 = all_labels[bad];
+        let result = verifier.verify_with_merkle_proofs(
+            &proof,
+            &metadata,
+            &cfg,
+            &init_cfg,
+            &tree.root(),
+            &labels,
+        );
+        assert!(matches!(result, Err(Error::InvalidMerkleProof { index: i }) if i == index));
+
+        // A label in the tree not satisfying the difficulty
+        all_labels[index as usize] = all_labels[bad];
+        let tree = MerkleTree::new(&all_labels);
+        let labels = with_proofs(&tree, &all_labels);
+        let result = verifier.verify_with_merkle_proofs(
+            &proof,
+            &metadata,
+            &cfg,
+            &init_cfg,
+            &tree.root(),
+            &labels,
+        );
+        assert!(matches!(
+            result,
+            Err(Error::InvalidMsb { index: i, .. } | Error::InvalidLsb { index: i, .. }) if i == index
+        ));
+
+        let mut labels = labels;
+        labels.remove(&indices[2]);
+        let result = verifier.verify_with_merkle_proofs(
+            &proof,
+            &metadata,
+            &cfg,
+            &init_cfg,
+            &tree.root(),
+            &labels,
+        );
+        assert!(matches!(result, Err(Error::MissingLabel { index: i }) if i == indices[2]));
+    }
+
+    #[test]
+    fn verify_pow_batch() {
+        let cfg = ProofConfig {
+            k1: 3,
+            k2: 3,
+            k3: 3,
+            pow_difficulty: [0xFF; 32],
+        };
+        let mut pow_verifier = Box::new(MockPowVerifier::new());
+        pow_verifier
+            .expect_verify()
+            .returning(|pow, _, _, _, _| match pow {
+                1 => Ok(()),
+                _ => Err(crate::pow::Error::InvalidPoW),
+            });
+        let verifier = Verifier::new(pow_verifier);
+
+        let metadata = PostMetadata {
+            num_units: 4,
+            ..Default::default()
+        };
+        let proof = |nonce, pow| Proof {
+            nonce,
+            indices: Cow::from(vec![]),
+            pow,
+        };
+        let proofs = [
+            (proof(0, 1), metadata),
+            (proof(0, 2), metadata),
+            // nonce group out of bounds
+            (proof(16 * 256, 1), metadata),
+            // no units
+            (
+                proof(0, 1),
+                PostMetadata {
+                    num_units: 0,
+                    ..metadata
+                },
+            ),
+            (proof(17, 1), metadata),
+        ];
+        assert_eq!(
+            vec![true, false, false, false, true],
+            verifier.verify_pow_batch(&proofs, &[0; 32], &cfg)
+        );
+    }
+
     #[test]
     fn verify_metadata() {
         let valid_meta = ProofMetadata {
@@ -1,14 +1,63 @@
-use std::sync::atomic::AtomicBool;
+use std::collections::{HashMap, HashSet};
+use std::sync::{atomic::AtomicBool, mpsc, Arc};
 
 use post::{
-    config::{InitConfig, ScryptParams},
+    config::{InitConfig, ProofConfig, ScryptParams},
+    context::PostContext,
     initialize::{CpuInitializer, Initialize},
-    metadata::ProofMetadata,
-    pow::randomx::{PoW, RandomXFlag},
-    prove::generate_proof,
-    verification::Verifier,
+    metadata::{PostMetadata, ProofMetadata},
+    pow::{
+        randomx::{PoW, RandomXFlag},
+        PowAlgorithm,
+    },
+    prove::{
+        analyze, contested_labels, generate_all_proofs, generate_proof, generate_proof_select,
+        generate_proof_with_datadirs, generate_proof_with_events, generate_proof_with_metadata,
+        generate_proof_with_options, generate_proof_with_report, generate_spot_proof,
+        threads_or_available, Candidate, Proof, ProveError, ProvingEvent, ProvingObserver,
+        ProvingOptions, ProvingParams, ProvingSession, StopCondition, StopReason,
+    },
+    reader::ReaderConfig,
+    verification::{Error, Verifier},
+    verify_core::LabelChecker,
 };
-use tempfile::tempdir;
+use tempfile::{tempdir, TempDir};
+
+/// Initializes 4 units of POS data for the node ID `[77; 32]`.
+fn init_test_data() -> (TempDir, PostMetadata, ProofConfig, InitConfig) {
+    init_test_data_with(ScryptParams::new(2, 1, 1), &[0u8; 32])
+}
+
+fn init_test_data_with(
+    scrypt: ScryptParams,
+    commitment_atx_id: &[u8; 32],
+) -> (TempDir, PostMetadata, ProofConfig, InitConfig) {
+    let datadir = tempdir().unwrap();
+    let cfg = ProofConfig {
+        k1: 23,
+        k2: 32,
+        k3: 10,
+        pow_difficulty: [0xFF; 32],
+    };
+    let init_cfg = InitConfig {
+        min_num_units: 1,
+        max_num_units: 1000,
+        labels_per_unit: 256 * 16,
+        scrypt,
+    };
+    let metadata = CpuInitializer::new(init_cfg.scrypt)
+        .initialize(
+            datadir.path(),
+            &[77; 32],
+            commitment_atx_id,
+            init_cfg.labels_per_unit,
+            4,
+            1000,
+            None,
+        )
+        .unwrap();
+    (datadir, metadata, cfg, init_cfg)
+}
 
 #[test]
 fn test_generate_and_verify() {
@@ -61,6 +110,141 @@ fn test_generate_and_verify() {
         .expect_err("proof should be invalid");
 }
 
+#[test]
+fn test_verify_sample() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, init_cfg) = init_test_data();
+
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let stop = AtomicBool::new(false);
+    let proof = generate_proof(datadir.path(), challenge, cfg, 32, 1, pow_flags, stop).unwrap();
+
+    // Spot check some of the indices
+    let metadata = ProofMetadata::new(metadata, *challenge);
+    let verifier = Verifier::new(Box::new(PoW::new(pow_flags).unwrap()));
+    let checked = verifier
+        .verify_sample(
+            &proof,
+            &metadata,
+            &cfg,
+            &init_cfg,
+            5,
+            &mut rand::thread_rng(),
+        )
+        .expect("sample should be valid");
+    assert_eq!(5, checked.len());
+
+    let mut invalid_proof = proof;
+    invalid_proof.pow -= 1;
+    verifier
+        .verify_sample(
+            &invalid_proof,
+            &metadata,
+            &cfg,
+            &init_cfg,
+            cfg.k2 as usize,
+            &mut rand::thread_rng(),
+        )
+        .expect_err("sample should be invalid");
+}
+
+#[test]
+fn test_verify_with_margins() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, init_cfg) = init_test_data();
+
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let stop = AtomicBool::new(false);
+    let proof = generate_proof(datadir.path(), challenge, cfg, 32, 1, pow_flags, stop).unwrap();
+
+    let metadata = ProofMetadata::new(metadata, *challenge);
+    let verifier = Verifier::new(Box::new(PoW::new(pow_flags).unwrap()));
+    let margins = verifier
+        .verify_with_margins(&proof, &metadata, &cfg, &init_cfg)
+        .expect("proof should be valid");
+    assert_eq!(cfg.k3 as usize, margins.len());
+    for margin in margins {
+        assert!(margin.value < margin.difficulty);
+        assert!((0.0..1.0).contains(&margin.ratio()));
+    }
+
+    let mut invalid_proof = proof;
+    invalid_proof.pow -= 1;
+    verifier
+        .verify_with_margins(&invalid_proof, &metadata, &cfg, &init_cfg)
+        .expect_err("proof should be invalid");
+}
+
+#[test]
+fn test_generate_and_verify_with_context() {
+    let (datadir, metadata, cfg, init_cfg) = init_test_data();
+
+    let context = PostContext::new(&metadata, &cfg).unwrap();
+    assert_eq!(metadata.total_labels(), context.num_labels());
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let pow = Arc::new(PoW::new(pow_flags).unwrap());
+    let options = ProvingOptions {
+        threads: Some(1),
+        context: Some(context),
+        pow_prover: Some(pow.clone()),
+        ..Default::default()
+    };
+    // The context is reused over many proofs.
+    let proofs = [
+        b"hello world, challenge me!!!!!!!",
+        b"hello world, challenge me again!",
+    ]
+    .map(|challenge| {
+        let proof = generate_proof_with_options(
+            datadir.path(),
+            challenge,
+            cfg,
+            32,
+            AtomicBool::new(false),
+            options.clone(),
+        )
+        .unwrap();
+        (proof, ProofMetadata::new(metadata, *challenge))
+    });
+
+    let verifier = Verifier::new(Box::new(pow));
+    for (proof, proof_metadata) in &proofs {
+        verifier
+            .verify_with_context(proof, proof_metadata, &context, &init_cfg)
+            .expect("proof should be valid");
+        verifier
+            .verify(proof, proof_metadata, &cfg, &init_cfg)
+            .expect("proof should be valid");
+    }
+
+    // A context of another number of units is rejected.
+    let (proof, proof_metadata) = &proofs[0];
+    let other = PostContext::new(
+        &PostMetadata {
+            num_units: 5,
+            ..metadata
+        },
+        &cfg,
+    )
+    .unwrap();
+    let result = verifier.verify_with_context(proof, proof_metadata, &other, &init_cfg);
+    assert!(matches!(result, Err(Error::ContextMismatch(_))));
+
+    let options = ProvingOptions {
+        context: Some(other),
+        ..options
+    };
+    generate_proof_with_options(
+        datadir.path(),
+        challenge,
+        cfg,
+        32,
+        AtomicBool::new(false),
+        options,
+    )
+    .expect_err("proving with a context of other metadata should fail");
+}
+
 #[test]
 /// With small unit size, the difficulty MSB != 0 which
 /// triggers different conditionals in the verifier.
@@ -113,3 +297,1314 @@ fn test_generate_and_verify_difficulty_msb_not_zero() {
         .verify(&invalid_proof, &metadata, &cfg, &init_cfg)
         .expect_err("proof should be invalid");
 }
+
+#[test]
+fn test_generate_with_nonstandard_target_indices() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, init_cfg) = init_test_data();
+
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let options = ProvingOptions {
+        threads: Some(1),
+        target_indices: Some(cfg.k2 / 2),
+        ..Default::default()
+    };
+    let stop = AtomicBool::new(false);
+    let proof =
+        generate_proof_with_options(datadir.path(), challenge, cfg, 32, stop, options).unwrap();
+
+    // The proof doesn't have k2 indices so it's not valid under the protocol rules
+    let metadata = ProofMetadata::new(metadata, *challenge);
+    let verifier = Verifier::new(Box::new(PoW::new(pow_flags).unwrap()));
+    let result = verifier.verify(&proof, &metadata, &cfg, &init_cfg);
+    assert!(matches!(result, Err(Error::InvalidIndicesLen { .. })));
+}
+
+#[test]
+fn test_generate_with_custom_params() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, _) = init_test_data();
+
+    // Every label satisfies the difficulty, the first K2 labels make a proof.
+    let options = ProvingOptions {
+        threads: Some(1),
+        params: Some(ProvingParams {
+            difficulty: u64::MAX,
+            pow_difficulty: [0xFF; 32],
+        }),
+        skip_pow: true,
+        ..Default::default()
+    };
+    let proof = generate_proof_with_options(
+        datadir.path(),
+        challenge,
+        cfg,
+        16,
+        AtomicBool::new(false),
+        options,
+    )
+    .unwrap();
+    assert!(proof.nonce < 16);
+    let indices = proof
+        .unpack_indices(metadata.total_labels(), cfg.k2)
+        .unwrap();
+    assert_eq!((0..cfg.k2 as u64).collect::<Vec<_>>(), indices);
+}
+
+#[test]
+fn test_generate_select_lowest_pow() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, init_cfg) = init_test_data();
+
+    // Share the PoW between proving and verifying
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let pow = Arc::new(PoW::new(pow_flags).unwrap());
+    let options = ProvingOptions {
+        threads: Some(1),
+        pow_prover: Some(pow.clone()),
+        ..Default::default()
+    };
+    let stop = AtomicBool::new(false);
+    let lowest_pow = std::sync::Mutex::new(None);
+    let proof = generate_proof_select(
+        datadir.path(),
+        challenge,
+        cfg,
+        32,
+        stop,
+        options,
+        |candidates: &[Candidate]| {
+            let (pos, best) = candidates
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, c)| c.pow)
+                .unwrap();
+            *lowest_pow.lock().unwrap() = Some(best.pow);
+            pos
+        },
+    )
+    .unwrap();
+    assert_eq!(Some(proof.pow), *lowest_pow.lock().unwrap());
+
+    let metadata = ProofMetadata::new(metadata, *challenge);
+    let verifier = Verifier::new(Box::new(pow));
+    verifier
+        .verify(&proof, &metadata, &cfg, &init_cfg)
+        .expect("proof should be valid");
+}
+
+#[test]
+fn test_generate_select_stops_when_all_nonces_are_candidates() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, _) = init_test_data();
+
+    // Every nonce reaches a single index quickly.
+    let observer = Arc::new(RecordingObserver::default());
+    let options = ProvingOptions {
+        threads: Some(1),
+        target_indices: Some(1),
+        skip_pow: true,
+        observer: Some(observer.clone()),
+        ..Default::default()
+    };
+    let candidates = std::sync::Mutex::new(Vec::new());
+    generate_proof_select(
+        datadir.path(),
+        challenge,
+        cfg,
+        32,
+        AtomicBool::new(false),
+        options,
+        |found: &[Candidate]| {
+            *candidates.lock().unwrap() = found.iter().map(|c| c.nonce).collect();
+            0
+        },
+    )
+    .unwrap();
+    assert_eq!((0..32).collect::<Vec<_>>(), *candidates.lock().unwrap());
+    // The pass stopped before reading all of the data.
+    let bytes_read = observer
+        .bytes_read
+        .load(std::sync::atomic::Ordering::Relaxed);
+    assert!(bytes_read < metadata.expected_total_bytes());
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_generate_and_verify_from_source() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, init_cfg) = init_test_data();
+
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let stop = AtomicBool::new(false);
+    let proof = generate_proof(datadir.path(), challenge, cfg, 32, 1, pow_flags, stop).unwrap();
+
+    let mut data = Vec::new();
+    for idx in 0..metadata.expected_file_count() {
+        data.extend(std::fs::read(datadir.path().join(format!("postdata_{idx}.bin"))).unwrap());
+    }
+    let metadata = ProofMetadata::new(metadata, *challenge);
+    let verifier = Verifier::new(Box::new(PoW::new(pow_flags).unwrap()));
+    verifier
+        .verify_from_source(
+            &proof,
+            &metadata,
+            &cfg,
+            &init_cfg,
+            &mut std::io::Cursor::new(&data),
+        )
+        .await
+        .expect("proof should be valid");
+
+    // Labels that weren't proven don't satisfy the difficulty
+    let mut invalid_data = data.clone();
+    invalid_data.iter_mut().for_each(|b| *b = !*b);
+    verifier
+        .verify_from_source(
+            &proof,
+            &metadata,
+            &cfg,
+            &init_cfg,
+            &mut std::io::Cursor::new(&invalid_data),
+        )
+        .await
+        .expect_err("proof should be invalid");
+
+    // Source too short
+    let result = verifier
+        .verify_from_source(
+            &proof,
+            &metadata,
+            &cfg,
+            &init_cfg,
+            &mut std::io::Cursor::new(&data[..16]),
+        )
+        .await;
+    assert!(matches!(result, Err(Error::ReadingLabel { .. })));
+}
+
+#[test]
+fn test_generate_skipping_pow() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, init_cfg) = init_test_data();
+
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let options = ProvingOptions {
+        threads: Some(1),
+        skip_pow: true,
+        ..Default::default()
+    };
+    let stop = AtomicBool::new(false);
+    let proof =
+        generate_proof_with_options(datadir.path(), challenge, cfg, 32, stop, options).unwrap();
+    assert!(proof.is_pow_skipped());
+
+    let metadata = ProofMetadata::new(metadata, *challenge);
+    let verifier = Verifier::new(Box::new(PoW::new(pow_flags).unwrap()));
+    let result = verifier.verify(&proof, &metadata, &cfg, &init_cfg);
+    assert!(matches!(result, Err(Error::InvalidPoW(_))));
+}
+
+#[test]
+fn test_generate_with_constant_pow() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, _, cfg, _) = init_test_data();
+
+    let options = ProvingOptions {
+        threads: Some(1),
+        pow_algorithm: PowAlgorithm::Constant(1234),
+        ..Default::default()
+    };
+    let proof = generate_proof_with_options(
+        datadir.path(),
+        challenge,
+        cfg,
+        32,
+        AtomicBool::new(false),
+        options,
+    )
+    .unwrap();
+    assert_eq!(1234, proof.pow);
+}
+
+#[test]
+fn test_generate_with_precomputed_pows() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, _, cfg, _) = init_test_data();
+
+    // Only the groups of the first pass, the others fall back to the constant PoW.
+    let pows = HashMap::from([(0, 42), (1, 43)]);
+    let options = ProvingOptions {
+        threads: Some(1),
+        pow_algorithm: PowAlgorithm::Constant(1234),
+        precomputed_pows: pows.clone(),
+        ..Default::default()
+    };
+    let proof = generate_proof_with_options(
+        datadir.path(),
+        challenge,
+        cfg,
+        32,
+        AtomicBool::new(false),
+        options,
+    )
+    .unwrap();
+    let expected = pows.get(&proof.nonce_group()).copied().unwrap_or(1234);
+    assert_eq!(expected, proof.pow);
+}
+
+/// A proof generated with an easier difficulty than required must be rejected.
+#[test]
+fn test_reject_proof_with_downgraded_difficulty() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, init_cfg) = init_test_data();
+
+    let metadata = ProofMetadata::new(metadata, *challenge);
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let verifier = Verifier::new(Box::new(PoW::new(pow_flags).unwrap()));
+
+    // Many more labels satisfy the difficulty with a bigger K1
+    let easy_cfg = post::config::ProofConfig { k1: 2000, ..cfg };
+    let proof = generate_proof(
+        datadir.path(),
+        challenge,
+        easy_cfg,
+        32,
+        1,
+        pow_flags,
+        AtomicBool::new(false),
+    )
+    .unwrap();
+    verifier
+        .verify(&proof, &metadata, &easy_cfg, &init_cfg)
+        .expect("proof should be valid for the easier difficulty");
+    let result = verifier.verify(&proof, &metadata, &cfg, &init_cfg);
+    assert!(matches!(
+        result,
+        Err(Error::InvalidMsb { .. } | Error::InvalidLsb { .. })
+    ));
+
+    // The PoW is checked against the required PoW difficulty
+    let strict_cfg = post::config::ProofConfig {
+        pow_difficulty: [0x00; 32],
+        ..cfg
+    };
+    let proof = generate_proof(
+        datadir.path(),
+        challenge,
+        cfg,
+        32,
+        1,
+        pow_flags,
+        AtomicBool::new(false),
+    )
+    .unwrap();
+    let result = verifier.verify(&proof, &metadata, &strict_cfg, &init_cfg);
+    assert!(matches!(result, Err(Error::InvalidPoW(_))));
+}
+
+/// The nonce, the PoW and the indices of a proof must agree.
+#[test]
+fn test_reject_proof_with_mismatched_nonce_and_pow() {
+    use post::pow::{PowVerifier, Prover};
+
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, post_metadata, cfg, init_cfg) = init_test_data();
+
+    // A real PoW difficulty, so that a PoW is valid only for some of the nonce groups.
+    let mut pow_difficulty = [0xFF; 32];
+    pow_difficulty[0] = 0x03;
+    let cfg = ProofConfig {
+        pow_difficulty,
+        ..cfg
+    };
+
+    let metadata = ProofMetadata::new(post_metadata, *challenge);
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let pow = Arc::new(PoW::new(pow_flags).unwrap());
+    let proof = generate_proof(
+        datadir.path(),
+        challenge,
+        cfg,
+        32,
+        1,
+        pow_flags,
+        AtomicBool::new(false),
+    )
+    .unwrap();
+    let verifier = Verifier::new(Box::new(pow.clone()));
+    verifier
+        .verify(&proof, &metadata, &cfg, &init_cfg)
+        .expect("proof should be valid");
+
+    let is_label_error = |result: &Result<(), Error>| {
+        matches!(
+            result,
+            Err(Error::InvalidMsb { .. } | Error::InvalidLsb { .. })
+        )
+    };
+
+    // Another nonce of the same group: the PoW is still valid, the labels aren't.
+    let neighbour = Proof {
+        nonce: proof.nonce ^ 1,
+        ..proof.clone()
+    };
+    assert_eq!(proof.nonce_group(), neighbour.nonce_group());
+    assert!(is_label_error(
+        &verifier.verify(&neighbour, &metadata, &cfg, &init_cfg)
+    ));
+
+    let params = ProvingParams::new(&post_metadata, &cfg).unwrap();
+    let pow_challenge: [u8; 8] = challenge[..8].try_into().unwrap();
+    let is_valid_pow = |pow_value: u64, group: u32| {
+        pow.verify(
+            pow_value,
+            group as u8,
+            &pow_challenge,
+            &params.pow_difficulty,
+            &post_metadata.node_id,
+        )
+        .is_ok()
+    };
+    // Another nonce group, such that neither group's PoW is valid for the other one.
+    let (other_group, other_pow) = (1..256)
+        .map(|offset| (proof.nonce_group() + offset) % 256)
+        .filter(|&group| !is_valid_pow(proof.pow, group))
+        .map(|group| {
+            let pow_value = pow
+                .prove(
+                    group as u8,
+                    &pow_challenge,
+                    &params.pow_difficulty,
+                    &post_metadata.node_id,
+                )
+                .unwrap();
+            (group, pow_value)
+        })
+        .find(|&(_, pow_value)| !is_valid_pow(pow_value, proof.nonce_group()))
+        .unwrap();
+    let other_nonce = other_group * 16 + proof.nonce % 16;
+
+    // A nonce of another group with a valid PoW for that group.
+    let moved = Proof {
+        nonce: other_nonce,
+        pow: other_pow,
+        ..proof.clone()
+    };
+    assert!(is_label_error(
+        &verifier.verify(&moved, &metadata, &cfg, &init_cfg)
+    ));
+
+    // The PoW of the other group with the original nonce.
+    let swapped_pow = Proof {
+        pow: other_pow,
+        ..proof.clone()
+    };
+    let result = verifier.verify(&swapped_pow, &metadata, &cfg, &init_cfg);
+    assert!(matches!(result, Err(Error::InvalidPoW(_))));
+
+    // The original PoW with a nonce moved to the other group.
+    let moved_nonce = Proof {
+        nonce: other_nonce,
+        ..proof
+    };
+    let result = verifier.verify(&moved_nonce, &metadata, &cfg, &init_cfg);
+    assert!(matches!(result, Err(Error::InvalidPoW(_))));
+}
+
+#[test]
+fn test_generate_with_events() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, _, cfg, _) = init_test_data();
+
+    let options = ProvingOptions {
+        threads: Some(1),
+        skip_pow: true,
+        ..Default::default()
+    };
+    let (tx, rx) = mpsc::channel();
+    let proof = generate_proof_with_events(
+        datadir.path(),
+        challenge,
+        cfg,
+        16,
+        AtomicBool::new(false),
+        options,
+        tx,
+    )
+    .unwrap();
+
+    let events = rx.iter().collect::<Vec<_>>();
+    let (last, passes) = events.split_last().unwrap();
+    let mut next_start = 0;
+    for event in passes {
+        let ProvingEvent::PassComplete { range, found } = event;
+        assert_eq!(next_start, range.start);
+        assert_eq!(&None, found);
+        next_start = range.end;
+    }
+    let ProvingEvent::PassComplete { range, found } = last;
+    assert_eq!(next_start, range.start);
+    assert!(range.contains(&proof.nonce));
+    assert_eq!(Some(&proof), found.as_ref());
+}
+
+#[derive(Default)]
+struct RecordingObserver {
+    events: std::sync::Mutex<Vec<String>>,
+    bytes_read: std::sync::atomic::AtomicU64,
+    candidates: std::sync::Mutex<HashSet<(u32, u64)>>,
+    proof: std::sync::Mutex<Option<Proof<'static>>>,
+}
+
+impl ProvingObserver for RecordingObserver {
+    fn on_pass_start(&self, range: std::ops::Range<u32>) {
+        self.events.lock().unwrap().push(format!("start {range:?}"));
+    }
+
+    fn on_bytes_read(&self, n: u64) {
+        self.bytes_read
+            .fetch_add(n, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn on_candidate(&self, nonce: u32, index: u64) {
+        self.candidates.lock().unwrap().insert((nonce, index));
+    }
+
+    fn on_proof_found(&self, proof: &Proof) {
+        *self.proof.lock().unwrap() = Some(Proof {
+            indices: proof.indices.to_vec().into(),
+            ..*proof
+        });
+    }
+
+    fn on_pass_end(&self, found: bool) {
+        self.events.lock().unwrap().push(format!("end {found}"));
+    }
+}
+
+#[test]
+fn test_generate_with_observer() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, _) = init_test_data();
+
+    let observer = Arc::new(RecordingObserver::default());
+    let options = ProvingOptions {
+        threads: Some(1),
+        skip_pow: true,
+        observer: Some(observer.clone()),
+        ..Default::default()
+    };
+    let proof = generate_proof_with_options(
+        datadir.path(),
+        challenge,
+        cfg,
+        16,
+        AtomicBool::new(false),
+        options,
+    )
+    .unwrap();
+
+    let events = observer.events.lock().unwrap().clone();
+    let passes = (proof.nonce / 16) as usize + 1;
+    let mut expected = Vec::new();
+    for pass in 0..passes as u32 {
+        expected.push(format!("start {:?}", pass * 16..pass * 16 + 16));
+        expected.push(format!("end {}", pass + 1 == passes as u32));
+    }
+    assert_eq!(expected, events);
+    assert_eq!(Some(&proof), observer.proof.lock().unwrap().as_ref());
+
+    // The last pass might stop reading early.
+    let bytes_read = observer
+        .bytes_read
+        .load(std::sync::atomic::Ordering::Relaxed);
+    assert!(bytes_read > (passes as u64 - 1) * metadata.expected_total_bytes());
+    assert!(bytes_read <= passes as u64 * metadata.expected_total_bytes());
+
+    let candidates = observer.candidates.lock().unwrap();
+    let indices = proof
+        .unpack_indices(metadata.total_labels(), cfg.k2)
+        .unwrap();
+    for index in indices {
+        assert!(candidates.contains(&(proof.nonce, index)));
+    }
+}
+
+#[test]
+fn test_generate_with_report() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, _, cfg, _) = init_test_data();
+
+    let options = ProvingOptions {
+        threads: Some(1),
+        skip_pow: true,
+        ..Default::default()
+    };
+    let report = generate_proof_with_report(
+        datadir.path(),
+        challenge,
+        cfg,
+        16,
+        AtomicBool::new(false),
+        options,
+    )
+    .unwrap();
+
+    assert!(report.passes_taken >= 1);
+    assert_eq!(report.passes_taken * 16, report.nonces_searched);
+    let last_pass = report.nonces_searched - 16..report.nonces_searched;
+    assert!(last_pass.contains(&report.proof.nonce));
+}
+
+#[test]
+fn test_generate_with_metadata() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, _) = init_test_data();
+    // The metadata is not read from the datadir
+    std::fs::remove_file(datadir.path().join("postdata_metadata.json")).unwrap();
+
+    let options = ProvingOptions {
+        threads: Some(1),
+        skip_pow: true,
+        ..Default::default()
+    };
+    let proof = generate_proof_with_metadata(
+        datadir.path(),
+        &metadata,
+        challenge,
+        cfg,
+        32,
+        AtomicBool::new(false),
+        options.clone(),
+    )
+    .unwrap();
+    assert!(proof.is_pow_skipped());
+
+    // The metadata expects more data than there is
+    let bigger = PostMetadata {
+        num_units: metadata.num_units + 1,
+        ..metadata
+    };
+    let result = generate_proof_with_metadata(
+        datadir.path(),
+        &bigger,
+        challenge,
+        cfg,
+        32,
+        AtomicBool::new(false),
+        options,
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_generate_with_datadirs() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, _) = init_test_data();
+    let other_datadir = tempdir().unwrap();
+
+    // Move every other file to the other directory.
+    for index in (1..metadata.expected_file_count()).step_by(2) {
+        let name = format!("postdata_{index}.bin");
+        std::fs::rename(datadir.path().join(&name), other_datadir.path().join(&name)).unwrap();
+    }
+    let datadirs = [datadir.path(), other_datadir.path()];
+
+    let options = ProvingOptions {
+        threads: Some(1),
+        skip_pow: true,
+        ..Default::default()
+    };
+    let generate = |datadirs: &[&std::path::Path]| {
+        generate_proof_with_datadirs(
+            datadirs,
+            challenge,
+            cfg,
+            32,
+            AtomicBool::new(false),
+            options.clone(),
+        )
+    };
+    let proof = generate(&datadirs).unwrap();
+    assert!(proof.is_pow_skipped());
+
+    // Files are missing in a single directory.
+    assert!(generate(&datadirs[..1]).is_err());
+    assert!(generate(&[]).is_err());
+
+    // More data than in the metadata.
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(other_datadir.path().join("postdata_1.bin"))
+        .unwrap();
+    std::io::Write::write_all(&mut file, &[0; 16]).unwrap();
+    assert!(generate(&datadirs).is_err());
+}
+
+#[test]
+fn test_analyze() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, _, cfg, _) = init_test_data();
+
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let counts = analyze(datadir.path(), challenge, cfg, 0..16, 1, pow_flags).unwrap();
+    assert_eq!(
+        (0..16).collect::<HashSet<_>>(),
+        counts.keys().copied().collect()
+    );
+
+    let proof = generate_proof(
+        datadir.path(),
+        challenge,
+        cfg,
+        16,
+        1,
+        pow_flags,
+        AtomicBool::new(false),
+    )
+    .unwrap();
+    // The first pass finds a proof if and only if a nonce collected K2 indices.
+    let k2 = cfg.k2 as usize;
+    if counts.values().any(|&count| count >= k2) {
+        assert!(counts[&proof.nonce] >= k2);
+    } else {
+        assert!(proof.nonce >= 16);
+    }
+
+    assert!(analyze(datadir.path(), challenge, cfg, 0..0, 1, pow_flags).is_err());
+    assert!(analyze(datadir.path(), challenge, cfg, 4090..4100, 1, pow_flags).is_err());
+}
+
+#[test]
+fn test_analyze_counts_every_index() {
+    use post::pow::Prover;
+
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, _) = init_test_data();
+
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let counts = analyze(datadir.path(), challenge, cfg, 0..16, 1, pow_flags).unwrap();
+
+    // Check the labels one by one with the PoW of the only nonce group.
+    let mut data = Vec::new();
+    for idx in 0..metadata.expected_file_count() {
+        data.extend(std::fs::read(datadir.path().join(format!("postdata_{idx}.bin"))).unwrap());
+    }
+    let params = ProvingParams::new(&metadata, &cfg).unwrap();
+    let pow = PoW::new(pow_flags)
+        .unwrap()
+        .prove(
+            0,
+            &challenge[..8].try_into().unwrap(),
+            &params.pow_difficulty,
+            &metadata.node_id,
+        )
+        .unwrap();
+    let expected = (0..16)
+        .map(|nonce| {
+            let checker = LabelChecker::new(challenge, nonce, pow, params.difficulty);
+            let count = data
+                .chunks_exact(16)
+                .filter(|label| checker.check(label.try_into().unwrap()).is_ok())
+                .count();
+            (nonce, count)
+        })
+        .collect::<HashMap<_, _>>();
+    assert_eq!(expected, counts);
+}
+
+#[test]
+fn test_generate_all_proofs() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, _) = init_test_data();
+
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let counts = analyze(datadir.path(), challenge, cfg, 0..32, 1, pow_flags).unwrap();
+    let proofs = generate_all_proofs(
+        datadir.path(),
+        challenge,
+        cfg,
+        16,
+        AtomicBool::new(false),
+        ProvingOptions {
+            threads: Some(1),
+            ..Default::default()
+        },
+        2,
+    )
+    .unwrap();
+
+    // Every nonce of the 2 passes that collected K2 indices, in order.
+    let k2 = cfg.k2 as usize;
+    let mut expected = counts
+        .iter()
+        .filter(|(_, &count)| count >= k2)
+        .map(|(&nonce, _)| nonce)
+        .collect::<Vec<_>>();
+    expected.sort_unstable();
+    assert_eq!(
+        expected,
+        proofs.iter().map(|proof| proof.nonce).collect::<Vec<_>>()
+    );
+    for proof in &proofs {
+        let indices = proof
+            .unpack_indices(metadata.total_labels(), cfg.k2)
+            .unwrap();
+        assert_eq!(k2, indices.len());
+    }
+
+    assert!(generate_all_proofs(
+        datadir.path(),
+        challenge,
+        cfg,
+        16,
+        AtomicBool::new(false),
+        ProvingOptions {
+            threads: Some(1),
+            ..Default::default()
+        },
+        0,
+    )
+    .is_err());
+}
+
+#[test]
+fn test_generate_spot_proof() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, _) = init_test_data();
+
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let (nonce, index, pow) =
+        generate_spot_proof(datadir.path(), challenge, cfg, 16, 1, pow_flags).unwrap();
+
+    let mut data = Vec::new();
+    for idx in 0..metadata.expected_file_count() {
+        data.extend(std::fs::read(datadir.path().join(format!("postdata_{idx}.bin"))).unwrap());
+    }
+    let offset = index as usize * 16;
+    let label: [u8; 16] = data[offset..offset + 16].try_into().unwrap();
+    let params = ProvingParams::new(&metadata, &cfg).unwrap();
+    let checker = LabelChecker::new(challenge, nonce, pow, params.difficulty);
+    assert!(checker.check(&label).is_ok());
+}
+
+#[test]
+fn test_contested_labels() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, _) = init_test_data();
+
+    let options = ProvingOptions {
+        threads: Some(1),
+        skip_pow: true,
+        ..Default::default()
+    };
+    let proof = generate_proof_with_options(
+        datadir.path(),
+        challenge,
+        cfg,
+        32,
+        AtomicBool::new(false),
+        options,
+    )
+    .unwrap();
+
+    let mut data = Vec::new();
+    for idx in 0..metadata.expected_file_count() {
+        data.extend(std::fs::read(datadir.path().join(format!("postdata_{idx}.bin"))).unwrap());
+    }
+    // Move every other file to another directory.
+    let other_datadir = tempdir().unwrap();
+    for index in (1..metadata.expected_file_count()).step_by(2) {
+        let name = format!("postdata_{index}.bin");
+        std::fs::rename(datadir.path().join(&name), other_datadir.path().join(&name)).unwrap();
+    }
+    let datadirs = [datadir.path(), other_datadir.path()];
+
+    let reader_cfg = ReaderConfig::default();
+    let labels = contested_labels(&proof, &metadata, cfg.k2, &datadirs, &reader_cfg).unwrap();
+    assert_eq!(cfg.k2 as usize, labels.len());
+    let num_labels = metadata.total_labels();
+    let indices = proof.unpack_indices(num_labels, cfg.k2).unwrap();
+    for (index, label) in indices.into_iter().zip(labels) {
+        let offset = index as usize * 16;
+        assert_eq!(&data[offset..offset + 16], label.as_slice());
+    }
+}
+
+#[test]
+fn test_generate_until_stop_condition() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, _, cfg, _) = init_test_data();
+
+    let generate = |stop_condition| {
+        let (tx, rx) = mpsc::channel();
+        let options = ProvingOptions {
+            threads: Some(1),
+            // There are fewer labels than that, no nonce can reach it.
+            target_indices: Some(20_000),
+            skip_pow: true,
+            diagnostics: Some(tx),
+            stop_condition,
+            ..Default::default()
+        };
+        let err = generate_proof_with_options(
+            datadir.path(),
+            challenge,
+            cfg,
+            32,
+            AtomicBool::new(false),
+            options,
+        )
+        .expect_err("no proof should be found");
+        let passes = rx.iter().map(|d| d.nonces).collect::<Vec<_>>();
+        (err.downcast::<ProveError>().unwrap(), passes)
+    };
+
+    let (err, passes) = generate(StopCondition {
+        max_passes: Some(2),
+        ..Default::default()
+    });
+    assert!(matches!(
+        err,
+        ProveError::StopConditionReached(StopReason::MaxPasses(2))
+    ));
+    assert_eq!(vec![0..32, 32..64], passes);
+
+    let (err, passes) = generate(StopCondition {
+        max_nonce: Some(48),
+        ..Default::default()
+    });
+    assert!(matches!(
+        err,
+        ProveError::StopConditionReached(StopReason::MaxNonce(48))
+    ));
+    assert_eq!(vec![0..32, 32..48], passes);
+
+    let (err, passes) = generate(StopCondition {
+        max_duration: Some(std::time::Duration::ZERO),
+        ..Default::default()
+    });
+    assert!(matches!(
+        err,
+        ProveError::StopConditionReached(StopReason::MaxDuration(_))
+    ));
+    assert!(passes.is_empty());
+}
+
+#[test]
+fn test_generate_with_zero_threads() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, _, cfg, _) = init_test_data();
+
+    let err = generate_proof(
+        datadir.path(),
+        challenge,
+        cfg,
+        32,
+        0,
+        RandomXFlag::get_recommended_flags(),
+        AtomicBool::new(false),
+    )
+    .expect_err("proving with 0 threads should fail");
+    assert!(matches!(
+        err.downcast_ref::<ProveError>(),
+        Some(ProveError::InvalidThreadCount(0))
+    ));
+
+    // Retrying with a valid number of threads succeeds.
+    let options = ProvingOptions {
+        threads: Some(threads_or_available(0)),
+        skip_pow: true,
+        ..Default::default()
+    };
+    generate_proof_with_options(
+        datadir.path(),
+        challenge,
+        cfg,
+        32,
+        AtomicBool::new(false),
+        options,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_generate_with_pow_budget() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, _, cfg, _) = init_test_data();
+
+    let generate = |max_pow_computations, precomputed_pows| {
+        let (tx, rx) = mpsc::channel();
+        let options = ProvingOptions {
+            threads: Some(1),
+            // There are fewer labels than that, no nonce can reach it.
+            target_indices: Some(20_000),
+            skip_pow: true,
+            diagnostics: Some(tx),
+            max_pow_computations: Some(max_pow_computations),
+            precomputed_pows,
+            ..Default::default()
+        };
+        let err = generate_proof_with_options(
+            datadir.path(),
+            challenge,
+            cfg,
+            32,
+            AtomicBool::new(false),
+            options,
+        )
+        .expect_err("no proof should be found");
+        let passes = rx.iter().map(|d| d.nonces).collect::<Vec<_>>();
+        (err.downcast::<ProveError>().unwrap(), passes)
+    };
+
+    // 2 nonce groups per pass, the third pass would go over the budget.
+    let (err, passes) = generate(5, HashMap::new());
+    assert!(matches!(err, ProveError::PoWBudgetExhausted));
+    assert_eq!(vec![0..32, 32..64], passes);
+
+    // The precomputed PoWs are free.
+    let (err, passes) = generate(2, HashMap::from([(0, 0), (1, 0)]));
+    assert!(matches!(err, ProveError::PoWBudgetExhausted));
+    assert_eq!(vec![0..32, 32..64], passes);
+
+    let (err, passes) = generate(0, HashMap::new());
+    assert!(matches!(err, ProveError::PoWBudgetExhausted));
+    assert!(passes.is_empty());
+}
+
+#[test]
+fn test_generate_with_pipelined_pow() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, init_cfg) = init_test_data();
+
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let pow = Arc::new(PoW::new(pow_flags).unwrap());
+    let options = ProvingOptions {
+        threads: Some(2),
+        pow_prover: Some(pow.clone()),
+        pipeline_pow: true,
+        ..Default::default()
+    };
+    let proof = generate_proof_with_options(
+        datadir.path(),
+        challenge,
+        cfg,
+        16,
+        AtomicBool::new(false),
+        options,
+    )
+    .unwrap();
+    let metadata = ProofMetadata::new(metadata, *challenge);
+    let verifier = Verifier::new(Box::new(pow));
+    verifier
+        .verify(&proof, &metadata, &cfg, &init_cfg)
+        .expect("proof should be valid");
+
+    // The limits apply to the prefetched PoWs too.
+    let generate = |stop_condition, max_pow_computations| {
+        let (tx, rx) = mpsc::channel();
+        let options = ProvingOptions {
+            threads: Some(2),
+            // There are fewer labels than that, no nonce can reach it.
+            target_indices: Some(20_000),
+            skip_pow: true,
+            pipeline_pow: true,
+            diagnostics: Some(tx),
+            stop_condition,
+            max_pow_computations,
+            ..Default::default()
+        };
+        let err = generate_proof_with_options(
+            datadir.path(),
+            challenge,
+            cfg,
+            32,
+            AtomicBool::new(false),
+            options,
+        )
+        .expect_err("no proof should be found");
+        let passes = rx.iter().map(|d| d.nonces).collect::<Vec<_>>();
+        (err.downcast::<ProveError>().unwrap(), passes)
+    };
+    let (err, passes) = generate(
+        StopCondition {
+            max_nonce: Some(80),
+            ..Default::default()
+        },
+        None,
+    );
+    assert!(matches!(
+        err,
+        ProveError::StopConditionReached(StopReason::MaxNonce(80))
+    ));
+    assert_eq!(vec![0..32, 32..64, 64..80], passes);
+
+    let (err, passes) = generate(StopCondition::default(), Some(5));
+    assert!(matches!(err, ProveError::PoWBudgetExhausted));
+    assert_eq!(vec![0..32, 32..64], passes);
+}
+
+/// A PoW prover and an observer recording during which pass every PoW was calculated.
+#[derive(Default)]
+struct PassTracker {
+    pass: std::sync::Mutex<Option<std::ops::Range<u32>>>,
+    // The nonce group of every PoW with the pass in progress at the time.
+    proven: std::sync::Mutex<Vec<(u8, Option<std::ops::Range<u32>>)>>,
+    changed: std::sync::Condvar,
+}
+
+impl PassTracker {
+    const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+    // The PoWs of groups after it are never calculated in the test.
+    const LAST_GROUP: u8 = 1;
+}
+
+impl post::pow::Prover for PassTracker {
+    fn prove(
+        &self,
+        nonce_group: u8,
+        _challenge: &[u8; 8],
+        _difficulty: &[u8; 32],
+        _miner_id: &[u8; 32],
+    ) -> Result<u64, post::pow::Error> {
+        let mut pass = self.pass.lock().unwrap();
+        // The PoW of a following pass waits for some pass to start.
+        if nonce_group > 0 {
+            pass = self
+                .changed
+                .wait_timeout_while(pass, Self::TIMEOUT, |pass| pass.is_none())
+                .unwrap()
+                .0;
+        }
+        self.proven
+            .lock()
+            .unwrap()
+            .push((nonce_group, pass.clone()));
+        self.changed.notify_all();
+        Ok(0)
+    }
+}
+
+impl ProvingObserver for PassTracker {
+    fn on_pass_start(&self, range: std::ops::Range<u32>) {
+        *self.pass.lock().unwrap() = Some(range);
+        self.changed.notify_all();
+    }
+
+    fn on_pass_end(&self, _found: bool) {
+        let mut pass = self.pass.lock().unwrap();
+        // The pass doesn't end before the PoW of the next one is calculated.
+        let next_group = (pass.as_ref().unwrap().end / 16) as u8;
+        if next_group <= Self::LAST_GROUP {
+            pass = self
+                .changed
+                .wait_timeout_while(pass, Self::TIMEOUT, |_| {
+                    !self
+                        .proven
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .any(|(group, _)| *group == next_group)
+                })
+                .unwrap()
+                .0;
+        }
+        *pass = None;
+    }
+}
+
+#[test]
+fn test_pipelined_pow_is_calculated_during_the_search() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, _, cfg, _) = init_test_data();
+
+    let tracker = Arc::new(PassTracker::default());
+    let options = ProvingOptions {
+        threads: Some(2),
+        // There are fewer labels than that, no nonce can reach it.
+        target_indices: Some(20_000),
+        pow_prover: Some(tracker.clone()),
+        observer: Some(tracker.clone()),
+        pipeline_pow: true,
+        stop_condition: StopCondition {
+            max_passes: Some(2),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    generate_proof_with_options(
+        datadir.path(),
+        challenge,
+        cfg,
+        16,
+        AtomicBool::new(false),
+        options,
+    )
+    .expect_err("no proof should be found");
+
+    // The PoW of the first pass is calculated before it, the PoW of the second one
+    // during the first pass. There is no third pass to calculate the PoW for.
+    assert_eq!(
+        vec![(0, None), (1, Some(0..16))],
+        *tracker.proven.lock().unwrap()
+    );
+}
+
+/// The labels are regenerated with the scrypt params and commitment
+/// used for the initialization, anything else fails the verification.
+#[test]
+fn test_verify_with_custom_scrypt_params() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, metadata, cfg, init_cfg) =
+        init_test_data_with(ScryptParams::new(8, 2, 2), &[13; 32]);
+
+    let pow_flags = RandomXFlag::get_recommended_flags();
+    let stop = AtomicBool::new(false);
+    let proof = generate_proof(datadir.path(), challenge, cfg, 32, 1, pow_flags, stop).unwrap();
+
+    let metadata = ProofMetadata::new(metadata, *challenge);
+    let verifier = Verifier::new(Box::new(PoW::new(pow_flags).unwrap()));
+    verifier
+        .verify(&proof, &metadata, &cfg, &init_cfg)
+        .expect("proof should be valid");
+
+    let other_scrypt = InitConfig {
+        scrypt: ScryptParams::new(2, 1, 1),
+        ..init_cfg
+    };
+    let result = verifier.verify(&proof, &metadata, &cfg, &other_scrypt);
+    assert!(matches!(
+        result,
+        Err(Error::InvalidMsb { .. } | Error::InvalidLsb { .. })
+    ));
+
+    let other_commitment = ProofMetadata {
+        commitment_atx_id: [14; 32],
+        ..metadata
+    };
+    let result = verifier.verify(&proof, &other_commitment, &cfg, &init_cfg);
+    assert!(matches!(
+        result,
+        Err(Error::InvalidMsb { .. } | Error::InvalidLsb { .. })
+    ));
+}
+
+#[test]
+fn test_pause_and_resume_session() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, _, cfg, _) = init_test_data();
+
+    let (tx, rx) = mpsc::channel();
+    let options = ProvingOptions {
+        threads: Some(1),
+        // There are fewer labels than that, no nonce can reach it.
+        target_indices: Some(20_000),
+        skip_pow: true,
+        diagnostics: Some(tx),
+        // A single pass for every resume.
+        stop_condition: StopCondition {
+            max_passes: Some(1),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut session = ProvingSession::new(datadir.path(), challenge, cfg, 64, options).unwrap();
+    assert_eq!(64, session.remaining().count());
+    assert_eq!(Some(&(0..64)), session.remaining().next());
+
+    // Stopped before starting, nothing is done.
+    let err = session.resume(&AtomicBool::new(true)).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProveError>(),
+        Some(ProveError::Cancelled)
+    ));
+    assert_eq!(64, session.remaining().count());
+
+    for _ in 0..2 {
+        let err = session.resume(&AtomicBool::new(false)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProveError>(),
+            Some(ProveError::StopConditionReached(StopReason::MaxPasses(1)))
+        ));
+        session.pause();
+    }
+    assert_eq!(62, session.remaining().count());
+    assert_eq!(Some(&(128..192)), session.remaining().next());
+    drop(session);
+
+    // Every pass was done exactly once.
+    let passes = rx.iter().map(|d| d.nonces).collect::<Vec<_>>();
+    assert_eq!(vec![0..64, 64..128], passes);
+}
+
+#[test]
+fn test_resume_session_after_proof() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, _, cfg, _) = init_test_data();
+
+    let options = ProvingOptions {
+        threads: Some(1),
+        skip_pow: true,
+        ..Default::default()
+    };
+    let mut session = ProvingSession::new(datadir.path(), challenge, cfg, 16, options).unwrap();
+
+    let first = session.resume(&AtomicBool::new(false)).unwrap();
+    let next_pass = (first.nonce / 16 + 1) * 16;
+    assert_eq!(next_pass, session.remaining().next().unwrap().start);
+
+    // The second proof is searched for from the pass after the first proof.
+    let second = session.resume(&AtomicBool::new(false)).unwrap();
+    assert!(second.nonce >= next_pass);
+    assert_eq!(
+        (second.nonce / 16 + 1) * 16,
+        session.remaining().next().unwrap().start
+    );
+}
+
+/// A PoW prover counting the PoWs it calculated.
+#[derive(Default)]
+struct CountingPow {
+    proven: std::sync::Mutex<Vec<u8>>,
+}
+
+impl post::pow::Prover for CountingPow {
+    fn prove(
+        &self,
+        nonce_group: u8,
+        _challenge: &[u8; 8],
+        _difficulty: &[u8; 32],
+        _miner_id: &[u8; 32],
+    ) -> Result<u64, post::pow::Error> {
+        self.proven.lock().unwrap().push(nonce_group);
+        Ok(nonce_group as u64)
+    }
+}
+
+#[test]
+fn test_pause_keeps_given_pow_prover() {
+    let challenge = b"hello world, challenge me!!!!!!!";
+    let (datadir, _, cfg, _) = init_test_data();
+
+    let pow = Arc::new(CountingPow::default());
+    let options = ProvingOptions {
+        threads: Some(1),
+        // There are fewer labels than that, no nonce can reach it.
+        target_indices: Some(20_000),
+        pow_prover: Some(pow.clone()),
+        stop_condition: StopCondition {
+            max_passes: Some(1),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut session = ProvingSession::new(datadir.path(), challenge, cfg, 16, options).unwrap();
+    for _ in 0..2 {
+        assert!(session.resume(&AtomicBool::new(false)).is_err());
+        session.pause();
+    }
+    // The second pass used the same prover (not a new RandomX one).
+    assert_eq!(vec![0, 1], *pow.proven.lock().unwrap());
+}
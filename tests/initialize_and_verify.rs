@@ -3,7 +3,8 @@ use std::io::Write;
 use post::{
     config::ScryptParams,
     initialize::{CpuInitializer, Initialize},
-    pos_verification::verify_files,
+    metadata,
+    pos_verification::{verify_files, verify_files_with_metadata},
 };
 
 use tempfile::tempdir;
@@ -46,3 +47,23 @@ fn test_generate_and_verify() {
     verify_files(datadir.path(), 100.0, None, Some(0), scrypt).unwrap();
     verify_files(datadir.path(), 100.0, Some(2), None, scrypt).unwrap();
 }
+
+#[test]
+fn verify_relocated_data() {
+    let datadir = tempdir().unwrap();
+    let scrypt = ScryptParams::new(2, 1, 1);
+    CpuInitializer::new(scrypt)
+        .initialize(datadir.path(), &[0u8; 32], &[0u8; 32], 256, 2, 700, None)
+        .unwrap();
+    let metadata = metadata::load(datadir.path()).unwrap();
+
+    // Move the POS data files away from the metadata
+    let relocated = tempdir().unwrap();
+    for idx in 0..metadata.expected_file_count() {
+        let name = format!("postdata_{idx}.bin");
+        std::fs::rename(datadir.path().join(&name), relocated.path().join(&name)).unwrap();
+    }
+
+    verify_files_with_metadata(&metadata, relocated.path(), 100.0, None, None, scrypt).unwrap();
+    assert!(verify_files(datadir.path(), 100.0, None, None, scrypt).is_err());
+}
@@ -0,0 +1,115 @@
+//! The verification must not allocate proportionally to the number of labels,
+//! which is declared by the prover and can be huge.
+//!
+//! It's a separate test binary, as it replaces the global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use post::{
+    config::{InitConfig, ProofConfig, ScryptParams},
+    context::PostContext,
+    metadata::ProofMetadata,
+    pow::{self, PowVerifier},
+    prove::{Proof, Prover, Prover8_56, ProvingParams},
+    verification::Verifier,
+};
+
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let now = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(now, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+struct AcceptAnyPoW;
+
+impl PowVerifier for AcceptAnyPoW {
+    fn verify(
+        &self,
+        _pow: u64,
+        _nonce_group: u8,
+        _challenge: &[u8; 8],
+        _difficulty: &[u8; 32],
+        _miner_id: &[u8; 32],
+    ) -> Result<(), pow::Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn verification_memory_doesnt_depend_on_num_labels() {
+    let cfg = ProofConfig {
+        k1: 1 << 31,
+        k2: 64,
+        k3: 64,
+        pow_difficulty: [0xFF; 32],
+    };
+    // 2^44 labels (256 TiB) declared
+    let num_labels = 1u64 << 44;
+    let init_cfg = InitConfig {
+        min_num_units: 1,
+        max_num_units: 1,
+        labels_per_unit: num_labels,
+        scrypt: ScryptParams::new(2, 1, 1),
+    };
+    let metadata = ProofMetadata {
+        node_id: [0; 32],
+        commitment_atx_id: [0; 32],
+        challenge: [0; 32],
+        num_units: 1,
+    };
+    // Find a valid proof in labels spread over all the declared ones,
+    // so that the verification checks every K3 label.
+    let data_labels = 1u64 << 20;
+    let stride = num_labels / data_labels;
+    let label = |index: u64| ((index / stride) as u128).to_le_bytes();
+    let data = (0..data_labels)
+        .flat_map(|i| label(i * stride))
+        .collect::<Vec<_>>();
+    let context = PostContext::for_proof(&metadata, &cfg, &init_cfg).unwrap();
+    let params = ProvingParams::from_context(&context).unwrap();
+    let mut pow_prover = pow::MockProver::new();
+    pow_prover.expect_prove().returning(|_, _, _, _| Ok(0));
+    let prover =
+        Prover8_56::new(&metadata.challenge, 0..16, params, &pow_prover, &[0; 32]).unwrap();
+    let mut candidates = vec![Vec::new(); 16];
+    let (nonce, indices) = prover
+        .prove(&data, 0, |nonce, index| {
+            let indices = &mut candidates[nonce as usize];
+            indices.push(index * stride);
+            (indices.len() == cfg.k2 as usize).then(|| std::mem::take(indices))
+        })
+        .expect("no proof found in the test data");
+    drop(data);
+    let proof = Proof::new(nonce, &indices, num_labels, 0);
+    let verifier = Verifier::new(Box::new(AcceptAnyPoW));
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    // The labels generated from the commitment don't satisfy the difficulty,
+    // the verification fails at the first one.
+    _ = verifier.verify(&proof, &metadata, &cfg, &init_cfg);
+    verifier
+        .verify_with_label_fn(&proof, &metadata, &cfg, &init_cfg, label)
+        .unwrap();
+    let peak = PEAK.load(Ordering::Relaxed) - before;
+    assert!(peak < 64 * 1024, "verification allocated {peak} bytes");
+}
@@ -0,0 +1,257 @@
+//! The core of proof verification.
+//!
+//! It contains the pure math needed to verify a proof, given the labels:
+//! - decompressing the proven indices,
+//! - checking if an AES-encrypted label satisfies the difficulty.
+//!
+//! It's a `no_std` crate using only `core` (no allocations, threads or IO), so it can be
+//! reused by lightweight verifiers built for constrained environments. The CI builds it
+//! for a target without `std` to keep it so. The `verification` module of `post-rs`
+//! builds the full verifier on top of it (re-exported there as `post::verify_core`).
+#![cfg_attr(not(test), no_std)]
+
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use aes::Aes128;
+
+/// The number of nonces sharing one AES cipher (a nonce group).
+pub const NONCES_PER_AES: u32 = 16;
+
+/// The number of labels encrypted at once by [LabelChecker::check_batch].
+/// The AES backends process 8 blocks in parallel.
+pub const AES_BATCH: usize = 8;
+
+/// Calculate the number of bits required to store the value.
+pub const fn required_bits(value: u64) -> usize {
+    if value == 0 {
+        return 0;
+    }
+    (value.ilog2() + 1) as usize
+}
+
+/// Round `n` up to a multiple of `mult`.
+pub const fn next_multiple_of(n: usize, mult: usize) -> usize {
+    let r = n % mult;
+    if r == 0 {
+        n
+    } else {
+        n + (mult - r)
+    }
+}
+
+/// Calculate the expected length of compressed indices.
+pub const fn expected_indices_bytes(required_bits: usize, k2: u32) -> usize {
+    let total_bits = required_bits * k2 as usize;
+    next_multiple_of(total_bits, 8) / 8
+}
+
+/// Get the index at position `pos` from indices compressed with `bits` bits per index.
+///
+/// Returns `None` if the index doesn't fit in `indices` or `bits` is not in 1..=64.
+pub fn decompress_index(indices: &[u8], bits: usize, pos: usize) -> Option<u64> {
+    if bits == 0 || bits > 64 {
+        return None;
+    }
+    let start = pos.checked_mul(bits)?;
+    if start.checked_add(bits)? > indices.len() * 8 {
+        return None;
+    }
+    let mut index = 0u64;
+    for bit in 0..bits {
+        let at = start + bit;
+        if indices[at / 8] & (1 << (at % 8)) != 0 {
+            index |= 1 << bit;
+        }
+    }
+    Some(index)
+}
+
+/// Split the difficulty into the MSB (checked against a byte of the first AES output)
+/// and LSB (56 bits, checked against the output of the second, per-nonce AES).
+pub const fn split_difficulty(difficulty: u64) -> (u8, u64) {
+    ((difficulty >> 56) as u8, difficulty & 0x00ff_ffff_ffff_ffff)
+}
+
+/// Derive the AES key for the nonce group.
+/// AES key = blake3(challenge, nonce_group, pow)
+pub fn cipher_key(challenge: &[u8; 32], nonce_group: u32, pow: u64) -> [u8; 16] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(challenge);
+    hasher.update(&nonce_group.to_le_bytes());
+    hasher.update(&pow.to_le_bytes());
+    hasher.finalize().as_bytes()[..16].try_into().unwrap()
+}
+
+/// Derive the AES key for checking the LSB part of the difficulty for a nonce.
+/// AES key = blake3(challenge, nonce_group, pow, nonce)
+pub fn lazy_cipher_key(challenge: &[u8; 32], nonce: u32, nonce_group: u32, pow: u64) -> [u8; 16] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(challenge);
+    hasher.update(&nonce_group.to_le_bytes());
+    hasher.update(&pow.to_le_bytes());
+    hasher.update(&nonce.to_le_bytes());
+    hasher.finalize().as_bytes()[..16].try_into().unwrap()
+}
+
+/// A label that doesn't satisfy the difficulty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelError {
+    InvalidMsb { msb: u8, difficulty_msb: u8 },
+    InvalidLsb { lsb: u64, difficulty_lsb: u64 },
+}
+
+/// Checks labels against the difficulty for a single nonce.
+pub struct LabelChecker {
+    cipher: Aes128,
+    lazy_cipher: Aes128,
+    output_index: usize,
+    difficulty_msb: u8,
+    difficulty_lsb: u64,
+}
+
+impl LabelChecker {
+    pub fn new(challenge: &[u8; 32], nonce: u32, pow: u64, difficulty: u64) -> Self {
+        let nonce_group = nonce / NONCES_PER_AES;
+        let (difficulty_msb, difficulty_lsb) = split_difficulty(difficulty);
+        Self {
+            cipher: Aes128::new(&cipher_key(challenge, nonce_group, pow).into()),
+            lazy_cipher: Aes128::new(&lazy_cipher_key(challenge, nonce, nonce_group, pow).into()),
+            output_index: (nonce % NONCES_PER_AES) as usize,
+            difficulty_msb,
+            difficulty_lsb,
+        }
+    }
+
+    /// The value of the label compared with the difficulty: the MSB in the top 8 bits
+    /// and the LSB in the lower 56 bits. The label satisfies the difficulty
+    /// if the value is below it.
+    ///
+    /// Unlike [LabelChecker::check], it always computes the LSB.
+    pub fn value(&self, label: &[u8; 16]) -> u64 {
+        let mut output = GenericArray::from([0u8; 16]);
+        self.cipher
+            .encrypt_block_b2b(GenericArray::from_slice(label), &mut output);
+        let msb = output[self.output_index];
+        self.lazy_cipher
+            .encrypt_block_b2b(GenericArray::from_slice(label), &mut output);
+        let lsb = u64::from_le_bytes(output[..8].try_into().unwrap()) & 0x00ff_ffff_ffff_ffff;
+        (msb as u64) << 56 | lsb
+    }
+
+    /// The difficulty the labels are checked against.
+    pub fn difficulty(&self) -> u64 {
+        (self.difficulty_msb as u64) << 56 | self.difficulty_lsb
+    }
+
+    /// Check if the label satisfies the difficulty.
+    pub fn check(&self, label: &[u8; 16]) -> Result<(), LabelError> {
+        let mut output = GenericArray::from([0u8; 16]);
+        self.cipher
+            .encrypt_block_b2b(GenericArray::from_slice(label), &mut output);
+        self.check_msb(label, output[self.output_index])
+    }
+
+    /// Like [LabelChecker::check] for many labels, encrypting [AES_BATCH] of them at once
+    /// (like the prover), which is faster than checking them one by one.
+    ///
+    /// Fails with the position of the first label that doesn't satisfy the difficulty.
+    pub fn check_batch(&self, labels: &[[u8; 16]]) -> Result<(), (usize, LabelError)> {
+        let mut inputs = [GenericArray::from([0u8; 16]); AES_BATCH];
+        let mut outputs = [GenericArray::from([0u8; 16]); AES_BATCH];
+        for (batch, chunk) in labels.chunks(AES_BATCH).enumerate() {
+            for (input, label) in inputs.iter_mut().zip(chunk) {
+                *input = GenericArray::from(*label);
+            }
+            let n = chunk.len();
+            self.cipher
+                .encrypt_blocks_b2b(&inputs[..n], &mut outputs[..n])
+                .unwrap();
+            for (i, (label, output)) in chunk.iter().zip(&outputs).enumerate() {
+                self.check_msb(label, output[self.output_index])
+                    .map_err(|err| (batch * AES_BATCH + i, err))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the label given the MSB of its encryption.
+    fn check_msb(&self, label: &[u8; 16], msb: u8) -> Result<(), LabelError> {
+        if msb > self.difficulty_msb {
+            return Err(LabelError::InvalidMsb {
+                msb,
+                difficulty_msb: self.difficulty_msb,
+            });
+        }
+        if msb == self.difficulty_msb {
+            // Need to check LSB
+            let mut output = GenericArray::from([0u8; 16]);
+            self.lazy_cipher
+                .encrypt_block_b2b(GenericArray::from_slice(label), &mut output);
+            let lsb = u64::from_le_bytes(output[..8].try_into().unwrap()) & 0x00ff_ffff_ffff_ffff;
+            if lsb >= self.difficulty_lsb {
+                return Err(LabelError::InvalidLsb {
+                    lsb,
+                    difficulty_lsb: self.difficulty_lsb,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompress_index_out_of_bounds() {
+        assert_eq!(Some(0b101), decompress_index(&[0b0010_1101], 3, 0));
+        assert_eq!(Some(0b101), decompress_index(&[0b0010_1101], 3, 1));
+        assert_eq!(None, decompress_index(&[0b0010_1101], 3, 2));
+        assert_eq!(None, decompress_index(&[0xFF], 0, 0));
+        assert_eq!(None, decompress_index(&[0xFF; 9], 65, 0));
+    }
+
+    #[test]
+    fn split() {
+        assert_eq!(
+            (0xAB, 0x00CD_EF01_2345_6789),
+            split_difficulty(0xABCD_EF01_2345_6789)
+        );
+    }
+
+    #[test]
+    fn value_agrees_with_check() {
+        let difficulty = 0x20ff_ffff_ffff_ffff;
+        let checker = LabelChecker::new(&[7; 32], 3, 5, difficulty);
+        assert_eq!(difficulty, checker.difficulty());
+        for i in 0..1000u64 {
+            let mut label = [0u8; 16];
+            label[..8].copy_from_slice(&i.to_le_bytes());
+            let value = checker.value(&label);
+            assert_eq!(value < difficulty, checker.check(&label).is_ok());
+        }
+    }
+
+    #[test]
+    fn batched_check_agrees_with_check() {
+        let difficulty = 0x20ff_ffff_ffff_ffff;
+        let checker = LabelChecker::new(&[7; 32], 3, 5, difficulty);
+        let labels = (0..100u64)
+            .map(|i| {
+                let mut label = [0u8; 16];
+                label[..8].copy_from_slice(&i.to_le_bytes());
+                label
+            })
+            .collect::<Vec<_>>();
+        for start in 0..labels.len() {
+            for len in [0, 1, 7, 8, 9, 17, 100] {
+                let labels = &labels[start..(start + len).min(labels.len())];
+                let expected = labels
+                    .iter()
+                    .enumerate()
+                    .find_map(|(i, label)| checker.check(label).err().map(|err| (i, err)));
+                assert_eq!(expected.map_or(Ok(()), Err), checker.check_batch(labels));
+            }
+        }
+    }
+}